@@ -0,0 +1,90 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use deribit_api::bench::Dispatcher;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::hint::black_box;
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+
+const WORKERS: usize = 4;
+
+// A feed subscribed to the book and trades of many instruments
+fn channels(count: usize) -> Vec<String> {
+    (0..count)
+        .flat_map(|i| {
+            [
+                format!("book.BTC-{i}JUN26-C.100ms"),
+                format!("trades.BTC-{i}JUN26-C.100ms"),
+            ]
+        })
+        .collect()
+}
+
+// Routing by channel name, as before channel keys: the name is hashed with SipHash once
+// to pick the worker and again to find its subscribers
+fn route_by_name(by_name: &HashMap<String, usize>, channel: &str) -> (usize, bool) {
+    let mut hasher = DefaultHasher::new();
+    channel.hash(&mut hasher);
+    let worker = (hasher.finish() % WORKERS as u64) as usize;
+    (worker, by_name.contains_key(channel))
+}
+
+fn route(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route");
+    for count in [10, 500] {
+        let channels = channels(count);
+        let dispatcher = Dispatcher::new(channels.clone());
+        let by_name = channels
+            .iter()
+            .enumerate()
+            .map(|(id, channel)| (channel.clone(), id))
+            .collect::<HashMap<_, _>>();
+
+        group.bench_with_input(
+            BenchmarkId::new("by_name", count),
+            &channels,
+            |b, channels| {
+                b.iter(|| {
+                    for channel in channels {
+                        black_box(route_by_name(&by_name, black_box(channel)));
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("by_key", count),
+            &channels,
+            |b, channels| {
+                b.iter(|| {
+                    for channel in channels {
+                        black_box(dispatcher.route(black_box(channel), WORKERS));
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn dispatch(c: &mut Criterion) {
+    let channels = channels(500);
+    let frames = channels
+        .iter()
+        .map(|channel| {
+            Utf8Bytes::from(format!(
+                r#"{{"jsonrpc":"2.0","method":"subscription","params":{{"channel":"{channel}","data":{{"timestamp":1755765833825,"change_id":1,"bids":[["new",64123.5,10.0]],"asks":[]}}}}}}"#
+            ))
+        })
+        .collect::<Vec<_>>();
+    let mut dispatcher = Dispatcher::new(channels);
+
+    c.bench_function("dispatch", |b| {
+        b.iter(|| {
+            for frame in &frames {
+                dispatcher.dispatch(black_box(frame));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, route, dispatch);
+criterion_main!(benches);
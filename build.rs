@@ -1,47 +1,192 @@
 use anyhow::{Result, anyhow};
-use proc_macro2::TokenStream;
+use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote};
 use serde_json::{Map, Value};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const PROD_API_SPEC_URL: &str = "https://www.deribit.com/static/deribit_api_v2.json";
 const TESTNET_API_SPEC_URL: &str = "https://test.deribit.com/static/deribit_api_v2.json";
 
+// Method tags that say who may call a method rather than what it is about
+const ACCESS_TAGS: &[&str] = &[
+    "public",
+    "private",
+    "internal",
+    "websocket_only",
+    "deprecated",
+];
+// Categories listed first in the API index, the rest follow alphabetically
+const MAIN_CATEGORIES: &[&str] = &["market_data", "trading", "wallet", "account_management"];
+// Parameters several methods share: the struct generated for each group, the field it
+// is flattened into and the parameters it holds. A method gets a group if it has all its
+// parameters with the types and requiredness most such methods have them with, and
+// none of them is taken by an earlier group.
+const PARAM_GROUPS: &[(&str, &str, &[&str])] = &[
+    ("OffsetPagination", "pagination", &["count", "offset"]),
+    (
+        "ContinuationPagination",
+        "pagination",
+        &["count", "continuation"],
+    ),
+    (
+        "TimeRange",
+        "time_range",
+        &["start_timestamp", "end_timestamp"],
+    ),
+    ("CurrencyKind", "currency_kind", &["currency", "kind"]),
+];
+// The canonical currency enum, with every currency of the per-endpoint currency enums
+const CURRENCY_ENUM: &str = "Currency";
+// Values of currency enums that stand for several currencies rather than one
+const NOT_CURRENCIES: &[&str] = &["any", "grouped", "cross"];
+
 #[derive(Debug)]
 struct ApiMethod {
     name: String,
     params: Vec<Parameter>,
     response_type: TokenStream,
+    // Spec tags, e.g. `private` and `trading`
+    tags: Vec<String>,
+    // `RequiredScope` expression, for private methods the spec gives scopes for
+    scope: Option<TokenStream>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Parameter {
     name: String,
     param_type: TokenStream,
     required: bool,
 }
 
+/// Extra derives for generated types, read from `[package.metadata.deribit-api]` or
+/// `[workspace.metadata.deribit-api]`:
+///
+/// ```toml
+/// [package.metadata.deribit-api]
+/// derives = ["Eq"]
+///
+/// [package.metadata.deribit-api.type-derives]
+/// Instrument = ["utoipa::ToSchema"]
+/// ```
+#[derive(Default)]
+struct DeriveConfig {
+    all: Vec<syn::Path>,
+    by_type: HashMap<String, Vec<syn::Path>>,
+}
+
+impl DeriveConfig {
+    fn from_metadata(metadata: &toml::Value) -> Result<Self> {
+        fn paths(value: &toml::Value) -> Result<Vec<syn::Path>> {
+            value
+                .as_array()
+                .ok_or_else(|| anyhow!("expected an array of derive paths"))?
+                .iter()
+                .map(|path| {
+                    let path = path
+                        .as_str()
+                        .ok_or_else(|| anyhow!("expected a derive path string"))?;
+                    syn::parse_str(path).map_err(|e| anyhow!("invalid derive path {path}: {e}"))
+                })
+                .collect()
+        }
+
+        let mut config = Self::default();
+        if let Some(all) = metadata.get("derives") {
+            config.all = paths(all)?;
+        }
+        if let Some(by_type) = metadata.get("type-derives").and_then(|t| t.as_table()) {
+            for (type_name, derives) in by_type {
+                config.by_type.insert(type_name.clone(), paths(derives)?);
+            }
+        }
+        Ok(config)
+    }
+
+    // Build scripts don't get the dependent crate's manifest, so this uses the closest
+    // manifest above the target directory, which is where the dependent workspace lives
+    // unless `CARGO_TARGET_DIR` points elsewhere
+    fn load() -> Result<Self> {
+        let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+        for dir in out_dir.ancestors() {
+            let manifest_path = dir.join("Cargo.toml");
+            let Ok(manifest) = fs::read_to_string(&manifest_path) else {
+                continue;
+            };
+            println!("cargo:rerun-if-changed={}", manifest_path.display());
+            let Ok(manifest) = toml::from_str::<toml::Value>(&manifest) else {
+                continue;
+            };
+            let metadata = ["package", "workspace"]
+                .iter()
+                .find_map(|section| manifest.get(section)?.get("metadata")?.get("deribit-api"));
+            if let Some(metadata) = metadata {
+                return Self::from_metadata(metadata);
+            }
+        }
+        Ok(Self::default())
+    }
+
+    fn attribute(&self, type_name: &str) -> TokenStream {
+        self.attribute_except(type_name, &[])
+    }
+
+    // Like `attribute`, leaving out the derives the type already has, e.g. `Eq`
+    fn attribute_except(&self, type_name: &str, derived: &[&str]) -> TokenStream {
+        let derives = self
+            .all
+            .iter()
+            .chain(self.by_type.get(type_name).into_iter().flatten())
+            .filter(|path| {
+                path.segments
+                    .last()
+                    .is_none_or(|segment| !derived.iter().any(|name| segment.ident == name))
+            })
+            .collect::<Vec<_>>();
+        if derives.is_empty() {
+            quote! {}
+        } else {
+            quote! { #[derive(#(#derives),*)] }
+        }
+    }
+}
+
 struct DeribitApiGen {
     spec: Value,
+    derives: DeriveConfig,
+    // Methods missing from the other environment's spec, with the `SpecEnvironment`
+    // variant they are exclusive to
+    exclusive: HashMap<String, &'static str>,
+    // Values of each generated enum
+    enums: BTreeMap<String, Vec<String>>,
     generated_code: TokenStream,
     generated_types: HashSet<String>,
+    // Request types per category and channel types per channel pattern, for the API index
+    request_index: BTreeMap<String, Vec<(String, String)>>,
+    channel_index: Vec<(String, String)>,
     ref_names: HashMap<String, String>,
 }
 
 impl DeribitApiGen {
-    fn new(spec_url: &str) -> Result<Self> {
-        // Download API spec
-        let spec = Self::download_api_spec(spec_url)?;
+    fn new(
+        spec: Value,
+        derives: DeriveConfig,
+        exclusive: HashMap<String, &'static str>,
+    ) -> Result<Self> {
         let generated_code = TokenStream::new();
         let generated_types = HashSet::new();
         let ref_names = HashMap::new();
         let mut api_gen = Self {
             spec,
+            derives,
+            exclusive,
+            enums: BTreeMap::new(),
             generated_code,
             generated_types,
+            request_index: BTreeMap::new(),
+            channel_index: Vec::new(),
             ref_names,
         };
 
@@ -49,6 +194,8 @@ impl DeribitApiGen {
         api_gen.generate_ref_names();
         api_gen.generate_methods()?;
         api_gen.generate_subscription_code();
+        api_gen.generate_currency_code();
+        api_gen.generate_api_index();
         Ok(api_gen)
     }
 
@@ -135,11 +282,20 @@ impl DeribitApiGen {
 
                 let params = self.extract_parameters(method_name, method_spec);
                 let response_type = self.extract_response_type(method_name, method_spec);
+                let tags = method_spec
+                    .get("tags")
+                    .and_then(|t| t.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|t| Some(t.as_str()?.to_string()))
+                    .collect();
 
                 Some(ApiMethod {
                     name: method_name.to_string(),
                     params,
                     response_type,
+                    tags,
+                    scope: required_scope(method_spec),
                 })
             })
             .collect();
@@ -193,7 +349,15 @@ impl DeribitApiGen {
                             .and_then(|r| r.as_bool())
                             .unwrap_or(false);
                         let schema = param_obj.get("schema")?.as_object()?;
-                        let param_type = self.determine_type(&type_name, schema);
+                        let param_type =
+                            money_type(param_name, self.determine_type(&type_name, schema));
+                        // Parameters are often described next to their schema
+                        let param_type =
+                            if param_type.to_string() == "i64" && is_timestamp(&param_obj) {
+                                quote! { crate::Timestamp }
+                            } else {
+                                param_type
+                            };
 
                         Some(Parameter {
                             name: param_name.to_string(),
@@ -250,7 +414,7 @@ impl DeribitApiGen {
                                         .and_then(|properties| {
                                             value.as_object().map(|p| {
                                                 let mut properties = properties.clone();
-                                                properties.extend(p.clone().into_iter());
+                                                properties.extend(p.clone());
                                                 Value::Object(properties)
                                             })
                                         })
@@ -264,7 +428,7 @@ impl DeribitApiGen {
                                         .and_then(|required| {
                                             value.as_array().map(|r| {
                                                 let mut required = required.clone();
-                                                required.extend(r.clone().into_iter());
+                                                required.extend(r.clone());
                                                 Value::Array(required)
                                             })
                                         })
@@ -281,15 +445,26 @@ impl DeribitApiGen {
             return self.determine_type(&type_name, &schema);
         }
 
-        let schema_type = schema.get("type").and_then(|t| t.as_str()).or_else(|| {
-            if schema.contains_key("properties") {
-                Some("object")
-            } else if schema.contains_key("items") {
-                Some("array")
-            } else {
-                None
-            }
-        });
+        // The spec occasionally uses non-standard type names (e.g. "list" for an object
+        // with properties), in which case the type is inferred like for untyped schemas
+        let schema_type = schema
+            .get("type")
+            .and_then(|t| t.as_str())
+            .filter(|t| {
+                matches!(
+                    *t,
+                    "string" | "integer" | "number" | "boolean" | "array" | "object"
+                )
+            })
+            .or_else(|| {
+                if schema.contains_key("properties") {
+                    Some("object")
+                } else if schema.contains_key("items") {
+                    Some("array")
+                } else {
+                    None
+                }
+            });
 
         match schema_type {
             Some("string") => {
@@ -297,34 +472,24 @@ impl DeribitApiGen {
                     let enum_name = format_ident!("{}", to_valid_pascal_case(&type_name));
 
                     if self.generated_types.insert(enum_name.to_string()) {
-                        let enum_values = enum_values
+                        let values = enum_values
                             .iter()
-                            .map(|v| {
-                                let value = v
-                                    .as_str()
-                                    .map(|s| s.to_string())
-                                    .unwrap_or_else(|| v.to_string());
-                                let value_name = format_ident!("{}", to_valid_pascal_case(&value));
-                                quote! {
-                                    #[serde(rename = #value)]
-                                    #value_name
-                                }
-                            })
+                            .map(|v| v.as_str().map_or_else(|| v.to_string(), String::from))
                             .collect::<Vec<_>>();
-
-                        self.generated_code.extend(quote! {
-                            #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
-                            pub enum #enum_name {
-                                #[default]
-                                #(#enum_values),*
-                            }
-                        });
+                        // `Currency` is generated last, with the values of all currency
+                        // enums, see `generate_currency_code`
+                        if enum_name != CURRENCY_ENUM {
+                            let tokens = self.enum_tokens(&enum_name.to_string(), &values);
+                            self.generated_code.extend(tokens);
+                        }
+                        self.enums.insert(enum_name.to_string(), values);
                     }
                     quote! { #enum_name }
                 } else {
                     quote! { String }
                 }
             }
+            Some("integer") if is_timestamp(&schema) => quote! { crate::Timestamp },
             Some("integer") => quote! { i64 },
             Some("number") => quote! { f64 },
             Some("boolean") => quote! { bool },
@@ -396,9 +561,12 @@ impl DeribitApiGen {
                                         .get("required")
                                         .and_then(|r| r.as_bool())
                                         .unwrap_or(false);
-                                    let property_type = self.determine_type(
-                                        &property_type_name,
-                                        property.get("schema")?.as_object()?,
+                                    let property_type = money_type(
+                                        key,
+                                        self.determine_type(
+                                            &property_type_name,
+                                            property.get("schema")?.as_object()?,
+                                        ),
                                     );
                                     Some(field_tokens(
                                         key,
@@ -421,15 +589,18 @@ impl DeribitApiGen {
                                 }
                                 properties_tokens.push(field_tokens(
                                     key,
-                                    &property_type,
+                                    &money_type(key, property_type),
                                     required_properties.contains(&key.as_str()),
                                 ));
                             }
                             properties_tokens
                         };
 
+                        let extra_derives = self.derives.attribute(&struct_name.to_string());
                         self.generated_code.extend(quote! {
                             #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+                            #extra_derives
+                            #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
                             pub struct #struct_name {
                                 #(#properties),*
                             }
@@ -444,21 +615,208 @@ impl DeribitApiGen {
         }
     }
 
+    // Generates the structs of `PARAM_GROUPS` shared by at least two methods, returning
+    // each with its field name and parameters
+    fn generate_param_groups(
+        &mut self,
+        methods: &[ApiMethod],
+    ) -> Vec<(&'static str, &'static str, Vec<Parameter>)> {
+        let mut groups = Vec::new();
+        for &(group_name, field_name, names) in PARAM_GROUPS {
+            let mut signatures: Vec<(Vec<&Parameter>, usize)> = Vec::new();
+            for method in methods {
+                let Some(params) = names
+                    .iter()
+                    .map(|name| method.params.iter().find(|param| param.name == *name))
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    continue;
+                };
+                match signatures
+                    .iter_mut()
+                    .find(|(signature, _)| same_params(signature, &params))
+                {
+                    Some((_, count)) => *count += 1,
+                    None => signatures.push((params, 1)),
+                }
+            }
+            // The first of the most common signatures
+            let Some((params, count)) =
+                signatures.into_iter().rev().max_by_key(|(_, count)| *count)
+            else {
+                continue;
+            };
+            if count < 2 {
+                continue;
+            }
+            let params = params.into_iter().cloned().collect::<Vec<_>>();
+            let struct_name = format_ident!("{}", group_name);
+            let fields = params
+                .iter()
+                .map(|param| field_tokens(&param.name, &param.param_type, param.required));
+            let extra_derives = self.derives.attribute(group_name);
+            let builder = builder_tokens(
+                &struct_name,
+                params.iter().map(|param| {
+                    let field = format_ident!("{}", to_valid_snake_case(&param.name));
+                    (param, quote! { #field })
+                }),
+            );
+            self.generated_code.extend(quote! {
+                #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+                #extra_derives
+                #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+                pub struct #struct_name {
+                    #(#fields),*
+                }
+
+                #builder
+            });
+            groups.push((group_name, field_name, params));
+        }
+        groups
+    }
+
     fn generate_methods(&mut self) -> Result<()> {
-        for method in self.extract_methods()? {
+        let methods = self.extract_methods()?;
+        let groups = self.generate_param_groups(&methods);
+        // `DeribitApiExt` methods are named without the `public/` or `private/` prefix,
+        // unless that leaves two with the same name, e.g. `subscribe`
+        let short_name = |name: &str| name.rsplit('/').next().unwrap_or(name).to_string();
+        let mut short_names = HashMap::<String, usize>::new();
+        for method in &methods {
+            *short_names.entry(short_name(&method.name)).or_default() += 1;
+        }
+        let mut api_methods = Vec::new();
+        for method in methods {
             let struct_name = format_ident!("{}Request", to_valid_pascal_case(&method.name));
             let method_name = &method.name;
             let response_type = &method.response_type;
 
+            // Parameters of a group are replaced by the group's struct, flattened in place
+            // of the first of them
+            let mut params = method.params.iter().map(Some).collect::<Vec<_>>();
+            let mut flattened = Vec::new();
+            let mut group_impls = TokenStream::new();
+            // Every parameter with where it is assigned, for the builder methods
+            let mut targets = Vec::new();
+            for (group_name, field_name, group_params) in &groups {
+                let positions = group_params
+                    .iter()
+                    .map(|group_param| {
+                        params.iter().position(|param| {
+                            param.is_some_and(|param| same_params(&[group_param], &[param]))
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>();
+                let Some(positions) = positions else {
+                    continue;
+                };
+                let first = positions.iter().copied().min().unwrap_or_default();
+                let group = format_ident!("{}", group_name);
+                let field = format_ident!("{}", field_name);
+                for (position, group_param) in positions.into_iter().zip(group_params) {
+                    params[position] = None;
+                    let param_field = format_ident!("{}", to_valid_snake_case(&group_param.name));
+                    targets.push((position, group_param, quote! { #field.#param_field }));
+                }
+                flattened.push((
+                    first,
+                    quote! {
+                        #[serde(flatten)]
+                        pub #field: #group
+                    },
+                ));
+                group_impls.extend(quote! {
+                    impl crate::ParamGroup<#group> for #struct_name {
+                        fn group(&self) -> &#group {
+                            &self.#field
+                        }
+                        fn group_mut(&mut self) -> &mut #group {
+                            &mut self.#field
+                        }
+                    }
+                });
+            }
+
+            targets.extend(params.iter().enumerate().filter_map(|(position, param)| {
+                let param = (*param)?;
+                let field = format_ident!("{}", to_valid_snake_case(&param.name));
+                Some((position, param, quote! { #field }))
+            }));
+            targets.sort_by_key(|(position, _, _)| *position);
+            let targets = targets
+                .into_iter()
+                .map(|(_, param, target)| (param, target))
+                .collect::<Vec<_>>();
+            let builder = builder_tokens(&struct_name, targets.iter().cloned());
+
             // Generate fields
-            let fields = method
-                .params
+            let mut fields = params
                 .iter()
-                .map(|param| field_tokens(&param.name, &param.param_type, param.required))
+                .enumerate()
+                .filter_map(|(position, param)| {
+                    let param = (*param)?;
+                    let tokens = field_tokens(&param.name, &param.param_type, param.required);
+                    Some((position, tokens))
+                })
                 .collect::<Vec<_>>();
+            fields.extend(flattened);
+            fields.sort_by_key(|(position, _)| *position);
+            let fields = fields.into_iter().map(|(_, tokens)| tokens);
+
+            let mut categories = method
+                .tags
+                .iter()
+                .filter(|tag| !ACCESS_TAGS.contains(&tag.as_str()))
+                .peekable();
+            if categories.peek().is_none() {
+                self.request_index
+                    .entry("other".to_string())
+                    .or_default()
+                    .push((method_name.clone(), struct_name.to_string()));
+            }
+            for category in categories {
+                self.request_index
+                    .entry(category.clone())
+                    .or_default()
+                    .push((method_name.clone(), struct_name.to_string()));
+            }
 
+            let extra_derives = self.derives.attribute(&struct_name.to_string());
+            let exclusive = self.exclusive.get(method_name);
+            let exclusive_note = exclusive.map(|environment| {
+                let (only, missing) = match *environment {
+                    "Production" => ("production", "testnet"),
+                    _ => ("testnet", "production"),
+                };
+                format!(
+                    "Only available on {only}: `{method_name}` is missing from the {missing} spec."
+                )
+            });
+            let struct_note = exclusive_note
+                .as_ref()
+                .map(|note| quote! { #[doc = #note] });
+            let method_note = exclusive_note.map(|note| quote! { #[doc = ""] #[doc = #note] });
+            let exclusive_to = exclusive.map(|environment| {
+                let environment = format_ident!("{environment}");
+                quote! {
+                    const EXCLUSIVE_TO: Option<crate::SpecEnvironment> =
+                        Some(crate::SpecEnvironment::#environment);
+                }
+            });
+            let required_scope = method.scope.as_ref().map(|scope| {
+                quote! {
+                    fn required_scope(&self) -> Option<crate::RequiredScope> {
+                        Some(#scope)
+                    }
+                }
+            });
             self.generated_code.extend(quote! {
                 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+                #extra_derives
+                #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+                #struct_note
                 pub struct #struct_name {
                     #(#fields),*
                 }
@@ -468,12 +826,319 @@ impl DeribitApiGen {
                     fn method_name(&self) -> &'static str {
                         #method_name
                     }
+                    #required_scope
+                    #exclusive_to
                 }
+
+                #builder
+
+                #group_impls
             });
+
+            let api_name = match short_names[&short_name(method_name)] {
+                1 => short_name(method_name),
+                _ => method_name.replace('/', "_"),
+            };
+            let api_name = format_ident!("{}", to_valid_snake_case(&api_name));
+            let doc = format!("Calls `{method_name}`, see [`{struct_name}`].");
+            let mut args = Vec::new();
+            let mut new_args = Vec::new();
+            let mut optional = Vec::new();
+            for (param, target) in &targets {
+                let name = format_ident!("{}", to_valid_snake_case(&param.name));
+                let field_type = &param.param_type;
+                if !param.required {
+                    args.push(quote! { #name: Option<#field_type> });
+                    optional.push(quote! { request.#target = #name; });
+                } else if field_type.to_string() == "String" {
+                    args.push(quote! { #name: impl Into<String> });
+                    new_args.push(quote! { #name });
+                } else {
+                    args.push(quote! { #name: #field_type });
+                    new_args.push(quote! { #name });
+                }
+            }
+            let signature = quote! {
+                fn #api_name(
+                    &self,
+                    #(#args),*
+                ) -> impl std::future::Future<Output = crate::Result<#response_type>> + Send
+            };
+            let request_mut = (!optional.is_empty()).then(|| quote! { mut });
+            api_methods.push((
+                quote! {
+                    #[doc = #doc]
+                    #method_note
+                    #[allow(clippy::too_many_arguments)]
+                    #signature;
+                },
+                quote! {
+                    #[allow(clippy::too_many_arguments)]
+                    #signature {
+                        let #request_mut request = #struct_name::new(#(#new_args),*);
+                        #(#optional)*
+                        self.call(request)
+                    }
+                },
+            ));
         }
+
+        let (declarations, implementations): (Vec<_>, Vec<_>) = api_methods.into_iter().unzip();
+        self.generated_code.extend(quote! {
+            /// One method per endpoint for every `DeribitApi`, taking its parameters in
+            /// order, optional ones as `Option`s, so scripts can make calls without
+            /// building request structs, e.g. `client.get_order_book("BTC-PERPETUAL", Some(5))`.
+            ///
+            /// Methods are named after the endpoint without its `public/` or `private/`
+            /// prefix, unless two endpoints would share the name, e.g. `public_subscribe`.
+            /// Where `DeribitClient` has an inherent method of the same name, e.g.
+            /// `reset_mmp`, that one is called; call the endpoint's with
+            /// `DeribitApiExt::reset_mmp(&client, ..)`.
+            pub trait DeribitApiExt: crate::DeribitApi {
+                #(#declarations)*
+            }
+
+            impl<A: crate::DeribitApi + ?Sized> DeribitApiExt for A {
+                #(#implementations)*
+            }
+        });
         Ok(())
     }
 
+    fn enum_tokens(&self, enum_name: &str, values: &[String]) -> TokenStream {
+        // Values added to the API after the spec was generated decode into a catch-all
+        // variant instead of failing the whole message, and the enum is
+        // `#[non_exhaustive]` so a rebuild from a newer spec adding variants doesn't break
+        // matches downstream
+        let unknown_name = unknown_variant(values);
+        let value_names = values
+            .iter()
+            .map(|value| format_ident!("{}", to_valid_pascal_case(value)))
+            .collect::<Vec<_>>();
+        let enum_values = values
+            .iter()
+            .zip(&value_names)
+            .map(|(value, value_name)| {
+                quote! {
+                    #[serde(rename = #value)]
+                    #value_name
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let record_unknown = format!("crate::diagnostics::record_unknown::<{enum_name}, _>");
+        let enum_name_str = enum_name;
+        // `Copy` is out of reach because of the catch-all's `String`
+        let extra_derives = self.derives.attribute_except(enum_name, &["Eq", "Hash"]);
+        let enum_name = format_ident!("{}", enum_name);
+        quote! {
+            #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+            #extra_derives
+            #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+            #[non_exhaustive]
+            pub enum #enum_name {
+                #[default]
+                #(#enum_values,)*
+                /// A value missing from the API spec, reported by `DeribitClient::diagnostics`.
+                #[serde(untagged, deserialize_with = #record_unknown)]
+                #unknown_name(String)
+            }
+
+            /// The value as sent over the wire.
+            impl std::fmt::Display for #enum_name {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    f.write_str(match self {
+                        #(Self::#value_names => #values,)*
+                        Self::#unknown_name(value) => value,
+                    })
+                }
+            }
+
+            /// Parses the value as sent over the wire. Values missing from the
+            /// spec are rejected; only decoding falls back to the catch-all variant.
+            impl std::str::FromStr for #enum_name {
+                type Err = crate::ParseEnumError;
+
+                fn from_str(value: &str) -> Result<Self, Self::Err> {
+                    match value {
+                        #(#values => Ok(Self::#value_names),)*
+                        _ => Err(crate::ParseEnumError {
+                            value: value.to_string(),
+                            target: #enum_name_str,
+                        }),
+                    }
+                }
+            }
+        }
+    }
+
+    // Generates `Currency` with the currencies of all currency enums, the spec's own
+    // `currency` type first, and conversions between it and each of the others. Variants
+    // of the same currency have the same name in all of them, e.g. `Btc` for `BTC` and
+    // `btc`.
+    fn generate_currency_code(&mut self) {
+        let others = self
+            .enums
+            .keys()
+            .filter(|name| *name != CURRENCY_ENUM && is_currency_enum(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut currencies = self.enums.get(CURRENCY_ENUM).cloned().unwrap_or_default();
+        let extra = others
+            .iter()
+            .flat_map(|name| &self.enums[name])
+            .filter(|value| is_currency(value))
+            .map(|value| value.to_uppercase())
+            .filter(|value| !currencies.contains(value))
+            .collect::<BTreeSet<_>>();
+        currencies.extend(extra);
+        if currencies.is_empty() {
+            return;
+        }
+        let tokens = self.enum_tokens(CURRENCY_ENUM, &currencies);
+        self.generated_code.extend(tokens);
+        let name = CURRENCY_ENUM.to_string();
+        self.enums.insert(name, currencies.clone());
+
+        let currency_unknown = unknown_variant(&currencies);
+        for name in others {
+            let values = &self.enums[&name];
+            let enum_name = format_ident!("{}", name);
+            let unknown_name = unknown_variant(values);
+            let shared = values
+                .iter()
+                .filter(|value| is_currency(value))
+                .map(|value| format_ident!("{}", to_valid_pascal_case(value)))
+                .collect::<Vec<_>>();
+
+            // From the enum, which fails for values like `any`
+            let from_unknown = quote! {
+                #enum_name::#unknown_name(value) => {
+                    let value = value.to_uppercase();
+                    value.parse().unwrap_or(Self::#currency_unknown(value))
+                }
+            };
+            self.generated_code
+                .extend(if values.iter().all(|value| is_currency(value)) {
+                    quote! {
+                        impl From<#enum_name> for Currency {
+                            fn from(value: #enum_name) -> Self {
+                                match value {
+                                    #(#enum_name::#shared => Self::#shared,)*
+                                    #from_unknown
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    quote! {
+                        impl TryFrom<#enum_name> for Currency {
+                            type Error = crate::UnsupportedCurrency;
+
+                            fn try_from(value: #enum_name) -> Result<Self, Self::Error> {
+                                Ok(match value {
+                                    #(#enum_name::#shared => Self::#shared,)*
+                                    #from_unknown
+                                    other => {
+                                        return Err(crate::UnsupportedCurrency {
+                                            value: other.to_string(),
+                                            target: #CURRENCY_ENUM,
+                                        });
+                                    }
+                                })
+                            }
+                        }
+                    }
+                });
+
+            // Into the enum, which fails for currencies it doesn't list. Currencies
+            // missing from the spec are passed on, like when decoding them, in the case
+            // of the enum's own values.
+            let lowercase = values
+                .iter()
+                .filter(|value| is_currency(value))
+                .all(|value| *value == value.to_lowercase());
+            let into_unknown = if lowercase {
+                quote! {
+                    Currency::#currency_unknown(value) => Self::#unknown_name(value.to_lowercase())
+                }
+            } else {
+                quote! {
+                    Currency::#currency_unknown(value) => Self::#unknown_name(value)
+                }
+            };
+            self.generated_code
+                .extend(if shared.len() == currencies.len() {
+                    quote! {
+                        impl From<Currency> for #enum_name {
+                            fn from(value: Currency) -> Self {
+                                match value {
+                                    #(Currency::#shared => Self::#shared,)*
+                                    #into_unknown,
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    quote! {
+                        impl TryFrom<Currency> for #enum_name {
+                            type Error = crate::UnsupportedCurrency;
+
+                            fn try_from(value: Currency) -> Result<Self, Self::Error> {
+                                Ok(match value {
+                                    #(Currency::#shared => Self::#shared,)*
+                                    #into_unknown,
+                                    other => {
+                                        return Err(crate::UnsupportedCurrency {
+                                            value: other.to_string(),
+                                            target: #name,
+                                        });
+                                    }
+                                })
+                            }
+                        }
+                    }
+                });
+        }
+    }
+
+    // Documents an empty `api_index` module listing the request types by the category
+    // tags of the spec, and the subscription channel types, so they can be browsed on
+    // docs.rs instead of in one flat namespace
+    fn generate_api_index(&mut self) {
+        let mut categories = self.request_index.keys().collect::<Vec<_>>();
+        categories.sort_by_key(|category| {
+            let main = MAIN_CATEGORIES.iter().position(|main| main == category);
+            (main.unwrap_or(MAIN_CATEGORIES.len()), *category == "other")
+        });
+
+        let mut doc = String::from(
+            "Index of the generated request and subscription types, grouped by the \
+             categories of the API spec. A method may appear in several categories.\n",
+        );
+        for category in categories {
+            let title = category.replace('_', " ").replace("rfq", "RFQ");
+            let mut title_chars = title.chars();
+            let title = title_chars
+                .next()
+                .map(|first| first.to_uppercase().chain(title_chars).collect::<String>())
+                .unwrap_or_default();
+            doc.push_str(&format!("\n# {title}\n\n"));
+            for (method, request) in &self.request_index[category] {
+                doc.push_str(&format!("- `{method}`: [`{request}`]\n"));
+            }
+        }
+        doc.push_str("\n# Subscriptions\n\n");
+        for (channel, subscription) in &self.channel_index {
+            doc.push_str(&format!("- `{channel}`: [`{subscription}`]\n"));
+        }
+
+        self.generated_code.extend(quote! {
+            #[doc = #doc]
+            pub mod api_index {}
+        });
+    }
+
     fn get_client_code(&self) -> String {
         // Convert TokenStream to syn::File for prettyplease
         if let Ok(file) = syn::parse2::<syn::File>(self.generated_code.clone()) {
@@ -536,8 +1201,14 @@ impl DeribitApiGen {
                 })
                 .collect::<Vec<_>>();
 
+            let entry = (channel_key.clone(), channel_struct_name.to_string());
+            self.channel_index.push(entry);
+
+            let extra_derives = self.derives.attribute(&channel_struct_name.to_string());
             self.generated_code.extend(quote! {
                 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+                #extra_derives
+                #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
                 pub struct #channel_struct_name {
                     #(#fields_tokens),*
                 }
@@ -553,6 +1224,75 @@ impl DeribitApiGen {
     }
 }
 
+// Names of the methods in `spec`, e.g. `public/get_time`
+fn method_names(spec: &Value) -> BTreeSet<String> {
+    spec.get("paths")
+        .and_then(|p| p.as_object())
+        .into_iter()
+        .flatten()
+        .filter(|(_, path_spec)| path_spec.get("get").is_some())
+        .map(|(path, _)| path.trim_start_matches('/').to_string())
+        .collect()
+}
+
+// Enum values of `enums` missing from `other`, as (enum, value) pairs
+fn missing_enum_values(
+    enums: &BTreeMap<String, Vec<String>>,
+    other: &BTreeMap<String, Vec<String>>,
+) -> Vec<(String, String)> {
+    enums
+        .iter()
+        .flat_map(|(name, values)| {
+            let other_values = other.get(name);
+            values
+                .iter()
+                .filter(move |value| other_values.is_none_or(|other| !other.contains(value)))
+                .map(move |value| (name.clone(), value.clone()))
+        })
+        .collect()
+}
+
+// Methods and enum values present in only one of the specs, see `parity::SpecDiff`
+#[derive(Debug, PartialEq)]
+struct SpecDiff {
+    prod_only_methods: Vec<String>,
+    testnet_only_methods: Vec<String>,
+    prod_only_enum_values: Vec<(String, String)>,
+    testnet_only_enum_values: Vec<(String, String)>,
+}
+
+impl SpecDiff {
+    fn new(prod_gen: &DeribitApiGen, testnet_gen: &DeribitApiGen) -> Self {
+        let prod_methods = method_names(&prod_gen.spec);
+        let testnet_methods = method_names(&testnet_gen.spec);
+        Self {
+            prod_only_methods: prod_methods.difference(&testnet_methods).cloned().collect(),
+            testnet_only_methods: testnet_methods.difference(&prod_methods).cloned().collect(),
+            prod_only_enum_values: missing_enum_values(&prod_gen.enums, &testnet_gen.enums),
+            testnet_only_enum_values: missing_enum_values(&testnet_gen.enums, &prod_gen.enums),
+        }
+    }
+}
+
+// `parity::SpecDiff` expression describing how the specs differ
+fn spec_diff_code(diff: &SpecDiff) -> String {
+    let prod_only_methods = &diff.prod_only_methods;
+    let testnet_only_methods = &diff.testnet_only_methods;
+    let (prod_enums, prod_values): (Vec<_>, Vec<_>) =
+        diff.prod_only_enum_values.iter().cloned().unzip();
+    let (testnet_enums, testnet_values): (Vec<_>, Vec<_>) =
+        diff.testnet_only_enum_values.iter().cloned().unzip();
+    quote! {
+        crate::parity::SpecDiff {
+            prod_only_methods: &[#(#prod_only_methods),*],
+            testnet_only_methods: &[#(#testnet_only_methods),*],
+            prod_only_enum_values: &[#((#prod_enums, #prod_values)),*],
+            testnet_only_enum_values: &[#((#testnet_enums, #testnet_values)),*],
+        }
+    }
+    .to_string()
+}
+
 fn get_deep_value<'a>(path: &Vec<&str>, value: &'a Value) -> Option<&'a Value> {
     let mut value = value;
     for key in path {
@@ -561,6 +1301,143 @@ fn get_deep_value<'a>(path: &Vec<&str>, value: &'a Value) -> Option<&'a Value> {
     Some(value)
 }
 
+// The scopes named by the `scopes` note of a method, any of which will do, e.g.
+// "`trade:read_write` or `block_rfq:read_write` (when `block_rfq` = `true`)" or
+// "`wallet:read_write` and mainaccount". Alternatives needing different access aren't
+// expressed, so such a method isn't checked
+fn required_scope(method_spec: &Value) -> Option<TokenStream> {
+    let note = method_spec.get("scopes")?.as_str()?;
+    let mut areas = Vec::new();
+    let mut accesses = Vec::new();
+    // Scopes are quoted, as are the parameters the alternatives depend on
+    for (area, access) in note
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .filter_map(|s| s.split_once(':'))
+    {
+        // A few methods say `wallets`
+        areas.push(if area == "wallets" { "wallet" } else { area });
+        accesses.push(access);
+    }
+    let access = *accesses.first()?;
+    if accesses.iter().any(|other| *other != access) {
+        return None;
+    }
+    let write = access == "read_write";
+    let main_account = note.contains("mainaccount");
+    Some(quote! {
+        crate::RequiredScope {
+            areas: &[#(#areas),*],
+            write: #write,
+            main_account: #main_account,
+        }
+    })
+}
+
+// A `new` taking the required parameters, in order, and a setter for each optional one,
+// each assigned to its target, e.g. `pagination.count` for one of a flattened group
+fn builder_tokens<'a>(
+    struct_name: &proc_macro2::Ident,
+    params: impl Iterator<Item = (&'a Parameter, TokenStream)>,
+) -> TokenStream {
+    let mut args = Vec::new();
+    let mut assignments = Vec::new();
+    let mut setters = Vec::new();
+    for (param, target) in params {
+        let name = format_ident!("{}", to_valid_snake_case(&param.name));
+        let field_type = &param.param_type;
+        // Strings are taken as `impl Into<String>`, so literals need no `to_string`
+        let (arg_type, value) = if field_type.to_string() == "String" {
+            (quote! { impl Into<String> }, quote! { #name.into() })
+        } else {
+            (quote! { #field_type }, quote! { #name })
+        };
+        if param.required {
+            args.push(quote! { #name: #arg_type });
+            assignments.push(quote! { this.#target = #value; });
+        } else {
+            setters.push(quote! {
+                pub fn #name(mut self, #name: #arg_type) -> Self {
+                    self.#target = Some(#value);
+                    self
+                }
+            });
+        }
+    }
+    quote! {
+        impl #struct_name {
+            #[allow(clippy::too_many_arguments, clippy::field_reassign_with_default)]
+            pub fn new(#(#args),*) -> Self {
+                #[allow(unused_mut)]
+                let mut this = <Self as Default>::default();
+                #(#assignments)*
+                this
+            }
+
+            #(#setters)*
+        }
+    }
+}
+
+// `crate::Money` for numbers named like prices, amounts, fees and balances, e.g.
+// `mark_price`, which is `f64` or, with the `decimal` feature, `Decimal`
+fn money_type(name: &str, field_type: TokenStream) -> TokenStream {
+    let monetary = matches!(
+        name.rsplit('_').next(),
+        Some("price" | "amount" | "fee" | "fees" | "balance")
+    );
+    if monetary && field_type.to_string() == "f64" {
+        quote! { crate::Money }
+    } else {
+        field_type
+    }
+}
+
+// The catch-all variant of an enum with `values`, `Other` if a value is named `Unknown`
+fn unknown_variant(values: &[String]) -> Ident {
+    if values
+        .iter()
+        .any(|value| to_valid_pascal_case(value) == "Unknown")
+    {
+        format_ident!("Other")
+    } else {
+        format_ident!("Unknown")
+    }
+}
+
+// Whether an enum lists currencies, e.g. `WalletCurrency` or `CurrencyWithAny`
+fn is_currency_enum(name: &str) -> bool {
+    name.ends_with("Currency") || name.contains("CurrencyWith")
+}
+
+// Whether a value of a currency enum is a currency rather than e.g. `any`
+fn is_currency(value: &str) -> bool {
+    !NOT_CURRENCIES.contains(&value.to_lowercase().as_str())
+}
+
+// Whether the spec describes an integer as milliseconds since the Unix epoch, e.g.
+// `creation_timestamp`
+fn is_timestamp(schema: &Map<String, Value>) -> bool {
+    schema
+        .get("description")
+        .and_then(|d| d.as_str())
+        .is_some_and(|d| {
+            d.to_lowercase()
+                .contains("milliseconds since the unix epoch")
+        })
+}
+
+// Whether two parameter lists have the same names, types and requiredness
+fn same_params(a: &[&Parameter], b: &[&Parameter]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.name == b.name
+                && a.required == b.required
+                && a.param_type.to_string() == b.param_type.to_string()
+        })
+}
+
 fn field_tokens(name: &str, field_type: &TokenStream, required: bool) -> TokenStream {
     let mut tokens = TokenStream::new();
     let field_name = format_ident!("{}", to_valid_snake_case(name));
@@ -571,6 +1448,17 @@ fn field_tokens(name: &str, field_type: &TokenStream, required: bool) -> TokenSt
         });
     }
 
+    // `serde_json::Value` has no `Arbitrary` impl, so such fields are built from arbitrary JSON
+    if field_type
+        .to_string()
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .any(|ident| ident == "Value")
+    {
+        tokens.extend(quote! {
+            #[cfg_attr(feature = "fuzz", arbitrary(with = crate::fuzz::arbitrary_json))]
+        });
+    }
+
     if required {
         tokens.extend(quote! {
             #[serde(default)]
@@ -688,6 +1576,10 @@ fn get_prod_spec_url() -> String {
     env::var("DERIBIT_API_SPEC").unwrap_or(PROD_API_SPEC_URL.to_string())
 }
 
+fn get_testnet_spec_url() -> String {
+    env::var("DERIBIT_TESTNET_API_SPEC").unwrap_or(TESTNET_API_SPEC_URL.to_string())
+}
+
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     // Rebuild if manifest changes (we read an optional spec URL from it)
@@ -700,10 +1592,38 @@ fn main() {
     // Feature flags are passed through env as CARGO_FEATURE_<FEATURE_NAME>
     println!("cargo:rerun-if-env-changed=CARGO_FEATURE_TESTNET");
     println!("cargo:rerun-if-env-changed=CARGO_FEATURE_BUNDLED_SPEC");
+    println!("cargo:rerun-if-env-changed=CARGO_FEATURE_PARITY_WARNINGS");
+    println!("cargo:rerun-if-env-changed=DERIBIT_TESTNET_API_SPEC");
 
     let out_dir = env::var("OUT_DIR").unwrap();
-    let prod_spec_url = get_prod_spec_url();
-    let prod_gen = DeribitApiGen::new(&prod_spec_url).unwrap();
+    let prod_spec = DeribitApiGen::download_api_spec(&get_prod_spec_url()).unwrap();
+    let testnet_spec = env::var("CARGO_FEATURE_TESTNET")
+        .is_ok()
+        .then(|| DeribitApiGen::download_api_spec(&get_testnet_spec_url()).unwrap());
+
+    // Request types of methods missing from the other environment get a doc note and
+    // `ApiRequest::EXCLUSIVE_TO`, with `parity-warnings` they are listed as build warnings
+    let (mut prod_exclusive, mut testnet_exclusive) = (HashMap::new(), HashMap::new());
+    if let Some(testnet_spec) = &testnet_spec {
+        let prod_methods = method_names(&prod_spec);
+        let testnet_methods = method_names(testnet_spec);
+        let warn = env::var("CARGO_FEATURE_PARITY_WARNINGS").is_ok();
+        for method in prod_methods.difference(&testnet_methods) {
+            if warn {
+                println!("cargo:warning={method} is only available on production");
+            }
+            prod_exclusive.insert(method.clone(), "Production");
+        }
+        for method in testnet_methods.difference(&prod_methods) {
+            if warn {
+                println!("cargo:warning={method} is only available on testnet");
+            }
+            testnet_exclusive.insert(method.clone(), "Testnet");
+        }
+    }
+
+    let prod_gen =
+        DeribitApiGen::new(prod_spec, DeriveConfig::load().unwrap(), prod_exclusive).unwrap();
     let dest_prod = Path::new(&out_dir).join("deribit_client_prod.rs");
     fs::write(&dest_prod, prod_gen.get_client_code()).unwrap();
     // Env var for discoverability (points to prod by convention)
@@ -712,9 +1632,110 @@ fn main() {
         dest_prod.display()
     );
 
-    if env::var("CARGO_FEATURE_TESTNET").is_ok() {
-        let testnet_gen = DeribitApiGen::new(TESTNET_API_SPEC_URL).unwrap();
+    if let Some(testnet_spec) = testnet_spec {
+        let testnet_gen = DeribitApiGen::new(
+            testnet_spec,
+            DeriveConfig::load().unwrap(),
+            testnet_exclusive,
+        )
+        .unwrap();
         let dest_testnet = Path::new(&out_dir).join("deribit_client_testnet.rs");
         fs::write(&dest_testnet, testnet_gen.get_client_code()).unwrap();
+        let dest_diff = Path::new(&out_dir).join("deribit_spec_diff.rs");
+        fs::write(
+            &dest_diff,
+            spec_diff_code(&SpecDiff::new(&prod_gen, &testnet_gen)),
+        )
+        .unwrap();
+    }
+}
+
+// Run by `tests/build_script.rs`, which includes this file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A spec with the given methods, each taking a `kind` parameter with the given values
+    fn fixture_spec(methods: &[(&str, &[&str])]) -> Value {
+        let paths = methods
+            .iter()
+            .map(|(method, kinds)| {
+                let path = json!({
+                    "get": {
+                        "parameters": [{
+                            "name": "kind",
+                            "in": "query",
+                            "required": true,
+                            "schema": { "type": "string", "enum": kinds },
+                        }],
+                        "responses": { "200": { "content": { "application/json": { "schema": {
+                            "allOf": [{ "properties": { "result": { "type": "string" } } }],
+                        } } } } },
+                        "tags": ["public"],
+                    }
+                });
+                (format!("/{method}"), path)
+            })
+            .collect::<Map<_, _>>();
+        json!({
+            "components": { "schemas": { "types": {} }, "parameters": {} },
+            "paths": paths,
+        })
+    }
+
+    fn generate(spec: Value, exclusive: &[(&str, &'static str)]) -> DeribitApiGen {
+        let exclusive = exclusive
+            .iter()
+            .map(|(method, environment)| (method.to_string(), *environment))
+            .collect();
+        DeribitApiGen::new(spec, DeriveConfig::default(), exclusive).unwrap()
+    }
+
+    #[test]
+    fn spec_diff_lists_methods_and_values_in_one_spec_only() {
+        let prod = generate(
+            fixture_spec(&[
+                ("public/get_book", &["future", "option"]),
+                ("public/get_legacy", &["spot"]),
+            ]),
+            &[("public/get_legacy", "Production")],
+        );
+        let testnet = generate(
+            fixture_spec(&[
+                ("public/get_book", &["future", "option", "combo"]),
+                ("public/get_preview", &["spot"]),
+            ]),
+            &[("public/get_preview", "Testnet")],
+        );
+
+        let diff = SpecDiff::new(&prod, &testnet);
+        assert_eq!(
+            diff,
+            SpecDiff {
+                prod_only_methods: vec!["public/get_legacy".to_string()],
+                testnet_only_methods: vec!["public/get_preview".to_string()],
+                prod_only_enum_values: vec![(
+                    "PublicGetLegacyKind".to_string(),
+                    "spot".to_string()
+                )],
+                testnet_only_enum_values: vec![
+                    ("PublicGetBookKind".to_string(), "combo".to_string()),
+                    ("PublicGetPreviewKind".to_string(), "spot".to_string()),
+                ],
+            }
+        );
+        assert!(SpecDiff::new(&prod, &prod).prod_only_methods.is_empty());
+
+        let code = spec_diff_code(&diff);
+        assert!(code.contains(r#"prod_only_methods : & ["public/get_legacy"]"#));
+
+        // Exclusive methods are marked with a doc note and constant, not deprecated
+        let code = prod.generated_code.to_string();
+        assert!(code.contains(
+            "Only available on production: `public/get_legacy` is missing from the testnet spec."
+        ));
+        assert!(code.contains("Some (crate :: SpecEnvironment :: Production)"));
+        assert!(!code.contains("deprecated"));
     }
 }
@@ -0,0 +1,218 @@
+//! `#[derive(ApiRequest)]` and `#[derive(Subscription)]` for user-defined Deribit
+//! requests and channels, re-exported by `deribit-api` with its `derive` feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{Data, DeriveInput, Fields, Ident, LitStr, Type, parse_macro_input};
+
+/// Implements `deribit_api::ApiRequest` for a `Serialize` struct holding the params of an
+/// endpoint, e.g. one newer than the crate's spec:
+///
+/// ```ignore
+/// #[derive(Serialize, ApiRequest)]
+/// #[api_request(method = "public/get_new_thing", response = Vec<String>)]
+/// struct GetNewThing {
+///     currency: String,
+/// }
+/// ```
+///
+/// `method` is the full method name, `response` the type its result deserializes into
+/// (`serde_json::Value` if it isn't modelled).
+#[proc_macro_derive(ApiRequest, attributes(api_request))]
+pub fn derive_api_request(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_api_request(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_api_request(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut method = None;
+    let mut response = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("api_request"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("method") {
+                method = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("response") {
+                response = Some(meta.value()?.parse::<Type>()?);
+            } else {
+                return Err(meta.error("expected `method` or `response`"));
+            }
+            Ok(())
+        })?;
+    }
+    let method = method.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing `#[api_request(method = \"...\")]` attribute",
+        )
+    })?;
+    // `ApiRequest::is_private` and the client's routing rely on the scope prefix
+    if !["public/", "private/"]
+        .iter()
+        .any(|scope| method.value().starts_with(scope))
+    {
+        return Err(syn::Error::new_spanned(
+            &method,
+            "method must start with `public/` or `private/`",
+        ));
+    }
+    let response = response.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing `#[api_request(response = ...)]` attribute",
+        )
+    })?;
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::deribit_api::ApiRequest for #name #ty_generics #where_clause {
+            type Response = #response;
+
+            fn method_name(&self) -> &'static str {
+                #method
+            }
+        }
+    })
+}
+
+/// Implements `deribit_api::Subscription` for a struct holding the parameters of a
+/// channel, e.g. an undocumented one:
+///
+/// ```ignore
+/// #[derive(Subscription)]
+/// #[subscription(channel = "new_thing.{instrument_name}.{interval}", data = NewThing)]
+/// struct NewThingChannel {
+///     instrument_name: String,
+///     interval: SubscriptionInterval,
+/// }
+/// ```
+///
+/// Each `{field}` in `channel`, `{type}` for `r#type`, is replaced with the field's value,
+/// stringified like the parameters of the spec's channels. `data` is the type
+/// notifications deserialize into.
+#[proc_macro_derive(Subscription, attributes(subscription))]
+pub fn derive_subscription(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_subscription(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_subscription(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut channel = None;
+    let mut data = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("subscription"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("channel") {
+                channel = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("data") {
+                data = Some(meta.value()?.parse::<Type>()?);
+            } else {
+                return Err(meta.error("expected `channel` or `data`"));
+            }
+            Ok(())
+        })?;
+    }
+    let channel = channel.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing `#[subscription(channel = \"...\")]` attribute",
+        )
+    })?;
+    let data = data.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing `#[subscription(data = ...)]` attribute",
+        )
+    })?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .filter_map(|field| field.ident.as_ref())
+                .collect(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "channel parameters must be named fields",
+                ));
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "`Subscription` can only be derived for structs",
+            ));
+        }
+    };
+    let parts = template_parts(&channel, &fields)?
+        .into_iter()
+        .map(|part| match part {
+            Part::Literal(text) => quote! { #text },
+            Part::Field(field) => quote! { &::deribit_api::sub_param_to_string(&self.#field) },
+        });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::deribit_api::Subscription for #name #ty_generics #where_clause {
+            type Data = #data;
+
+            fn channel_string(&self) -> String {
+                let mut channel = String::new();
+                #(channel.push_str(#parts);)*
+                channel
+            }
+        }
+    })
+}
+
+enum Part<'a> {
+    Literal(String),
+    Field(&'a Ident),
+}
+
+// Splits a channel template into literal text and `{field}` placeholders, checking that
+// each placeholder names one of `fields`
+fn template_parts<'a>(template: &LitStr, fields: &[&'a Ident]) -> syn::Result<Vec<Part<'a>>> {
+    let error = |message: String| syn::Error::new_spanned(template, message);
+    let mut parts = Vec::new();
+    let mut rest = template.value();
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(error("unmatched `}` in channel".to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| error("unmatched `{` in channel".to_string()))?;
+        let name = &rest[start + 1..end];
+        let field = fields
+            .iter()
+            .find(|field| field.unraw() == name)
+            .ok_or_else(|| error(format!("channel parameter `{name}` is not a field")))?;
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        parts.push(Part::Field(field));
+        rest = rest[end + 1..].to_string();
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest));
+    }
+    Ok(parts)
+}
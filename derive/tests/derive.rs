@@ -0,0 +1,92 @@
+use deribit_api::{ApiRequest, Subscription, SubscriptionInterval};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+struct Thing {
+    name: String,
+}
+
+#[derive(Serialize, ApiRequest)]
+#[api_request(method = "private/get_things", response = Vec<Thing>)]
+struct GetThings {
+    currency: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<u32>,
+}
+
+#[derive(Serialize, ApiRequest)]
+#[api_request(method = "public/echo", response = serde_json::Value)]
+struct Echo<T: Serialize> {
+    value: T,
+}
+
+// The client only needs the trait, so derived requests work with `call`
+fn response_of<T: ApiRequest>(_: &T, result: serde_json::Value) -> T::Response {
+    serde_json::from_value(result).unwrap()
+}
+
+#[test]
+fn derived_requests_carry_method_and_response() {
+    let req = GetThings {
+        currency: "BTC".to_string(),
+        count: None,
+    };
+    assert_eq!(req.method_name(), "private/get_things");
+    assert!(req.is_private());
+    assert_eq!(req.to_params(), json!({ "currency": "BTC" }));
+    assert_eq!(req.to_raw_params().get(), r#"{"currency":"BTC"}"#);
+    assert_eq!(
+        response_of(&req, json!([{ "name": "a" }])),
+        vec![Thing {
+            name: "a".to_string()
+        }]
+    );
+
+    let echo = Echo { value: 42 };
+    assert_eq!(echo.method_name(), "public/echo");
+    assert!(!echo.is_private());
+    assert_eq!(response_of(&echo, json!("x")), json!("x"));
+}
+
+#[derive(Subscription)]
+#[subscription(channel = "things.{instrument_name}.{interval}", data = Vec<Thing>)]
+struct ThingsChannel {
+    instrument_name: String,
+    interval: SubscriptionInterval,
+}
+
+#[derive(Subscription)]
+#[subscription(channel = "things.{type}.all", data = Thing)]
+struct TypedThingsChannel<T: Serialize> {
+    r#type: T,
+}
+
+#[derive(Subscription)]
+#[subscription(channel = "things", data = serde_json::Value)]
+struct AllThingsChannel;
+
+fn data_of<S: Subscription>(_: &S, data: serde_json::Value) -> S::Data {
+    serde_json::from_value(data).unwrap()
+}
+
+#[test]
+fn derived_subscriptions_fill_channel_templates() {
+    let channel = ThingsChannel {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        interval: SubscriptionInterval::_100ms,
+    };
+    assert_eq!(channel.channel_string(), "things.BTC-PERPETUAL.100ms");
+    assert_eq!(
+        data_of(&channel, json!([{ "name": "a" }])),
+        vec![Thing {
+            name: "a".to_string()
+        }]
+    );
+
+    assert_eq!(
+        TypedThingsChannel { r#type: 7 }.channel_string(),
+        "things.7.all"
+    );
+    assert_eq!(AllThingsChannel.channel_string(), "things");
+}
@@ -32,7 +32,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut stream = client.subscribe_raw("trades.BTC-PERPETUAL.raw").await?;
 
     while let Some(Ok(msg)) = stream.next().await {
-        println!("{:?}", msg);
+        println!("{}", msg.get());
     }
 
     Ok(())
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Raw bytes, as a misbehaving server or proxy could send them
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        deribit_api::fuzz::decode_message(text, 4);
+    }
+});
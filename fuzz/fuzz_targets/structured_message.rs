@@ -0,0 +1,12 @@
+#![no_main]
+
+use deribit_api::fuzz::arbitrary::Unstructured;
+use libfuzzer_sys::fuzz_target;
+
+// Well-formed JSON-RPC messages with arbitrary ids, channels and payloads
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    if let Ok(text) = deribit_api::fuzz::arbitrary_message(&mut u) {
+        deribit_api::fuzz::decode_message(&text, 4);
+    }
+});
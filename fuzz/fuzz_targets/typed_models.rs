@@ -0,0 +1,16 @@
+#![no_main]
+
+use deribit_api::{
+    ApiRequest, Order, PrivateBuyRequest, Subscription, TickerInstrumentNameChannel,
+};
+use libfuzzer_sys::fuzz_target;
+
+// Encoding of arbitrary requests and channels, and decoding of typed response data
+fuzz_target!(
+    |input: (PrivateBuyRequest, TickerInstrumentNameChannel, &[u8])| {
+        let (request, channel, data) = input;
+        let _ = request.to_params();
+        let _ = channel.channel_string();
+        let _ = serde_json::from_slice::<Order>(data);
+    }
+);
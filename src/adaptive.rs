@@ -0,0 +1,199 @@
+//! Subscriptions that step down to a coarser interval while the consumer can't keep up,
+//! see `DeribitClient::subscribe_adaptive`.
+
+use crate::{
+    DeribitClient, Error, PrivateUnsubscribeRequest, PublicUnsubscribeRequest, Result,
+    Subscription, SubscriptionInterval, SubscriptionStream,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// Channel interval suffixes, finest first
+const INTERVALS: [SubscriptionInterval; 3] = [
+    SubscriptionInterval::Raw,
+    SubscriptionInterval::_100ms,
+    SubscriptionInterval::Agg2,
+];
+
+/// When an adaptive subscription changes interval.
+#[derive(Debug, Clone)]
+pub struct AdaptiveIntervalConfig {
+    /// Period over which lag events are counted.
+    pub window: Duration,
+    /// Lag events within the window that step the interval down, raw to 100ms to agg2.
+    pub max_lag_events: usize,
+    /// Time without lag after which the interval steps back up, never finer than the
+    /// subscribed one. Checked as messages arrive.
+    pub recover_after: Duration,
+}
+
+impl Default for AdaptiveIntervalConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            max_lag_events: 3,
+            recover_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// An item of an adaptive subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdaptiveEvent<T> {
+    Message(T),
+    /// The subscription moved to `channel`. Messages that follow come at `interval`.
+    IntervalChanged {
+        channel: String,
+        interval: SubscriptionInterval,
+    },
+}
+
+struct Adaptive<S: Subscription> {
+    client: Arc<DeribitClient>,
+    config: AdaptiveIntervalConfig,
+    // Channel without the interval suffix
+    prefix: String,
+    finest: usize,
+    level: usize,
+    stream: SubscriptionStream<S::Data>,
+    lags: VecDeque<Instant>,
+    last_change: Instant,
+    _subscription: PhantomData<fn() -> S>,
+}
+
+impl<S: Subscription> Adaptive<S> {
+    fn channel(&self, level: usize) -> String {
+        format!(
+            "{}.{}",
+            self.prefix,
+            crate::sub_param_to_string(&INTERVALS[level])
+        )
+    }
+
+    // Subscribes at `level` before leaving the current channel, so no update is missed
+    async fn switch(&mut self, level: usize) -> Result<AdaptiveEvent<S::Data>> {
+        let previous = self.channel(self.level);
+        let channel = self.channel(level);
+        let published = self
+            .client
+            .subscribe_published(&channel, self.client.subscription_capacity(&channel))
+            .await?;
+        self.stream = self.client.decode_stream::<S>(channel.clone(), published);
+        self.level = level;
+        self.lags.clear();
+        self.last_change = Instant::now();
+        // The new channel is already flowing, so a failure here only costs bandwidth
+        if let Err(e) = self.client.unsubscribe_unused(&previous).await {
+            tracing::warn!(channel = previous, error = %e, "unsubscribe failed");
+        }
+        tracing::info!(
+            from = previous,
+            to = channel,
+            "subscription interval changed"
+        );
+        Ok(AdaptiveEvent::IntervalChanged {
+            channel,
+            interval: INTERVALS[level].clone(),
+        })
+    }
+}
+
+impl DeribitClient {
+    /// Like `subscribe`, but steps the channel's interval down (raw to 100ms to agg2)
+    /// after `max_lag_events` `Error::SubscriptionLagged` within the configured window,
+    /// and back up once the consumer has kept up for `recover_after`. Each change is
+    /// yielded as `AdaptiveEvent::IntervalChanged`; lag errors are still yielded too.
+    ///
+    /// The subscription's channel must end with an interval, e.g.
+    /// `book.BTC-PERPETUAL.raw`, or this fails with `Error::InvalidSubscriptionChannel`.
+    /// A channel left behind is unsubscribed unless other streams still receive it.
+    pub async fn subscribe_adaptive<S: Subscription + Send + 'static>(
+        self: &Arc<Self>,
+        subscription: S,
+        config: AdaptiveIntervalConfig,
+    ) -> Result<impl Stream<Item = Result<AdaptiveEvent<S::Data>>> + Send + 'static + use<S>> {
+        let channel = subscription.channel_string();
+        let (prefix, finest) = channel
+            .rsplit_once('.')
+            .and_then(|(prefix, interval)| {
+                let level = INTERVALS
+                    .iter()
+                    .position(|known| crate::sub_param_to_string(known) == interval)?;
+                Some((prefix.to_string(), level))
+            })
+            .ok_or_else(|| Error::InvalidSubscriptionChannel(channel.clone()))?;
+        let state = Adaptive::<S> {
+            client: self.clone(),
+            config,
+            prefix,
+            finest,
+            level: finest,
+            stream: self.subscribe(subscription).await?,
+            lags: VecDeque::new(),
+            last_change: Instant::now(),
+            _subscription: PhantomData,
+        };
+        Ok(futures_util::stream::unfold(
+            state,
+            |mut state| async move {
+                let now = Instant::now();
+                let recovered = state.lags.back().map_or(state.last_change, |&lag| lag);
+                if state.level > state.finest
+                    && now.duration_since(recovered) >= state.config.recover_after
+                {
+                    let event = state.switch(state.level - 1).await;
+                    return Some((event, state));
+                }
+                match state.stream.next().await? {
+                    Ok(msg) => Some((Ok(AdaptiveEvent::Message(msg)), state)),
+                    Err(Error::SubscriptionLagged(skipped)) => {
+                        let now = Instant::now();
+                        state.lags.push_back(now);
+                        while state
+                            .lags
+                            .front()
+                            .is_some_and(|&lag| now.duration_since(lag) > state.config.window)
+                        {
+                            state.lags.pop_front();
+                        }
+                        if state.lags.len() >= state.config.max_lag_events
+                            && state.level + 1 < INTERVALS.len()
+                        {
+                            let event = state.switch(state.level + 1).await;
+                            return Some((event, state));
+                        }
+                        Some((Err(Error::SubscriptionLagged(skipped)), state))
+                    }
+                    Err(e) => Some((Err(e), state)),
+                }
+            },
+        ))
+    }
+
+    // Unsubscribes from `channel` once no stream receives it anymore
+    async fn unsubscribe_unused(&self, channel: &str) -> Result<()> {
+        let unused = self
+            .subscription_stats
+            .lock()
+            .unwrap()
+            .get(channel)
+            .is_none_or(|stats| stats.snapshot(channel).subscribers == 0);
+        if !unused {
+            return Ok(());
+        }
+        let channels = vec![channel.to_string()];
+        if self.authenticated.load(Ordering::Acquire) {
+            self.call(PrivateUnsubscribeRequest { channels }).await?;
+        } else {
+            self.call(PublicUnsubscribeRequest { channels }).await?;
+        }
+        let mut session = self.session.lock().unwrap();
+        session.subscriptions.remove(channel);
+        session.session_subscriptions.remove(channel);
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! Managing the address book, and waiting for new addresses to be verified, see
+//! `DeribitClient::wait_for_address`.
+//!
+//! An address added with `private/add_to_address_book` starts out `waiting` for the
+//! account owner to confirm it by email (and 2FA, if enabled), may then be held
+//! `confirmed` for a cooling-off period, and is `ready` once transfers and withdrawals
+//! to it are allowed. Deribit support can `admin_locked` it at any point.
+
+use crate::{
+    AddressBookItem, AddressBookType, DeribitClient, PrivateAddToAddressBookRequest,
+    PrivateGetAddressBookRequest, PrivateRemoveFromAddressBookRequest, Result, Status,
+    WalletCurrency,
+};
+use futures_util::{Stream, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+
+impl AddressBookItem {
+    /// Whether transfers and withdrawals to this address are allowed.
+    pub fn is_ready(&self) -> bool {
+        self.status == Some(Status::Ready)
+    }
+
+    /// Whether the address still awaits the owner's confirmation or the end of its
+    /// cooling-off period.
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, Some(Status::Waiting | Status::Confirmed))
+    }
+}
+
+/// How waiting for an address to be verified ended, see
+/// `DeribitClient::wait_for_address`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressVerification {
+    /// The address can be used.
+    Ready(AddressBookItem),
+    /// Deribit support locked the address.
+    Locked(AddressBookItem),
+    /// The address left the address book, e.g. because the confirmation was declined.
+    Removed,
+    /// Still pending when the timeout expired, as last seen.
+    TimedOut(Option<AddressBookItem>),
+}
+
+impl DeribitClient {
+    /// The entry of `address` in the address book of `currency` and `type`, if any.
+    pub async fn address(
+        &self,
+        currency: WalletCurrency,
+        r#type: AddressBookType,
+        address: &str,
+    ) -> Result<Option<AddressBookItem>> {
+        let items = self
+            .call(PrivateGetAddressBookRequest { currency, r#type })
+            .await?;
+        Ok(items.into_iter().find(|item| item.address == address))
+    }
+
+    /// Adds an address to the address book, or returns its entry if it is already
+    /// there, so a retried request does not trigger a second confirmation email.
+    pub async fn add_address(
+        &self,
+        request: PrivateAddToAddressBookRequest,
+    ) -> Result<AddressBookItem> {
+        if let Some(item) = self
+            .address(
+                request.currency.clone(),
+                request.r#type.clone(),
+                &request.address,
+            )
+            .await?
+        {
+            return Ok(item);
+        }
+        self.call(request).await
+    }
+
+    /// Removes an address from the address book, returning whether it was there.
+    pub async fn remove_address(
+        &self,
+        currency: WalletCurrency,
+        r#type: AddressBookType,
+        address: &str,
+    ) -> Result<bool> {
+        if self
+            .address(currency.clone(), r#type.clone(), address)
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+        self.call(PrivateRemoveFromAddressBookRequest {
+            currency,
+            r#type,
+            address: address.to_string(),
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Polls the address book every `poll_every`, yielding the entry of `address` each
+    /// time its status changes, starting with the current one. `None` means it is not
+    /// in the address book. Ends after the address is ready, locked or gone.
+    pub fn address_status_changes(
+        self: &Arc<Self>,
+        currency: WalletCurrency,
+        r#type: AddressBookType,
+        address: &str,
+        poll_every: Duration,
+    ) -> impl Stream<Item = Result<Option<AddressBookItem>>> + Send + 'static + use<> {
+        let mut polls = tokio::time::interval(poll_every.max(Duration::from_millis(1)));
+        polls.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let query = (currency, r#type, address.to_string());
+        futures_util::stream::unfold(
+            (self.clone(), query, polls, None, false),
+            |(client, query, mut polls, last, done)| async move {
+                if done {
+                    return None;
+                }
+                loop {
+                    polls.tick().await;
+                    let (currency, r#type, address) = &query;
+                    let item = match client
+                        .address(currency.clone(), r#type.clone(), address)
+                        .await
+                    {
+                        Ok(item) => item,
+                        Err(e) => return Some((Err(e), (client, query, polls, last, false))),
+                    };
+                    let status = item.as_ref().map(|item| item.status.clone());
+                    if last.as_ref() == Some(&status) {
+                        continue;
+                    }
+                    let done = item.as_ref().is_none_or(|item| {
+                        item.is_ready() || item.status == Some(Status::AdminLocked)
+                    });
+                    return Some((Ok(item), (client, query, polls, Some(status), done)));
+                }
+            },
+        )
+    }
+
+    /// Waits up to `timeout` for `address` to be confirmed and leave its cooling-off
+    /// period, polling the address book every `poll_every`. Errors of the polls end the
+    /// wait.
+    pub async fn wait_for_address(
+        self: &Arc<Self>,
+        currency: WalletCurrency,
+        r#type: AddressBookType,
+        address: &str,
+        poll_every: Duration,
+        timeout: Duration,
+    ) -> Result<AddressVerification> {
+        let changes = self.address_status_changes(currency, r#type, address, poll_every);
+        let mut changes = std::pin::pin!(changes);
+        let mut last = None;
+        let waited = tokio::time::timeout(timeout, async {
+            while let Some(item) = changes.next().await {
+                match item? {
+                    Some(item) if item.is_ready() => return Ok(AddressVerification::Ready(item)),
+                    Some(item) if item.status == Some(Status::AdminLocked) => {
+                        return Ok(AddressVerification::Locked(item));
+                    }
+                    Some(item) => {
+                        tracing::debug!(address = item.address, status = ?item.status, "address pending");
+                        last = Some(item);
+                    }
+                    None => return Ok(AddressVerification::Removed),
+                }
+            }
+            Ok(AddressVerification::Removed)
+        })
+        .await;
+        waited.unwrap_or(Ok(AddressVerification::TimedOut(last)))
+    }
+}
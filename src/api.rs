@@ -0,0 +1,73 @@
+//! The calling surface of a client as a trait, see `DeribitApi`.
+//!
+//! Strategy code written against `DeribitApi` instead of `DeribitClient` runs unchanged
+//! on a `SandboxedClient`, and in tests on a mock that answers calls from canned
+//! responses and streams notifications built with `SubscriptionStream::from_json`. The
+//! generated `DeribitApiExt` adds a method per endpoint to every implementation.
+
+use crate::{ApiRequest, DeribitClient, Result, SandboxedClient, Subscription, SubscriptionStream};
+use std::future::Future;
+use std::sync::Arc;
+
+/// Makes calls and subscriptions, implemented by `DeribitClient` and `SandboxedClient`.
+pub trait DeribitApi: Send + Sync {
+    /// Makes a call, like `DeribitClient::call`.
+    fn call<T: ApiRequest + Send>(
+        &self,
+        request: T,
+    ) -> impl Future<Output = Result<T::Response>> + Send;
+
+    /// Subscribes to a channel, like `DeribitClient::subscribe`.
+    fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> impl Future<Output = Result<SubscriptionStream<S::Data>>> + Send;
+}
+
+impl DeribitApi for DeribitClient {
+    fn call<T: ApiRequest + Send>(
+        &self,
+        request: T,
+    ) -> impl Future<Output = Result<T::Response>> + Send {
+        DeribitClient::call(self, request)
+    }
+
+    fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> impl Future<Output = Result<SubscriptionStream<S::Data>>> + Send {
+        DeribitClient::subscribe(self, subscription)
+    }
+}
+
+impl DeribitApi for SandboxedClient {
+    fn call<T: ApiRequest + Send>(
+        &self,
+        request: T,
+    ) -> impl Future<Output = Result<T::Response>> + Send {
+        SandboxedClient::call(self, request)
+    }
+
+    fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> impl Future<Output = Result<SubscriptionStream<S::Data>>> + Send {
+        SandboxedClient::subscribe(self, subscription)
+    }
+}
+
+impl<A: DeribitApi + ?Sized> DeribitApi for Arc<A> {
+    fn call<T: ApiRequest + Send>(
+        &self,
+        request: T,
+    ) -> impl Future<Output = Result<T::Response>> + Send {
+        (**self).call(request)
+    }
+
+    fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> impl Future<Output = Result<SubscriptionStream<S::Data>>> + Send {
+        (**self).subscribe(subscription)
+    }
+}
@@ -0,0 +1,50 @@
+//! Benchmark entry points, enabled with the `bench` feature. Not a stable API.
+//!
+//! They expose the reader's internal steps to the criterion benchmarks in `benches/`,
+//! e.g. `cargo bench --features bench`.
+
+use crate::{ChannelKey, Published, Subscribers, json};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+
+/// Subscribers of a set of channels, fed notifications as the reader feeds them.
+pub struct Dispatcher {
+    subscribers: Subscribers,
+    _receivers: Vec<broadcast::Receiver<Arc<Published>>>,
+}
+
+impl Dispatcher {
+    pub fn new(channels: impl IntoIterator<Item = String>) -> Self {
+        let mut subscribers = Subscribers::default();
+        let receivers = channels
+            .into_iter()
+            .map(|channel| subscribers.subscribe(channel, 1, Default::default()))
+            .collect();
+        Self {
+            subscribers,
+            _receivers: receivers,
+        }
+    }
+
+    /// Routes a notification on `channel` as the reader does with decode workers: picks
+    /// one of `workers` and resolves the channel's subscribers there. Returns the worker
+    /// and whether the channel has subscribers.
+    pub fn route(&self, channel: &str, workers: usize) -> (usize, bool) {
+        let key = ChannelKey::new(channel);
+        (
+            key.shard(workers),
+            self.subscribers.id(key, channel).is_some(),
+        )
+    }
+
+    /// Parses a notification frame and publishes it to the channel's subscribers.
+    pub fn dispatch(&mut self, frame: &Utf8Bytes) {
+        if let Some(notification) = json::notification(frame) {
+            let channel = &notification.channel;
+            let key = ChannelKey::new(channel);
+            self.subscribers
+                .publish(key, channel, notification.data, frame.clone());
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Block trades agreed off the book, and Block RFQs, for both sides.
+//!
+//! A bilateral block trade is agreed on terms, a timestamp, a nonce and the trades with
+//! their directions from the maker's side. One party signs them with
+//! `private/verify_block_trade` and hands `SignedBlockTrade` to the other, which executes
+//! it with `private/execute_block_trade` under the opposite role. Executing requires the
+//! very same terms, and the shared timestamp and nonce make sure it happens once. A
+//! signature that is no longer wanted should be invalidated before it is used.
+//!
+//! A Block RFQ is created by a taker, quoted by makers, and traded by the taker accepting
+//! the best quote of a side, see `BlockRfqBuilder`, `DeribitClient::quote_block_rfq` and
+//! `DeribitClient::accept_block_rfq`.
+
+use crate::{
+    BlockRfq, BlockRfqLegsParam, BlockRfqLegsQuote, BlockRfqQuote, BlockRfqTradeLegs, BlockTrade,
+    BlockTradeTrades, DeribitClient, Direction, Money, PrivateAcceptBlockRfqRequest,
+    PrivateAcceptBlockRfqTimeInForce, PrivateAddBlockRfqQuoteRequest, PrivateCreateBlockRfqRequest,
+    PrivateExecuteBlockTradeRequest, PrivateInvalidateBlockTradeSignatureRequest,
+    PrivateVerifyBlockTradeRequest, Result, Role, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Keeps nonces of terms created within the same millisecond apart
+static NONCES: AtomicU64 = AtomicU64::new(0);
+
+/// What both parties of a block trade agree on, see `DeribitClient::sign_block_trade`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockTradeTerms {
+    /// When the terms were agreed; the exchange only accepts recent ones.
+    pub timestamp: Timestamp,
+    /// Makes the terms unique, so they execute once.
+    pub nonce: String,
+    /// The trades, with directions from the maker's side.
+    pub trades: Vec<BlockTradeTrades>,
+}
+
+impl Default for BlockTradeTerms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockTradeTerms {
+    /// Terms stamped now with a fresh nonce and no trades yet.
+    pub fn new() -> Self {
+        let timestamp = Timestamp::now();
+        let nonce = format!(
+            "{}-{timestamp}-{}",
+            std::process::id(),
+            NONCES.fetch_add(1, Ordering::Relaxed)
+        );
+        Self {
+            timestamp,
+            nonce,
+            trades: Vec::new(),
+        }
+    }
+
+    /// Adds a trade of `amount` of `instrument_name` at `price`, `direction` being the
+    /// maker's.
+    pub fn trade(
+        mut self,
+        instrument_name: impl Into<String>,
+        direction: Direction,
+        price: Money,
+        amount: Money,
+    ) -> Self {
+        self.trades.push(BlockTradeTrades {
+            instrument_name: instrument_name.into(),
+            price,
+            amount: Some(amount),
+            direction,
+        });
+        self
+    }
+}
+
+/// Block trade terms signed by one party, to hand to the other, see
+/// `DeribitClient::execute_block_trade`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedBlockTrade {
+    pub terms: BlockTradeTerms,
+    /// The role of the party that signed.
+    pub role: Role,
+    pub signature: String,
+}
+
+impl DeribitClient {
+    /// Signs `terms` as `role`, for the counterparty to execute.
+    pub async fn sign_block_trade(
+        &self,
+        terms: &BlockTradeTerms,
+        role: Role,
+    ) -> Result<SignedBlockTrade> {
+        let verified = self
+            .call(PrivateVerifyBlockTradeRequest {
+                timestamp: terms.timestamp,
+                nonce: terms.nonce.clone(),
+                role: role.clone(),
+                trades: terms.trades.clone(),
+            })
+            .await?;
+        Ok(SignedBlockTrade {
+            terms: terms.clone(),
+            role,
+            signature: verified.signature,
+        })
+    }
+
+    /// Executes a block trade the counterparty signed, taking the other role.
+    pub async fn execute_block_trade(&self, counterparty: &SignedBlockTrade) -> Result<BlockTrade> {
+        let role = match counterparty.role {
+            Role::Maker => Role::Taker,
+            _ => Role::Maker,
+        };
+        self.call(PrivateExecuteBlockTradeRequest {
+            timestamp: counterparty.terms.timestamp,
+            nonce: counterparty.terms.nonce.clone(),
+            role,
+            trades: counterparty.terms.trades.clone(),
+            counterparty_signature: counterparty.signature.clone(),
+        })
+        .await
+    }
+
+    /// Invalidates a signature made with `sign_block_trade`, so the counterparty can no
+    /// longer execute it.
+    pub async fn invalidate_block_trade_signature(&self, signed: &SignedBlockTrade) -> Result<()> {
+        self.call(PrivateInvalidateBlockTradeSignatureRequest {
+            signature: signed.signature.clone(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Quotes `rfq` as a maker: buying or selling its structure, as `direction` says, at
+    /// `leg_prices`, one per leg in order, for its whole amount.
+    pub async fn quote_block_rfq(
+        &self,
+        rfq: &BlockRfq,
+        direction: Direction,
+        leg_prices: &[Money],
+        label: Option<String>,
+    ) -> Result<BlockRfqQuote> {
+        let legs = rfq
+            .legs
+            .iter()
+            .flatten()
+            .zip(leg_prices)
+            .map(|(leg, price)| BlockRfqLegsQuote {
+                instrument_name: leg.instrument_name.clone().unwrap_or_default(),
+                price: *price,
+                ratio: leg.ratio.unwrap_or(1),
+                direction: leg.direction.clone().unwrap_or_default(),
+            })
+            .collect();
+        self.call(PrivateAddBlockRfqQuoteRequest {
+            label,
+            block_rfq_id: rfq.block_rfq_id.unwrap_or_default(),
+            amount: rfq.amount.unwrap_or_default(),
+            direction,
+            legs,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Trades `rfq` as its taker, buying or selling its structure, as `direction` says,
+    /// at the best quote of that side. Fill-or-kill: nothing trades unless the whole
+    /// amount does at `price` or better.
+    pub async fn accept_block_rfq(
+        &self,
+        rfq: &BlockRfq,
+        direction: Direction,
+        price: Money,
+        amount: Money,
+    ) -> Result<Value> {
+        let legs = rfq
+            .legs
+            .iter()
+            .flatten()
+            .map(|leg| BlockRfqTradeLegs {
+                instrument_name: leg.instrument_name.clone().unwrap_or_default(),
+                direction: leg.direction.clone().unwrap_or_default(),
+                ratio: leg.ratio.unwrap_or(1),
+            })
+            .collect();
+        self.call(PrivateAcceptBlockRfqRequest {
+            block_rfq_id: rfq.block_rfq_id.unwrap_or_default(),
+            price,
+            amount,
+            direction,
+            hedge: None,
+            legs,
+            time_in_force: PrivateAcceptBlockRfqTimeInForce::FillOrKill,
+        })
+        .await
+    }
+}
+
+impl BlockRfq {
+    /// The best quoted price for a taker `direction`: the lowest ask to buy, the highest
+    /// bid to sell.
+    pub fn best_price(&self, direction: &Direction) -> Option<Money> {
+        match direction {
+            Direction::Buy => self
+                .asks
+                .iter()
+                .flatten()
+                .filter_map(|ask| ask.price)
+                .reduce(|best, price| if price < best { price } else { best }),
+            _ => self
+                .bids
+                .iter()
+                .flatten()
+                .filter_map(|bid| bid.price)
+                .reduce(|best, price| if price > best { price } else { best }),
+        }
+    }
+}
+
+/// A Block RFQ a taker asks makers to quote, see `BlockRfqBuilder::create`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockRfqBuilder {
+    request: PrivateCreateBlockRfqRequest,
+}
+
+impl BlockRfqBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a leg of `amount` of `instrument_name`, bought or sold as `direction` says
+    /// when the structure is bought.
+    pub fn leg(
+        mut self,
+        instrument_name: impl Into<String>,
+        direction: Direction,
+        amount: Money,
+    ) -> Self {
+        self.request.legs.push(BlockRfqLegsParam {
+            instrument_name: instrument_name.into(),
+            amount,
+            direction,
+        });
+        self
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.request.label = Some(label.into());
+        self
+    }
+
+    /// Only asks these makers to quote.
+    pub fn makers(mut self, makers: Vec<String>) -> Self {
+        self.request.makers = Some(makers);
+        self
+    }
+
+    /// Shows the taker's name to the makers.
+    pub fn disclosed(mut self) -> Self {
+        self.request.disclosed = Some(true);
+        self
+    }
+
+    /// Creates the RFQ with `private/create_block_rfq`. Its quotes come with
+    /// `private/get_block_rfqs` or `block_rfq.taker.{currency}`; trade the best one
+    /// with `DeribitClient::accept_block_rfq`.
+    pub async fn create(&self, client: &DeribitClient) -> Result<BlockRfq> {
+        client.call(self.request.clone()).await
+    }
+}
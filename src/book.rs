@@ -0,0 +1,489 @@
+//! Local order books kept current from `book.{instrument}.{interval}` updates, see
+//! `DeribitClient::local_order_book`.
+//!
+//! Updates carry absolute amounts per price level and link to the previous update with
+//! `prev_change_id`, so a book can be rebuilt from any snapshot by applying the updates
+//! that follow it. A break in the links means updates were lost, and the book is
+//! fetched again with `public/get_order_book`.
+//!
+//! `BookDelta` carries the same updates in a compact form, for fanning them out within a
+//! process.
+//!
+//! `DeribitClient::poll_order_books` instead fetches whole books at a fixed interval,
+//! for periodic snapshots without following every change.
+
+use crate::{
+    BookInstrumentNameChannel, BookNotification, BookNotificationRaw, BookNotificationRawType,
+    DeribitClient, Error, PriceLevelUpdateAction, PublicGetOrderBookRequest, Result, Subscription,
+    SubscriptionInterval, SubscriptionStream, Timestamp,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Deepest book `public/get_order_book` returns
+const SNAPSHOT_DEPTH: i64 = 10_000;
+
+// Price as a map key, ordered with `f64::total_cmp`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Price(f64);
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// An order book as of `change_id`, built from a snapshot and the updates since.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalOrderBook {
+    pub instrument_name: String,
+    pub change_id: i64,
+    /// Time of the last update.
+    pub timestamp: Option<Timestamp>,
+    bids: BTreeMap<Price, f64>,
+    asks: BTreeMap<Price, f64>,
+}
+
+/// Price levels of a book, best first, e.g. to store or send it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BookSnapshot {
+    pub instrument_name: String,
+    pub change_id: i64,
+    pub timestamp: Option<Timestamp>,
+    /// `(price, amount)` pairs.
+    pub bids: Vec<(f64, f64)>,
+    pub asks: Vec<(f64, f64)>,
+}
+
+/// An update that doesn't follow the book: the changes after `change_id` up to
+/// `prev_change_id` are missing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BookGap {
+    pub change_id: i64,
+    pub prev_change_id: i64,
+}
+
+/// A notification of a `book.{instrument}.{interval}` channel in a compact form for
+/// fanning out to many consumers: prices and amounts in shared arrays, so clones are a
+/// few reference counts, and levels take 16 bytes instead of 40. New and changed levels
+/// are both an amount to set; deleted levels have amount zero.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "BookNotificationRaw", into = "BookNotificationRaw")]
+pub struct BookDelta {
+    pub instrument_name: Arc<str>,
+    pub change_id: i64,
+    pub prev_change_id: Option<i64>,
+    pub timestamp: Option<Timestamp>,
+    /// Whether the delta replaces the book rather than changing it.
+    pub snapshot: bool,
+    // Bid levels first, then ask levels
+    prices: Arc<[f64]>,
+    amounts: Arc<[f64]>,
+    bid_count: usize,
+}
+
+impl BookDelta {
+    pub fn bid_prices(&self) -> &[f64] {
+        &self.prices[..self.bid_count]
+    }
+
+    pub fn bid_amounts(&self) -> &[f64] {
+        &self.amounts[..self.bid_count]
+    }
+
+    pub fn ask_prices(&self) -> &[f64] {
+        &self.prices[self.bid_count..]
+    }
+
+    pub fn ask_amounts(&self) -> &[f64] {
+        &self.amounts[self.bid_count..]
+    }
+
+    /// Changed bid levels as `(price, amount)`.
+    pub fn bids(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.bid_prices()
+            .iter()
+            .copied()
+            .zip(self.bid_amounts().iter().copied())
+    }
+
+    /// Changed ask levels as `(price, amount)`.
+    pub fn asks(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.ask_prices()
+            .iter()
+            .copied()
+            .zip(self.ask_amounts().iter().copied())
+    }
+}
+
+impl From<&BookNotificationRaw> for BookDelta {
+    fn from(update: &BookNotificationRaw) -> Self {
+        let levels = update.bids.iter().chain(&update.asks);
+        let amount = |(action, _, amount): &(PriceLevelUpdateAction, f64, f64)| match action {
+            PriceLevelUpdateAction::Delete => 0.0,
+            _ => *amount,
+        };
+        Self {
+            instrument_name: update.instrument_name.as_str().into(),
+            change_id: update.change_id,
+            prev_change_id: update.prev_change_id,
+            timestamp: update.timestamp,
+            snapshot: update.r#type == Some(BookNotificationRawType::Snapshot),
+            prices: levels.clone().map(|(_, price, _)| *price).collect(),
+            amounts: levels.map(amount).collect(),
+            bid_count: update.bids.len(),
+        }
+    }
+}
+
+impl From<BookNotificationRaw> for BookDelta {
+    fn from(update: BookNotificationRaw) -> Self {
+        Self::from(&update)
+    }
+}
+
+impl From<BookDelta> for BookNotificationRaw {
+    fn from(delta: BookDelta) -> Self {
+        let level = |(price, amount): (f64, f64)| {
+            let action = if amount > 0.0 {
+                PriceLevelUpdateAction::Change
+            } else {
+                PriceLevelUpdateAction::Delete
+            };
+            (action, price, amount)
+        };
+        Self {
+            bids: delta.bids().map(level).collect(),
+            asks: delta.asks().map(level).collect(),
+            change_id: delta.change_id,
+            instrument_name: delta.instrument_name.to_string(),
+            prev_change_id: delta.prev_change_id,
+            timestamp: delta.timestamp,
+            r#type: Some(if delta.snapshot {
+                BookNotificationRawType::Snapshot
+            } else {
+                BookNotificationRawType::Change
+            }),
+        }
+    }
+}
+
+/// The `book.{instrument_name}.{interval}` channel decoded into `BookDelta`s. Typed
+/// subscribers of a channel share each decoded message, so any number of consumers
+/// subscribing to it share one delta per update.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookDeltaChannel {
+    pub instrument_name: String,
+    pub interval: SubscriptionInterval,
+}
+
+impl Subscription for BookDeltaChannel {
+    type Data = BookDelta;
+
+    fn channel_string(&self) -> String {
+        BookInstrumentNameChannel {
+            instrument_name: self.instrument_name.clone(),
+            interval: self.interval.clone(),
+        }
+        .channel_string()
+    }
+}
+
+impl LocalOrderBook {
+    /// Applies a notification of a `book.{instrument}.{interval}` channel, like
+    /// `apply_delta`.
+    pub fn apply(&mut self, update: &BookNotificationRaw) -> std::result::Result<bool, BookGap> {
+        self.apply_delta(&BookDelta::from(update))
+    }
+
+    /// Applies a delta: snapshots replace the book; changes apply if they link to it,
+    /// and are ignored if the book already contains them. Returns whether the book
+    /// changed.
+    pub fn apply_delta(&mut self, delta: &BookDelta) -> std::result::Result<bool, BookGap> {
+        if delta.snapshot {
+            *self = Self {
+                instrument_name: delta.instrument_name.to_string(),
+                ..Default::default()
+            };
+        } else if delta.change_id <= self.change_id {
+            return Ok(false);
+        } else if let Some(prev_change_id) = delta.prev_change_id
+            // Levels are set to absolute amounts, so an update overlapping the book is safe
+            && prev_change_id > self.change_id
+        {
+            return Err(BookGap {
+                change_id: self.change_id,
+                prev_change_id,
+            });
+        }
+        for (price, amount) in delta.bids() {
+            set_level(&mut self.bids, price, amount);
+        }
+        for (price, amount) in delta.asks() {
+            set_level(&mut self.asks, price, amount);
+        }
+        self.change_id = delta.change_id;
+        self.timestamp = delta.timestamp.or(self.timestamp);
+        Ok(true)
+    }
+
+    /// Highest bid as `(price, amount)`.
+    pub fn best_bid(&self) -> Option<(f64, f64)> {
+        self.bids().next()
+    }
+
+    /// Lowest ask as `(price, amount)`.
+    pub fn best_ask(&self) -> Option<(f64, f64)> {
+        self.asks().next()
+    }
+
+    pub fn mid_price(&self) -> Option<f64> {
+        Some((self.best_bid()?.0 + self.best_ask()?.0) / 2.0)
+    }
+
+    pub fn spread(&self) -> Option<f64> {
+        Some(self.best_ask()?.0 - self.best_bid()?.0)
+    }
+
+    /// Bid levels as `(price, amount)`, highest first.
+    pub fn bids(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.bids
+            .iter()
+            .rev()
+            .map(|(price, amount)| (price.0, *amount))
+    }
+
+    /// Ask levels as `(price, amount)`, lowest first.
+    pub fn asks(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.asks.iter().map(|(price, amount)| (price.0, *amount))
+    }
+
+    /// Total amount bid at `price` or higher, what a sell down to `price` could fill.
+    pub fn bid_amount_above(&self, price: f64) -> f64 {
+        self.bids
+            .range(Price(price)..)
+            .map(|(_, amount)| amount)
+            .sum()
+    }
+
+    /// Total amount offered at `price` or lower, what a buy up to `price` could fill.
+    pub fn ask_amount_below(&self, price: f64) -> f64 {
+        self.asks
+            .range(..=Price(price))
+            .map(|(_, amount)| amount)
+            .sum()
+    }
+
+    /// The best `levels` levels of each side, all of them if `None`.
+    pub fn snapshot(&self, levels: Option<usize>) -> BookSnapshot {
+        let levels = levels.unwrap_or(usize::MAX);
+        BookSnapshot {
+            instrument_name: self.instrument_name.clone(),
+            change_id: self.change_id,
+            timestamp: self.timestamp,
+            bids: self.bids().take(levels).collect(),
+            asks: self.asks().take(levels).collect(),
+        }
+    }
+
+    // Parses a `public/get_order_book` result, whose spec type lacks the change id
+    fn from_order_book(instrument_name: &str, result: &Value) -> Self {
+        let levels = |side: &str| {
+            result[side]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|level| Some((Price(level[0].as_f64()?), level[1].as_f64()?)))
+                .filter(|(_, amount)| *amount > 0.0)
+                .collect()
+        };
+        Self {
+            instrument_name: instrument_name.to_string(),
+            change_id: result["change_id"].as_i64().unwrap_or_default(),
+            timestamp: result["timestamp"].as_i64().map(Timestamp),
+            bids: levels("bids"),
+            asks: levels("asks"),
+        }
+    }
+}
+
+fn set_level(side: &mut BTreeMap<Price, f64>, price: f64, amount: f64) {
+    if amount <= 0.0 {
+        side.remove(&Price(price));
+    } else {
+        side.insert(Price(price), amount);
+    }
+}
+
+struct Managed {
+    client: Arc<DeribitClient>,
+    instrument_name: String,
+    updates: SubscriptionStream<BookDelta>,
+    book: Option<Arc<LocalOrderBook>>,
+}
+
+// Fetches the book with the change id it's at
+async fn fetch(client: &DeribitClient, instrument_name: &str) -> Result<Arc<LocalOrderBook>> {
+    let request = PublicGetOrderBookRequest {
+        instrument_name: instrument_name.to_string(),
+        depth: Some(SNAPSHOT_DEPTH),
+    };
+    let result = client
+        .call_raw("public/get_order_book", serde_json::to_value(request)?)
+        .await?;
+    Ok(Arc::new(LocalOrderBook::from_order_book(
+        instrument_name,
+        &result,
+    )))
+}
+
+// Fetches a book as a `BookNotification`, which unlike the spec type keeps the change id
+async fn fetch_snapshot(
+    client: &DeribitClient,
+    instrument_name: &str,
+    depth: Option<i64>,
+) -> Result<BookNotification> {
+    let request = PublicGetOrderBookRequest {
+        instrument_name: instrument_name.to_string(),
+        depth,
+    };
+    let result = client
+        .call_raw("public/get_order_book", serde_json::to_value(request)?)
+        .await?;
+    Ok(serde_json::from_value(result)?)
+}
+
+impl Managed {
+    // Applies `update`, fetching the book first if there is none or the update doesn't
+    // follow it. Returns whether the book changed.
+    async fn apply(&mut self, update: &BookDelta) -> Result<bool> {
+        let starts_over = update.snapshot;
+        if let Some(book) = &mut self.book
+            && let Ok(changed) = Arc::make_mut(book).apply_delta(update)
+        {
+            return Ok(changed);
+        }
+        if !starts_over {
+            tracing::warn!(
+                instrument = self.instrument_name,
+                change_id = self.book.as_ref().map(|book| book.change_id),
+                prev_change_id = update.prev_change_id,
+                "order book out of sequence, fetching it again"
+            );
+        }
+        // A fetched book is newer than the update that was just received, so a gap here
+        // means the update is stale and is skipped
+        let mut book = if starts_over {
+            LocalOrderBook::default()
+        } else {
+            Arc::unwrap_or_clone(fetch(&self.client, &self.instrument_name).await?)
+        };
+        let _ = book.apply_delta(update);
+        self.book = Some(Arc::new(book));
+        Ok(true)
+    }
+}
+
+impl DeribitClient {
+    /// Streams the order book of `instrument_name`, maintained locally from the
+    /// `book.{instrument_name}.{interval}` channel, after each change. The book is
+    /// fetched again whenever updates are missing: out of sequence, after
+    /// `Error::SubscriptionLagged`, or when the subscription didn't start with a snapshot.
+    ///
+    /// Each item shares the book with the stream until the next one, so holding on to an
+    /// item makes the next update copy the book.
+    pub async fn local_order_book(
+        self: &Arc<Self>,
+        instrument_name: &str,
+        interval: SubscriptionInterval,
+    ) -> Result<impl Stream<Item = Result<Arc<LocalOrderBook>>> + Send + 'static + use<>> {
+        let channel = BookDeltaChannel {
+            instrument_name: instrument_name.to_string(),
+            interval,
+        };
+        let state = Managed {
+            client: self.clone(),
+            instrument_name: channel.instrument_name.clone(),
+            updates: self.subscribe(channel.clone()).await?,
+            book: None,
+        };
+        let channel = channel.channel_string();
+        Ok(futures_util::stream::unfold(state, move |mut state| {
+            let channel = channel.clone();
+            async move {
+                loop {
+                    let changed = match state.updates.next().await? {
+                        Ok(update) => state.apply(&update).await,
+                        Err(Error::SubscriptionLagged(skipped)) => {
+                            tracing::warn!(
+                                channel,
+                                skipped,
+                                "order book lagged, fetching it again"
+                            );
+                            fetch(&state.client, &state.instrument_name)
+                                .await
+                                .map(|book| {
+                                    state.book = Some(book);
+                                    true
+                                })
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match changed {
+                        Ok(true) => {
+                            let book = state.book.clone().expect("set once changed");
+                            return Some((Ok(book), state));
+                        }
+                        Ok(false) => {}
+                        Err(e) => return Some((Err(e), state)),
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Fetches the books of `instruments` to `depth` levels with `public/get_order_book`
+    /// every `every`, e.g. to feed `PostgresSink::write_book_snapshots`. The requests are
+    /// spread evenly over the interval and wait out rate limiting like any other call
+    /// (see `DeribitClientBuilder::retry_rate_limited`); when a round takes longer than
+    /// `every`, the next one starts late rather than in a burst. A failed fetch is
+    /// yielded and polling goes on, so a sink should skip it rather than stop, see
+    /// `PostgresSink::skip_stream_errors`.
+    pub fn poll_order_books(
+        self: &Arc<Self>,
+        instruments: &[&str],
+        depth: Option<i64>,
+        every: Duration,
+    ) -> impl Stream<Item = Result<BookNotification>> + Send + 'static + use<> {
+        let instruments: Vec<String> = instruments.iter().map(|name| name.to_string()).collect();
+        let spacing = every / instruments.len().max(1) as u32;
+        let mut polls = tokio::time::interval(spacing.max(Duration::from_millis(1)));
+        polls.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let rounds = instruments.into_iter().cycle();
+        futures_util::stream::unfold(
+            (self.clone(), rounds, polls),
+            move |(client, mut rounds, mut polls)| async move {
+                let instrument_name = rounds.next()?;
+                polls.tick().await;
+                let book = fetch_snapshot(&client, &instrument_name, depth).await;
+                if let Err(e) = &book {
+                    tracing::warn!(instrument = instrument_name, error = %e, "order book poll failed");
+                }
+                Some((book, (client, rounds, polls)))
+            },
+        )
+    }
+}
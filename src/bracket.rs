@@ -0,0 +1,386 @@
+//! Bracket orders emulated on the client: an entry order and, once it fills, a
+//! take-profit and a stop-loss order closing the position, one cancelling the other, see
+//! `OrderManager::bracket`.
+//!
+//! Deribit can link orders itself (`linked_order_type` and `otoco_config`), which keeps
+//! working while the client is down, and should be preferred where it fits. Emulating
+//! them allows exits sized to what the entry actually filled, but protection depends on
+//! the client running:
+//!
+//! - between the entry filling and the exits being placed, the position is unprotected;
+//! - between one exit filling and the other being cancelled, both are working. The exits
+//!   are reduce-only, so the second can at worst close what is left, never open a new
+//!   position.
+//!
+//! The orders of a bracket are labelled after it, `{label}`, `{label}-tp` and
+//! `{label}-sl`, so `OrderManager::resume_bracket` can pick it up again after a restart
+//! from `private/get_order_state_by_label`, placing what is missing and cancelling what
+//! is left over.
+
+use crate::{
+    Currency, Direction, Instrument, InvalidOrder, Order, OrderBuilder, OrderManager, OrderRequest,
+    OrderState, PrivateGetOrderStateByLabelRequest, Result, Trigger,
+    UserOrdersInstrumentNameRawChannel, money_f64,
+};
+use futures_util::{Stream, StreamExt};
+
+/// An entry order with a take-profit and a stop-loss exit, see `OrderManager::bracket`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bracket {
+    /// Names the bracket and labels its orders.
+    pub label: String,
+    pub direction: Direction,
+    pub amount: f64,
+    /// Limit price of the entry; a market order if `None`.
+    pub entry_price: Option<f64>,
+    /// Limit price of the take-profit order.
+    pub take_profit: f64,
+    /// Trigger price of the stop-loss market order.
+    pub stop_loss: f64,
+    /// The price the stop-loss triggers on.
+    pub trigger: Trigger,
+}
+
+impl Bracket {
+    fn take_profit_label(&self) -> String {
+        format!("{}-tp", self.label)
+    }
+
+    fn stop_loss_label(&self) -> String {
+        format!("{}-sl", self.label)
+    }
+
+    fn entry(&self, instrument: &Instrument) -> std::result::Result<OrderRequest, InvalidOrder> {
+        let entry = match self.entry_price {
+            Some(price) => OrderBuilder::limit(self.direction.clone(), self.amount, price),
+            None => OrderBuilder::market(self.direction.clone(), self.amount),
+        };
+        entry.label(&self.label).build(instrument)
+    }
+
+    // The exits for `amount` of the entry filled
+    fn exits(
+        &self,
+        instrument: &Instrument,
+        amount: f64,
+    ) -> std::result::Result<(OrderRequest, OrderRequest), InvalidOrder> {
+        let exit = match self.direction {
+            Direction::Sell => Direction::Buy,
+            _ => Direction::Sell,
+        };
+        let take_profit = OrderBuilder::limit(exit.clone(), amount, self.take_profit)
+            .label(self.take_profit_label())
+            .reduce_only()
+            .build(instrument)?;
+        let stop_loss =
+            OrderBuilder::stop_market(exit, amount, self.stop_loss, self.trigger.clone())
+                .label(self.stop_loss_label())
+                .reduce_only()
+                .build(instrument)?;
+        Ok((take_profit, stop_loss))
+    }
+
+    fn validate(&self, instrument: &Instrument) -> std::result::Result<(), InvalidOrder> {
+        let long = self.direction != Direction::Sell;
+        if (self.take_profit > self.stop_loss) != long {
+            return Err(InvalidOrder::ExitsReversed);
+        }
+        self.entry(instrument)?;
+        self.exits(instrument, self.amount)?;
+        Ok(())
+    }
+}
+
+/// A step of a bracket, yielded by `OrderManager::bracket`.
+// Yielded a few times per bracket, not worth boxing the orders for
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BracketEvent {
+    /// The entry order was placed.
+    EntryPlaced(Order),
+    /// The entry ended without filling, so there is nothing to protect.
+    EntryCancelled(Order),
+    /// The entry is done and both exits are working for the amount it filled.
+    ExitsPlaced {
+        entry: Order,
+        take_profit: Order,
+        stop_loss: Order,
+    },
+    /// An exit ended without filling, cancelled or rejected, and was placed again for
+    /// what it left open. The other exit is left working.
+    ExitReplaced { ended: Order, replacement: Order },
+    /// An exit ended without filling and what it left open is below the minimum trade
+    /// amount, so it isn't placed again. The other exit is left working, and the bracket
+    /// is over once both exits are done.
+    ExitAbandoned(Order),
+    /// `exit` filled and `sibling`, if it was placed, is cancelled. The bracket is over.
+    /// A stop-loss fills through the order it created when it triggered, which is the
+    /// one given.
+    Closed { exit: Order, sibling: Option<Order> },
+}
+
+#[derive(Debug)]
+enum Phase {
+    Entry,
+    Exits {
+        take_profit: Option<String>,
+        stop_loss: Option<String>,
+    },
+    Done,
+}
+
+// A bracket in progress
+struct Run {
+    manager: OrderManager,
+    instrument: Instrument,
+    bracket: Bracket,
+    entry: String,
+    phase: Phase,
+    // Exits ended with too little left open to place again
+    abandoned: Vec<String>,
+}
+
+impl Run {
+    // Places or cancels what the latest order states call for, returning what happened
+    async fn advance(&mut self) -> Result<Option<BracketEvent>> {
+        loop {
+            // Neither exit is working, nor will be again
+            if self.abandoned.len() == 2 {
+                self.phase = Phase::Done;
+            }
+            match &mut self.phase {
+                Phase::Done => return Ok(None),
+                Phase::Entry => {
+                    let Some(entry) = self.manager.order(&self.entry).filter(Order::is_done) else {
+                        return Ok(None);
+                    };
+                    if entry.filled_amount.map_or(0.0, money_f64) <= 0.0 {
+                        self.phase = Phase::Done;
+                        return Ok(Some(BracketEvent::EntryCancelled(entry)));
+                    }
+                    self.phase = Phase::Exits {
+                        take_profit: None,
+                        stop_loss: None,
+                    };
+                }
+                Phase::Exits {
+                    take_profit,
+                    stop_loss,
+                } => {
+                    let order = |id: &Option<String>| {
+                        id.as_deref()
+                            .and_then(|id| self.manager.through_trigger(id))
+                    };
+                    let (tp, sl) = (order(take_profit), order(stop_loss));
+                    let filled = |exit: &Option<Order>| {
+                        exit.as_ref()
+                            .is_some_and(|exit| exit.order_state == OrderState::Filled)
+                    };
+                    let closed = if filled(&tp) {
+                        Some((tp.clone(), sl.clone()))
+                    } else if filled(&sl) {
+                        Some((sl.clone(), tp.clone()))
+                    } else {
+                        None
+                    };
+                    if let Some((Some(exit), sibling)) = closed {
+                        let sibling = match sibling {
+                            Some(sibling) if !sibling.is_done() => {
+                                Some(self.manager.cancel(&sibling.order_id).await?)
+                            }
+                            sibling => sibling,
+                        };
+                        self.phase = Phase::Done;
+                        return Ok(Some(BracketEvent::Closed { exit, sibling }));
+                    }
+                    let entry = self.manager.order(&self.entry).unwrap_or_default();
+                    let amount = entry.filled_amount.map_or(0.0, money_f64);
+                    // An exit that ended unfilled is placed again for what it left open,
+                    // leaving the other one working
+                    for (id, exit, take) in
+                        [(&mut *take_profit, tp, true), (&mut *stop_loss, sl, false)]
+                    {
+                        let Some(ended) = exit.filter(Order::is_done) else {
+                            continue;
+                        };
+                        let tracked = id.clone().unwrap_or_default();
+                        if self.abandoned.contains(&tracked) {
+                            continue;
+                        }
+                        let left = amount - ended.filled_amount.map_or(0.0, money_f64);
+                        if left < self.instrument.amount_step() {
+                            self.abandoned.push(tracked);
+                            return Ok(Some(BracketEvent::ExitAbandoned(ended)));
+                        }
+                        let (tp_request, sl_request) =
+                            self.bracket.exits(&self.instrument, left)?;
+                        let request = if take { tp_request } else { sl_request };
+                        let replacement = self.manager.place(request).await?.order;
+                        *id = Some(replacement.order_id.clone());
+                        return Ok(Some(BracketEvent::ExitReplaced { ended, replacement }));
+                    }
+                    if take_profit.is_some() && stop_loss.is_some() {
+                        return Ok(None);
+                    }
+                    let (tp_request, sl_request) = self.bracket.exits(&self.instrument, amount)?;
+                    if take_profit.is_none() {
+                        *take_profit = Some(self.manager.place(tp_request).await?.order.order_id);
+                    }
+                    if stop_loss.is_none() {
+                        *stop_loss = Some(self.manager.place(sl_request).await?.order.order_id);
+                    }
+                    let (Some(take_profit), Some(stop_loss)) =
+                        (order(take_profit), order(stop_loss))
+                    else {
+                        return Ok(None);
+                    };
+                    return Ok(Some(BracketEvent::ExitsPlaced {
+                        entry,
+                        take_profit,
+                        stop_loss,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl OrderManager {
+    /// Places the entry of `bracket` on `instrument` and follows it: once the entry is
+    /// done, places the exits for the amount it filled, and once either exit fills,
+    /// cancels the other. An exit cancelled or rejected is placed again for what it left
+    /// open, so cancel the bracket's orders only once the stream is dropped. A partially
+    /// filled entry is only protected once it is done, so give a limit entry a time in
+    /// force or cancel it.
+    ///
+    /// The bracket only advances while the stream is polled. An error placing or
+    /// cancelling an order is yielded and retried once the next order update arrives.
+    pub async fn bracket(
+        &self,
+        instrument: &Instrument,
+        bracket: Bracket,
+    ) -> Result<impl Stream<Item = Result<BracketEvent>> + Send + 'static + use<>> {
+        bracket.validate(instrument)?;
+        let orders = self
+            .client
+            .subscribe(UserOrdersInstrumentNameRawChannel {
+                instrument_name: instrument.instrument_name.clone(),
+            })
+            .await?;
+        let entry = self.place(bracket.entry(instrument)?).await?.order;
+        let run = Run {
+            manager: self.clone(),
+            instrument: instrument.clone(),
+            bracket,
+            entry: entry.order_id.clone(),
+            phase: Phase::Entry,
+            abandoned: Vec::new(),
+        };
+        Ok(follow(run, orders, Some(BracketEvent::EntryPlaced(entry))))
+    }
+
+    /// Picks up a bracket placed before a restart, as found by the labels of its orders,
+    /// and follows it as `bracket` does. Ends right away if its entry isn't found.
+    pub async fn resume_bracket(
+        &self,
+        instrument: &Instrument,
+        bracket: Bracket,
+    ) -> Result<impl Stream<Item = Result<BracketEvent>> + Send + 'static + use<>> {
+        bracket.validate(instrument)?;
+        let orders = self
+            .client
+            .subscribe(UserOrdersInstrumentNameRawChannel {
+                instrument_name: instrument.instrument_name.clone(),
+            })
+            .await?;
+        let currency = match &instrument.settlement_currency {
+            Some(currency) => serde_json::to_value(currency)?,
+            None => serde_json::to_value(&instrument.base_currency)?,
+        };
+        let currency: Currency = serde_json::from_value(currency)?;
+        let mut latest = Vec::new();
+        for label in [
+            bracket.label.clone(),
+            bracket.take_profit_label(),
+            bracket.stop_loss_label(),
+        ] {
+            let orders = self
+                .client
+                .call(PrivateGetOrderStateByLabelRequest {
+                    currency: currency.clone(),
+                    label: Some(label),
+                })
+                .await?;
+            let orders: Vec<_> = orders
+                .into_iter()
+                .filter(|order| order.instrument_name.as_ref() == Some(&instrument.instrument_name))
+                .collect();
+            // The orders a triggered stop-loss created share its label, and are tracked
+            // once the stop is
+            let order = orders
+                .iter()
+                .filter(|order| order.trigger_order_id.is_none())
+                .max_by_key(|order| order.last_update_timestamp)
+                .cloned();
+            if let Some(order) = &order {
+                self.placed(order.clone());
+                for triggered in orders.into_iter().filter(|triggered| {
+                    triggered.trigger_order_id.as_ref() == Some(&order.order_id)
+                }) {
+                    self.notified(triggered);
+                }
+            }
+            latest.push(order.map(|order| order.order_id));
+        }
+        let [entry, take_profit, stop_loss] = <[_; 3]>::try_from(latest).unwrap();
+        let phase = match (&entry, &take_profit, &stop_loss) {
+            (None, ..) => Phase::Done,
+            (_, None, None) => Phase::Entry,
+            _ => Phase::Exits {
+                take_profit,
+                stop_loss,
+            },
+        };
+        let run = Run {
+            manager: self.clone(),
+            instrument: instrument.clone(),
+            bracket,
+            entry: entry.unwrap_or_default(),
+            phase,
+            abandoned: Vec::new(),
+        };
+        Ok(follow(run, orders, None))
+    }
+}
+
+fn follow(
+    run: Run,
+    orders: impl Stream<Item = Result<Order>> + Send + 'static,
+    first: Option<BracketEvent>,
+) -> impl Stream<Item = Result<BracketEvent>> + Send + 'static {
+    futures_util::stream::iter(first.map(Ok)).chain(futures_util::stream::unfold(
+        (run, orders.boxed(), false),
+        |(mut run, mut orders, mut failed)| async move {
+            loop {
+                // After a failed request, nothing is retried before the next order update
+                if !failed {
+                    match run.advance().await {
+                        Ok(Some(event)) => return Some((Ok(event), (run, orders, false))),
+                        Ok(None) => {}
+                        Err(e) => return Some((Err(e), (run, orders, true))),
+                    }
+                }
+                failed = false;
+                if matches!(run.phase, Phase::Done) {
+                    return None;
+                }
+                match orders.next().await? {
+                    Ok(order) => {
+                        run.manager.notified(order);
+                    }
+                    Err(e) => return Some((Err(e), (run, orders, false))),
+                }
+            }
+        },
+    ))
+}
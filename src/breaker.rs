@@ -0,0 +1,167 @@
+//! Circuit breaker for order entry, see `DeribitClientBuilder::circuit_breaker`.
+
+use crate::{Error, ResponseMeta, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Methods guarded by the circuit breaker. Cancellations are never blocked.
+pub const ORDER_ENTRY_METHODS: &[&str] = &[
+    "private/buy",
+    "private/sell",
+    "private/edit",
+    "private/edit_by_label",
+];
+
+/// When the order entry circuit breaker opens and closes again.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Period over which submissions and rejections are counted.
+    pub window: Duration,
+    /// Rejections within the window needed before the rejection rate is considered.
+    pub min_rejections: usize,
+    /// Share of submissions within the window that may be rejected (0.0 to 1.0).
+    pub max_rejection_rate: f64,
+    /// Error codes that open the breaker the first time they are returned.
+    pub trip_codes: Vec<i32>,
+    /// How long the breaker stays open before a trial submission is let through. The
+    /// breaker closes if the trial succeeds and opens for another cool-down otherwise.
+    pub cool_down: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            min_rejections: 5,
+            max_rejection_rate: 0.5,
+            trip_codes: Vec::new(),
+            cool_down: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CircuitState {
+    Closed,
+    /// Submissions fail locally with `Error::CircuitOpen`.
+    Open,
+    /// The cool-down has passed and the next submission is a trial.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    // Time and rejection flag of recent submissions
+    outcomes: VecDeque<(Instant, bool)>,
+    opened_at: Option<Instant>,
+    // Start of the trial submission, which counts as failed after a cool-down without
+    // an outcome (e.g. because the call was dropped)
+    trial_started: Option<Instant>,
+}
+
+/// Permission to submit an order, see `CircuitBreaker::permit`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Permit {
+    trial: bool,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            outcomes: VecDeque::new(),
+            opened_at: None,
+            trial_started: None,
+        }
+    }
+
+    pub(crate) fn state(&self, now: Instant) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+            Some(at) if now.duration_since(at) < self.config.cool_down => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    pub(crate) fn permit(&mut self, now: Instant) -> Result<Permit> {
+        let Some(opened_at) = self.opened_at else {
+            return Ok(Permit { trial: false });
+        };
+        let open_for = now.duration_since(opened_at);
+        if open_for < self.config.cool_down {
+            return Err(Error::CircuitOpen {
+                retry_in: self.config.cool_down - open_for,
+            });
+        }
+        if let Some(started) = self.trial_started {
+            let trial_for = now.duration_since(started);
+            if trial_for < self.config.cool_down {
+                return Err(Error::CircuitOpen {
+                    retry_in: self.config.cool_down - trial_for,
+                });
+            }
+        }
+        self.trial_started = Some(now);
+        Ok(Permit { trial: true })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        now: Instant,
+        permit: Permit,
+        result: &Result<(Value, ResponseMeta)>,
+    ) {
+        let rejection = match result {
+            Ok(_) => None,
+            Err(Error::RpcError(error)) => Some(Some(error.code)),
+            // Transport errors say nothing about the orders, except for a failed trial
+            Err(_) if permit.trial => Some(None),
+            Err(_) => return,
+        };
+
+        if permit.trial {
+            self.trial_started = None;
+            if rejection.is_some() {
+                self.opened_at = Some(now);
+            } else {
+                self.reset();
+            }
+            return;
+        }
+        // Submissions let through before the breaker opened
+        if self.opened_at.is_some() {
+            return;
+        }
+
+        self.outcomes.push_back((now, rejection.is_some()));
+        while let Some((at, _)) = self.outcomes.front()
+            && now.duration_since(*at) > self.config.window
+        {
+            self.outcomes.pop_front();
+        }
+
+        let tripped =
+            matches!(rejection, Some(Some(code)) if self.config.trip_codes.contains(&code));
+        let rejections = self
+            .outcomes
+            .iter()
+            .filter(|(_, rejected)| *rejected)
+            .count();
+        let rate = rejections as f64 / self.outcomes.len() as f64;
+        if tripped
+            || (rejections >= self.config.min_rejections && rate > self.config.max_rejection_rate)
+        {
+            self.opened_at = Some(now);
+            self.outcomes.clear();
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.outcomes.clear();
+        self.opened_at = None;
+        self.trial_started = None;
+    }
+}
@@ -0,0 +1,245 @@
+//! Cancelling many orders at once, see `DeribitClient::cancel_orders`.
+//!
+//! Deribit has a mass-cancel method per scope, `private/cancel_all`,
+//! `private/cancel_all_by_currency`, `private/cancel_all_by_instrument`,
+//! `private/cancel_by_label`, each taking a different subset of filters, and a matching
+//! `private/get_open_orders*` method listing what it would cancel. `CancelFilter` picks
+//! the pair for the scope and filters given.
+
+use crate::{
+    Currency, CurrencyKind, DeribitClient, Error, Kind, KindWithComboAll, Order, OrderType2,
+    PrivateCancelAllByCurrencyRequest, PrivateCancelAllByInstrumentRequest,
+    PrivateCancelAllByKindOrTypeRequest, PrivateCancelAllRequest, PrivateCancelByLabelRequest,
+    PrivateGetOpenOrdersByCurrencyRequest, PrivateGetOpenOrdersByInstrumentRequest,
+    PrivateGetOpenOrdersByLabelRequest, PrivateGetOpenOrdersRequest, Result, SimpleOrderType,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Scope {
+    All,
+    Currency(Currency),
+    Instrument(String),
+    Label {
+        label: String,
+        currency: Option<Currency>,
+    },
+}
+
+/// Which open orders `DeribitClient::cancel_orders` cancels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelFilter {
+    scope: Scope,
+    kind: Option<Kind>,
+    r#type: Option<SimpleOrderType>,
+    dry_run: bool,
+}
+
+impl CancelFilter {
+    fn new(scope: Scope) -> Self {
+        Self {
+            scope,
+            kind: None,
+            r#type: None,
+            dry_run: false,
+        }
+    }
+
+    /// Every open order of the account.
+    pub fn all() -> Self {
+        Self::new(Scope::All)
+    }
+
+    /// The open orders in instruments of `currency`.
+    pub fn currency(currency: Currency) -> Self {
+        Self::new(Scope::Currency(currency))
+    }
+
+    /// The open orders in `instrument_name`.
+    pub fn instrument(instrument_name: impl Into<String>) -> Self {
+        Self::new(Scope::Instrument(instrument_name.into()))
+    }
+
+    /// The open orders labelled `label`, in instruments of `currency` if given. Kind and
+    /// type filters can't be combined with it.
+    pub fn label(label: impl Into<String>, currency: Option<Currency>) -> Self {
+        Self::new(Scope::Label {
+            label: label.into(),
+            currency,
+        })
+    }
+
+    /// Only orders in instruments of `kind`. Can't be combined with a single instrument
+    /// or a label.
+    pub fn kind(mut self, kind: Kind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Only orders of `r#type`, e.g. only stops. Can't be combined with a label.
+    pub fn order_type(mut self, r#type: SimpleOrderType) -> Self {
+        self.r#type = Some(r#type);
+        self
+    }
+
+    /// Lists the orders that would be cancelled without cancelling them.
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    // Refuses filters the scope's methods can't express, rather than cancelling more
+    fn check(&self) -> Result<()> {
+        match self.scope {
+            Scope::Instrument(_) if self.kind.is_some() => Err(Error::UnsupportedCancelFilter(
+                "a kind filter can't be combined with an instrument",
+            )),
+            Scope::Label { .. } if self.kind.is_some() || self.r#type.is_some() => {
+                Err(Error::UnsupportedCancelFilter(
+                    "kind and type filters can't be combined with a label",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn kind_with_combo(&self) -> Result<Option<KindWithComboAll>> {
+        Ok(match &self.kind {
+            Some(kind) => Some(serde_json::from_value(serde_json::to_value(kind)?)?),
+            None => None,
+        })
+    }
+
+    // The type filter as the `get_open_orders*` methods spell it
+    fn listed_type(&self) -> Result<Option<OrderType2>> {
+        Ok(match &self.r#type {
+            Some(SimpleOrderType::Stop) => Some(OrderType2::StopAll),
+            Some(SimpleOrderType::Take) => Some(OrderType2::TakeAll),
+            Some(SimpleOrderType::TrailingStop) => Some(OrderType2::TrailingAll),
+            Some(r#type) => Some(serde_json::from_value(serde_json::to_value(r#type)?)?),
+            None => None,
+        })
+    }
+}
+
+/// What `DeribitClient::cancel_orders` did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelSummary {
+    /// Whether this was a dry run, so nothing was cancelled.
+    pub dry_run: bool,
+    /// How many orders were cancelled, or would have been in a dry run.
+    pub count: usize,
+    /// In a dry run, the open orders that would have been cancelled. Empty otherwise.
+    pub orders: Vec<Order>,
+}
+
+impl DeribitClient {
+    /// Cancels the open orders matching `filter` with a single mass-cancel request, or
+    /// with `CancelFilter::dry_run` lists them instead. Fails with
+    /// `Error::UnsupportedCancelFilter`, cancelling nothing, for filters the scope's
+    /// methods can't express.
+    pub async fn cancel_orders(&self, filter: &CancelFilter) -> Result<CancelSummary> {
+        filter.check()?;
+        if filter.dry_run {
+            let orders = self.matching_orders(filter).await?;
+            return Ok(CancelSummary {
+                dry_run: true,
+                count: orders.len(),
+                orders,
+            });
+        }
+        let count = match &filter.scope {
+            Scope::All if filter.kind.is_none() && filter.r#type.is_none() => {
+                self.call(PrivateCancelAllRequest::default()).await?
+            }
+            Scope::All => {
+                self.call(PrivateCancelAllByKindOrTypeRequest {
+                    currency: "any".into(),
+                    kind: filter.kind_with_combo()?,
+                    r#type: filter.r#type.clone(),
+                    ..Default::default()
+                })
+                .await?
+            }
+            Scope::Currency(currency) => {
+                self.call(PrivateCancelAllByCurrencyRequest {
+                    currency_kind: CurrencyKind {
+                        currency: currency.clone(),
+                        kind: filter.kind_with_combo()?,
+                    },
+                    r#type: filter.r#type.clone(),
+                    ..Default::default()
+                })
+                .await?
+            }
+            Scope::Instrument(instrument_name) => {
+                self.call(PrivateCancelAllByInstrumentRequest {
+                    instrument_name: instrument_name.clone(),
+                    r#type: filter.r#type.clone(),
+                    ..Default::default()
+                })
+                .await?
+            }
+            Scope::Label { label, currency } => {
+                self.call(PrivateCancelByLabelRequest {
+                    label: label.clone(),
+                    currency: currency.clone(),
+                })
+                .await?
+            }
+        };
+        Ok(CancelSummary {
+            dry_run: false,
+            count: count as usize,
+            orders: Vec::new(),
+        })
+    }
+
+    async fn matching_orders(&self, filter: &CancelFilter) -> Result<Vec<Order>> {
+        let r#type = filter.listed_type()?;
+        match &filter.scope {
+            Scope::All => {
+                self.call(PrivateGetOpenOrdersRequest {
+                    kind: filter.kind.clone(),
+                    r#type,
+                })
+                .await
+            }
+            Scope::Currency(currency) => {
+                self.call(PrivateGetOpenOrdersByCurrencyRequest {
+                    currency: currency.clone(),
+                    kind: filter.kind.clone(),
+                    r#type,
+                })
+                .await
+            }
+            Scope::Instrument(instrument_name) => {
+                self.call(PrivateGetOpenOrdersByInstrumentRequest {
+                    instrument_name: instrument_name.clone(),
+                    r#type,
+                })
+                .await
+            }
+            Scope::Label {
+                label,
+                currency: Some(currency),
+            } => {
+                self.call(PrivateGetOpenOrdersByLabelRequest {
+                    currency: currency.clone(),
+                    label: Some(label.clone()),
+                })
+                .await
+            }
+            // Listing by label takes a currency, so list them all
+            Scope::Label {
+                label,
+                currency: None,
+            } => {
+                let orders = self.call(PrivateGetOpenOrdersRequest::default()).await?;
+                Ok(orders
+                    .into_iter()
+                    .filter(|order| order.label == *label)
+                    .collect())
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! `user.trades` and `user.orders` streams that resume where the previous run stopped,
+//! see `DeribitClient::user_trades_checkpointed`.
+//!
+//! The position of the last processed event is persisted in a `CheckpointStore`. On
+//! restart the stream subscribes first, then backfills the events after the checkpoint
+//! from the history endpoints, and continues with the live events, skipping those the
+//! backfill already covered.
+
+use crate::{
+    Currency, CurrencyKind, DeribitClient, Kind, KindWithComboAll, OffsetPagination, Order,
+    PrivateGetOpenOrdersByCurrencyRequest, PrivateGetOrderHistoryByCurrencyRequest,
+    PrivateGetUserTradesByCurrencyAndTimeRequest, Result, Sorting, SubscriptionInterval, TimeRange,
+    Timestamp, UserOrdersKindCurrencyRawChannel, UserTrade, UserTradesKindCurrencyChannel,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+// Events per history request
+const PAGE_SIZE: usize = 1000;
+
+/// Position of the last processed event: its timestamp and the ids of the events
+/// processed at that timestamp, since several can share one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub timestamp: Timestamp,
+    pub ids: Vec<String>,
+}
+
+impl Checkpoint {
+    /// Whether the event with `timestamp` and `id` comes after the checkpoint.
+    pub fn is_new(&self, timestamp: Timestamp, id: &str) -> bool {
+        timestamp > self.timestamp
+            || (timestamp == self.timestamp && !self.ids.iter().any(|seen| seen == id))
+    }
+
+    /// Moves the checkpoint past the event with `timestamp` and `id`.
+    pub fn advance(&mut self, timestamp: Timestamp, id: &str) {
+        if timestamp > self.timestamp {
+            self.timestamp = timestamp;
+            self.ids.clear();
+        }
+        if self.is_new(timestamp, id) {
+            self.ids.push(id.to_string());
+        }
+    }
+}
+
+/// Persists checkpoints between runs, keyed by subscription channel.
+pub trait CheckpointStore: Send + Sync {
+    fn load(&self, key: &str) -> Result<Option<Checkpoint>>;
+    fn save(&self, key: &str, checkpoint: &Checkpoint) -> Result<()>;
+}
+
+/// Stores each checkpoint as a JSON file in a directory, created on first save.
+#[derive(Debug, Clone)]
+pub struct FileCheckpointStore {
+    dir: PathBuf,
+}
+
+impl FileCheckpointStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn load(&self, key: &str) -> Result<Option<Checkpoint>> {
+        match std::fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Written to a temporary file first, so a crash never leaves a truncated checkpoint
+    fn save(&self, key: &str, checkpoint: &Checkpoint) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(checkpoint)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+impl DeribitClient {
+    /// Streams the account's trades of `currency` and `kind` one by one, starting after
+    /// the checkpoint stored in `store`, or with the live trades when there is none.
+    ///
+    /// An event counts as processed, and the checkpoint is saved, once the next one is
+    /// requested, so after a crash the last event may be delivered again but none is
+    /// skipped. A lagging consumer gets `Error::SubscriptionLagged`; restarting the stream
+    /// backfills what was missed.
+    pub async fn user_trades_checkpointed<S: CheckpointStore + 'static>(
+        &self,
+        currency: Currency,
+        kind: KindWithComboAll,
+        store: S,
+    ) -> Result<impl Stream<Item = Result<UserTrade>> + Send + 'static + use<S>> {
+        let currency_with_any = currency.clone().try_into()?;
+        let channel = UserTradesKindCurrencyChannel {
+            kind: kind.clone(),
+            currency: currency_with_any,
+            interval: SubscriptionInterval::Raw,
+        };
+        let key = crate::Subscription::channel_string(&channel);
+        let live = self.subscribe(channel).await?;
+        let checkpoint = store.load(&key)?;
+        let mut backfill = Vec::new();
+        if let Some(checkpoint) = &checkpoint {
+            let mut start_timestamp = checkpoint.timestamp;
+            loop {
+                let page = self
+                    .call(PrivateGetUserTradesByCurrencyAndTimeRequest {
+                        currency_kind: CurrencyKind {
+                            currency: currency.clone(),
+                            kind: Some(kind.clone()),
+                        },
+                        time_range: TimeRange {
+                            start_timestamp,
+                            end_timestamp: Timestamp::now(),
+                        },
+                        count: Some(PAGE_SIZE as i64),
+                        sorting: Some(Sorting::Asc),
+                        historical: None,
+                    })
+                    .await?;
+                let Some(last) = page.trades.last() else {
+                    break;
+                };
+                // A page of trades sharing one timestamp can't be paged by timestamp
+                start_timestamp = last.timestamp.max(Timestamp(start_timestamp.0 + 1));
+                backfill.extend(page.trades);
+                if !page.has_more {
+                    break;
+                }
+            }
+        }
+        Ok(resume(
+            key,
+            store,
+            checkpoint,
+            backfill,
+            live,
+            |trade: &UserTrade| (trade.timestamp, &trade.trade_id),
+        ))
+    }
+
+    /// Like `user_trades_checkpointed`, for order updates. Backfills the open orders and
+    /// the order history updated after the checkpoint, including unfilled cancelled orders.
+    pub async fn user_orders_checkpointed<S: CheckpointStore + 'static>(
+        &self,
+        currency: Currency,
+        kind: KindWithComboAll,
+        store: S,
+    ) -> Result<impl Stream<Item = Result<Order>> + Send + 'static + use<S>> {
+        let channel = UserOrdersKindCurrencyRawChannel {
+            kind: kind.clone(),
+            currency: currency.clone().try_into()?,
+        };
+        let key = crate::Subscription::channel_string(&channel);
+        let live = self
+            .subscribe(channel)
+            .await?
+            .map(|order| order.map(|order| vec![order]));
+        let checkpoint = store.load(&key)?;
+        let mut backfill = Vec::new();
+        if let Some(checkpoint) = &checkpoint {
+            for kind in open_order_kinds(&kind) {
+                let open = self
+                    .call(PrivateGetOpenOrdersByCurrencyRequest {
+                        currency: currency.clone(),
+                        kind,
+                        r#type: None,
+                    })
+                    .await?;
+                backfill.extend(open);
+            }
+            // Newest first, so page until reaching the checkpoint
+            let mut offset = 0;
+            loop {
+                let page = self
+                    .call(PrivateGetOrderHistoryByCurrencyRequest {
+                        currency_kind: CurrencyKind {
+                            currency: currency.clone(),
+                            kind: Some(kind.clone()),
+                        },
+                        pagination: OffsetPagination {
+                            count: Some(PAGE_SIZE as i64),
+                            offset: Some(offset),
+                        },
+                        include_unfilled: Some(true),
+                        ..Default::default()
+                    })
+                    .await?;
+                let done = page.len() < PAGE_SIZE
+                    || page
+                        .last()
+                        .is_some_and(|order| order.last_update_timestamp < checkpoint.timestamp);
+                offset += page.len() as i64;
+                backfill.extend(page);
+                if done {
+                    break;
+                }
+            }
+            backfill.sort_by_key(|order: &Order| order.last_update_timestamp);
+            backfill.dedup_by(|a, b| {
+                a.order_id == b.order_id && a.last_update_timestamp == b.last_update_timestamp
+            });
+        }
+        Ok(resume(
+            key,
+            store,
+            checkpoint,
+            backfill,
+            live,
+            |order: &Order| (order.last_update_timestamp, &order.order_id),
+        ))
+    }
+}
+
+// `private/get_open_orders_by_currency` takes no `combo` or `any` kind
+fn open_order_kinds(kind: &KindWithComboAll) -> Vec<Option<Kind>> {
+    match kind {
+        KindWithComboAll::Future => vec![Some(Kind::Future)],
+        KindWithComboAll::Option => vec![Some(Kind::Option)],
+        KindWithComboAll::Spot => vec![Some(Kind::Spot)],
+        KindWithComboAll::FutureCombo => vec![Some(Kind::FutureCombo)],
+        KindWithComboAll::OptionCombo => vec![Some(Kind::OptionCombo)],
+        KindWithComboAll::Combo => vec![Some(Kind::FutureCombo), Some(Kind::OptionCombo)],
+        KindWithComboAll::Any => vec![None],
+        KindWithComboAll::Unknown(kind) => vec![Some(Kind::Unknown(kind.clone()))],
+    }
+}
+
+struct Resume<T> {
+    key: String,
+    store: Arc<dyn CheckpointStore>,
+    checkpoint: Checkpoint,
+    // Yielded but not yet processed, saved once the next event is requested
+    pending: Option<(Timestamp, String)>,
+    queue: VecDeque<T>,
+    live: Pin<Box<dyn Stream<Item = Result<Vec<T>>> + Send>>,
+}
+
+// Yields the backfill and then the live events, skipping those up to the checkpoint
+fn resume<T: Send + 'static>(
+    key: String,
+    store: impl CheckpointStore + 'static,
+    checkpoint: Option<Checkpoint>,
+    backfill: Vec<T>,
+    live: impl Stream<Item = Result<Vec<T>>> + Send + 'static,
+    position: fn(&T) -> (Timestamp, &str),
+) -> impl Stream<Item = Result<T>> + Send + 'static {
+    let state = Resume {
+        key,
+        store: Arc::new(store),
+        checkpoint: checkpoint.unwrap_or_default(),
+        pending: None,
+        queue: backfill.into(),
+        live: Box::pin(live),
+    };
+    futures_util::stream::unfold(state, move |mut state| async move {
+        if let Some((timestamp, id)) = state.pending.take() {
+            state.checkpoint.advance(timestamp, &id);
+            if let Err(e) = state.store.save(&state.key, &state.checkpoint) {
+                return Some((Err(e), state));
+            }
+        }
+        loop {
+            while let Some(event) = state.queue.pop_front() {
+                let (timestamp, id) = position(&event);
+                if state.checkpoint.is_new(timestamp, id) {
+                    state.pending = Some((timestamp, id.to_string()));
+                    return Some((Ok(event), state));
+                }
+            }
+            match state.live.next().await? {
+                Ok(events) => state.queue.extend(events),
+                Err(e) => return Some((Err(e), state)),
+            }
+        }
+    })
+}
@@ -0,0 +1,151 @@
+//! Timers aligned to Deribit's clock rather than the local one, see `MarketClock`.
+
+use crate::{DeribitClient, PublicGetTimeRequest, Result, Timestamp};
+use futures_util::Stream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+/// Estimated offset between the local clock and the server's. Clones share the estimate,
+/// so a background task can keep re-syncing while timers use it.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    // Server clock minus local clock
+    offset_micros: Arc<AtomicI64>,
+}
+
+impl ClockSync {
+    /// Measures the offset with `samples` `public/get_time` calls, keeping the one with
+    /// the shortest round trip, whose midpoint best matches when the server answered.
+    /// Returns that round trip.
+    pub async fn sync(&self, client: &DeribitClient, samples: usize) -> Result<Duration> {
+        let mut best: Option<(Duration, i64)> = None;
+        for _ in 0..samples.max(1) {
+            let sent_at = local_micros();
+            let started = Instant::now();
+            let (_, meta) = client.call_with_meta(PublicGetTimeRequest {}).await?;
+            let round_trip = started.elapsed();
+            let midpoint = sent_at + round_trip.as_micros() as i64 / 2;
+            let offset = meta.us_out as i64 - midpoint;
+            if best.is_none_or(|(shortest, _)| round_trip < shortest) {
+                best = Some((round_trip, offset));
+            }
+        }
+        let (round_trip, offset) = best.expect("at least one sample");
+        self.offset_micros.store(offset, Ordering::Relaxed);
+        Ok(round_trip)
+    }
+
+    /// Server clock minus the local clock, in microseconds. Zero until synced.
+    pub fn offset_micros(&self) -> i64 {
+        self.offset_micros.load(Ordering::Relaxed)
+    }
+
+    /// Estimated server time in milliseconds, the unit of Deribit timestamps.
+    pub fn now_millis(&self) -> i64 {
+        (local_micros() + self.offset_micros()).div_euclid(1000)
+    }
+
+    // Sleeps until the server time reaches `timestamp`, re-reading the offset after each
+    // wake-up so a re-sync in between is honored
+    async fn sleep_until(&self, timestamp: i64) {
+        loop {
+            let remaining = timestamp - self.now_millis();
+            if remaining <= 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(remaining as u64)).await;
+        }
+    }
+}
+
+fn local_micros() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is after 1970")
+        .as_micros() as i64
+}
+
+/// Bar, funding and expiry timers driven by a `ClockSync`. Every tick is computed from
+/// the server clock, so timers don't drift the way `tokio::time::interval` does, and ticks
+/// missed while the consumer was busy are skipped rather than delivered late.
+#[derive(Debug, Clone, Default)]
+pub struct MarketClock {
+    clock: ClockSync,
+}
+
+impl MarketClock {
+    pub fn new(clock: ClockSync) -> Self {
+        Self { clock }
+    }
+
+    pub fn clock(&self) -> &ClockSync {
+        &self.clock
+    }
+
+    /// Yields the server timestamp of each bar boundary, i.e. each multiple of `period`
+    /// since the epoch, e.g. every minute on the minute for a one-minute period.
+    pub fn bars(&self, period: Duration) -> impl Stream<Item = Timestamp> + Send + 'static + use<> {
+        self.aligned(period.as_millis() as i64, 0)
+    }
+
+    /// Yields the daily 08:00 UTC settlement, when perpetual session funding is realized
+    /// and dated futures and options settle.
+    pub fn funding(&self) -> impl Stream<Item = Timestamp> + Send + 'static + use<> {
+        self.aligned(24 * HOUR_MILLIS, 8 * HOUR_MILLIS)
+    }
+
+    /// Yields each of `timestamps`, e.g. the `Instrument::expiration_timestamp` of some
+    /// instruments, once the server clock reaches it, in order. Past timestamps are
+    /// skipped.
+    pub fn expiries(
+        &self,
+        timestamps: &[Timestamp],
+    ) -> impl Stream<Item = Timestamp> + Send + 'static + use<> {
+        let mut timestamps = timestamps.to_vec();
+        timestamps.sort_unstable();
+        timestamps.dedup();
+        let now = self.clock.now_millis();
+        let upcoming = timestamps.into_iter().filter(move |t| t.0 > now);
+        let clock = self.clock.clone();
+        futures_util::stream::unfold(upcoming, move |mut upcoming| {
+            let clock = clock.clone();
+            async move {
+                let timestamp = upcoming.next()?;
+                clock.sleep_until(timestamp.0).await;
+                Some((timestamp, upcoming))
+            }
+        })
+    }
+
+    /// Resolves once the server clock reaches `timestamp`.
+    pub async fn at(&self, timestamp: Timestamp) {
+        self.clock.sleep_until(timestamp.0).await;
+    }
+
+    fn aligned(
+        &self,
+        period: i64,
+        offset: i64,
+    ) -> impl Stream<Item = Timestamp> + Send + 'static + use<> {
+        let period = period.max(1);
+        let clock = self.clock.clone();
+        futures_util::stream::unfold(None, move |last: Option<i64>| {
+            let clock = clock.clone();
+            async move {
+                let now = clock.now_millis();
+                let mut next = (now - offset).div_euclid(period) * period + period + offset;
+                // The offset may have moved back since the last tick
+                if let Some(last) = last
+                    && next <= last
+                {
+                    next = last + period;
+                }
+                clock.sleep_until(next).await;
+                Some((Timestamp(next), Some(next)))
+            }
+        })
+    }
+}
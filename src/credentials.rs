@@ -0,0 +1,275 @@
+//! Loading API keys for `public/auth`, see `CredentialProvider`.
+//!
+//! `DeribitClientBuilder::credentials` takes a provider and authenticates once connected,
+//! so a bot names where its keys live instead of loading them itself:
+//! `EnvCredentials` reads environment variables, `FileCredentials` a TOML file (with the
+//! `credentials-file` feature) and `KeyringCredentials` the OS keyring (with the
+//! `keyring` feature). Providers are asked again on every `DeribitClient::authenticate`,
+//! so rotated keys are picked up on the next connection.
+//!
+//! With `DeribitClientBuilder::sign_auth`, the secret never leaves the process: the
+//! `client_signature` grant sends an HMAC-SHA256 of a timestamp, a nonce and some data,
+//! keyed with the secret, instead. Either way the client keeps the provider, so
+//! `DeribitClient::reconnect` logs in again the same way.
+
+use crate::{
+    DeribitClient, Error, PublicAuthGrantType, PublicAuthRequest, PublicAuthResponse, Result,
+    Timestamp,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Keeps nonces of signatures made within the same millisecond apart
+static NONCES: AtomicU64 = AtomicU64::new(0);
+
+/// A client id and secret, as created on the API page of the account.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl Credentials {
+    pub fn new(client_id: impl Into<String>, client_secret: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where API keys come from. Fails with `Error::Credentials` when they can't be loaded.
+pub trait CredentialProvider: std::fmt::Debug + Send + Sync {
+    fn credentials(&self) -> Result<Credentials>;
+}
+
+impl CredentialProvider for Credentials {
+    fn credentials(&self) -> Result<Credentials> {
+        Ok(self.clone())
+    }
+}
+
+/// Credentials from environment variables, `DERIBIT_CLIENT_ID` and
+/// `DERIBIT_CLIENT_SECRET` by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvCredentials {
+    client_id_var: String,
+    client_secret_var: String,
+}
+
+impl Default for EnvCredentials {
+    fn default() -> Self {
+        Self::vars("DERIBIT_CLIENT_ID", "DERIBIT_CLIENT_SECRET")
+    }
+}
+
+impl EnvCredentials {
+    /// Reads the client id and secret from the variables named.
+    pub fn vars(client_id_var: impl Into<String>, client_secret_var: impl Into<String>) -> Self {
+        Self {
+            client_id_var: client_id_var.into(),
+            client_secret_var: client_secret_var.into(),
+        }
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn credentials(&self) -> Result<Credentials> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|e| Error::Credentials(format!("{name}: {e}")))
+        };
+        Ok(Credentials::new(
+            var(&self.client_id_var)?,
+            var(&self.client_secret_var)?,
+        ))
+    }
+}
+
+/// Credentials from a TOML file with `client_id` and `client_secret` at the top, or in
+/// the table of a profile:
+///
+/// ```toml
+/// client_id = "main-id"
+/// client_secret = "main-secret"
+///
+/// [testnet]
+/// client_id = "test-id"
+/// client_secret = "test-secret"
+/// ```
+#[cfg(feature = "credentials-file")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCredentials {
+    path: std::path::PathBuf,
+    profile: Option<String>,
+}
+
+#[cfg(feature = "credentials-file")]
+impl FileCredentials {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            profile: None,
+        }
+    }
+
+    /// Reads the table `profile` instead of the top of the file.
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+}
+
+#[cfg(feature = "credentials-file")]
+impl CredentialProvider for FileCredentials {
+    fn credentials(&self) -> Result<Credentials> {
+        #[derive(serde::Deserialize)]
+        struct Keys {
+            client_id: String,
+            client_secret: String,
+        }
+
+        let path = self.path.display();
+        let text = std::fs::read_to_string(&self.path)
+            .map_err(|e| Error::Credentials(format!("{path}: {e}")))?;
+        let table: toml::Table =
+            toml::from_str(&text).map_err(|e| Error::Credentials(format!("{path}: {e}")))?;
+        let table = match &self.profile {
+            Some(profile) => match table.get(profile) {
+                Some(toml::Value::Table(table)) => table.clone(),
+                _ => {
+                    return Err(Error::Credentials(format!("{path}: no profile {profile}")));
+                }
+            },
+            None => table,
+        };
+        let keys: Keys = table
+            .try_into()
+            .map_err(|e| Error::Credentials(format!("{path}: {e}")))?;
+        Ok(Credentials::new(keys.client_id, keys.client_secret))
+    }
+}
+
+/// Credentials from the OS keyring: the secret stored for `client_id` under `service`,
+/// e.g. with `KeyringCredentials::store`.
+#[cfg(feature = "keyring")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyringCredentials {
+    service: String,
+    client_id: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringCredentials {
+    pub fn new(service: impl Into<String>, client_id: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+            client_id: client_id.into(),
+        }
+    }
+
+    /// Stores `client_secret` in the keyring, for `credentials` to find.
+    pub fn store(&self, client_secret: &str) -> Result<()> {
+        self.entry()?
+            .set_password(client_secret)
+            .map_err(|e| Error::Credentials(e.to_string()))
+    }
+
+    fn entry(&self) -> Result<keyring::Entry> {
+        keyring::Entry::new(&self.service, &self.client_id)
+            .map_err(|e| Error::Credentials(e.to_string()))
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl CredentialProvider for KeyringCredentials {
+    fn credentials(&self) -> Result<Credentials> {
+        let client_secret = self
+            .entry()?
+            .get_password()
+            .map_err(|e| Error::Credentials(e.to_string()))?;
+        Ok(Credentials::new(self.client_id.clone(), client_secret))
+    }
+}
+
+impl DeribitClient {
+    /// Authenticates with `public/auth` and the client credentials `provider` loads.
+    pub async fn authenticate(
+        &self,
+        provider: &dyn CredentialProvider,
+    ) -> Result<PublicAuthResponse> {
+        let credentials = provider.credentials()?;
+        self.call(PublicAuthRequest {
+            grant_type: PublicAuthGrantType::ClientCredentials,
+            client_id: credentials.client_id,
+            client_secret: credentials.client_secret,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Authenticates with `public/auth` and a `client_signature` grant, signing with the
+    /// secret `provider` loads rather than sending it.
+    pub async fn authenticate_with_signature(
+        &self,
+        provider: &dyn CredentialProvider,
+    ) -> Result<PublicAuthResponse> {
+        let credentials = provider.credentials()?;
+        let timestamp = Timestamp::now();
+        let nonce = format!(
+            "{}-{timestamp}-{}",
+            std::process::id(),
+            NONCES.fetch_add(1, Ordering::Relaxed)
+        );
+        let data = String::new();
+        let signature = sign(&credentials.client_secret, timestamp, &nonce, &data);
+        self.call(PublicAuthRequest {
+            grant_type: PublicAuthGrantType::ClientSignature,
+            client_id: credentials.client_id,
+            timestamp,
+            signature,
+            nonce: Some(nonce),
+            data: Some(data),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Authenticates with the builder's `credentials`, signing if it asked for
+    /// `sign_auth`. Fails with `Error::Credentials` if it was given none.
+    pub async fn login(&self) -> Result<PublicAuthResponse> {
+        let provider = self
+            .builder
+            .credentials
+            .as_deref()
+            .ok_or_else(|| Error::Credentials("no credentials configured".to_string()))?;
+        if self.builder.sign_auth {
+            self.authenticate_with_signature(provider).await
+        } else {
+            self.authenticate(provider).await
+        }
+    }
+}
+
+// Hex HMAC-SHA256 of "{timestamp}\n{nonce}\n{data}", keyed with the secret
+fn sign(client_secret: &str, timestamp: Timestamp, nonce: &str, data: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(client_secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(format!("{timestamp}\n{nonce}\n{data}").as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
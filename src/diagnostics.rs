@@ -0,0 +1,169 @@
+//! Reports about server data the generated types only partially understand and calls
+//! slower than expected, see `DeribitClient::diagnostics`.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use serde_json::value::RawValue;
+use std::cell::RefCell;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// An event on the `DeribitClient::diagnostics` stream.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum Diagnostic {
+    /// A value missing from the API spec was decoded into the `Unknown` variant of a
+    /// generated enum. Usually means the spec the client was built from is outdated.
+    UnknownEnumValue {
+        /// Subscription channel, or method name for responses.
+        channel: String,
+        /// Path of the field within the message, e.g. `trades[0].direction`.
+        field: String,
+        /// Name of the generated enum.
+        enum_name: &'static str,
+        /// The value itself.
+        value: String,
+    },
+    /// A call took longer than `DeribitClientBuilder::slow_call_threshold`.
+    SlowCall {
+        method: String,
+        /// From sending the request until its response arrived, including waiting for an
+        /// in-flight slot.
+        total: Duration,
+        /// Processing time reported by the server (`usDiff`).
+        server: Duration,
+        /// The rest: network latency and queueing on either side.
+        network: Duration,
+    },
+    /// The result of a call didn't fit its generated type, but did in another shape, see
+    /// `DeribitClientBuilder::tolerate_result_shapes`. Means the endpoint changed since
+    /// the spec the client was built from.
+    ResultShapeChanged {
+        method: String,
+        /// The shape that decoded, e.g. ``field `cancelled` of an object``.
+        decoded_as: String,
+    },
+}
+
+thread_local! {
+    // Unknown enum values seen by the decode running on this thread, if it collects them
+    static UNKNOWN_VALUES: RefCell<Option<Vec<(&'static str, String)>>> =
+        const { RefCell::new(None) };
+}
+
+// Deserializes the catch-all variant of the generated enum `T`
+pub(crate) fn record_unknown<'de, T, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    UNKNOWN_VALUES.with_borrow_mut(|seen| {
+        if let Some(seen) = seen {
+            let enum_name = std::any::type_name::<T>().rsplit("::").next().unwrap();
+            seen.push((enum_name, value.clone()));
+        }
+    });
+    Ok(value)
+}
+
+// Decodes `value` as `T`, reporting unknown enum values when anyone is listening. Takes a
+// reference since notifications are shared between subscribers.
+pub(crate) fn decode<T: DeserializeOwned>(
+    value: &Value,
+    channel: &str,
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> serde_json::Result<T> {
+    if diagnostics.receiver_count() == 0 {
+        return T::deserialize(value);
+    }
+    let previous = UNKNOWN_VALUES.replace(Some(Vec::new()));
+    let decoded = T::deserialize(value);
+    let seen = UNKNOWN_VALUES.replace(previous).unwrap_or_default();
+    for (enum_name, unknown) in seen {
+        let field = field_path(value, &unknown).unwrap_or_default();
+        let _ = diagnostics.send(Diagnostic::UnknownEnumValue {
+            channel: channel.to_string(),
+            field: field.trim_start_matches('.').to_string(),
+            enum_name,
+            value: unknown,
+        });
+    }
+    decoded
+}
+
+// Like `decode`, falling back to the shapes a result may have changed to or from since the
+// spec was written, and reporting the one that decoded
+pub(crate) fn decode_tolerant<T: DeserializeOwned>(
+    value: &Value,
+    method: &str,
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> serde_json::Result<T> {
+    let error = match decode(value, method, diagnostics) {
+        Ok(decoded) => return Ok(decoded),
+        Err(e) => e,
+    };
+    for (decoded_as, alternate) in alternate_shapes(value) {
+        if let Ok(decoded) = decode(&alternate, method, diagnostics) {
+            tracing::warn!(method, decoded_as, error = %error, "result shape changed");
+            let _ = diagnostics.send(Diagnostic::ResultShapeChanged {
+                method: method.to_string(),
+                decoded_as,
+            });
+            return Ok(decoded);
+        }
+    }
+    Err(error)
+}
+
+// An object where a scalar was expected may hold it in a field, and a scalar where an
+// object or list was expected may be a list of one or JSON in a string
+fn alternate_shapes(value: &Value) -> Vec<(String, Value)> {
+    match value {
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(key, field)| (format!("field `{key}` of an object"), field.clone()))
+            .collect(),
+        Value::Array(items) if items.len() == 1 => {
+            vec![("single element of a list".to_string(), items[0].clone())]
+        }
+        Value::Array(_) | Value::Null => Vec::new(),
+        scalar => {
+            let mut shapes = vec![(
+                "list of one".to_string(),
+                Value::Array(vec![scalar.clone()]),
+            )];
+            if let Some(parsed) = scalar.as_str().and_then(|s| serde_json::from_str(s).ok()) {
+                shapes.push(("JSON in a string".to_string(), parsed));
+            }
+            shapes
+        }
+    }
+}
+
+// Like `decode`, for JSON not parsed yet. It is only parsed into a `Value` when someone
+// listens, to locate unknown enum values.
+pub(crate) fn decode_raw<T: DeserializeOwned>(
+    raw: &RawValue,
+    channel: &str,
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> serde_json::Result<T> {
+    if diagnostics.receiver_count() == 0 {
+        return crate::json::from_str(raw.get());
+    }
+    decode(&crate::json::from_str(raw.get())?, channel, diagnostics)
+}
+
+// Path to the first string equal to `needle`, since deserializers don't track their position
+fn field_path(value: &Value, needle: &str) -> Option<String> {
+    match value {
+        Value::String(s) if s == needle => Some(String::new()),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .find_map(|(i, item)| field_path(item, needle).map(|path| format!("[{i}]{path}"))),
+        Value::Object(fields) => fields
+            .iter()
+            .find_map(|(key, field)| field_path(field, needle).map(|path| format!(".{key}{path}"))),
+        _ => None,
+    }
+}
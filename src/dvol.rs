@@ -0,0 +1,238 @@
+//! DVOL, Deribit's 30-day implied volatility index: its candle history, live values and
+//! a rolling store of candles kept from both, see `DvolStore`.
+//!
+//! `public/get_volatility_index_data` returns at most a page of candles per call, newest
+//! last, with a `continuation` to pass as the `end_timestamp` of the call for the page
+//! before it. The `deribit_volatility_index.{index_name}` channel publishes the index
+//! about once a second.
+
+use crate::{
+    Currency, DeribitClient, DeribitVolatilityIndexIndexNameChannel, IndexNameForDvol,
+    PublicGetVolatilityIndexDataRequest, Result, TimeRange, Timestamp, VixResolution,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// DVOL over `[timestamp, timestamp + resolution)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DvolCandle {
+    pub timestamp: Timestamp,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+}
+
+impl DvolCandle {
+    /// Parses a row of `public/get_volatility_index_data`, `[timestamp, open, high, low,
+    /// close]`.
+    pub fn from_row(row: &Value) -> Option<Self> {
+        let row = row.as_array()?;
+        let value = |i: usize| row.get(i)?.as_f64();
+        Some(Self {
+            timestamp: Timestamp(row.first()?.as_i64()?),
+            open: value(1)?,
+            high: value(2)?,
+            low: value(3)?,
+            close: value(4)?,
+        })
+    }
+}
+
+/// The DVOL index of `currency`, e.g. `btc_usd` for BTC; `Unknown` if there is none.
+pub fn dvol_index(currency: &Currency) -> IndexNameForDvol {
+    let index_name = format!(
+        "{}_usd",
+        crate::sub_param_to_string(currency).to_lowercase()
+    );
+    serde_json::from_value(Value::String(index_name.clone()))
+        .unwrap_or(IndexNameForDvol::Unknown(index_name))
+}
+
+fn resolution_millis(resolution: &VixResolution) -> Option<i64> {
+    match resolution {
+        VixResolution::_1 => Some(1_000),
+        VixResolution::_60 => Some(60_000),
+        VixResolution::_3600 => Some(3_600_000),
+        VixResolution::_43200 => Some(43_200_000),
+        VixResolution::_1d => Some(86_400_000),
+        VixResolution::Unknown(_) => None,
+    }
+}
+
+impl DeribitClient {
+    /// DVOL candles of `currency` at `resolution` between `start_timestamp` and
+    /// `end_timestamp`, oldest first, following `continuation` across pages.
+    pub async fn dvol_history(
+        &self,
+        currency: Currency,
+        resolution: VixResolution,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+    ) -> Result<Vec<DvolCandle>> {
+        let mut candles = Vec::new();
+        let mut end = end_timestamp;
+        loop {
+            let page = self
+                .call(PublicGetVolatilityIndexDataRequest {
+                    currency: currency.clone(),
+                    time_range: TimeRange {
+                        start_timestamp,
+                        end_timestamp: end,
+                    },
+                    resolution: resolution.clone(),
+                })
+                .await?;
+            candles.extend(page.data.iter().flatten().filter_map(DvolCandle::from_row));
+            match page.continuation.map(Timestamp) {
+                Some(continuation) if continuation > start_timestamp && continuation < end => {
+                    end = continuation;
+                }
+                _ => break,
+            }
+        }
+        candles.sort_by_key(|candle| candle.timestamp);
+        candles.dedup_by_key(|candle| candle.timestamp);
+        Ok(candles)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    resolution_millis: i64,
+    capacity: usize,
+    candles: VecDeque<DvolCandle>,
+}
+
+impl State {
+    fn insert(&mut self, candle: DvolCandle) {
+        match self
+            .candles
+            .binary_search_by_key(&candle.timestamp, |c| c.timestamp)
+        {
+            Ok(i) => self.candles[i] = candle,
+            Err(i) => self.candles.insert(i, candle),
+        }
+        while self.candles.len() > self.capacity {
+            self.candles.pop_front();
+        }
+    }
+
+    fn push(&mut self, timestamp: Timestamp, volatility: f64) -> Option<DvolCandle> {
+        let start =
+            Timestamp(timestamp.0.div_euclid(self.resolution_millis) * self.resolution_millis);
+        match self.candles.back_mut() {
+            Some(last) if last.timestamp == start => {
+                last.high = last.high.max(volatility);
+                last.low = last.low.min(volatility);
+                last.close = volatility;
+                Some(*last)
+            }
+            // A value of a candle already rolled over
+            Some(last) if last.timestamp > start => None,
+            _ => {
+                let candle = DvolCandle {
+                    timestamp: start,
+                    open: volatility,
+                    high: volatility,
+                    low: volatility,
+                    close: volatility,
+                };
+                self.insert(candle);
+                Some(candle)
+            }
+        }
+    }
+}
+
+/// The latest DVOL candles of one currency, at most `capacity` of them. Clones share
+/// the candles, so a tracking task can keep them current while others read them.
+#[derive(Debug, Clone)]
+pub struct DvolStore {
+    resolution: VixResolution,
+    state: Arc<Mutex<State>>,
+}
+
+impl DvolStore {
+    /// A store of `capacity` candles at `resolution`, `None` for `VixResolution::Unknown`.
+    pub fn new(resolution: VixResolution, capacity: usize) -> Option<Self> {
+        let resolution_millis = resolution_millis(&resolution)?;
+        Some(Self {
+            resolution,
+            state: Arc::new(Mutex::new(State {
+                resolution_millis,
+                capacity: capacity.max(1),
+                candles: VecDeque::new(),
+            })),
+        })
+    }
+
+    /// Adds a candle, e.g. from `DeribitClient::dvol_history`, replacing the one with
+    /// the same timestamp. The oldest candles beyond `capacity` are dropped.
+    pub fn insert(&self, candle: DvolCandle) {
+        self.state.lock().unwrap().insert(candle);
+    }
+
+    /// Folds a value of the index at `timestamp` into the candle of its interval,
+    /// starting a new one as needed, and returns that candle. Values older than the
+    /// latest candle are ignored.
+    pub fn push(&self, timestamp: Timestamp, volatility: f64) -> Option<DvolCandle> {
+        self.state.lock().unwrap().push(timestamp, volatility)
+    }
+
+    /// The stored candles, oldest first.
+    pub fn candles(&self) -> Vec<DvolCandle> {
+        self.state.lock().unwrap().candles.iter().copied().collect()
+    }
+
+    /// The latest candle, still forming while tracking.
+    pub fn latest(&self) -> Option<DvolCandle> {
+        self.state.lock().unwrap().candles.back().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().candles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fills the store with the history of `currency` and keeps it current from
+    /// `deribit_volatility_index`, yielding each candle a value changed. The candles are
+    /// only updated while the stream is polled.
+    pub async fn track(
+        &self,
+        client: &DeribitClient,
+        currency: Currency,
+    ) -> Result<impl Stream<Item = Result<DvolCandle>> + Send + 'static + use<>> {
+        let values = client
+            .subscribe(DeribitVolatilityIndexIndexNameChannel {
+                index_name: dvol_index(&currency),
+            })
+            .await?;
+        let (resolution_millis, capacity) = {
+            let state = self.state.lock().unwrap();
+            (state.resolution_millis, state.capacity as i64)
+        };
+        let end = Timestamp::now();
+        let start = Timestamp((end.0.div_euclid(resolution_millis) - capacity) * resolution_millis);
+        let history = client
+            .dvol_history(currency, self.resolution.clone(), start, end)
+            .await?;
+        for candle in history {
+            self.insert(candle);
+        }
+        let store = self.clone();
+        Ok(values.filter_map(move |value| {
+            let candle = match value {
+                Ok(value) => store.push(value.timestamp, value.volatility).map(Ok),
+                Err(e) => Some(Err(e)),
+            };
+            std::future::ready(candle)
+        }))
+    }
+}
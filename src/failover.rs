@@ -0,0 +1,355 @@
+//! Moving calls and subscriptions to another endpoint when the connection degrades, see
+//! `FailoverClient`.
+//!
+//! The active connection is probed with `DeribitClient::health` on a schedule, and so is
+//! a standby connection to another endpoint, kept warm to take over. After a number of
+//! probes in a row failed or took too long, the standby becomes the active connection.
+//! Subscriptions made through the `FailoverClient` subscribe again on it; calls made
+//! from then on are sent over it. A standby that fails as many probes is dropped, and
+//! the next endpoint is connected in its place on the following probe.
+
+use crate::{ApiRequest, DeribitClient, Env, Error, Result, Subscription};
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, Stream, StreamExt};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// When the active connection counts as degraded.
+#[derive(Debug, Clone)]
+pub struct FailoverConfig {
+    /// How often the active connection is probed.
+    pub probe_interval: Duration,
+    /// Longest acceptable round trip of a probe; slower probes count as failed.
+    pub max_round_trip: Duration,
+    /// Failed probes in a row after which another endpoint is looked for.
+    pub degraded_probes: u32,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(5),
+            max_round_trip: Duration::from_millis(500),
+            degraded_probes: 3,
+        }
+    }
+}
+
+/// What `FailoverClient::monitor` saw.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FailoverEvent {
+    /// The active endpoint failed `degraded_probes` probes in a row.
+    Degraded { url: String },
+    /// Another endpoint could not be connected as the standby, or failed its probes.
+    Unavailable { url: String, error: String },
+    /// A connection to another endpoint is ready to take over.
+    StandbyReady { url: String, round_trip: Duration },
+    /// Calls and subscriptions moved from one endpoint to the other.
+    Migrated {
+        from: String,
+        to: String,
+        round_trip: Duration,
+    },
+    /// No healthy standby was ready, so the active connection is kept.
+    NoHealthyEndpoint,
+}
+
+type Connect = Arc<dyn Fn(Env) -> BoxFuture<'static, Result<DeribitClient>> + Send + Sync>;
+
+/// A client over the healthiest of several endpoints, e.g. the production endpoint and a
+/// gateway in another region. Clones share the connection.
+#[derive(Clone)]
+pub struct FailoverClient {
+    endpoints: Arc<[Env]>,
+    connect: Connect,
+    config: FailoverConfig,
+    // Index of the active endpoint and its client
+    active: Arc<watch::Sender<(usize, Arc<DeribitClient>)>>,
+}
+
+impl std::fmt::Debug for FailoverClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FailoverClient")
+            .field("endpoints", &self.endpoints)
+            .field("config", &self.config)
+            .field("active", &self.active.borrow().0)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FailoverClient {
+    /// Connects to the first of `endpoints` that `connect` succeeds with. `connect` is
+    /// called again for each standby connection, so it should return a client ready for
+    /// use, e.g. built with the same options and authenticated. Fails with
+    /// the last endpoint's error, or `Error::NoEndpoints` if `endpoints` is empty.
+    pub async fn connect<F, Fut>(
+        endpoints: Vec<Env>,
+        config: FailoverConfig,
+        connect: F,
+    ) -> Result<Self>
+    where
+        F: Fn(Env) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<DeribitClient>> + Send + 'static,
+    {
+        let connect: Connect = Arc::new(move |env| connect(env).boxed());
+        let mut last_error = None;
+        for (index, env) in endpoints.iter().enumerate() {
+            match connect(env.clone()).await {
+                Ok(client) => {
+                    let (active, _) = watch::channel((index, Arc::new(client)));
+                    return Ok(Self {
+                        endpoints: endpoints.into(),
+                        connect,
+                        config,
+                        active: Arc::new(active),
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!(url = env.url(), error = %e, "endpoint unavailable");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or(Error::NoEndpoints))
+    }
+
+    /// The active connection. Hold on to it only briefly, as it isn't replaced for
+    /// holders on failover.
+    pub fn client(&self) -> Arc<DeribitClient> {
+        self.active.borrow().1.clone()
+    }
+
+    /// The endpoint of the active connection.
+    pub fn endpoint(&self) -> Env {
+        self.endpoints[self.active.borrow().0].clone()
+    }
+
+    pub async fn call<T: ApiRequest>(&self, req: T) -> Result<T::Response> {
+        self.client().call(req).await
+    }
+
+    pub async fn call_raw(&self, method: &str, params: Value) -> Result<Value> {
+        self.client().call_raw(method, params).await
+    }
+
+    /// Subscribes on the active connection, and again on each connection that replaces
+    /// it. Messages published while moving are missed; when they matter, check for gaps
+    /// (see `integrity`). A failed subscribe is yielded and retried after the next
+    /// failover.
+    pub fn subscribe<S>(
+        &self,
+        subscription: S,
+    ) -> impl Stream<Item = Result<S::Data>> + Send + 'static + use<S>
+    where
+        S: Subscription + Clone + Send + Sync + 'static,
+    {
+        let mut active = self.active.subscribe();
+        active.mark_changed();
+        futures_util::stream::unfold(
+            (active, subscription, None),
+            |(mut active, subscription, mut stream)| async move {
+                loop {
+                    let Some(messages) = &mut stream else {
+                        active.changed().await.ok()?;
+                        let client = active.borrow_and_update().1.clone();
+                        match client.subscribe(subscription.clone()).await {
+                            Ok(messages) => stream = Some(messages),
+                            Err(e) => return Some((Err(e), (active, subscription, None))),
+                        }
+                        continue;
+                    };
+                    let message = tokio::select! {
+                        message = messages.next() => message,
+                        changed = active.changed() => {
+                            changed.ok()?;
+                            active.mark_changed();
+                            None
+                        }
+                    };
+                    match message {
+                        Some(message) => return Some((message, (active, subscription, stream))),
+                        // Connection gone or replaced
+                        None => stream = None,
+                    }
+                }
+            },
+        )
+    }
+
+    /// Probes the active connection every `probe_interval` and moves to the standby once
+    /// it is degraded, yielding what happens as it happens. The standby is a connection
+    /// to another endpoint kept warm and probed on the same schedule, so failing over
+    /// doesn't wait for a connection to be made. Nothing is probed or connected unless
+    /// the stream is polled.
+    pub fn monitor(&self) -> impl Stream<Item = FailoverEvent> + Send + 'static + use<> {
+        let mut probes = tokio::time::interval(self.config.probe_interval);
+        probes.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let monitor = Monitor {
+            failover: self.clone(),
+            probes,
+            failed: 0,
+            standby: None,
+            next_candidate: 0,
+            events: VecDeque::new(),
+        };
+        futures_util::stream::unfold(monitor, |mut monitor| async move {
+            loop {
+                if let Some(event) = monitor.events.pop_front() {
+                    return Some((event, monitor));
+                }
+                monitor.probes.tick().await;
+                monitor.round().await;
+            }
+        })
+    }
+
+    // Round trip of a health check, `None` if it failed or took too long
+    async fn probe(&self, client: &DeribitClient) -> Option<Duration> {
+        let health = client.health(Duration::MAX);
+        tokio::time::timeout(self.config.max_round_trip, health)
+            .await
+            .ok()
+            .and_then(|report| report.round_trip)
+    }
+}
+
+// The state of `FailoverClient::monitor`
+struct Monitor {
+    failover: FailoverClient,
+    probes: tokio::time::Interval,
+    // Failed probes of the active connection in a row
+    failed: u32,
+    standby: Option<Standby>,
+    // Index of the next endpoint to connect as the standby
+    next_candidate: usize,
+    events: VecDeque<FailoverEvent>,
+}
+
+// A connection to another endpoint, ready to take over
+struct Standby {
+    index: usize,
+    client: DeribitClient,
+    // Round trip of the latest probe, `None` if it failed
+    round_trip: Option<Duration>,
+    // Failed probes in a row
+    failed: u32,
+}
+
+impl Monitor {
+    // Probes the active connection and the standby, replaces a standby that is gone
+    // and fails over if the active connection is degraded
+    async fn round(&mut self) {
+        let (index, client) = self.failover.active.borrow().clone();
+        let failover = &self.failover;
+        let standby_probe = async {
+            match &self.standby {
+                Some(standby) => failover.probe(&standby.client).await,
+                None => None,
+            }
+        };
+        let (active_probe, standby_probe) =
+            futures_util::future::join(failover.probe(&client), standby_probe).await;
+        self.failed = match active_probe {
+            Some(_) => 0,
+            None => self.failed + 1,
+        };
+        self.update_standby(standby_probe);
+        if self.standby.is_none() {
+            self.connect_standby(index).await;
+        }
+        if self.failed < self.failover.config.degraded_probes {
+            return;
+        }
+        self.failed = 0;
+        let url = self.failover.endpoints[index].url().to_string();
+        tracing::warn!(url, "endpoint degraded");
+        self.events.push_back(FailoverEvent::Degraded { url });
+        let event = self.fail_over(index);
+        self.events.push_back(event);
+    }
+
+    // Records the standby's probe, dropping it after `degraded_probes` failures in a row
+    fn update_standby(&mut self, round_trip: Option<Duration>) {
+        let Some(standby) = &mut self.standby else {
+            return;
+        };
+        standby.round_trip = round_trip;
+        standby.failed = match round_trip {
+            Some(_) => 0,
+            None => standby.failed + 1,
+        };
+        if standby.failed >= self.failover.config.degraded_probes {
+            let url = self.failover.endpoints[standby.index].url().to_string();
+            let error = "health check failed".to_string();
+            self.events
+                .push_back(FailoverEvent::Unavailable { url, error });
+            self.standby = None;
+        }
+    }
+
+    // Connects to and probes the next endpoint other than the active one, keeping it as
+    // the standby if healthy
+    async fn connect_standby(&mut self, active: usize) {
+        let endpoints = &self.failover.endpoints;
+        let Some(index) = (0..endpoints.len())
+            .map(|i| (self.next_candidate + i) % endpoints.len())
+            .find(|&i| i != active)
+        else {
+            return;
+        };
+        self.next_candidate = index + 1;
+        let url = endpoints[index].url().to_string();
+        let client = match (self.failover.connect)(endpoints[index].clone()).await {
+            Ok(client) => client,
+            Err(e) => {
+                let error = e.to_string();
+                self.events
+                    .push_back(FailoverEvent::Unavailable { url, error });
+                return;
+            }
+        };
+        let Some(round_trip) = self.failover.probe(&client).await else {
+            let error = "health check failed".to_string();
+            self.events
+                .push_back(FailoverEvent::Unavailable { url, error });
+            return;
+        };
+        self.standby = Some(Standby {
+            index,
+            client,
+            round_trip: Some(round_trip),
+            failed: 0,
+        });
+        self.events
+            .push_back(FailoverEvent::StandbyReady { url, round_trip });
+    }
+
+    // Makes the standby active if its latest probe succeeded
+    fn fail_over(&mut self, from: usize) -> FailoverEvent {
+        let (index, client, round_trip) = match self.standby.take() {
+            Some(Standby {
+                index,
+                client,
+                round_trip: Some(round_trip),
+                ..
+            }) => (index, client, round_trip),
+            standby => {
+                self.standby = standby;
+                return FailoverEvent::NoHealthyEndpoint;
+            }
+        };
+        let from = self.failover.endpoints[from].url().to_string();
+        let to = self.failover.endpoints[index].url().to_string();
+        tracing::info!(from, to, ?round_trip, "failing over");
+        self.failover.active.send_replace((index, Arc::new(client)));
+        FailoverEvent::Migrated {
+            from,
+            to,
+            round_trip,
+        }
+    }
+}
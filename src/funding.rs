@@ -0,0 +1,172 @@
+//! Perpetual funding: live rates from the ticker, the hourly history, and what a position
+//! paid or received over a time range, see `DeribitClient::accrued_funding`.
+//!
+//! Funding accrues continuously and is settled with the session profit. Deribit records
+//! the rate of each hour in `public/get_funding_rate_history`; longs pay shorts when it
+//! is positive, in proportion to the position's value in the base currency.
+
+use crate::{
+    ContractType, DeribitClient, InstrumentName, Money, PublicGetFundingRateHistoryRequest,
+    PublicGetFundingRateHistoryResponse, Result, SubscriptionInterval, TickerInstrumentNameChannel,
+    TimeRange, Timestamp, money,
+};
+use futures_util::{Stream, StreamExt};
+
+const HOUR_MILLIS: i64 = 3_600_000;
+
+// Range of each history request, to keep responses small
+const HISTORY_CHUNK_MILLIS: i64 = 30 * 24 * HOUR_MILLIS;
+
+/// The funding rate of a perpetual from its ticker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundingUpdate {
+    pub instrument_name: String,
+    pub timestamp: Timestamp,
+    /// Rate at this moment.
+    pub current_funding: f64,
+    /// Rate over the last 8 hours.
+    pub funding_8h: f64,
+    pub index_price: Money,
+}
+
+/// The funding rate of one hour.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FundingRate {
+    /// End of the hour.
+    pub timestamp: Timestamp,
+    pub interest_1h: f64,
+    pub interest_8h: f64,
+    pub index_price: Money,
+}
+
+impl From<&PublicGetFundingRateHistoryResponse> for FundingRate {
+    fn from(record: &PublicGetFundingRateHistoryResponse) -> Self {
+        let rate = |value: &Option<serde_json::Value>| {
+            value
+                .as_ref()
+                .and_then(serde_json::Value::as_f64)
+                .unwrap_or_default()
+        };
+        Self {
+            timestamp: record.timestamp.unwrap_or_default(),
+            interest_1h: rate(&record.interest_1h),
+            interest_8h: rate(&record.interest_8h),
+            index_price: record.index_price.unwrap_or_default(),
+        }
+    }
+}
+
+/// Funding received by `size` (negative when short) of a perpetual over the hours of
+/// `rates`, in the settlement currency; negative when paid. `size` is in USD for inverse
+/// perpetuals and in the base currency for linear ones. Fails with
+/// `Error::InvalidMoney` for a rate that isn't a finite number.
+pub fn accrued_funding(
+    contract: ContractType,
+    size: Money,
+    rates: &[FundingRate],
+) -> Result<Money> {
+    let zero = Money::default();
+    rates.iter().try_fold(zero, |accrued, rate| {
+        let paid = match contract {
+            ContractType::Inverse if rate.index_price > zero => size / rate.index_price,
+            ContractType::Inverse => zero,
+            ContractType::Linear => size * rate.index_price,
+        };
+        Ok(accrued - paid * money(rate.interest_1h)?)
+    })
+}
+
+impl DeribitClient {
+    /// Yields the funding rate of the perpetual `instrument_name` from its ticker at
+    /// 100ms, each time it changes.
+    pub async fn subscribe_funding(
+        &self,
+        instrument_name: &str,
+    ) -> Result<impl Stream<Item = Result<FundingUpdate>> + Send + 'static + use<>> {
+        let tickers = self
+            .subscribe(TickerInstrumentNameChannel {
+                instrument_name: instrument_name.to_string(),
+                interval: SubscriptionInterval::_100ms,
+            })
+            .await?;
+        let mut last = None;
+        Ok(tickers.filter_map(move |ticker| {
+            let update = match ticker {
+                Ok(ticker) => {
+                    let rates = (ticker.current_funding, ticker.funding_8h);
+                    match rates {
+                        (Some(current_funding), Some(funding_8h)) if last != Some(rates) => {
+                            last = Some(rates);
+                            Some(Ok(FundingUpdate {
+                                instrument_name: ticker.instrument_name,
+                                timestamp: ticker.timestamp,
+                                current_funding,
+                                funding_8h,
+                                index_price: ticker.index_price,
+                            }))
+                        }
+                        _ => None,
+                    }
+                }
+                Err(e) => Some(Err(e)),
+            };
+            std::future::ready(update)
+        }))
+    }
+
+    /// The hourly funding rates of `instrument_name` between `start_timestamp` and
+    /// `end_timestamp`, oldest first. Long ranges take one request per 30 days.
+    pub async fn funding_rate_history(
+        &self,
+        instrument_name: &str,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+    ) -> Result<Vec<FundingRate>> {
+        let mut rates = Vec::new();
+        let mut start = start_timestamp;
+        while start < end_timestamp {
+            let end = Timestamp(start.0 + HISTORY_CHUNK_MILLIS).min(end_timestamp);
+            let records = self
+                .call(PublicGetFundingRateHistoryRequest {
+                    instrument_name: instrument_name.to_string(),
+                    time_range: TimeRange {
+                        start_timestamp: start,
+                        end_timestamp: end,
+                    },
+                })
+                .await?;
+            rates.extend(records.iter().map(FundingRate::from));
+            start = end;
+        }
+        rates.sort_by_key(|rate| rate.timestamp);
+        // Chunks share their boundary
+        rates.dedup_by_key(|rate| rate.timestamp);
+        Ok(rates)
+    }
+
+    /// Funding received by `size` of the perpetual `instrument_name` (negative when
+    /// short) held from `start_timestamp` to `end_timestamp`, in the settlement
+    /// currency; negative when paid. Only whole hours ending in the range count.
+    pub async fn accrued_funding(
+        &self,
+        instrument_name: &str,
+        size: Money,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+    ) -> Result<Money> {
+        let contract = instrument_name
+            .parse::<InstrumentName>()
+            .map_or(ContractType::Inverse, |name| ContractType::of(&name));
+        let rates = self
+            .funding_rate_history(instrument_name, start_timestamp, end_timestamp)
+            .await?;
+        let rates: Vec<_> = rates
+            .into_iter()
+            .filter(|rate| {
+                rate.timestamp.0 - HOUR_MILLIS >= start_timestamp.0
+                    && rate.timestamp <= end_timestamp
+            })
+            .collect();
+        accrued_funding(contract, size, &rates)
+    }
+}
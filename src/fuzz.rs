@@ -0,0 +1,77 @@
+//! Fuzzing entry points, enabled with the `fuzz` feature.
+//!
+//! With it, protocol messages and all generated types implement `arbitrary::Arbitrary`.
+//! The targets in `fuzz/` drive the functions below, e.g. `cargo +nightly fuzz run decode_message`.
+
+pub use arbitrary;
+
+use crate::{ChannelKey, JsonRPCMessage, Subscribers, json, notification_key};
+use arbitrary::{Arbitrary, Result, Unstructured};
+use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
+use serde_json::{Map, Number, Value};
+
+// Nesting limit for generated JSON, deeper than any Deribit message
+const MAX_DEPTH: usize = 8;
+
+fn arbitrary_value(u: &mut Unstructured, depth: usize) -> Result<Value> {
+    let max_kind = if depth >= MAX_DEPTH { 4 } else { 6 };
+    Ok(match u.int_in_range(0..=max_kind)? {
+        0 => Value::Null,
+        1 => Value::Bool(u.arbitrary()?),
+        2 => Value::from(u.arbitrary::<i64>()?),
+        3 => Number::from_f64(u.arbitrary()?).map_or(Value::Null, Value::Number),
+        4 => Value::String(u.arbitrary()?),
+        5 => {
+            let len = u.int_in_range(0..=8)?;
+            let mut items = Vec::with_capacity(len);
+            for _ in 0..len {
+                items.push(arbitrary_value(u, depth + 1)?);
+            }
+            Value::Array(items)
+        }
+        _ => {
+            let len = u.int_in_range(0..=8)?;
+            let mut fields = Map::new();
+            for _ in 0..len {
+                fields.insert(u.arbitrary()?, arbitrary_value(u, depth + 1)?);
+            }
+            Value::Object(fields)
+        }
+    })
+}
+
+/// Builds a value from arbitrary JSON, falling back to the default when the JSON doesn't
+/// fit `T`. Used for fields holding `serde_json::Value`, which has no `Arbitrary` impl.
+pub fn arbitrary_json<T: DeserializeOwned + Default>(u: &mut Unstructured) -> Result<T> {
+    Ok(serde_json::from_value(arbitrary_value(u, 0)?).unwrap_or_default())
+}
+
+/// Like `arbitrary_json`, for fields holding raw JSON text.
+pub fn arbitrary_raw_json(u: &mut Unstructured) -> Result<Box<RawValue>> {
+    Ok(serde_json::value::to_raw_value(&arbitrary_value(u, 0)?).expect("values serialize"))
+}
+
+/// Serializes an arbitrary well-formed server message (response, error, notification or
+/// heartbeat), which reaches deeper into `decode_message` than random bytes.
+pub fn arbitrary_message(u: &mut Unstructured) -> Result<String> {
+    let message = JsonRPCMessage::arbitrary(u)?;
+    Ok(serde_json::to_string(&message).expect("protocol messages serialize"))
+}
+
+/// Runs `text` through the same steps the reader applies to a server message: routing
+/// notifications to one of `workers` decode workers, decoding and publishing.
+pub fn decode_message(text: &str, workers: usize) {
+    let mut subscribers = Subscribers::default();
+    if let Some(key) = notification_key(text) {
+        let _ = key.shard(workers.max(1));
+    }
+    if let Some(notification) = json::notification(text) {
+        let channel = &notification.channel;
+        let key = ChannelKey::new(channel);
+        let _receiver = subscribers.subscribe(channel.to_string(), 1, Default::default());
+        subscribers.publish(key, channel, notification.data, text.to_string().into());
+    } else {
+        let _ = json::from_str::<JsonRPCMessage>(text);
+    }
+}
@@ -0,0 +1,187 @@
+//! Which exchanges make up Deribit's price indices, see `IndexTracker`.
+//!
+//! Each index is a weighted average of prices from several exchanges. The
+//! `deribit_price_ranking.{index_name}` channel publishes all of them on every update,
+//! including those currently excluded, which is what index-arb strategies need to know
+//! when an exchange drops out.
+
+use crate::{
+    DeribitClient, DeribitPriceRankingIndexNameChannel, DeribitPriceRankingNotification, IndexName,
+    PublicGetIndexPriceNamesRequest, PublicGetIndexPriceNamesResponse, Result, Timestamp,
+    money_f64,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// An exchange's entry in an index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Constituent {
+    /// Exchange identifier, e.g. `bitstamp`.
+    pub exchange: String,
+    pub enabled: bool,
+    /// Weight in percent.
+    pub weight: f64,
+    /// Price after Deribit's adjustments, the one entering the index.
+    pub price: Option<f64>,
+    /// Price as reported by the exchange.
+    pub original_price: Option<f64>,
+    /// Last update from the exchange.
+    pub timestamp: Option<Timestamp>,
+}
+
+impl Constituent {
+    /// Whether the exchange currently moves the index.
+    pub fn is_contributing(&self) -> bool {
+        self.enabled && self.weight > 0.0
+    }
+}
+
+impl From<DeribitPriceRankingNotification> for Constituent {
+    fn from(ranking: DeribitPriceRankingNotification) -> Self {
+        Self {
+            exchange: ranking.identifier.unwrap_or_default(),
+            enabled: ranking.enabled.unwrap_or_default(),
+            weight: ranking.weight.unwrap_or_default(),
+            price: ranking.price.map(money_f64),
+            original_price: ranking.original_price.map(money_f64),
+            timestamp: ranking.timestamp,
+        }
+    }
+}
+
+/// All exchanges of an index as of the last ranking update.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexComposition {
+    pub index_name: String,
+    pub constituents: Vec<Constituent>,
+}
+
+impl IndexComposition {
+    pub fn contributing(&self) -> impl Iterator<Item = &Constituent> {
+        self.constituents.iter().filter(|c| c.is_contributing())
+    }
+
+    pub fn get(&self, exchange: &str) -> Option<&Constituent> {
+        self.constituents.iter().find(|c| c.exchange == exchange)
+    }
+
+    /// Contributing exchanges whose last update is older than `max_age_millis` at `now`,
+    /// still in the index but likely not quoting.
+    pub fn stale(&self, now: Timestamp, max_age_millis: i64) -> impl Iterator<Item = &Constituent> {
+        self.contributing()
+            .filter(move |c| c.timestamp.is_some_and(|t| now.0 - t.0 > max_age_millis))
+    }
+}
+
+/// A ranking update with the exchanges that stopped or resumed contributing since the
+/// previous one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexUpdate {
+    pub composition: IndexComposition,
+    pub outages: Vec<String>,
+    pub restored: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    names: Option<Vec<PublicGetIndexPriceNamesResponse>>,
+    compositions: HashMap<String, IndexComposition>,
+}
+
+/// Caches index names and the latest composition of tracked indices. Clones share the
+/// cache, so a tracking task can keep it current while others read it.
+#[derive(Debug, Clone, Default)]
+pub struct IndexTracker {
+    state: Arc<Mutex<State>>,
+}
+
+impl IndexTracker {
+    /// Index names with their combo settings, fetched with `public/get_index_price_names`
+    /// on first use.
+    pub async fn names(
+        &self,
+        client: &DeribitClient,
+    ) -> Result<Vec<PublicGetIndexPriceNamesResponse>> {
+        if let Some(names) = &self.state.lock().unwrap().names {
+            return Ok(names.clone());
+        }
+        self.refresh_names(client).await
+    }
+
+    /// Fetches the index names again, e.g. after new ones are listed.
+    pub async fn refresh_names(
+        &self,
+        client: &DeribitClient,
+    ) -> Result<Vec<PublicGetIndexPriceNamesResponse>> {
+        let names = client
+            .call(PublicGetIndexPriceNamesRequest {
+                extended: Some(true),
+            })
+            .await?;
+        self.state.lock().unwrap().names = Some(names.clone());
+        Ok(names)
+    }
+
+    /// The latest composition of `index_name`, if tracked.
+    pub fn composition(&self, index_name: &IndexName) -> Option<IndexComposition> {
+        let key = crate::sub_param_to_string(index_name);
+        self.state.lock().unwrap().compositions.get(&key).cloned()
+    }
+
+    /// Subscribes to the ranking of `index_name`, keeping its composition in the cache
+    /// and yielding each update. Outages are exchanges that contributed in the previous
+    /// update and no longer do, because they were disabled, weighted out or dropped.
+    pub async fn track(
+        &self,
+        client: &DeribitClient,
+        index_name: IndexName,
+    ) -> Result<impl Stream<Item = Result<IndexUpdate>> + Send + 'static + use<>> {
+        let key = crate::sub_param_to_string(&index_name);
+        let rankings = client
+            .subscribe(DeribitPriceRankingIndexNameChannel { index_name })
+            .await?;
+        let state = self.state.clone();
+        Ok(rankings.map(move |rankings| {
+            let composition = IndexComposition {
+                index_name: key.clone(),
+                constituents: rankings?.into_iter().map(Constituent::from).collect(),
+            };
+            let previous = state
+                .lock()
+                .unwrap()
+                .compositions
+                .insert(key.clone(), composition.clone());
+            let was_contributing = |exchange: &str| {
+                previous
+                    .as_ref()
+                    .and_then(|previous| previous.get(exchange))
+                    .is_some_and(Constituent::is_contributing)
+            };
+            let outages = previous
+                .iter()
+                .flat_map(IndexComposition::contributing)
+                .filter(|c| {
+                    !composition
+                        .get(&c.exchange)
+                        .is_some_and(Constituent::is_contributing)
+                })
+                .map(|c| c.exchange.clone())
+                .collect();
+            // Everything contributing in the first update is the baseline, not a recovery
+            let restored = match &previous {
+                Some(_) => composition
+                    .contributing()
+                    .filter(|c| !was_contributing(&c.exchange))
+                    .map(|c| c.exchange.clone())
+                    .collect(),
+                None => Vec::new(),
+            };
+            Ok(IndexUpdate {
+                composition,
+                outages,
+                restored,
+            })
+        }))
+    }
+}
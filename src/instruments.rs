@@ -0,0 +1,319 @@
+//! A local copy of the listed instruments, see `InstrumentCache`.
+//!
+//! Instruments are fetched with `public/get_instruments` and kept current from the
+//! `instrument.state.any.any` channel, which announces listings and delistings, with a
+//! periodic full refresh to catch changed metadata.
+//!
+//! `Instrument` gets helpers to round prices and amounts to what the exchange accepts,
+//! so orders aren't rejected for an invalid price or amount.
+
+use crate::{
+    CurrencyWithAny, DeribitClient, Direction, Error, Instrument,
+    InstrumentStateKindCurrencyChannel, Kind, KindWithAny, PublicGetInstrumentRequest,
+    PublicGetInstrumentsRequest, Result, StateNotification, StateNotificationState, Timestamp,
+    money_f64,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Relative distance from a multiple of a step still counted as on it, to absorb floating
+// point error
+const STEP_TOLERANCE: f64 = 1e-9;
+
+impl Instrument {
+    /// Tick size at `price`, taking `tick_size_steps` into account, e.g. for options
+    /// whose tick grows with the price.
+    pub fn tick_size_at(&self, price: f64) -> f64 {
+        self.tick_size_steps
+            .iter()
+            .flatten()
+            .filter_map(|step| Some((money_f64(step.above_price?), step.tick_size?)))
+            .filter(|(above_price, _)| price > *above_price)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map_or(self.tick_size, |(_, tick_size)| tick_size)
+    }
+
+    /// Amounts must be multiples of this: the minimum trade amount, e.g. 10 USD for
+    /// BTC-PERPETUAL or 0.1 BTC for BTC options.
+    pub fn amount_step(&self) -> f64 {
+        let min_trade_amount = money_f64(self.min_trade_amount);
+        if min_trade_amount > 0.0 {
+            min_trade_amount
+        } else {
+            self.contract_size
+        }
+    }
+
+    /// `price` rounded to the nearest tick.
+    pub fn round_price_to_tick(&self, price: f64) -> f64 {
+        snap(price, self.tick_size_at(price), f64::round)
+    }
+
+    /// `price` rounded to a tick away from the market: down for buys and up for sells,
+    /// so a limit order never becomes more aggressive than asked for.
+    pub fn round_price_passive(&self, price: f64, direction: &Direction) -> f64 {
+        let tick_size = self.tick_size_at(price);
+        match direction {
+            Direction::Sell => snap(price, tick_size, f64::ceil),
+            _ => snap(price, tick_size, f64::floor),
+        }
+    }
+
+    /// `amount` rounded down to a multiple of `amount_step`, so an order never exceeds
+    /// the size asked for. Zero when below the minimum.
+    pub fn round_amount_to_contract(&self, amount: f64) -> f64 {
+        snap(amount, self.amount_step(), f64::floor)
+    }
+
+    /// Whether `price` is positive and on a tick.
+    pub fn is_valid_price(&self, price: f64) -> bool {
+        price > 0.0 && is_multiple(price, self.tick_size_at(price))
+    }
+
+    /// Whether `amount` is at least the minimum trade amount and a multiple of
+    /// `amount_step`.
+    pub fn is_valid_amount(&self, amount: f64) -> bool {
+        amount >= self.amount_step() * (1.0 - STEP_TOLERANCE)
+            && is_multiple(amount, self.amount_step())
+    }
+}
+
+// Rounds `value` to a multiple of `step` with `round`, leaving values already on a step
+// alone and trimming the floating point error multiplying back adds
+fn snap(value: f64, step: f64, round: fn(f64) -> f64) -> f64 {
+    if step <= 0.0 || !value.is_finite() {
+        return value;
+    }
+    let steps = value / step;
+    let steps = if (steps - steps.round()).abs() < STEP_TOLERANCE {
+        steps.round()
+    } else {
+        round(steps)
+    };
+    let scale = 10f64.powi(decimals(step));
+    (steps * step * scale).round() / scale
+}
+
+fn is_multiple(value: f64, step: f64) -> bool {
+    step <= 0.0 || {
+        let steps = value / step;
+        (steps - steps.round()).abs() < STEP_TOLERANCE
+    }
+}
+
+// Decimal places of `step`, e.g. 4 for 0.0005
+fn decimals(step: f64) -> i32 {
+    let step = step.to_string();
+    step.split_once('.')
+        .map_or(0, |(_, fraction)| fraction.len() as i32)
+}
+
+/// Which instruments `InstrumentCache::filter` returns. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct InstrumentFilter {
+    pub kind: Option<Kind>,
+    /// Base currency, e.g. `BTC`.
+    pub currency: Option<String>,
+    /// Only instruments expiring after this time.
+    pub expires_after: Option<Timestamp>,
+    /// Only instruments expiring before this time.
+    pub expires_before: Option<Timestamp>,
+}
+
+impl InstrumentFilter {
+    fn matches(&self, instrument: &Instrument) -> bool {
+        self.kind
+            .as_ref()
+            .is_none_or(|kind| *kind == instrument.kind)
+            && self.currency.as_deref().is_none_or(|currency| {
+                crate::sub_param_to_string(&instrument.base_currency) == currency
+            })
+            && self
+                .expires_after
+                .is_none_or(|after| instrument.expiration_timestamp > after)
+            && self
+                .expires_before
+                .is_none_or(|before| instrument.expiration_timestamp < before)
+    }
+}
+
+/// A change to an `InstrumentCache`, yielded by `InstrumentCache::track`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstrumentEvent {
+    /// All instruments were fetched again; `count` are listed now.
+    Refreshed { count: usize },
+    /// An instrument was created or started trading.
+    Listed(Box<Instrument>),
+    /// An instrument settled, closed or was deactivated, and left the cache.
+    Delisted {
+        instrument_name: String,
+        state: StateNotificationState,
+    },
+}
+
+/// Caches the active instruments by name. Clones share the cache, so a tracking task can
+/// keep it current while others read it.
+#[derive(Debug, Clone, Default)]
+pub struct InstrumentCache {
+    instruments: Arc<Mutex<BTreeMap<String, Instrument>>>,
+}
+
+impl InstrumentCache {
+    /// Replaces the cache with the active instruments of all currencies and kinds,
+    /// returning how many there are.
+    pub async fn refresh(&self, client: &DeribitClient) -> Result<usize> {
+        let instruments = client
+            .call(PublicGetInstrumentsRequest {
+                currency: CurrencyWithAny::Any,
+                kind: None,
+                expired: Some(false),
+            })
+            .await?;
+        let instruments = instruments
+            .into_iter()
+            .map(|instrument| (instrument.instrument_name.clone(), instrument))
+            .collect::<BTreeMap<_, _>>();
+        let count = instruments.len();
+        *self.instruments.lock().unwrap() = instruments;
+        Ok(count)
+    }
+
+    pub fn get(&self, instrument_name: &str) -> Option<Instrument> {
+        self.instruments
+            .lock()
+            .unwrap()
+            .get(instrument_name)
+            .cloned()
+    }
+
+    /// Cached instruments matching `filter`, by name.
+    pub fn filter(&self, filter: &InstrumentFilter) -> Vec<Instrument> {
+        self.instruments
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|instrument| filter.matches(instrument))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.instruments.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Tick size of `instrument_name` at `price`, taking the instrument's
+    /// `tick_size_steps` into account, e.g. for options whose tick grows with the price.
+    pub fn tick_size(&self, instrument_name: &str, price: f64) -> Option<f64> {
+        self.instruments
+            .lock()
+            .unwrap()
+            .get(instrument_name)
+            .map(|instrument| instrument.tick_size_at(price))
+    }
+
+    /// Contract size of `instrument_name`, e.g. 10 USD for BTC perpetuals.
+    pub fn contract_size(&self, instrument_name: &str) -> Option<f64> {
+        self.instruments
+            .lock()
+            .unwrap()
+            .get(instrument_name)
+            .map(|instrument| instrument.contract_size)
+    }
+
+    /// Fills the cache and keeps it current: subscribes to `instrument.state.any.any`,
+    /// fetching listed instruments and dropping delisted ones as announced, and fetches
+    /// everything again every `refresh_every`. Yields each change; the cache is only
+    /// updated while the stream is polled.
+    pub async fn track(
+        &self,
+        client: &Arc<DeribitClient>,
+        refresh_every: Duration,
+    ) -> Result<impl Stream<Item = Result<InstrumentEvent>> + Send + 'static + use<>> {
+        let states = client
+            .subscribe(InstrumentStateKindCurrencyChannel {
+                kind: KindWithAny::Any,
+                currency: CurrencyWithAny::Any,
+            })
+            .await?;
+        let count = self.refresh(client).await?;
+        let mut refreshes = tokio::time::interval_at(
+            tokio::time::Instant::now() + refresh_every,
+            refresh_every.max(Duration::from_millis(1)),
+        );
+        refreshes.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let first = InstrumentEvent::Refreshed { count };
+        let state = (self.clone(), client.clone(), states, refreshes, Some(first));
+        Ok(futures_util::stream::unfold(
+            state,
+            |(cache, client, mut states, mut refreshes, first)| async move {
+                if let Some(first) = first {
+                    return Some((Ok(first), (cache, client, states, refreshes, None)));
+                }
+                loop {
+                    let event = tokio::select! {
+                        notification = states.next() => match notification? {
+                            Ok(notification) => cache.apply(&client, notification).await.transpose(),
+                            Err(e) => Some(Err(e)),
+                        },
+                        _ = refreshes.tick() => Some(
+                            cache
+                                .refresh(&client)
+                                .await
+                                .map(|count| InstrumentEvent::Refreshed { count }),
+                        ),
+                    };
+                    if let Some(event) = event {
+                        return Some((event, (cache, client, states, refreshes, None)));
+                    }
+                }
+            },
+        ))
+    }
+
+    async fn apply(
+        &self,
+        client: &DeribitClient,
+        notification: StateNotification,
+    ) -> Result<Option<InstrumentEvent>> {
+        let (Some(instrument_name), Some(state)) =
+            (notification.instrument_name, notification.state)
+        else {
+            return Ok(None);
+        };
+        match state {
+            StateNotificationState::Created | StateNotificationState::Started => {
+                let request = PublicGetInstrumentRequest {
+                    instrument_name: instrument_name.clone(),
+                };
+                let instrument = match client.call(request).await {
+                    Ok(instrument) => instrument,
+                    // Gone again before it could be fetched
+                    Err(Error::RpcError(e)) => {
+                        tracing::debug!(instrument = instrument_name, error = %e, "listed instrument not found");
+                        return Ok(None);
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.instruments
+                    .lock()
+                    .unwrap()
+                    .insert(instrument_name, instrument.clone());
+                Ok(Some(InstrumentEvent::Listed(Box::new(instrument))))
+            }
+            StateNotificationState::Unknown(_) => Ok(None),
+            state => {
+                let removed = self.instruments.lock().unwrap().remove(&instrument_name);
+                Ok(removed.map(|_| InstrumentEvent::Delisted {
+                    instrument_name,
+                    state,
+                }))
+            }
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! Checksums of recorded market data, to find gaps and corruption in a dataset before
+//! it is used for research.
+//!
+//! While recording, a `Digester` summarizes each period of a channel in a `Digest`:
+//! message count, an order-independent checksum and the sequence gaps seen live. Periods
+//! are aligned on message timestamps, so `verify` can recompute the digests from the
+//! recorded messages, in any order, and report where the dataset differs.
+//!
+//! Live, `SubscriptionStream::detect_gaps` uses the same sequences to tell a consumer
+//! when its view of a channel went stale.
+
+use crate::{
+    BookNotificationRaw, Error, PublicTrade, Result, SubscriptionStream, TickerNotification,
+    Timestamp,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// A message with a position in a per-key sequence, e.g. trades by `trade_seq` per
+/// instrument.
+pub trait Sequenced: Serialize {
+    /// Time of the message, which decides its period.
+    fn timestamp(&self) -> Timestamp;
+
+    /// Sequence key, e.g. the instrument, and the message's position in it.
+    fn sequence(&self) -> (&str, i64);
+
+    /// Position the previous message of the key must have had, `None` if the message
+    /// starts a new sequence. Contiguous by default.
+    fn previous(&self) -> Option<i64> {
+        Some(self.sequence().1 - 1)
+    }
+}
+
+impl Sequenced for PublicTrade {
+    fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    fn sequence(&self) -> (&str, i64) {
+        (&self.instrument_name, self.trade_seq)
+    }
+}
+
+// Changes link to the previous change, snapshots start over
+impl Sequenced for BookNotificationRaw {
+    fn timestamp(&self) -> Timestamp {
+        self.timestamp.unwrap_or_default()
+    }
+
+    fn sequence(&self) -> (&str, i64) {
+        (&self.instrument_name, self.change_id)
+    }
+
+    fn previous(&self) -> Option<i64> {
+        self.prev_change_id
+    }
+}
+
+// Tickers only have a timestamp, so missed ones show up as lag alone
+impl Sequenced for TickerNotification {
+    fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    fn sequence(&self) -> (&str, i64) {
+        (&self.instrument_name, self.timestamp.as_millis())
+    }
+
+    fn previous(&self) -> Option<i64> {
+        None
+    }
+}
+
+/// Data of a channel whose messages are `Sequenced`, either one per notification or a
+/// batch like trades.
+pub trait SequencedData {
+    type Message: Sequenced;
+
+    fn messages(&self) -> &[Self::Message];
+}
+
+impl<T: Sequenced> SequencedData for Vec<T> {
+    type Message = T;
+
+    fn messages(&self) -> &[T] {
+        self
+    }
+}
+
+impl SequencedData for BookNotificationRaw {
+    type Message = Self;
+
+    fn messages(&self) -> &[Self] {
+        std::slice::from_ref(self)
+    }
+}
+
+impl SequencedData for TickerNotification {
+    type Message = Self;
+
+    fn messages(&self) -> &[Self] {
+        std::slice::from_ref(self)
+    }
+}
+
+/// Messages missing from a sequence: `next` followed `after` without the ones between.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Gap {
+    pub key: String,
+    pub after: i64,
+    pub next: i64,
+}
+
+/// Summary of the messages of a channel whose timestamps fall in `[start, end)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    pub channel: String,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub count: u64,
+    /// Wrapping sum of the FNV-1a hashes of the messages serialized as JSON.
+    pub checksum: u64,
+    /// Gaps in the messages themselves, e.g. while the stream lagged.
+    pub gaps: Vec<Gap>,
+}
+
+/// Why `SubscriptionStream::detect_gaps` found messages missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GapDetected {
+    /// The next message doesn't follow the previous one of its key.
+    Sequence(Gap),
+    /// The stream fell behind and `skipped` notifications were dropped, see
+    /// `Error::SubscriptionLagged`.
+    Lagged { skipped: u64 },
+}
+
+/// An item of `SubscriptionStream::detect_gaps`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GapEvent<T> {
+    Message(T),
+    /// Messages were lost before the next one, so state built from earlier ones is stale.
+    GapDetected(GapDetected),
+}
+
+// The gap between the last position of a key and a message at `position` linking to
+// `previous`, if the message isn't a duplicate
+fn gap_after(key: &str, last: i64, position: i64, previous: Option<i64>) -> Option<Gap> {
+    previous
+        .filter(|&previous| position > last && previous != last)
+        .map(|_| Gap {
+            key: key.to_string(),
+            after: last,
+            next: position,
+        })
+}
+
+/// How a recorded dataset differs from the digests taken while recording it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The period has a different number of messages than were recorded. A period
+    /// without a digest expects none.
+    CountMismatch {
+        start: Timestamp,
+        expected: u64,
+        found: u64,
+    },
+    /// The period has as many messages as were recorded, but different ones.
+    ChecksumMismatch { start: Timestamp },
+    /// A gap in the dataset that the recorded stream didn't have.
+    Gap(Gap),
+}
+
+// Tracks the last position of each key and buckets messages into periods
+#[derive(Debug)]
+struct Periods {
+    channel: String,
+    period_millis: i64,
+    open: BTreeMap<Timestamp, Digest>,
+    last: HashMap<String, i64>,
+}
+
+impl Periods {
+    fn new(channel: String, period: Duration) -> Self {
+        Self {
+            channel,
+            period_millis: (period.as_millis() as i64).max(1),
+            open: BTreeMap::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    // Messages at or before the last position of their key are duplicates and skipped,
+    // like in a store that keeps each message once
+    fn push<T: Sequenced>(&mut self, msg: &T) {
+        let (key, position) = msg.sequence();
+        let gap = match self.last.get(key) {
+            Some(&last) if position <= last => return,
+            Some(&last) => gap_after(key, last, position, msg.previous()),
+            None => None,
+        };
+        self.last.insert(key.to_string(), position);
+
+        let start = msg.timestamp().0.div_euclid(self.period_millis) * self.period_millis;
+        let digest = self.open.entry(Timestamp(start)).or_insert_with(|| Digest {
+            channel: self.channel.clone(),
+            start: Timestamp(start),
+            end: Timestamp(start + self.period_millis),
+            count: 0,
+            checksum: 0,
+            gaps: Vec::new(),
+        });
+        digest.count += 1;
+        digest.checksum = digest.checksum.wrapping_add(hash(msg));
+        digest.gaps.extend(gap);
+    }
+
+    // Closes the periods ending before `timestamp`
+    fn close_before(&mut self, timestamp: Timestamp) -> Vec<Digest> {
+        let open = self
+            .open
+            .split_off(&Timestamp(timestamp.0 - self.period_millis + 1));
+        std::mem::replace(&mut self.open, open)
+            .into_values()
+            .collect()
+    }
+}
+
+// FNV-1a, stable across Rust versions unlike `DefaultHasher`
+fn hash<T: Serialize>(msg: &T) -> u64 {
+    let bytes = serde_json::to_vec(msg).unwrap_or_default();
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Computes the digests of a channel's messages as they are recorded.
+#[derive(Debug)]
+pub struct Digester<T> {
+    periods: Periods,
+    _message: PhantomData<fn(&T)>,
+}
+
+impl<T: Sequenced> Digester<T> {
+    /// Digests `channel` in periods of `period`, e.g. one minute.
+    pub fn new(channel: impl Into<String>, period: Duration) -> Self {
+        Self {
+            periods: Periods::new(channel.into(), period),
+            _message: PhantomData,
+        }
+    }
+
+    /// Adds a message, returning the digests of the periods that ended a full period
+    /// before it, which late messages of other keys can no longer reach.
+    pub fn push(&mut self, msg: &T) -> Vec<Digest> {
+        self.periods.push(msg);
+        let grace = self.periods.period_millis;
+        self.periods
+            .close_before(Timestamp(msg.timestamp().0 - grace))
+    }
+
+    /// Returns the digests of the periods still open.
+    pub fn finish(self) -> Vec<Digest> {
+        self.periods.open.into_values().collect()
+    }
+}
+
+/// Passes `messages` through while digesting them, e.g. between a subscription and
+/// `PostgresSink::write_trades`. Digests are yielded by the second stream as periods
+/// close, and the remaining ones once `messages` ends.
+pub fn digested<T: Sequenced + Send + 'static>(
+    messages: impl Stream<Item = Result<Vec<T>>> + Send + 'static,
+    channel: impl Into<String>,
+    period: Duration,
+) -> (
+    impl Stream<Item = Result<Vec<T>>> + Send + 'static,
+    impl Stream<Item = Digest> + Send + 'static,
+) {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let digester = Digester::new(channel, period);
+    let messages = futures_util::stream::unfold(
+        (Box::pin(messages), Some(digester), tx),
+        |(mut messages, mut digester, tx)| async move {
+            let batch = messages.next().await;
+            match (&batch, digester.as_mut()) {
+                (Some(Ok(batch)), Some(active)) => {
+                    for msg in batch {
+                        for digest in active.push(msg) {
+                            let _ = tx.send(digest);
+                        }
+                    }
+                }
+                (None, Some(_)) => {
+                    for digest in digester.take().unwrap().finish() {
+                        let _ = tx.send(digest);
+                    }
+                }
+                _ => {}
+            }
+            Some((batch?, (messages, digester, tx)))
+        },
+    );
+    (
+        messages,
+        tokio_stream::wrappers::UnboundedReceiverStream::new(rx),
+    )
+}
+
+/// Compares recorded messages of a channel, in any order, with the digests taken while
+/// recording them with the same `period`. Load the messages of exactly the digested time
+/// range, since periods without a digest are expected to be empty.
+pub fn verify<T: Sequenced>(
+    recorded: impl IntoIterator<Item = T>,
+    digests: &[Digest],
+    period: Duration,
+) -> Vec<Discrepancy> {
+    let mut recorded = recorded.into_iter().collect::<Vec<_>>();
+    recorded.sort_by(|a, b| a.sequence().cmp(&b.sequence()));
+    let mut periods = Periods::new(String::new(), period);
+    for msg in &recorded {
+        periods.push(msg);
+    }
+    let mut found = periods.open;
+
+    let mut discrepancies = Vec::new();
+    for expected in digests {
+        let actual = found.remove(&expected.start).unwrap_or_default();
+        if actual.count != expected.count {
+            discrepancies.push(Discrepancy::CountMismatch {
+                start: expected.start,
+                expected: expected.count,
+                found: actual.count,
+            });
+        } else if actual.checksum != expected.checksum {
+            discrepancies.push(Discrepancy::ChecksumMismatch {
+                start: expected.start,
+            });
+        }
+        discrepancies.extend(
+            actual
+                .gaps
+                .into_iter()
+                .filter(|gap| !expected.gaps.contains(gap))
+                .map(Discrepancy::Gap),
+        );
+    }
+    for (start, actual) in found {
+        discrepancies.push(Discrepancy::CountMismatch {
+            start,
+            expected: 0,
+            found: actual.count,
+        });
+        discrepancies.extend(actual.gaps.into_iter().map(Discrepancy::Gap));
+    }
+    discrepancies
+}
+
+impl<T> SubscriptionStream<T>
+where
+    T: SequencedData + serde::de::DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    /// Checks each message against the previous one of its key, yielding
+    /// `GapEvent::GapDetected` before a message that doesn't follow it, e.g. a raw book
+    /// change whose `prev_change_id` isn't the last change seen. `Error::SubscriptionLagged`
+    /// becomes `GapDetected::Lagged` and starts the sequences over, since every key may
+    /// have missed messages.
+    pub fn detect_gaps(self) -> impl Stream<Item = Result<GapEvent<T>>> + Send + 'static {
+        let last = HashMap::<String, i64>::new();
+        futures_util::stream::unfold(
+            (self, last, VecDeque::new()),
+            |(mut messages, mut last, mut pending)| async move {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (messages, last, pending)));
+                }
+                let event = match messages.next().await? {
+                    Ok(data) => {
+                        for msg in data.messages() {
+                            let (key, position) = msg.sequence();
+                            if let Some(&previous) = last.get(key) {
+                                pending.extend(
+                                    gap_after(key, previous, position, msg.previous()).map(|gap| {
+                                        GapEvent::GapDetected(GapDetected::Sequence(gap))
+                                    }),
+                                );
+                            }
+                            let latest = last.entry(key.to_string()).or_insert(position);
+                            *latest = position.max(*latest);
+                        }
+                        // Gaps come before the message that revealed them
+                        pending.push_back(GapEvent::Message(data));
+                        Ok(pending.pop_front().expect("holds the message"))
+                    }
+                    Err(Error::SubscriptionLagged(skipped)) => {
+                        last.clear();
+                        Ok(GapEvent::GapDetected(GapDetected::Lagged { skipped }))
+                    }
+                    Err(e) => Err(e),
+                };
+                Some((event, (messages, last, pending)))
+            },
+        )
+    }
+}
@@ -0,0 +1,90 @@
+//! Parsing of server messages in the hot paths, with simd-json when the `simd-json`
+//! feature is enabled.
+//!
+//! Responses, notification envelopes and the `data` typed subscribers decode all go
+//! through here. With serde_json a notification's `data` is kept as the `RawValue` slice
+//! of the frame; simd-json parses the whole envelope and writes `data` back out, so raw
+//! subscribers see it re-encoded (same values, no insignificant whitespace).
+
+use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+
+/// Channel and `data` of a `subscription` notification.
+pub(crate) struct Notification<'a> {
+    pub(crate) channel: Cow<'a, str>,
+    pub(crate) data: Box<RawValue>,
+}
+
+#[cfg(not(feature = "simd-json"))]
+pub(crate) fn from_str<T: DeserializeOwned>(text: &str) -> serde_json::Result<T> {
+    serde_json::from_str(text)
+}
+
+#[cfg(not(feature = "simd-json"))]
+pub(crate) fn notification(text: &str) -> Option<Notification<'_>> {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct RawNotification<'a> {
+        #[serde(borrow)]
+        method: Cow<'a, str>,
+        #[serde(borrow)]
+        params: RawParams<'a>,
+    }
+
+    #[derive(Deserialize)]
+    struct RawParams<'a> {
+        #[serde(borrow)]
+        channel: Cow<'a, str>,
+        data: Box<RawValue>,
+    }
+
+    let notification = serde_json::from_str::<RawNotification>(text).ok()?;
+    (notification.method == "subscription").then_some(Notification {
+        channel: notification.params.channel,
+        data: notification.params.data,
+    })
+}
+
+#[cfg(feature = "simd-json")]
+thread_local! {
+    // simd-json parses in place, while frames are shared with raw subscribers and taps, so
+    // it parses a copy. Reusing one buffer per thread leaves a memcpy, small next to the
+    // parse, instead of an allocation per message.
+    static BUFFERS: std::cell::RefCell<(Vec<u8>, simd_json::Buffers)> = Default::default();
+}
+
+#[cfg(feature = "simd-json")]
+fn with_copy<R>(text: &str, parse: impl FnOnce(&mut [u8], &mut simd_json::Buffers) -> R) -> R {
+    BUFFERS.with_borrow_mut(|(bytes, buffers)| {
+        bytes.clear();
+        bytes.extend_from_slice(text.as_bytes());
+        parse(bytes, buffers)
+    })
+}
+
+#[cfg(feature = "simd-json")]
+pub(crate) fn from_str<T: DeserializeOwned>(text: &str) -> serde_json::Result<T> {
+    with_copy(text, |bytes, buffers| {
+        simd_json::serde::from_slice_with_buffers(bytes, buffers)
+    })
+    .map_err(serde::de::Error::custom)
+}
+
+#[cfg(feature = "simd-json")]
+pub(crate) fn notification(text: &str) -> Option<Notification<'static>> {
+    use simd_json::prelude::*;
+
+    with_copy(text, |bytes, buffers| {
+        let message = simd_json::to_borrowed_value_with_buffers(bytes, buffers).ok()?;
+        if message.get_str("method")? != "subscription" {
+            return None;
+        }
+        let params = message.get("params")?;
+        Some(Notification {
+            channel: Cow::Owned(params.get_str("channel")?.to_string()),
+            data: RawValue::from_string(params.get("data")?.encode()).ok()?,
+        })
+    })
+}
@@ -2,15 +2,27 @@ use futures_util::{SinkExt, Stream, StreamExt};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use tokio::sync::{broadcast, mpsc, oneshot};
-use tokio_stream::wrappers::BroadcastStream;
+use serde_json::value::RawValue;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{Semaphore, broadcast, mpsc, oneshot, watch};
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
-use tokio_tungstenite::connect_async;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
 use tokio_tungstenite::tungstenite::Error as WSError;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tracing::Instrument as _;
 
 // Include the generated client code
 pub mod prod {
@@ -28,54 +40,238 @@ pub mod testnet {
 
 // Default to prod at crate root
 pub use prod::*;
+
+pub mod adaptive;
+pub mod address_book;
+pub mod api;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+pub mod block_trades;
+pub mod book;
+pub mod bracket;
+pub mod breaker;
+pub mod cancel;
+pub mod checkpoint;
+pub mod clock;
+pub mod credentials;
+pub mod diagnostics;
+pub mod dvol;
+pub mod failover;
+pub mod funding;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod index;
+pub mod instruments;
+pub mod integrity;
+mod json;
+pub mod margin;
+pub mod market;
+pub mod mass_quote;
+pub mod mmp;
+pub mod ohlc;
+pub mod options;
+pub mod order_builder;
+pub mod orders;
+pub mod paginate;
+#[cfg(feature = "testnet")]
+pub mod parity;
+pub mod pnl;
+pub mod positions;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod quoter;
+pub mod rate_limit;
+pub mod risk;
+pub mod sandbox;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod session;
+pub mod settlements;
+pub mod spot;
+pub mod stream;
+pub mod subaccounts;
+pub mod symbol;
+pub mod timestamp;
+pub mod tls;
+pub mod trades;
+pub mod trailing;
+pub mod transaction_log;
+
+pub use adaptive::{AdaptiveEvent, AdaptiveIntervalConfig};
+pub use address_book::AddressVerification;
+pub use api::DeribitApi;
+pub use block_trades::{BlockRfqBuilder, BlockTradeTerms, SignedBlockTrade};
+pub use book::{BookDelta, BookDeltaChannel, BookSnapshot, LocalOrderBook};
+pub use bracket::{Bracket, BracketEvent};
+pub use breaker::{CircuitBreakerConfig, CircuitState};
+pub use cancel::{CancelFilter, CancelSummary};
+pub use checkpoint::{Checkpoint, CheckpointStore, FileCheckpointStore};
+pub use clock::{ClockSync, MarketClock};
+#[cfg(feature = "credentials-file")]
+pub use credentials::FileCredentials;
+#[cfg(feature = "keyring")]
+pub use credentials::KeyringCredentials;
+pub use credentials::{CredentialProvider, Credentials, EnvCredentials};
+/// Derive `ApiRequest` and `Subscription` for endpoints and channels missing from the
+/// spec, see `deribit_api_derive`.
+#[cfg(feature = "derive")]
+pub use deribit_api_derive::{ApiRequest, Subscription};
+pub use diagnostics::Diagnostic;
+pub use dvol::{DvolCandle, DvolStore};
+pub use failover::{FailoverClient, FailoverConfig, FailoverEvent};
+pub use funding::{FundingRate, FundingUpdate};
+pub use index::{IndexTracker, IndexUpdate};
+pub use instruments::{InstrumentCache, InstrumentEvent, InstrumentFilter};
+pub use market::{MarketChange, MarketState, MarketStateTracker, MarketUpdate};
+pub use mass_quote::{InstrumentQuote, MassQuoter};
+pub use mmp::{MmpGuard, MmpLimits, MmpState};
+pub use ohlc::{Candle, CandleBuilder};
+pub use order_builder::{InvalidOrder, OrderBuilder, OrderRequest};
+pub use orders::{Fill, OrderManager};
+pub use paginate::Paginated;
+pub use pnl::{ContractType, Pnl, PnlLedger, PositionPnl};
+pub use positions::{PositionTracker, PositionUpdate};
+pub use quoter::{Quote, QuoteEvent, QuoteLevel, Quoter};
+pub use rate_limit::RateLimit;
+pub use risk::{Headroom, InstrumentLimits, LimitExceeded, RiskLimits};
+pub use sandbox::{MethodPolicy, SandboxedClient};
+#[cfg(feature = "tower")]
+pub use service::DeribitService;
+pub use session::Session;
+pub use spot::SpotBalance;
+pub use stream::SubscriptionStream;
+pub use subaccounts::{SubaccountBalances, SubaccountTransfer, Subaccounts};
+pub use symbol::{Expiry, InstrumentName, ParseInstrumentNameError};
+pub use timestamp::Timestamp;
+pub use tokio_tungstenite::tungstenite::Utf8Bytes;
+pub use tokio_util::sync::CancellationToken;
+pub use trailing::{TrailDistance, TrailingStop, TrailingStopEvent};
+pub use transaction_log::TransactionType;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 pub struct RpcError {
     pub code: i32,
     pub message: String,
+    #[cfg_attr(feature = "fuzz", arbitrary(with = crate::fuzz::arbitrary_json))]
     pub data: Option<Value>,
 }
 
+impl RpcError {
+    pub const TOO_MANY_REQUESTS: i32 = 10028;
+
+    /// How long to wait before retrying a `too_many_requests` error, from the `wait` or
+    /// `retry_after` field of `data` (ms). `None` for other errors or without either field.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if self.code != Self::TOO_MANY_REQUESTS {
+            return None;
+        }
+        let details = self.details()?;
+        ["wait", "retry_after"]
+            .iter()
+            .find_map(|field| details.other.get(*field)?.as_f64())
+            .map(|millis| Duration::from_secs_f64(millis.max(0.0) / 1000.0))
+    }
+
+    /// `data` parsed as the `{reason, param}` object most errors carry, e.g. which order
+    /// parameter was invalid. A plain string is taken as the reason. `None` without
+    /// `data` or with another shape.
+    pub fn details(&self) -> Option<RpcErrorDetails> {
+        match self.data.as_ref()? {
+            Value::String(reason) => Some(RpcErrorDetails {
+                reason: Some(reason.clone()),
+                ..Default::default()
+            }),
+            data @ Value::Object(_) => RpcErrorDetails::deserialize(data).ok(),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for RpcError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "RPC Error {}: {}", self.code, self.message)
+        write!(f, "RPC Error {}: {}", self.code, self.message)?;
+        match self.details() {
+            Some(RpcErrorDetails {
+                reason: Some(reason),
+                param: Some(param),
+                ..
+            }) => write!(f, " ({param}: {reason})"),
+            Some(RpcErrorDetails {
+                reason: Some(reason),
+                ..
+            }) => write!(f, " ({reason})"),
+            _ => Ok(()),
+        }
     }
 }
 
+/// Details of an `RpcError`, see `RpcError::details`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RpcErrorDetails {
+    pub reason: Option<String>,
+    /// The request parameter the error is about.
+    pub param: Option<String>,
+    /// Other fields, e.g. the rejected value or limits.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 enum JsonRpcVersion {
     #[serde(rename = "2.0")]
     V2,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct RpcRequest {
     jsonrpc: JsonRpcVersion,
     id: u64,
     method: String,
-    params: Value,
+    #[cfg_attr(feature = "fuzz", arbitrary(with = crate::fuzz::arbitrary_raw_json))]
+    params: Box<RawValue>,
+}
+
+/// Metadata Deribit attaches to every response.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct ResponseMeta {
+    /// Whether the response came from the Testnet environment.
+    pub testnet: bool,
+    /// Server time (µs) when the request was received.
+    #[serde(rename = "usIn")]
+    pub us_in: u64,
+    /// Server time (µs) when the response was sent.
+    #[serde(rename = "usOut")]
+    pub us_out: u64,
+    /// Server processing time (µs).
+    #[serde(rename = "usDiff")]
+    pub us_diff: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct RpcResponseBase {
     jsonrpc: JsonRpcVersion,
     id: u64,
-    testnet: bool,
-    #[serde(rename = "usIn")]
-    us_in: u64,
-    #[serde(rename = "usOut")]
-    us_out: u64,
-    #[serde(rename = "usDiff")]
-    us_diff: u64,
+    #[serde(flatten)]
+    meta: ResponseMeta,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct RpcOkResponse {
     #[serde(flatten)]
     base: RpcResponseBase,
+    #[cfg_attr(feature = "fuzz", arbitrary(with = crate::fuzz::arbitrary_json))]
     result: Value,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct RpcErrorResponse {
     #[serde(flatten)]
     base: RpcResponseBase,
@@ -83,26 +279,64 @@ struct RpcErrorResponse {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct SubscriptionParams {
     channel: String,
+    #[cfg_attr(feature = "fuzz", arbitrary(with = crate::fuzz::arbitrary_json))]
     data: Value,
     label: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 enum SubscriptionMethod {
     #[serde(rename = "subscription")]
     Subscription,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct SubscriptionNotification {
     jsonrpc: JsonRpcVersion,
     method: SubscriptionMethod,
     params: SubscriptionParams,
 }
 
+// Cheap first pass used to route notifications to decode workers without building `data`
+#[derive(Debug, Deserialize)]
+struct NotificationPeek<'a> {
+    #[serde(borrow)]
+    method: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    params: Option<ChannelPeek<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelPeek<'a> {
+    #[serde(borrow)]
+    channel: Option<Cow<'a, str>>,
+}
+
+fn notification_key(text: &str) -> Option<ChannelKey> {
+    let peek = serde_json::from_str::<NotificationPeek>(text).ok()?;
+    if peek.method.as_deref() != Some("subscription") {
+        return None;
+    }
+    Some(ChannelKey::new(&peek.params?.channel?))
+}
+
+// Id of a message that failed to decode, so the call waiting for it can be failed
+#[derive(Debug, Deserialize)]
+struct IdPeek {
+    id: Option<u64>,
+}
+
+fn response_id(text: &str) -> Option<u64> {
+    serde_json::from_str::<IdPeek>(text).ok()?.id
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 enum HeartbeatType {
     #[serde(rename = "heartbeat")]
     Heartbeat,
@@ -111,17 +345,20 @@ enum HeartbeatType {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct HeartbeatParams {
     r#type: HeartbeatType,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 enum HeartbeatMethod {
     #[serde(rename = "heartbeat")]
     Heartbeat,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 struct Heartbeat {
     jsonrpc: JsonRpcVersion,
     method: HeartbeatMethod,
@@ -129,6 +366,7 @@ struct Heartbeat {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 #[serde(untagged)]
 enum JsonRPCMessage {
     Heartbeat(Heartbeat),
@@ -141,17 +379,193 @@ enum JsonRPCMessage {
 pub enum Error {
     #[error("RPC error: {0}")]
     RpcError(RpcError),
+    /// Boxed, as it is by far the largest variant.
     #[error("WebSocket error: {0}")]
-    WebSocketError(#[from] WSError),
+    WebSocketError(Box<WSError>),
     #[error("JSON decode error: {0}")]
     JsonError(#[from] serde_json::Error),
     #[error("Invalid subscription channel: {0}")]
     InvalidSubscriptionChannel(String),
     #[error("Subscription messages lagged: {0}")]
     SubscriptionLagged(u64),
+    #[error("Connecting timed out")]
+    ConnectTimeout,
+    #[error("No endpoints to connect to")]
+    NoEndpoints,
+    #[error("{0} is not a valid amount of money")]
+    InvalidMoney(f64),
+    #[error("Environment mismatch: expected testnet={expected_testnet}, got a response with testnet={}", !expected_testnet)]
+    EnvironmentMismatch { expected_testnet: bool },
+    #[error("Order entry circuit breaker is open, retry in {retry_in:?}")]
+    CircuitOpen { retry_in: Duration },
+    #[error("Risk limit exceeded: {0}")]
+    RiskLimit(risk::LimitExceeded),
+    #[error("Invalid order: {0}")]
+    InvalidOrder(#[from] order_builder::InvalidOrder),
+    #[error("Unsupported currency: {0}")]
+    UnsupportedCurrency(#[from] UnsupportedCurrency),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Cancel filter not supported: {0}")]
+    UnsupportedCancelFilter(&'static str),
+    #[error("Method not allowed by the sandbox policy: {0}")]
+    MethodNotAllowed(String),
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Not authenticated")]
+    NotAuthenticated,
+    #[error("Credentials unavailable: {0}")]
+    Credentials(String),
+    #[error("The token's scope does not cover {method}, which requires {required}")]
+    InsufficientScope {
+        method: String,
+        required: RequiredScope,
+    },
+    #[cfg(feature = "postgres")]
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl From<WSError> for Error {
+    fn from(error: WSError) -> Self {
+        Error::WebSocketError(Box::new(error))
+    }
+}
+
+impl Error {
+    /// How long to wait before retrying, for rate-limited calls and an open circuit
+    /// breaker.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RpcError(error) => error.retry_after(),
+            Error::CircuitOpen { retry_in } => Some(*retry_in),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Prices, amounts, fees and balances in generated types: `f64`, or `rust_decimal::Decimal`
+/// with the `decimal` feature, for exact accounting. Decimals are still sent and
+/// received as JSON numbers, digit for digit.
+#[cfg(not(feature = "decimal"))]
+pub type Money = f64;
+/// Prices, amounts, fees and balances in generated types: `f64`, or `rust_decimal::Decimal`
+/// with the `decimal` feature, for exact accounting. Decimals are still sent and
+/// received as JSON numbers, digit for digit.
+#[cfg(feature = "decimal")]
+pub type Money = rust_decimal::Decimal;
+
+// `Money` from the result of `f64` math in hand-written code. Fails with
+// `Error::InvalidMoney` for NaN and infinities, and beyond `Decimal`'s range.
+#[cfg(not(feature = "decimal"))]
+pub(crate) fn money(value: f64) -> Result<Money> {
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(Error::InvalidMoney(value))
+    }
+}
+
+#[cfg(feature = "decimal")]
+pub(crate) fn money(value: f64) -> Result<Money> {
+    rust_decimal::prelude::FromPrimitive::from_f64(value).ok_or(Error::InvalidMoney(value))
+}
+
+// `Money` as `f64`, for the `f64` math of hand-written code, e.g. option pricing
+#[cfg(not(feature = "decimal"))]
+pub(crate) fn money_f64(value: Money) -> f64 {
+    value
+}
+
+#[cfg(feature = "decimal")]
+pub(crate) fn money_f64(value: Money) -> f64 {
+    value.as_f64()
+}
+
+/// Whether a frame was received from or sent to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    Inbound,
+    Outbound,
+}
+
+/// A text frame as it went over the wire, see `DeribitClient::raw_messages`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    pub direction: FrameDirection,
+    pub payload: Utf8Bytes,
+    pub timestamp: SystemTime,
+}
+
+// Frames buffered per `raw_messages` stream
+const RAW_MESSAGE_CAPACITY: usize = 1000;
+
+// Copies a frame to the `raw_messages` streams, if enabled
+fn tap(
+    raw_messages: &Option<broadcast::Sender<RawMessage>>,
+    direction: FrameDirection,
+    payload: &Utf8Bytes,
+) {
+    if let Some(tx) = raw_messages {
+        let _ = tx.send(RawMessage {
+            direction,
+            payload: payload.clone(),
+            timestamp: SystemTime::now(),
+        });
+    }
 }
 
-type Result<T> = std::result::Result<T, Error>;
+/// Why the connection ended, see `DeribitClient::disconnected`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The server sent a Close frame.
+    Closed { code: Option<u16>, reason: String },
+    /// The stream ended without a Close frame.
+    Eof,
+    /// The connection failed.
+    Error(String),
+    /// The client's `CancellationToken` was cancelled.
+    Cancelled,
+}
+
+type ResponseSender = oneshot::Sender<Result<(Value, ResponseMeta)>>;
+
+// Wait before retrying a rate-limited call whose error doesn't say how long
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_millis(500);
+
+// Pending request count below which abandoned requests are not pruned
+const MIN_PRUNE_AT: usize = 64;
+
+tokio::task_local! {
+    // Whether the call running holds an in-flight slot already, taken by
+    // `DeribitService::poll_ready`
+    pub(crate) static SLOT_HELD: bool;
+}
+
+async fn send_request(
+    request_channel: &mpsc::Sender<(RpcRequest, ResponseSender)>,
+    id: u64,
+    method: &str,
+    params: Box<RawValue>,
+) -> Result<(Value, ResponseMeta)> {
+    let request = RpcRequest {
+        jsonrpc: JsonRpcVersion::V2,
+        id,
+        method: method.to_string(),
+        params,
+    };
+
+    let (tx, rx) = oneshot::channel();
+
+    request_channel
+        .send((request, tx))
+        .await
+        .map_err(|_| WSError::ConnectionClosed)?;
+
+    rx.await.map_err(|_| WSError::ConnectionClosed)?
+}
 
 // ApiRequest trait for all request types
 pub trait ApiRequest: serde::Serialize {
@@ -162,19 +576,125 @@ pub trait ApiRequest: serde::Serialize {
         self.method_name().starts_with("private/")
     }
 
+    /// The scope a token needs for this method, from the spec, `None` for public methods
+    /// and the few private ones the spec says nothing about.
+    fn required_scope(&self) -> Option<RequiredScope> {
+        None
+    }
+
+    /// The environment the method exists in if it is missing from the other one's spec.
+    /// Only known with the `testnet` feature, which generates both; `None` otherwise.
+    const EXCLUSIVE_TO: Option<SpecEnvironment> = None;
+
     fn to_params(&self) -> Value {
         serde_json::to_value(self).unwrap_or_default()
     }
+
+    /// The params serialized straight to JSON text, without building a `Value`, as
+    /// `DeribitClient::call` sends them.
+    fn to_raw_params(&self) -> Box<RawValue> {
+        serde_json::value::to_raw_value(self).unwrap_or_else(|_| RawValue::NULL.to_owned())
+    }
+}
+
+/// A value a currency enum has no variant for, from converting between `Currency` and a
+/// per-endpoint currency enum like `WalletCurrency`, e.g. `any`, or a currency the
+/// endpoint doesn't take.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{value} is not a {target}")]
+pub struct UnsupportedCurrency {
+    pub value: String,
+    /// The enum converted into.
+    pub target: &'static str,
+}
+
+/// A value a generated enum has no variant for, from parsing it with `FromStr`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{value} is not a {target} value")]
+pub struct ParseEnumError {
+    pub value: String,
+    /// The enum parsed into.
+    pub target: &'static str,
+}
+
+/// A Deribit environment the client is generated for, see `ApiRequest::EXCLUSIVE_TO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpecEnvironment {
+    Production,
+    Testnet,
+}
+
+/// The OAuth scope a private method requires, e.g. `trade:read_write`, or one of a few,
+/// e.g. `trade:read_write` or `block_rfq:read_write`, see `ApiRequest::required_scope`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequiredScope {
+    /// e.g. `trade`, `wallet`, `account` or `block_trade`; access to any of them will do.
+    pub areas: &'static [&'static str],
+    /// Whether `read_write` access is needed rather than `read`.
+    pub write: bool,
+    /// Whether only the main account may call the method, not a subaccount.
+    pub main_account: bool,
+}
+
+impl RequiredScope {
+    /// Whether `scope`, as granted by `public/auth`, covers this one: `read_write`
+    /// access to an area covers `read`.
+    pub fn granted_by(&self, scope: &str) -> bool {
+        let mut tokens = scope.split_whitespace();
+        let covered = tokens.clone().any(|token| match token.split_once(':') {
+            Some((area, access)) if self.areas.contains(&area) => {
+                access == "read_write" || (access == "read" && !self.write)
+            }
+            _ => false,
+        });
+        covered && (!self.main_account || tokens.any(|token| token == "mainaccount"))
+    }
+}
+
+impl std::fmt::Display for RequiredScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let access = if self.write { "read_write" } else { "read" };
+        for (i, area) in self.areas.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" or ")?;
+            }
+            write!(f, "{area}:{access}")?;
+        }
+        if self.main_account {
+            f.write_str(" on the main account")?;
+        }
+        Ok(())
+    }
+}
+
+/// Implemented by request types with a group of parameters many methods share, e.g.
+/// `OffsetPagination` or `CurrencyKind`, flattened into them. Helpers can be generic over
+/// the group instead of handling the same fields per request type:
+///
+/// ```
+/// use deribit_api::{ApiRequest, OffsetPagination, ParamGroup};
+///
+/// fn next_page<R: ApiRequest + ParamGroup<OffsetPagination>>(request: &mut R, count: i64) {
+///     let pagination = request.group_mut();
+///     pagination.count = Some(count);
+///     pagination.offset = Some(pagination.offset.unwrap_or_default() + count);
+/// }
+/// ```
+pub trait ParamGroup<G> {
+    fn group(&self) -> &G;
+    fn group_mut(&mut self) -> &mut G;
 }
 
 // Subscription trait implemented by generated channel structs
 pub trait Subscription {
-    type Data: DeserializeOwned + Serialize + Send + 'static;
+    type Data: DeserializeOwned + Serialize + Clone + Send + Sync + 'static;
     fn channel_string(&self) -> String;
 }
 
-// Helper used by generated code to stringify subscription path parameters
-pub(crate) fn sub_param_to_string<T: Serialize>(value: &T) -> String {
+// Helper used by generated code and `#[derive(Subscription)]` to stringify
+// subscription path parameters
+#[doc(hidden)]
+pub fn sub_param_to_string<T: Serialize>(value: &T) -> String {
     let json = serde_json::to_value(value).unwrap_or(Value::Null);
     match json {
         Value::String(s) => s,
@@ -184,203 +704,1921 @@ pub(crate) fn sub_param_to_string<T: Serialize>(value: &T) -> String {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Env {
     Production,
     Testnet,
+    /// Any other WebSocket endpoint, e.g. a local mock server or a gateway.
+    Custom(String),
+    /// A custom endpoint fronting production or testnet, e.g. a gateway. The `testnet`
+    /// flag of its responses is checked against `testnet`, starting at connect time.
+    Gateway {
+        url: String,
+        testnet: bool,
+    },
 }
 
-#[derive(Debug)]
-pub struct DeribitClient {
-    authenticated: AtomicBool,
-    id_counter: Arc<AtomicU64>,
-    request_channel: mpsc::Sender<(RpcRequest, oneshot::Sender<Result<Value>>)>,
-    subscription_channel: mpsc::Sender<(String, oneshot::Sender<broadcast::Receiver<Value>>)>,
-}
-
-impl DeribitClient {
-    pub async fn connect(env: Env) -> Result<Self> {
-        let ws_url = match env {
+impl Env {
+    pub fn url(&self) -> &str {
+        match self {
             Env::Production => "wss://www.deribit.com/ws/api/v2",
             Env::Testnet => "wss://test.deribit.com/ws/api/v2",
+            Env::Custom(url) | Env::Gateway { url, .. } => url,
+        }
+    }
+
+    // The `testnet` flag responses must carry, unknown for custom endpoints
+    fn expected_testnet(&self) -> Option<bool> {
+        match self {
+            Env::Production => Some(false),
+            Env::Testnet => Some(true),
+            Env::Gateway { testnet, .. } => Some(*testnet),
+            Env::Custom(_) => None,
+        }
+    }
+}
+
+/// Dead-man's-switch settings, all disabled by default.
+///
+/// The heartbeat is set right after connecting, cancel-on-disconnect is enabled for the
+/// connection after every successful `public/auth`, and the inactivity watchdog sends
+/// `private/cancel_all` once no calls have been made through the client for the
+/// configured duration. The watchdog re-arms as soon as calls resume.
+#[derive(Debug, Clone, Default)]
+pub struct SafetyConfig {
+    /// Heartbeat interval in seconds passed to `public/set_heartbeat`.
+    pub heartbeat_interval: Option<i64>,
+    /// Enable `private/enable_cancel_on_disconnect` with connection scope once authenticated.
+    pub cancel_on_disconnect: bool,
+    /// Cancel all open orders when the client has been idle for this long.
+    pub inactivity_timeout: Option<Duration>,
+}
+
+/// Address families to use when the endpoint resolves to several addresses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    /// Try addresses in the order the resolver returned them.
+    #[default]
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+// Resolves the endpoint and connects to the first reachable address allowed by the
+// preference, optionally from a specific local address.
+async fn connect_tcp(
+    host: &str,
+    port: u16,
+    preference: IpPreference,
+    local_address: Option<IpAddr>,
+    nodelay: bool,
+) -> std::io::Result<TcpStream> {
+    let mut addrs = tokio::net::lookup_host((host, port))
+        .await?
+        .filter(|addr| match preference {
+            IpPreference::Ipv4Only => addr.is_ipv4(),
+            IpPreference::Ipv6Only => addr.is_ipv6(),
+            _ => true,
+        })
+        .filter(|addr| local_address.is_none_or(|local| local.is_ipv4() == addr.is_ipv4()))
+        .collect::<Vec<_>>();
+    match preference {
+        IpPreference::PreferIpv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+        IpPreference::PreferIpv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        _ => {}
+    }
+
+    let mut last_error = std::io::Error::new(
+        std::io::ErrorKind::AddrNotAvailable,
+        format!("no usable address for {host}"),
+    );
+    for addr in addrs {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
         };
+        if let Some(local_address) = local_address {
+            socket.bind(SocketAddr::new(local_address, 0))?;
+        }
+        socket.set_nodelay(nodelay)?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
 
-        let (mut ws_stream, _) = connect_async(ws_url).await?;
-        let (request_tx, mut request_rx) =
-            mpsc::channel::<(RpcRequest, oneshot::Sender<Result<Value>>)>(100);
-        let (subscription_tx, mut subscription_rx) =
-            mpsc::channel::<(String, oneshot::Sender<broadcast::Receiver<Value>>)>(100);
+#[derive(Clone)]
+pub struct DeribitClientBuilder {
+    env: Env,
+    safety: SafetyConfig,
+    decode_workers: usize,
+    headers: Vec<(String, String)>,
+    connect_timeout: Option<Duration>,
+    ip_preference: IpPreference,
+    local_address: Option<IpAddr>,
+    tcp_nodelay: bool,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    max_in_flight_requests: Option<NonZeroUsize>,
+    risk_limits: Option<RiskLimits>,
+    subscription_capacities: Vec<(String, usize)>,
+    tap_raw_messages: bool,
+    slow_call_threshold: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    rate_limit_retries: usize,
+    tolerant_methods: Vec<String>,
+    cancellation: Option<CancellationToken>,
+    credentials: Option<Arc<dyn CredentialProvider>>,
+    sign_auth: bool,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    tls_connector: Option<tls::Connector>,
+}
 
-        let id_counter = Arc::new(AtomicU64::new(0));
-        let id_counter_clone = id_counter.clone();
+impl std::fmt::Debug for DeribitClientBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeribitClientBuilder")
+            .field("env", &self.env)
+            .field("safety", &self.safety)
+            .field("decode_workers", &self.decode_workers)
+            .field("headers", &self.headers)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("ip_preference", &self.ip_preference)
+            .field("local_address", &self.local_address)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("max_in_flight_requests", &self.max_in_flight_requests)
+            .field("risk_limits", &self.risk_limits)
+            .field("subscription_capacities", &self.subscription_capacities)
+            .field("tap_raw_messages", &self.tap_raw_messages)
+            .field("slow_call_threshold", &self.slow_call_threshold)
+            .field("rate_limit", &self.rate_limit)
+            .field("rate_limit_retries", &self.rate_limit_retries)
+            .field("tolerant_methods", &self.tolerant_methods)
+            .field("cancellation", &self.cancellation)
+            .field("credentials", &self.credentials)
+            .field("sign_auth", &self.sign_auth)
+            .finish_non_exhaustive()
+    }
+}
 
-        tokio::spawn(async move {
-            let mut pending_requests: HashMap<u64, oneshot::Sender<Result<Value>>> = HashMap::new();
-            let mut subscribers: HashMap<String, broadcast::Sender<Value>> = HashMap::new();
+impl DeribitClientBuilder {
+    /// Adds an HTTP header to the WebSocket upgrade request. Invalid names or values are
+    /// reported when connecting.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
 
-            loop {
-                tokio::select! {
-                    msg = ws_stream.next() => {
-                        match msg {
-                            Some(Ok(Message::Text(text))) => {
-                                match serde_json::from_str::<JsonRPCMessage>(&text) {
-                                    Ok(JsonRPCMessage::Heartbeat(heartbeat)) => {
-                                        if heartbeat.params.r#type == HeartbeatType::TestRequest {
-                                            let test_request = RpcRequest {
-                                                jsonrpc: JsonRpcVersion::V2,
-                                                id: id_counter_clone.fetch_add(1, Ordering::Relaxed),
-                                                method: "public/test".to_string(),
-                                                params: Value::Null,
-                                            };
-                                            ws_stream
-                                                .send(Message::Text(
-                                                    serde_json::to_string(&test_request).unwrap().into(),
-                                                ))
-                                                .await
-                                                .unwrap();
-                                        }
-                                    }
-                                    Ok(JsonRPCMessage::Notification(notification)) => {
-                                        if let Some(tx) = subscribers.get(&notification.params.channel)
-                                            && tx.send(notification.params.data.clone()).is_err()
-                                        {
-                                            subscribers.remove(&notification.params.channel);
-                                        }
-                                    }
-                                    Ok(JsonRPCMessage::OkResponse(response)) => {
-                                        let result = Ok(response.result);
-                                        if let Some(tx) = pending_requests.remove(&response.base.id) {
-                                            let _ = tx.send(result);
-                                        }
-                                    }
-                                    Ok(JsonRPCMessage::ErrorResponse(response)) => {
-                                        let error = Err(Error::RpcError(response.error));
-                                        if let Some(tx) = pending_requests.remove(&response.base.id) {
-                                            let _ = tx.send(error);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        panic!("Received invalid json message: {e}\nOriginal message: {text}");
-                                    }
-                                }
-                            }
-                            Some(Ok(msg)) => {
-                                panic!("Received non-text message: {msg:?}");
-                            }
-                            Some(Err(e)) => {
-                                panic!("WebSocket error: {e:?}");
-                            }
-                            None => {
-                                panic!("WebSocket connection closed");
-                            }
-                        }
-                    }
-                    Some((request, tx)) = request_rx.recv() => {
-                        pending_requests.insert(request.id, tx);
-                        ws_stream
-                            .send(Message::Text(
-                                serde_json::to_string(&request).unwrap().into(),
-                            ))
-                            .await
-                            .unwrap();
-                    }
-                    Some((channel, oneshot_tx)) = subscription_rx.recv() => {
-                        if let Some(broadcast_tx) = subscribers.get(&channel) {
-                            let _ = oneshot_tx.send(broadcast_tx.subscribe());
-                        } else {
-                            let (broadcast_tx, broadcast_rx) = broadcast::channel(100);
-                            subscribers.insert(channel, broadcast_tx);
-                            let _ = oneshot_tx.send(broadcast_rx);
-                        }
-                    }
-                }
-            }
-        });
+    pub fn user_agent(self, user_agent: impl Into<String>) -> Self {
+        self.header("User-Agent", user_agent)
+    }
 
-        Ok(Self {
-            authenticated: AtomicBool::new(false),
-            id_counter,
-            request_channel: request_tx,
-            subscription_channel: subscription_tx,
-        })
+    /// Fails with `Error::ConnectTimeout` if resolving, connecting and the WebSocket
+    /// handshake take longer than `timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
     }
 
-    fn next_id(&self) -> u64 {
-        self.id_counter.fetch_add(1, Ordering::Relaxed)
+    pub fn ip_preference(mut self, preference: IpPreference) -> Self {
+        self.ip_preference = preference;
+        self
     }
 
-    pub async fn call_raw(&self, method: &str, params: Value) -> Result<Value> {
-        let request = RpcRequest {
-            jsonrpc: JsonRpcVersion::V2,
-            id: self.next_id(),
-            method: method.to_string(),
-            params,
-        };
+    /// Binds the socket to a local address, e.g. to route through a specific interface.
+    /// Only endpoint addresses of the same family are tried.
+    pub fn local_address(mut self, address: IpAddr) -> Self {
+        self.local_address = Some(address);
+        self
+    }
 
-        let (tx, rx) = oneshot::channel();
+    /// Sets `TCP_NODELAY` on the socket, on by default. Requests are small and pipelined,
+    /// so with Nagle's algorithm a request sent while an earlier one is unacknowledged
+    /// would be held back.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_nodelay = nodelay;
+        self
+    }
 
-        self.request_channel
-            .send((request, tx))
-            .await
-            .map_err(|_| WSError::ConnectionClosed)?;
+    /// Uses a custom TLS connector, e.g. one with a private root store or a pinned
+    /// certificate (see the `tls` module).
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn tls_connector(mut self, connector: tls::Connector) -> Self {
+        self.tls_connector = Some(connector);
+        self
+    }
 
-        let value = rx.await.map_err(|_| WSError::ConnectionClosed)??;
+    /// Fails order entry (`breaker::ORDER_ENTRY_METHODS`) locally with `Error::CircuitOpen`
+    /// while rejections spike, see `CircuitBreakerConfig`.
+    pub fn circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = Some(config);
+        self
+    }
 
-        if method == "public/auth" {
-            self.authenticated.store(true, Ordering::Release);
-        }
+    /// Caps the number of calls waiting for a response; further calls wait for a slot
+    /// before being sent.
+    pub fn max_in_flight_requests(mut self, max: NonZeroUsize) -> Self {
+        self.max_in_flight_requests = Some(max);
+        self
+    }
 
-        Ok(value)
+    /// Fails `private/buy` and `private/sell` locally with `Error::RiskLimit` when they
+    /// would breach `limits`. Usage starts at zero; call `DeribitClient::sync_risk_limits`
+    /// to load open orders, positions and minimum trade amounts from the exchange.
+    pub fn risk_limits(mut self, limits: RiskLimits) -> Self {
+        self.risk_limits = Some(limits);
+        self
     }
 
-    pub async fn call<T: ApiRequest>(&self, req: T) -> Result<T::Response> {
-        let value = self.call_raw(req.method_name(), req.to_params()).await?;
-        let typed: T::Response = serde_json::from_value(value)?;
-        Ok(typed)
+    /// Buffers up to `capacity` messages per subscriber on channels matching `pattern`,
+    /// where `*` matches any run of characters (e.g. `book.*.raw`). The first matching
+    /// pattern wins; other channels buffer 100 messages. Subscribers that fall further
+    /// behind get `Error::SubscriptionLagged`.
+    pub fn subscription_capacity(mut self, pattern: impl Into<String>, capacity: usize) -> Self {
+        let pattern = pattern.into();
+        self.subscription_capacities.push((pattern, capacity));
+        self
     }
 
-    pub async fn subscribe_raw(
-        &self,
-        channel: &str,
-    ) -> Result<impl Stream<Item = Result<Value>> + Send + 'static + use<>> {
-        let channels = vec![channel.to_string()];
-        let subscribed_channels = if self.authenticated.load(Ordering::Acquire) {
-            self.call(PrivateSubscribeRequest {
-                channels,
-                label: None,
-            })
-            .await?
-        } else {
-            self.call(PublicSubscribeRequest { channels }).await?
-        };
-        if let Some(channel) = subscribed_channels.first() {
-            let (tx, rx) = oneshot::channel();
-            self.subscription_channel
-                .send((channel.clone(), tx))
-                .await
-                .map_err(|_| WSError::ConnectionClosed)?;
-            let channel_rx = rx.await.map_err(|_| WSError::ConnectionClosed)?;
-            let stream = BroadcastStream::new(channel_rx).map(|msg| match msg {
-                Ok(msg) => Ok(msg),
-                Err(BroadcastStreamRecvError::Lagged(lag)) => Err(Error::SubscriptionLagged(lag)),
-            });
-            Ok(stream)
-        } else {
-            Err(Error::InvalidSubscriptionChannel(channel.to_string()))
+    pub fn safety(mut self, safety: SafetyConfig) -> Self {
+        self.safety = safety;
+        self
+    }
+
+    /// Decodes subscription notifications on `workers` tasks, sharded by channel so the
+    /// messages of each channel keep their order. Zero (the default) decodes everything
+    /// on the connection's reader task.
+    pub fn decode_workers(mut self, workers: usize) -> Self {
+        self.decode_workers = workers;
+        self
+    }
+
+    /// Copies every text frame sent and received to `DeribitClient::raw_messages`, for
+    /// debugging protocol issues. Off by default, since it costs a clone and a timestamp
+    /// per frame.
+    pub fn tap_raw_messages(mut self) -> Self {
+        self.tap_raw_messages = true;
+        self
+    }
+
+    /// Reports calls taking longer than `threshold` as `Diagnostic::SlowCall` and a
+    /// `warn` trace event, split into server processing and network time.
+    pub fn slow_call_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_call_threshold = Some(threshold);
+        self
+    }
+
+    /// Holds calls back until `limit` has credits for them, so the client paces itself
+    /// instead of running into `too_many_requests`.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Retries calls rejected with `too_many_requests` up to `max_retries` times, after
+    /// the wait the error reports (`RpcError::retry_after`, half a second if none). Other
+    /// calls are held back until then too, so they don't spend exhausted credits.
+    pub fn retry_rate_limited(mut self, max_retries: usize) -> Self {
+        self.rate_limit_retries = max_retries;
+        self
+    }
+
+    /// Decodes results of methods matching `pattern` (`*` matches any run of
+    /// characters) in other shapes when they don't fit their generated type, e.g. a
+    /// count the exchange started returning as `{"cancelled": 3}`: each field of an
+    /// object, the element of a list of one, a scalar as a list of one or JSON in a
+    /// string. The shape that decoded is reported as `Diagnostic::ResultShapeChanged`
+    /// and a `warn` trace event, so typed calls keep working until the spec catches up.
+    pub fn tolerate_result_shapes(mut self, pattern: impl Into<String>) -> Self {
+        self.tolerant_methods.push(pattern.into());
+        self
+    }
+
+    /// Shuts the client down when `token` is cancelled, e.g. by an application-wide
+    /// shutdown token: calls fail with `Error::Cancelled`, the connection is closed and
+    /// subscription streams end. See `DeribitClient::cancellation_token`.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Authenticates with the client credentials `provider` loads once connected, see
+    /// `DeribitClient::authenticate`, so private calls and subscriptions work as soon as
+    /// `connect` returns. Only `connect` authenticates; with `connect_with_driver`, call
+    /// `DeribitClient::login` once the driver runs.
+    pub fn credentials(mut self, provider: impl CredentialProvider + 'static) -> Self {
+        self.credentials = Some(Arc::new(provider));
+        self
+    }
+
+    /// Authenticates with a `client_signature` grant instead, so the client secret is
+    /// never sent, see `DeribitClient::authenticate_with_signature`.
+    pub fn sign_auth(mut self) -> Self {
+        self.sign_auth = true;
+        self
+    }
+
+    /// Connects and runs the connection on a spawned task, authenticating if given
+    /// `credentials`.
+    pub async fn connect(self) -> Result<DeribitClient> {
+        let (client, driver) = DeribitClient::connect_with(self).await?;
+        tokio::spawn(driver);
+        client.handshake().await?;
+        if client.builder.credentials.is_some() {
+            client.login().await?;
         }
+        Ok(client)
     }
 
-    // Typed subscription: accepts a generated Subscription and returns a typed broadcast receiver
-    pub async fn subscribe<S: Subscription + Send + 'static>(
-        &self,
-        subscription: S,
-    ) -> Result<impl Stream<Item = Result<S::Data>> + Send + 'static> {
-        let channel = subscription.channel_string();
-        let raw_stream = self.subscribe_raw(&channel).await?;
-        let typed_stream = raw_stream.map(|msg| match msg {
-            Ok(msg) => serde_json::from_value::<S::Data>(msg).map_err(Error::JsonError),
-            Err(e) => Err(e),
+    /// Connects without spawning anything for the connection: it runs in the returned
+    /// driver, which the caller spawns or awaits. Nothing is sent or received until it is
+    /// polled. Decode workers still run on their own tasks.
+    ///
+    /// The environment is verified and the `SafetyConfig` heartbeat set once the driver
+    /// runs, and the driver ends with `Disconnect::Error` if either fails.
+    pub async fn connect_with_driver(self) -> Result<(DeribitClient, ConnectionDriver)> {
+        let (client, driver) = DeribitClient::connect_with(self).await?;
+        let handshake = client.handshake();
+        let driver = ConnectionDriver::new(async move {
+            let handshake = async {
+                match handshake.await {
+                    Ok(()) => std::future::pending().await,
+                    Err(e) => Disconnect::Error(format!("connection handshake failed: {e}")),
+                }
+            };
+            tokio::select! {
+                reason = driver => reason,
+                reason = handshake => reason,
+            }
         });
-        Ok(typed_stream)
+        Ok((client, driver))
+    }
+}
+
+/// Runs the connection of a client created with `DeribitClientBuilder::connect_with_driver`,
+/// resolving once the connection has ended.
+#[must_use = "the connection makes no progress unless the driver is polled"]
+pub struct ConnectionDriver {
+    future: Pin<Box<dyn Future<Output = Disconnect> + Send>>,
+}
+
+impl ConnectionDriver {
+    fn new(future: impl Future<Output = Disconnect> + Send + 'static) -> Self {
+        Self {
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl Future for ConnectionDriver {
+    type Output = Disconnect;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Disconnect> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+impl std::fmt::Debug for ConnectionDriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionDriver").finish_non_exhaustive()
+    }
+}
+
+// A notification as broadcast to the subscribers of its channel. `data` stays raw JSON, so
+// only typed subscribers pay for parsing, and the first of them to decode it caches the
+// result in `decoded` for the others.
+#[derive(Debug)]
+struct Published {
+    // The whole message as received, for `DeribitClient::subscribe_raw_bytes`
+    frame: Utf8Bytes,
+    data: Arc<RawValue>,
+    // Receivers at publish time, caching only pays off with more than one
+    receivers: usize,
+    decoded: OnceLock<Box<dyn Any + Send + Sync>>,
+}
+
+// Hash of a channel name, computed once per frame: it picks the decode worker and keys
+// the subscribers, so a notification's channel name is never hashed twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ChannelKey(u64);
+
+impl ChannelKey {
+    // Multiplicative hash over 8-byte words like FxHash, much cheaper than the default
+    // SipHash and good enough as collisions are resolved by comparing names
+    fn new(channel: &str) -> Self {
+        const K: u64 = 0xf135_7aea_2e62_a9c5;
+        let mix = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(K);
+        let mut chunks = channel.as_bytes().chunks_exact(8);
+        let mut hash = (&mut chunks).fold(channel.len() as u64, |hash, chunk| {
+            mix(hash, u64::from_le_bytes(chunk.try_into().unwrap()))
+        });
+        let mut rest = [0; 8];
+        rest[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+        hash = mix(hash, u64::from_le_bytes(rest));
+        // The multiplications leave the low bits, which pick shards and buckets, weakest
+        Self(hash ^ (hash >> 32))
+    }
+
+    fn shard(self, workers: usize) -> usize {
+        (self.0 % workers as u64) as usize
+    }
+}
+
+// Keys are hashes already, so maps keyed by them use the key as is
+#[derive(Debug, Default)]
+struct ChannelKeyHasher(u64);
+
+impl Hasher for ChannelKeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u64(&mut self, key: u64) {
+        self.0 = key;
+    }
+}
+
+// Broadcast senders of the subscribed channels. Channel names are interned to small ids
+// at subscribe time, so publishing is a lookup by the frame's precomputed key followed
+// by indexing. Messages are shared behind an `Arc`, so subscribers don't each copy them.
+#[derive(Debug, Default)]
+struct Subscribers {
+    // Id of the first channel with each key
+    ids: HashMap<ChannelKey, usize, BuildHasherDefault<ChannelKeyHasher>>,
+    channels: Vec<String>,
+    // Next channel with the same key, only ever set on a hash collision
+    collisions: Vec<Option<usize>>,
+    senders: Vec<Option<broadcast::Sender<Arc<Published>>>>,
+    stats: Vec<Arc<ChannelStats>>,
+}
+
+impl Subscribers {
+    fn intern(&mut self, channel: String) -> usize {
+        let key = ChannelKey::new(&channel);
+        if let Some(id) = self.id(key, &channel) {
+            return id;
+        }
+        let id = self.channels.len();
+        if let Some(&first) = self.ids.get(&key) {
+            let mut last = first;
+            while let Some(next) = self.collisions[last] {
+                last = next;
+            }
+            self.collisions[last] = Some(id);
+        } else {
+            self.ids.insert(key, id);
+        }
+        self.channels.push(channel);
+        self.collisions.push(None);
+        self.senders.push(None);
+        self.stats.push(Arc::default());
+        id
+    }
+
+    fn id(&self, key: ChannelKey, channel: &str) -> Option<usize> {
+        let mut id = *self.ids.get(&key)?;
+        while self.channels[id] != channel {
+            id = self.collisions[id]?;
+        }
+        Some(id)
+    }
+
+    // `capacity` only applies when the channel has no subscribers yet
+    fn subscribe(
+        &mut self,
+        channel: String,
+        capacity: usize,
+        stats: Arc<ChannelStats>,
+    ) -> broadcast::Receiver<Arc<Published>> {
+        let id = self.intern(channel);
+        self.stats[id] = stats.clone();
+        self.senders[id]
+            .get_or_insert_with(|| {
+                let tx = broadcast::channel(capacity).0;
+                *stats.sender.lock().unwrap() = Some(tx.downgrade());
+                tx
+            })
+            .subscribe()
+    }
+
+    fn publish(&mut self, key: ChannelKey, channel: &str, data: Box<RawValue>, frame: Utf8Bytes) {
+        let Some(id) = self.id(key, channel) else {
+            return;
+        };
+        if self.senders[id].is_some() {
+            self.stats[id].record_message();
+        }
+        if let Some(tx) = &self.senders[id]
+            && tx
+                .send(Arc::new(Published {
+                    frame,
+                    data: Arc::from(data),
+                    receivers: tx.receiver_count(),
+                    decoded: OnceLock::new(),
+                }))
+                .is_err()
+        {
+            self.senders[id] = None;
+        }
+    }
+}
+
+// Counters of a subscribed channel, shared by the client and the task publishing it
+#[derive(Debug, Default)]
+struct ChannelStats {
+    messages: AtomicU64,
+    // Unix time of the last message in milliseconds, zero before the first
+    last_message_millis: AtomicU64,
+    lag_events: AtomicU64,
+    lagged_messages: AtomicU64,
+    // The channel's sender, for counting its receivers. Weak, so streams still end with
+    // the connection
+    sender: Mutex<Option<broadcast::WeakSender<Arc<Published>>>>,
+}
+
+impl ChannelStats {
+    fn record_message(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.last_message_millis.store(now, Ordering::Relaxed);
+    }
+
+    fn record_lag(&self, skipped: u64) {
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+        self.lagged_messages.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, channel: &str) -> SubscriptionStats {
+        let last_message_millis = self.last_message_millis.load(Ordering::Relaxed);
+        SubscriptionStats {
+            channel: channel.to_string(),
+            messages: self.messages.load(Ordering::Relaxed),
+            last_message: (last_message_millis > 0)
+                .then(|| UNIX_EPOCH + Duration::from_millis(last_message_millis)),
+            subscribers: self
+                .sender
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(broadcast::WeakSender::upgrade)
+                .map_or(0, |sender| sender.receiver_count()),
+            lag_events: self.lag_events.load(Ordering::Relaxed),
+            lagged_messages: self.lagged_messages.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Message flow of a subscribed channel, see `DeribitClient::subscription_stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubscriptionStats {
+    pub channel: String,
+    /// Notifications received since the first subscription.
+    pub messages: u64,
+    pub last_message: Option<SystemTime>,
+    /// Streams currently receiving the channel. A conflated subscription counts as one.
+    pub subscribers: usize,
+    /// Times a subscriber fell behind and got `Error::SubscriptionLagged`.
+    pub lag_events: u64,
+    /// Messages skipped by lagging subscribers.
+    pub lagged_messages: u64,
+}
+
+#[derive(Debug)]
+enum DecodeJob {
+    Notification(ChannelKey, Utf8Bytes),
+    Subscribe(SubscribeRequest),
+}
+
+// Channel, buffer capacity, its counters and where to send the receiver
+type SubscribeRequest = (
+    String,
+    usize,
+    Arc<ChannelStats>,
+    oneshot::Sender<broadcast::Receiver<Arc<Published>>>,
+);
+
+// Buffered messages per subscription channel, unless configured otherwise
+const DEFAULT_SUBSCRIPTION_CAPACITY: usize = 100;
+
+// Whether `name` (a channel or method) matches `pattern`, where `*` matches any run of
+// characters
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.len() >= part.len() && rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+// Each worker owns the subscribers of the channels sharded to it, so notifications and
+// registrations for a channel are always handled in order by the same task.
+fn spawn_decode_worker() -> mpsc::Sender<DecodeJob> {
+    let (tx, mut rx) = mpsc::channel::<DecodeJob>(1024);
+    tokio::spawn(async move {
+        let mut subscribers = Subscribers::default();
+        while let Some(job) = rx.recv().await {
+            match job {
+                DecodeJob::Notification(key, text) => {
+                    if let Some(notification) = json::notification(&text) {
+                        let channel = &notification.channel;
+                        subscribers.publish(key, channel, notification.data, text.clone());
+                    }
+                }
+                DecodeJob::Subscribe((channel, capacity, stats, oneshot_tx)) => {
+                    let _ = oneshot_tx.send(subscribers.subscribe(channel, capacity, stats));
+                }
+            }
+        }
+    });
+    tx
+}
+
+// Time of the last occurrence of an event, shareable with background tasks
+#[derive(Debug, Clone)]
+struct LastSeen {
+    since: Instant,
+    // Milliseconds after `since`
+    millis: Arc<AtomicU64>,
+}
+
+impl LastSeen {
+    fn new(since: Instant) -> Self {
+        Self {
+            since,
+            millis: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn touch(&self) {
+        self.millis
+            .store(self.since.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn age(&self) -> Duration {
+        let last = Duration::from_millis(self.millis.load(Ordering::Relaxed));
+        self.since.elapsed().saturating_sub(last)
+    }
+}
+
+// Connection state tracked from the calls made through the client
+#[derive(Debug, Default)]
+struct SessionState {
+    scope: Option<String>,
+    token_expires_at: Option<Instant>,
+    // From the last `public/auth`, for `fork_session`
+    refresh_token: Option<session::RefreshToken>,
+    // Named sessions opened with `open_session`, by name
+    named_sessions: BTreeMap<String, session::NamedSession>,
+    // The named session each channel was subscribed for, by channel
+    session_subscriptions: BTreeMap<String, String>,
+    heartbeat_interval: Option<i64>,
+    subscriptions: BTreeSet<String>,
+}
+
+/// Result of `DeribitClient::health`, designed to back readiness and liveness probes.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    /// Round trip of a `public/test` call, `None` if it failed.
+    pub round_trip: Option<Duration>,
+    /// Time since the last message of any kind was received.
+    pub last_message_age: Duration,
+    pub authenticated: bool,
+    /// Whether the access token from the last `public/auth` is within its lifetime,
+    /// `None` when not authenticated.
+    pub token_valid: Option<bool>,
+    /// Time since the last subscription notification, `None` without subscriptions.
+    pub last_notification_age: Option<Duration>,
+    /// Whether a notification arrived within the allowed age, `None` without subscriptions.
+    pub subscriptions_flowing: Option<bool>,
+}
+
+impl HealthReport {
+    /// The connection answers requests.
+    pub fn is_live(&self) -> bool {
+        self.round_trip.is_some()
+    }
+
+    /// Live, with a valid token if authenticated and flowing subscriptions if any.
+    pub fn is_ready(&self) -> bool {
+        self.is_live()
+            && self.token_valid != Some(false)
+            && self.subscriptions_flowing != Some(false)
+    }
+}
+
+/// Snapshot of the client's configuration and connection state, see `DeribitClient::info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientInfo {
+    pub url: String,
+    pub authenticated: bool,
+    /// Scope granted by the last `public/auth`.
+    pub scope: Option<String>,
+    /// Heartbeat interval in seconds, if one has been set on the connection.
+    pub heartbeat_interval: Option<i64>,
+    pub cancel_on_disconnect: bool,
+    pub inactivity_timeout: Option<Duration>,
+    pub decode_workers: usize,
+    /// Time since the last call made through the client.
+    pub idle_for: Duration,
+    /// Channels subscribed on this connection.
+    pub subscriptions: Vec<String>,
+    /// Requests sent and still waiting for a response.
+    pub pending_requests: usize,
+    pub max_in_flight_requests: Option<usize>,
+    /// Client-side rate limit, see `DeribitClientBuilder::rate_limit`.
+    pub rate_limit: Option<RateLimit>,
+    /// Credits the rate limit has left at the time of the snapshot.
+    pub remaining_credits: Option<u64>,
+    pub rate_limit_retries: usize,
+    /// Time left until calls held back after a `too_many_requests` rejection resume.
+    pub rate_limited_for: Option<Duration>,
+    /// Times the connection was re-established with `DeribitClient::reconnect`.
+    pub reconnects: u64,
+    pub circuit_state: Option<CircuitState>,
+    pub slow_call_threshold: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct DeribitClient {
+    env: Env,
+    decode_workers: usize,
+    session: Mutex<SessionState>,
+    authenticated: Arc<AtomicBool>,
+    id_counter: Arc<AtomicU64>,
+    request_channel: mpsc::Sender<(RpcRequest, ResponseSender)>,
+    subscription_channel: mpsc::Sender<SubscribeRequest>,
+    safety: SafetyConfig,
+    // Last call made through the client
+    last_activity: LastSeen,
+    // Last message of any kind received from the server
+    last_message: LastSeen,
+    // Last subscription notification received from the server
+    last_notification: LastSeen,
+    disconnect: watch::Receiver<Option<Disconnect>>,
+    diagnostics: broadcast::Sender<Diagnostic>,
+    // Size of the reader's pending request map
+    pending_requests: Arc<AtomicUsize>,
+    circuit_breaker: Option<Mutex<breaker::CircuitBreaker>>,
+    in_flight_slots: Option<Arc<Semaphore>>,
+    max_in_flight_requests: Option<usize>,
+    risk_guard: Option<Mutex<risk::RiskGuard>>,
+    subscription_capacities: Vec<(String, usize)>,
+    subscription_stats: Mutex<BTreeMap<String, Arc<ChannelStats>>>,
+    raw_messages: Option<broadcast::Sender<RawMessage>>,
+    slow_call_threshold: Option<Duration>,
+    credits: Option<Mutex<rate_limit::CreditBucket>>,
+    rate_limit_retries: usize,
+    tolerant_methods: Vec<String>,
+    // Calls wait until then after a `too_many_requests` error, when retrying those
+    rate_limited_until: Mutex<Option<Instant>>,
+    // Reconnections since the client was first connected
+    reconnects: u64,
+    cancellation: CancellationToken,
+    // The settings it was connected with, for `reconnect`
+    builder: DeribitClientBuilder,
+}
+
+impl DeribitClient {
+    pub fn builder(env: Env) -> DeribitClientBuilder {
+        DeribitClientBuilder {
+            env,
+            safety: SafetyConfig::default(),
+            decode_workers: 0,
+            headers: Vec::new(),
+            connect_timeout: None,
+            ip_preference: IpPreference::Any,
+            local_address: None,
+            tcp_nodelay: true,
+            circuit_breaker: None,
+            max_in_flight_requests: None,
+            risk_limits: None,
+            subscription_capacities: Vec::new(),
+            tap_raw_messages: false,
+            slow_call_threshold: None,
+            rate_limit: None,
+            rate_limit_retries: 0,
+            tolerant_methods: Vec::new(),
+            cancellation: None,
+            credentials: None,
+            sign_auth: false,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            tls_connector: None,
+        }
+    }
+
+    pub async fn connect(env: Env) -> Result<Self> {
+        Self::builder(env).connect().await
+    }
+
+    /// Connects again with all the builder settings this client was connected with,
+    /// sharing its cancellation token and logging in as `connect` did, e.g. once
+    /// `disconnected` resolves.
+    pub async fn reconnect(&self) -> Result<Self> {
+        let mut client = self.builder.clone().connect().await?;
+        client.reconnects = self.reconnects + 1;
+        Ok(client)
+    }
+
+    async fn connect_with(builder: DeribitClientBuilder) -> Result<(Self, ConnectionDriver)> {
+        let mut settings = builder.clone();
+        let expected_testnet = builder.env.expected_testnet();
+
+        let mut ws_request = builder.env.url().into_client_request()?;
+        for (name, value) in &builder.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| WSError::HttpFormat(e.into()))?;
+            let value = HeaderValue::from_str(value).map_err(|e| WSError::HttpFormat(e.into()))?;
+            ws_request.headers_mut().append(name, value);
+        }
+
+        let connect = async {
+            let uri = ws_request.uri();
+            let host = uri.host().unwrap_or_default();
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let default_port = if uri.scheme_str() == Some("wss") {
+                443
+            } else {
+                80
+            };
+            let port = uri.port_u16().unwrap_or(default_port);
+            let stream = connect_tcp(
+                host,
+                port,
+                builder.ip_preference,
+                builder.local_address,
+                builder.tcp_nodelay,
+            )
+            .await
+            .map_err(WSError::Io)?;
+
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            return tokio_tungstenite::client_async_tls_with_config(
+                ws_request,
+                stream,
+                None,
+                builder.tls_connector,
+            )
+            .await;
+            #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+            return tokio_tungstenite::client_async(
+                ws_request,
+                tokio_tungstenite::MaybeTlsStream::Plain(stream),
+            )
+            .await;
+        };
+        let (mut ws_stream, _) = match builder.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| Error::ConnectTimeout)??,
+            None => connect.await?,
+        };
+        tracing::info!(url = builder.env.url(), "connected");
+        let (request_tx, mut request_rx) = mpsc::channel::<(RpcRequest, ResponseSender)>(100);
+        let (subscription_tx, mut subscription_rx) = mpsc::channel::<SubscribeRequest>(100);
+
+        let id_counter = Arc::new(AtomicU64::new(0));
+        let id_counter_clone = id_counter.clone();
+
+        let (disconnect_tx, disconnect_rx) = watch::channel(None);
+
+        let started_at = Instant::now();
+        let last_message = LastSeen::new(started_at);
+        let last_message_clone = last_message.clone();
+        let last_notification = LastSeen::new(started_at);
+        let last_notification_clone = last_notification.clone();
+        let pending_count = Arc::new(AtomicUsize::new(0));
+        let pending_count_clone = pending_count.clone();
+
+        let cancellation = builder.cancellation.unwrap_or_default();
+        settings.cancellation = Some(cancellation.clone());
+        let cancelled = cancellation.clone().cancelled_owned();
+
+        let raw_messages = builder
+            .tap_raw_messages
+            .then(|| broadcast::channel(RAW_MESSAGE_CAPACITY).0);
+        let raw_messages_clone = raw_messages.clone();
+
+        let decode_workers = (builder.decode_workers > 0).then(|| {
+            (0..builder.decode_workers)
+                .map(|_| spawn_decode_worker())
+                .collect::<Vec<_>>()
+        });
+
+        let reader_span = tracing::info_span!("reader", url = builder.env.url());
+        let reader = async move {
+            let mut pending_requests: HashMap<u64, ResponseSender> = HashMap::new();
+            // Callers that drop a call before its response leave their entry behind, so
+            // closed ones are pruned whenever the map doubles in size
+            let mut prune_at = MIN_PRUNE_AT;
+            let mut subscribers = Subscribers::default();
+            let mut cancelled = std::pin::pin!(cancelled);
+
+            loop {
+                tokio::select! {
+                    _ = &mut cancelled => {
+                        let _ = ws_stream.close(None).await;
+                        let _ = disconnect_tx.send(Some(Disconnect::Cancelled));
+                        break;
+                    }
+                    msg = ws_stream.next() => {
+                        let text = match msg {
+                            Some(Ok(Message::Text(text))) => text,
+                            Some(Ok(Message::Binary(data))) => match Utf8Bytes::try_from(data) {
+                                Ok(text) => text,
+                                Err(_) => continue,
+                            },
+                            Some(Ok(Message::Ping(_))) => {
+                                // tungstenite queues the pong, flushing sends it right away
+                                let _ = ws_stream.flush().await;
+                                continue;
+                            }
+                            Some(Ok(Message::Pong(_) | Message::Frame(_))) => continue,
+                            Some(Ok(Message::Close(frame))) => {
+                                let (code, reason) = frame
+                                    .map(|frame| (Some(frame.code.into()), frame.reason.to_string()))
+                                    .unwrap_or_default();
+                                let _ = disconnect_tx.send(Some(Disconnect::Closed { code, reason }));
+                                break;
+                            }
+                            Some(Err(e)) => {
+                                let _ = disconnect_tx.send(Some(Disconnect::Error(e.to_string())));
+                                break;
+                            }
+                            None => {
+                                let _ = disconnect_tx.send(Some(Disconnect::Eof));
+                                break;
+                            }
+                        };
+                        tap(&raw_messages_clone, FrameDirection::Inbound, &text);
+                        last_message_clone.touch();
+                        if let Some(workers) = &decode_workers
+                            && let Some(key) = notification_key(&text)
+                        {
+                            last_notification_clone.touch();
+                            let worker = &workers[key.shard(workers.len())];
+                            let _ = worker.send(DecodeJob::Notification(key, text)).await;
+                            continue;
+                        } else if decode_workers.is_none()
+                            && let Some(notification) = json::notification(&text)
+                        {
+                            last_notification_clone.touch();
+                            let channel = &notification.channel;
+                            let key = ChannelKey::new(channel);
+                            subscribers.publish(key, channel, notification.data, text.clone());
+                            continue;
+                        }
+                        match json::from_str::<JsonRPCMessage>(&text) {
+                            Ok(JsonRPCMessage::Heartbeat(heartbeat)) => {
+                                if heartbeat.params.r#type == HeartbeatType::TestRequest {
+                                    let test_request = RpcRequest {
+                                        jsonrpc: JsonRpcVersion::V2,
+                                        id: id_counter_clone.fetch_add(1, Ordering::Relaxed),
+                                        method: "public/test".to_string(),
+                                        params: RawValue::NULL.to_owned(),
+                                    };
+                                    let frame = serde_json::to_string(&test_request).unwrap().into();
+                                    tap(&raw_messages_clone, FrameDirection::Outbound, &frame);
+                                    if let Err(e) = ws_stream.send(Message::Text(frame)).await
+                                    {
+                                        let _ = disconnect_tx.send(Some(Disconnect::Error(e.to_string())));
+                                        break;
+                                    }
+                                }
+                            }
+                            // Well-formed notifications were published above
+                            Ok(JsonRPCMessage::Notification(_)) => {}
+                            Ok(JsonRPCMessage::OkResponse(response)) => {
+                                let result = if let Some(expected_testnet) = expected_testnet
+                                    && response.base.meta.testnet != expected_testnet
+                                {
+                                    Err(Error::EnvironmentMismatch { expected_testnet })
+                                } else {
+                                    Ok((response.result, response.base.meta))
+                                };
+                                tracing::trace!(id = response.base.id, "received response");
+                                if let Some(tx) = pending_requests.remove(&response.base.id) {
+                                    let _ = tx.send(result);
+                                }
+                                pending_count_clone.store(pending_requests.len(), Ordering::Relaxed);
+                            }
+                            Ok(JsonRPCMessage::ErrorResponse(response)) => {
+                                let error = if let Some(expected_testnet) = expected_testnet
+                                    && response.base.meta.testnet != expected_testnet
+                                {
+                                    Err(Error::EnvironmentMismatch { expected_testnet })
+                                } else {
+                                    tracing::debug!(
+                                        id = response.base.id,
+                                        code = response.error.code,
+                                        error = %response.error.message,
+                                        "received error response"
+                                    );
+                                    Err(Error::RpcError(response.error))
+                                };
+                                if let Some(tx) = pending_requests.remove(&response.base.id) {
+                                    let _ = tx.send(error);
+                                }
+                                pending_count_clone.store(pending_requests.len(), Ordering::Relaxed);
+                            }
+                            // Malformed or unknown messages are dropped rather than taking the
+                            // connection down, failing the call they answer if there is one
+                            Err(e) => match response_id(&text)
+                                .and_then(|id| pending_requests.remove(&id).map(|tx| (id, tx)))
+                            {
+                                Some((id, tx)) => {
+                                    tracing::warn!(id, error = %e, "response failed to decode");
+                                    let _ = tx.send(Err(Error::JsonError(e)));
+                                    pending_count_clone.store(pending_requests.len(), Ordering::Relaxed);
+                                }
+                                None => tracing::warn!(error = %e, "dropped malformed message"),
+                            },
+                        }
+                    }
+                    Some((request, tx)) = request_rx.recv() => {
+                        if pending_requests.len() >= prune_at {
+                            pending_requests.retain(|_, tx| !tx.is_closed());
+                            prune_at = (pending_requests.len() * 2).max(MIN_PRUNE_AT);
+                        }
+                        pending_requests.insert(request.id, tx);
+                        pending_count_clone.store(pending_requests.len(), Ordering::Relaxed);
+                        tracing::trace!(id = request.id, method = %request.method, "sending request");
+                        let frame = serde_json::to_string(&request).unwrap().into();
+                        tap(&raw_messages_clone, FrameDirection::Outbound, &frame);
+                        if let Err(e) = ws_stream.send(Message::Text(frame)).await
+                        {
+                            let _ = disconnect_tx.send(Some(Disconnect::Error(e.to_string())));
+                            break;
+                        }
+                    }
+                    Some(request) = subscription_rx.recv() => {
+                        if let Some(workers) = &decode_workers {
+                            let worker = &workers[ChannelKey::new(&request.0).shard(workers.len())];
+                            let _ = worker.send(DecodeJob::Subscribe(request)).await;
+                        } else {
+                            let (channel, capacity, stats, oneshot_tx) = request;
+                            let _ =
+                                oneshot_tx.send(subscribers.subscribe(channel, capacity, stats));
+                        }
+                    }
+                }
+            }
+            let reason = disconnect_tx.borrow().clone().unwrap_or(Disconnect::Eof);
+            tracing::warn!(?reason, "disconnected");
+            reason
+        }
+        .instrument(reader_span);
+
+        let client = Self {
+            env: builder.env,
+            decode_workers: builder.decode_workers,
+            session: Mutex::new(SessionState::default()),
+            authenticated: Arc::new(AtomicBool::new(false)),
+            id_counter,
+            request_channel: request_tx,
+            subscription_channel: subscription_tx,
+            safety: builder.safety,
+            last_activity: LastSeen::new(started_at),
+            last_message,
+            last_notification,
+            disconnect: disconnect_rx,
+            diagnostics: broadcast::channel(100).0,
+            pending_requests: pending_count,
+            circuit_breaker: builder
+                .circuit_breaker
+                .map(|config| Mutex::new(breaker::CircuitBreaker::new(config))),
+            in_flight_slots: builder
+                .max_in_flight_requests
+                .map(|max| Arc::new(Semaphore::new(max.get()))),
+            max_in_flight_requests: builder.max_in_flight_requests.map(NonZeroUsize::get),
+            risk_guard: builder
+                .risk_limits
+                .map(|limits| Mutex::new(risk::RiskGuard::new(limits))),
+            subscription_capacities: builder.subscription_capacities,
+            subscription_stats: Mutex::default(),
+            raw_messages,
+            slow_call_threshold: builder.slow_call_threshold,
+            credits: builder
+                .rate_limit
+                .map(|limit| Mutex::new(rate_limit::CreditBucket::new(limit))),
+            rate_limit_retries: builder.rate_limit_retries,
+            tolerant_methods: builder.tolerant_methods,
+            rate_limited_until: Mutex::new(None),
+            reconnects: 0,
+            cancellation,
+            builder: settings,
+        };
+
+        let watchdog = client
+            .safety
+            .inactivity_timeout
+            .map(|timeout| client.inactivity_watchdog(timeout));
+        let driver = ConnectionDriver::new(async move {
+            let watchdog = async {
+                if let Some(watchdog) = watchdog {
+                    watchdog.await;
+                }
+                std::future::pending().await
+            };
+            tokio::select! {
+                reason = reader => reason,
+                reason = watchdog => reason,
+            }
+        });
+
+        Ok((client, driver))
+    }
+
+    // Verifies the environment with a `public/test` round trip when it is known, and sets
+    // the heartbeat from `SafetyConfig`. Both need the driver running.
+    fn handshake(&self) -> impl Future<Output = Result<()>> + Send + 'static + use<> {
+        let verify = self.env.expected_testnet().is_some();
+        let interval = self.safety.heartbeat_interval;
+        if interval.is_some() {
+            self.session.lock().unwrap().heartbeat_interval = interval;
+        }
+        let request_channel = self.request_channel.clone();
+        let test_id = self.next_id();
+        let id = self.next_id();
+        async move {
+            if verify {
+                // The reader rejects responses carrying the wrong `testnet` flag
+                let request = PublicTestRequest::default();
+                send_request(
+                    &request_channel,
+                    test_id,
+                    request.method_name(),
+                    request.to_raw_params(),
+                )
+                .await?;
+            }
+            if let Some(interval) = interval {
+                let request = PublicSetHeartbeatRequest { interval };
+                send_request(
+                    &request_channel,
+                    id,
+                    request.method_name(),
+                    request.to_raw_params(),
+                )
+                .await?;
+            }
+            Ok(())
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.id_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the client's configuration and connection state, e.g. for
+    /// exposing on a health endpoint.
+    pub fn info(&self) -> ClientInfo {
+        let now = Instant::now();
+        let session = self.session.lock().unwrap();
+        let credits = self.credits.as_ref().map(|credits| credits.lock().unwrap());
+        ClientInfo {
+            url: self.env.url().to_string(),
+            authenticated: self.authenticated.load(Ordering::Acquire),
+            scope: session.scope.clone(),
+            heartbeat_interval: session.heartbeat_interval,
+            cancel_on_disconnect: self.safety.cancel_on_disconnect,
+            inactivity_timeout: self.safety.inactivity_timeout,
+            decode_workers: self.decode_workers,
+            idle_for: self.last_activity.age(),
+            subscriptions: session.subscriptions.iter().cloned().collect(),
+            pending_requests: self.pending_requests.load(Ordering::Relaxed),
+            max_in_flight_requests: self.max_in_flight_requests,
+            rate_limit: credits.as_ref().map(|credits| credits.limit()),
+            remaining_credits: credits.map(|mut credits| credits.remaining(now)),
+            rate_limit_retries: self.rate_limit_retries,
+            rate_limited_for: self
+                .rate_limited_until
+                .lock()
+                .unwrap()
+                .map(|until| until.saturating_duration_since(now))
+                .filter(|left| !left.is_zero()),
+            reconnects: self.reconnects,
+            circuit_state: self.circuit_state(),
+            slow_call_threshold: self.slow_call_threshold,
+        }
+    }
+
+    /// Message counts, subscribers and lag of each channel subscribed through the client,
+    /// ordered by channel name.
+    pub fn subscription_stats(&self) -> Vec<SubscriptionStats> {
+        let stats = self.subscription_stats.lock().unwrap();
+        stats
+            .iter()
+            .map(|(channel, stats)| stats.snapshot(channel))
+            .collect()
+    }
+
+    /// Why the connection ended, `None` while it is still up.
+    pub fn disconnect_reason(&self) -> Option<Disconnect> {
+        self.disconnect.borrow().clone()
+    }
+
+    /// Resolves once the connection has ended. Pending calls then fail with
+    /// `WSError::ConnectionClosed` and subscription streams end.
+    pub async fn disconnected(&self) -> Disconnect {
+        let mut disconnect = self.disconnect.clone();
+        match disconnect.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone().unwrap(),
+            Err(_) => Disconnect::Error("connection task stopped".to_string()),
+        }
+    }
+
+    /// State of the order entry circuit breaker, if one is configured.
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        let breaker = self.circuit_breaker.as_ref()?;
+        Some(breaker.lock().unwrap().state(Instant::now()))
+    }
+
+    /// Closes the order entry circuit breaker without waiting for the cool-down.
+    pub fn reset_circuit_breaker(&self) {
+        if let Some(breaker) = &self.circuit_breaker {
+            breaker.lock().unwrap().reset();
+        }
+    }
+
+    /// Replaces the risk guard's usage for `currency` with the exchange's open orders and
+    /// positions, and loads minimum trade amounts. Fills of resting orders and most
+    /// cancellations are only seen by the guard after a sync, so call this periodically.
+    /// Does nothing without `DeribitClientBuilder::risk_limits`.
+    pub async fn sync_risk_limits(&self, currency: Currency) -> Result<()> {
+        if self.risk_guard.is_none() {
+            return Ok(());
+        }
+        let currency_with_any: CurrencyWithAny = currency.clone().try_into()?;
+        let instruments = self
+            .call(PublicGetInstrumentsRequest {
+                currency: currency_with_any.clone(),
+                ..Default::default()
+            })
+            .await?;
+        let positions = self
+            .call(PrivateGetPositionsRequest {
+                currency: Some(currency_with_any),
+                ..Default::default()
+            })
+            .await?;
+        let open_orders = self
+            .call(PrivateGetOpenOrdersByCurrencyRequest {
+                currency,
+                ..Default::default()
+            })
+            .await?;
+        if let Some(guard) = &self.risk_guard {
+            guard
+                .lock()
+                .unwrap()
+                .sync(&instruments, &positions, &open_orders);
+        }
+        Ok(())
+    }
+
+    /// What `instrument` can still take before the risk guard rejects orders, if one is
+    /// configured.
+    pub fn risk_headroom(&self, instrument: &str) -> Option<Headroom> {
+        let guard = self.risk_guard.as_ref()?;
+        Some(guard.lock().unwrap().headroom(instrument))
+    }
+
+    /// Stream of warnings about server data that was decoded leniently, e.g. enum values
+    /// missing from the API spec. Only decodes that happen while a stream is open are
+    /// checked, and events are dropped if the stream falls behind.
+    pub fn diagnostics(&self) -> impl Stream<Item = Diagnostic> + Send + 'static + use<> {
+        BroadcastStream::new(self.diagnostics.subscribe()).filter_map(|event| async { event.ok() })
+    }
+
+    /// Streams every text frame sent and received from now on, with its direction and
+    /// when the reader handled it. Needs `DeribitClientBuilder::tap_raw_messages`; without
+    /// it the stream ends right away. A consumer that falls more than 1000 frames behind
+    /// skips the oldest ones.
+    pub fn raw_messages(&self) -> impl Stream<Item = RawMessage> + Send + 'static + use<> {
+        let rx = match &self.raw_messages {
+            Some(tx) => tx.subscribe(),
+            None => broadcast::channel(1).1,
+        };
+        BroadcastStream::new(rx).filter_map(|frame| async { frame.ok() })
+    }
+
+    /// Checks that the connection answers a `public/test` call, that the access token is
+    /// within its lifetime, and that subscribed channels received a notification within
+    /// `max_notification_age`.
+    ///
+    /// Health checks don't count as activity for the inactivity watchdog.
+    pub async fn health(&self, max_notification_age: Duration) -> HealthReport {
+        let start = Instant::now();
+        let round_trip = self
+            .send("public/test", RawValue::NULL.to_owned())
+            .await
+            .ok()
+            .map(|_| start.elapsed());
+
+        let session = self.session.lock().unwrap();
+        let authenticated = self.authenticated.load(Ordering::Acquire);
+        let token_valid = authenticated.then(|| {
+            session
+                .token_expires_at
+                .is_none_or(|expires_at| Instant::now() < expires_at)
+        });
+        let last_notification_age =
+            (!session.subscriptions.is_empty()).then(|| self.last_notification.age());
+
+        HealthReport {
+            round_trip,
+            last_message_age: self.last_message.age(),
+            authenticated,
+            token_valid,
+            last_notification_age,
+            subscriptions_flowing: last_notification_age.map(|age| age <= max_notification_age),
+        }
+    }
+
+    // Sends `private/cancel_all` once the client has been idle for `timeout`. The task
+    // stops when the client is dropped or the connection is gone.
+    fn inactivity_watchdog(
+        &self,
+        timeout: Duration,
+    ) -> impl Future<Output = ()> + Send + 'static + use<> {
+        let request_channel = self.request_channel.downgrade();
+        let authenticated = self.authenticated.clone();
+        let id_counter = self.id_counter.clone();
+        let last_activity = self.last_activity.clone();
+        let check_every = (timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+
+        async move {
+            let mut fired = false;
+            let mut ticker = tokio::time::interval(check_every);
+            loop {
+                ticker.tick().await;
+                let Some(request_channel) = request_channel.upgrade() else {
+                    break;
+                };
+                if last_activity.age() < timeout {
+                    fired = false;
+                    continue;
+                }
+                if fired || !authenticated.load(Ordering::Acquire) {
+                    continue;
+                }
+                fired = true;
+                let request = RpcRequest {
+                    jsonrpc: JsonRpcVersion::V2,
+                    id: id_counter.fetch_add(1, Ordering::Relaxed),
+                    method: "private/cancel_all".to_string(),
+                    params: PrivateCancelAllRequest::default().to_raw_params(),
+                };
+                let (tx, _rx) = oneshot::channel();
+                if request_channel.send((request, tx)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, method: &str, params: Box<RawValue>) -> Result<(Value, ResponseMeta)> {
+        let send = async {
+            // Held until the response arrives or the call is dropped
+            let _slot = match &self.in_flight_slots {
+                Some(_) if SLOT_HELD.try_with(|held| *held).unwrap_or(false) => None,
+                Some(slots) => Some(slots.acquire().await.expect("semaphore is never closed")),
+                None => None,
+            };
+            let mut params = Some(params);
+            let mut retries = 0;
+            loop {
+                let until = *self.rate_limited_until.lock().unwrap();
+                if let Some(until) = until {
+                    tokio::time::sleep_until(until.into()).await;
+                }
+                if let Some(credits) = &self.credits {
+                    loop {
+                        let wait = credits.lock().unwrap().take(Instant::now());
+                        match wait {
+                            Some(wait) => tokio::time::sleep(wait).await,
+                            None => break,
+                        }
+                    }
+                }
+                let id = self.next_id();
+                // Fills in the id of the enclosing `call` span
+                tracing::Span::current().record("id", id);
+                let attempt = if retries < self.rate_limit_retries {
+                    params.clone()
+                } else {
+                    params.take()
+                };
+                let attempt = attempt.expect("params are taken by the last attempt only");
+                let result = send_request(&self.request_channel, id, method, attempt).await;
+                match &result {
+                    Err(Error::RpcError(error))
+                        if error.code == RpcError::TOO_MANY_REQUESTS
+                            && retries < self.rate_limit_retries =>
+                    {
+                        let wait = error.retry_after().unwrap_or(DEFAULT_RATE_LIMIT_WAIT);
+                        tracing::debug!(?wait, "rate limited, retrying");
+                        let until = Instant::now() + wait;
+                        let mut limited = self.rate_limited_until.lock().unwrap();
+                        *limited = Some(limited.map_or(until, |limited| limited.max(until)));
+                        retries += 1;
+                    }
+                    _ => return result,
+                }
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = self.cancellation.cancelled() => Err(Error::Cancelled),
+            result = send => result,
+        }
+    }
+
+    /// The token that shuts the client down, the one passed to
+    /// `DeribitClientBuilder::cancellation_token` or a new one. Cancelling it fails calls
+    /// with `Error::Cancelled`, closes the connection and ends subscription streams.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    pub async fn call_raw(&self, method: &str, params: Value) -> Result<Value> {
+        let (value, _) = self.call_raw_with_meta(method, params).await?;
+        Ok(value)
+    }
+
+    /// Like `call_raw`, also returning the response metadata.
+    pub async fn call_raw_with_meta(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<(Value, ResponseMeta)> {
+        let params = serde_json::value::to_raw_value(&params)?;
+        self.call_serialized(method, params).await
+    }
+
+    async fn call_serialized(
+        &self,
+        method: &str,
+        params: Box<RawValue>,
+    ) -> Result<(Value, ResponseMeta)> {
+        let span = tracing::debug_span!("call", method, id = tracing::field::Empty);
+        let result = self
+            .call_raw_inner(method, params)
+            .instrument(span.clone())
+            .await;
+        if let Err(e) = &result {
+            span.in_scope(|| tracing::warn!(error = %e, "call failed"));
+        }
+        result
+    }
+
+    async fn call_raw_inner(
+        &self,
+        method: &str,
+        params: Box<RawValue>,
+    ) -> Result<(Value, ResponseMeta)> {
+        self.last_activity.touch();
+
+        // Parsed back only where params are inspected, so other typed calls never build a
+        // `Value`
+        let parsed = (self.risk_guard.is_some() || method == "public/set_heartbeat")
+            .then(|| serde_json::from_str::<Value>(params.get()).unwrap_or_default());
+        if let (Some(guard), Some(parsed)) = (&self.risk_guard, &parsed) {
+            guard
+                .lock()
+                .unwrap()
+                .check(method, parsed)
+                .map_err(Error::RiskLimit)?;
+        }
+        let permit = match &self.circuit_breaker {
+            Some(breaker) if breaker::ORDER_ENTRY_METHODS.contains(&method) => {
+                Some(breaker.lock().unwrap().permit(Instant::now())?)
+            }
+            _ => None,
+        };
+        let interval = parsed
+            .as_ref()
+            .and_then(|parsed| parsed.get("interval"))
+            .and_then(Value::as_i64);
+        let instrument = parsed
+            .as_ref()
+            .and_then(|parsed| parsed.get("instrument_name"))
+            .and_then(Value::as_str)
+            .map(String::from);
+        let sent_at = Instant::now();
+        let result = self.send(method, params).await;
+        if let (Some(threshold), Ok((_, meta))) = (self.slow_call_threshold, &result) {
+            self.check_slow_call(method, sent_at.elapsed(), meta, threshold);
+        }
+        if let (Some(breaker), Some(permit)) = (&self.circuit_breaker, permit) {
+            breaker
+                .lock()
+                .unwrap()
+                .record(Instant::now(), permit, &result);
+        }
+        let (value, meta) = result?;
+        if let Some(guard) = &self.risk_guard {
+            guard
+                .lock()
+                .unwrap()
+                .record(method, instrument.as_deref(), &value);
+        }
+
+        match method {
+            "public/set_heartbeat" => {
+                self.session.lock().unwrap().heartbeat_interval = interval;
+            }
+            "public/disable_heartbeat" => {
+                self.session.lock().unwrap().heartbeat_interval = None;
+            }
+            "public/auth" => {
+                let mut session = self.session.lock().unwrap();
+                session.scope = value["scope"].as_str().map(String::from);
+                session.token_expires_at = value["expires_in"]
+                    .as_u64()
+                    .map(|secs| Instant::now() + Duration::from_secs(secs));
+                session.refresh_token = value["refresh_token"]
+                    .as_str()
+                    .map(|token| session::RefreshToken(token.to_string()));
+            }
+            _ => {}
+        }
+
+        if method == "public/auth" {
+            self.authenticated.store(true, Ordering::Release);
+            if self.safety.cancel_on_disconnect {
+                let request = PrivateEnableCancelOnDisconnectRequest {
+                    scope: Some(CodScopeParam::Connection),
+                };
+                self.send(request.method_name(), request.to_raw_params())
+                    .await?;
+            }
+        }
+
+        Ok((value, meta))
+    }
+
+    fn check_slow_call(
+        &self,
+        method: &str,
+        total: Duration,
+        meta: &ResponseMeta,
+        threshold: Duration,
+    ) {
+        if total <= threshold {
+            return;
+        }
+        let server = Duration::from_micros(meta.us_diff);
+        let network = total.saturating_sub(server);
+        tracing::warn!(
+            method,
+            total_ms = total.as_secs_f64() * 1000.0,
+            server_ms = server.as_secs_f64() * 1000.0,
+            network_ms = network.as_secs_f64() * 1000.0,
+            "slow call"
+        );
+        let _ = self.diagnostics.send(Diagnostic::SlowCall {
+            method: method.to_string(),
+            total,
+            server,
+            network,
+        });
+    }
+
+    pub async fn call<T: ApiRequest>(&self, req: T) -> Result<T::Response> {
+        let (typed, _) = self.call_with_meta(req).await?;
+        Ok(typed)
+    }
+
+    /// Like `call`, also returning the response metadata.
+    pub async fn call_with_meta<T: ApiRequest>(
+        &self,
+        req: T,
+    ) -> Result<(T::Response, ResponseMeta)> {
+        self.check_scope(&req)?;
+        let (value, meta) = self
+            .call_serialized(req.method_name(), req.to_raw_params())
+            .await?;
+        Ok((self.decode_result(req.method_name(), &value)?, meta))
+    }
+
+    /// Like `call`, authenticated with `access_token` instead of the connection's token,
+    /// e.g. to make calls for several accounts or sessions over one connection. Only
+    /// private methods take the token.
+    pub async fn call_as<T: ApiRequest>(&self, access_token: &str, req: T) -> Result<T::Response> {
+        let mut params = req.to_params();
+        if let Value::Object(params) = &mut params {
+            params.insert("access_token".to_string(), access_token.into());
+        }
+        let value = self.call_raw(req.method_name(), params).await?;
+        self.decode_result(req.method_name(), &value)
+    }
+
+    // Fails before sending a call the scope granted by the last `public/auth` doesn't
+    // cover, as the server would. Nothing is checked before authenticating.
+    fn check_scope<T: ApiRequest>(&self, req: &T) -> Result<()> {
+        let Some(required) = req.required_scope() else {
+            return Ok(());
+        };
+        match &self.session.lock().unwrap().scope {
+            Some(scope) if !required.granted_by(scope) => Err(Error::InsufficientScope {
+                method: req.method_name().to_string(),
+                required,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Decodes the result of `method`, tolerating changed shapes if configured to
+    fn decode_result<T: DeserializeOwned>(&self, method: &str, value: &Value) -> Result<T> {
+        let typed = if self
+            .tolerant_methods
+            .iter()
+            .any(|pattern| glob_matches(pattern, method))
+        {
+            diagnostics::decode_tolerant(value, method, &self.diagnostics)?
+        } else {
+            diagnostics::decode(value, method, &self.diagnostics)?
+        };
+        Ok(typed)
+    }
+
+    /// Like `call`, failing with `Error::Cancelled` as soon as `token` is cancelled, e.g.
+    /// when the task that made the call shuts down. The request may still have reached
+    /// the server.
+    pub async fn call_with_cancellation<T: ApiRequest>(
+        &self,
+        req: T,
+        token: &CancellationToken,
+    ) -> Result<T::Response> {
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => Err(Error::Cancelled),
+            result = self.call(req) => result,
+        }
+    }
+
+    /// Streams the `data` of each notification on `channel` as unparsed JSON, shared between
+    /// all subscribers of the channel, e.g. `serde_json::from_str::<Value>(msg.get())`.
+    pub async fn subscribe_raw(
+        &self,
+        channel: &str,
+    ) -> Result<impl Stream<Item = Result<Arc<RawValue>>> + Send + 'static + use<>> {
+        self.subscribe_raw_with_capacity(channel, self.subscription_capacity(channel))
+            .await
+    }
+
+    fn subscription_capacity(&self, channel: &str) -> usize {
+        self.subscription_capacities
+            .iter()
+            .find(|(pattern, _)| glob_matches(pattern, channel))
+            .map_or(DEFAULT_SUBSCRIPTION_CAPACITY, |(_, capacity)| *capacity)
+    }
+
+    /// Like `subscribe_raw`, buffering up to `capacity` messages instead of the configured
+    /// `DeribitClientBuilder::subscription_capacity`. The capacity is fixed by the first
+    /// subscriber of a channel; later ones share its buffer size.
+    pub async fn subscribe_raw_with_capacity(
+        &self,
+        channel: &str,
+        capacity: usize,
+    ) -> Result<impl Stream<Item = Result<Arc<RawValue>>> + Send + 'static + use<>> {
+        let published = self.subscribe_published(channel, capacity).await?;
+        Ok(published.map(|msg| msg.map(|msg| msg.data.clone())))
+    }
+
+    /// Like `subscribe_raw`, but a consumer that falls behind skips straight to the newest
+    /// message instead of getting `Error::SubscriptionLagged`. Suits channels where only
+    /// the latest value matters, e.g. tickers and index prices.
+    pub async fn subscribe_raw_conflated(
+        &self,
+        channel: &str,
+    ) -> Result<impl Stream<Item = Result<Arc<RawValue>>> + Send + 'static + use<>> {
+        let published = self.subscribe_published_conflated(channel).await?;
+        Ok(published.map(|msg| msg.map(|msg| msg.data.clone())))
+    }
+
+    /// Streams each notification on `channel` as the text frame received from the server,
+    /// byte for byte and without parsing `data`, e.g. to forward exact exchange payloads to
+    /// other processes.
+    pub async fn subscribe_raw_bytes(
+        &self,
+        channel: &str,
+    ) -> Result<impl Stream<Item = Result<Utf8Bytes>> + Send + 'static + use<>> {
+        let published = self
+            .subscribe_published(channel, self.subscription_capacity(channel))
+            .await?;
+        Ok(published.map(|msg| msg.map(|msg| msg.frame.clone())))
+    }
+
+    async fn subscribe_published(
+        &self,
+        channel: &str,
+        capacity: usize,
+    ) -> Result<impl Stream<Item = Result<Arc<Published>>> + Send + 'static + use<>> {
+        self.subscribe_published_as(channel, capacity, None).await
+    }
+
+    // Like `subscribe_published`, subscribing with `access_token` if given, for a named
+    // session
+    async fn subscribe_published_as(
+        &self,
+        channel: &str,
+        capacity: usize,
+        access_token: Option<&str>,
+    ) -> Result<impl Stream<Item = Result<Arc<Published>>> + Send + 'static + use<>> {
+        let (channel_rx, stats) = self
+            .subscribe_receiver(channel, capacity, access_token)
+            .await?;
+        Ok(BroadcastStream::new(channel_rx).map(move |msg| match msg {
+            Ok(msg) => Ok(msg),
+            Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                stats.record_lag(lag);
+                Err(Error::SubscriptionLagged(lag))
+            }
+        }))
+    }
+
+    async fn subscribe_published_conflated(
+        &self,
+        channel: &str,
+    ) -> Result<impl Stream<Item = Result<Arc<Published>>> + Send + 'static + use<>> {
+        let (mut channel_rx, _) = self
+            .subscribe_receiver(channel, self.subscription_capacity(channel), None)
+            .await?;
+        // Never yielded, `from_changes` skips the initial value
+        let (latest_tx, latest_rx) = watch::channel(Arc::new(Published {
+            frame: Utf8Bytes::default(),
+            data: Arc::from(RawValue::NULL.to_owned()),
+            receivers: 0,
+            decoded: OnceLock::new(),
+        }));
+        // Keeps only the newest message, until the connection or the stream goes away
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    msg = channel_rx.recv() => match msg {
+                        Ok(msg) => {
+                            latest_tx.send_replace(msg);
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = latest_tx.closed() => break,
+                }
+            }
+        });
+        Ok(WatchStream::from_changes(latest_rx).map(Ok))
+    }
+
+    // Registers `channel` with the server and the reader, returning its broadcast receiver
+    // and counters
+    async fn subscribe_receiver(
+        &self,
+        channel: &str,
+        capacity: usize,
+        access_token: Option<&str>,
+    ) -> Result<(broadcast::Receiver<Arc<Published>>, Arc<ChannelStats>)> {
+        let span = tracing::debug_span!("subscribe", channel);
+        let result = self
+            .subscribe_receiver_inner(channel, capacity, access_token)
+            .instrument(span.clone())
+            .await;
+        span.in_scope(|| match &result {
+            Ok(_) => tracing::debug!("subscribed"),
+            Err(e) => tracing::warn!(error = %e, "subscribe failed"),
+        });
+        result
+    }
+
+    async fn subscribe_receiver_inner(
+        &self,
+        channel: &str,
+        capacity: usize,
+        access_token: Option<&str>,
+    ) -> Result<(broadcast::Receiver<Arc<Published>>, Arc<ChannelStats>)> {
+        let channels = vec![channel.to_string()];
+        let subscribed_channels: Vec<String> = if let Some(access_token) = access_token {
+            let value = self
+                .call_raw(
+                    "private/subscribe",
+                    serde_json::json!({ "channels": channels, "access_token": access_token }),
+                )
+                .await?;
+            self.decode_result("private/subscribe", &value)?
+        } else if self.authenticated.load(Ordering::Acquire) {
+            self.call(PrivateSubscribeRequest {
+                channels,
+                label: None,
+            })
+            .await?
+        } else {
+            self.call(PublicSubscribeRequest { channels }).await?
+        };
+        if let Some(channel) = subscribed_channels.first() {
+            let stats = self
+                .subscription_stats
+                .lock()
+                .unwrap()
+                .entry(channel.clone())
+                .or_default()
+                .clone();
+            let (tx, rx) = oneshot::channel();
+            self.subscription_channel
+                .send((channel.clone(), capacity.max(1), stats.clone(), tx))
+                .await
+                .map_err(|_| WSError::ConnectionClosed)?;
+            let channel_rx = rx.await.map_err(|_| WSError::ConnectionClosed)?;
+            self.session
+                .lock()
+                .unwrap()
+                .subscriptions
+                .insert(channel.clone());
+            Ok((channel_rx, stats))
+        } else {
+            Err(Error::InvalidSubscriptionChannel(channel.to_string()))
+        }
+    }
+
+    // Typed subscription: accepts a generated Subscription and returns a typed broadcast receiver
+    pub async fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> Result<SubscriptionStream<S::Data>> {
+        let channel = subscription.channel_string();
+        let capacity = self.subscription_capacity(&channel);
+        let published = self.subscribe_published(&channel, capacity).await?;
+        Ok(self.decode_stream::<S>(channel, published))
+    }
+
+    /// Like `subscribe`, buffering up to `capacity` messages, see
+    /// `subscribe_raw_with_capacity`.
+    pub async fn subscribe_with_capacity<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+        capacity: usize,
+    ) -> Result<SubscriptionStream<S::Data>> {
+        let channel = subscription.channel_string();
+        let published = self.subscribe_published(&channel, capacity).await?;
+        Ok(self.decode_stream::<S>(channel, published))
+    }
+
+    /// Like `subscribe`, ending the stream once `token` is cancelled. Subscribing fails
+    /// with `Error::Cancelled` if it is cancelled before the server confirmed.
+    pub async fn subscribe_with_cancellation<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+        token: CancellationToken,
+    ) -> Result<SubscriptionStream<S::Data>> {
+        let channel = subscription.channel_string();
+        let capacity = self.subscription_capacity(&channel);
+        let published = tokio::select! {
+            biased;
+            _ = token.cancelled() => return Err(Error::Cancelled),
+            published = self.subscribe_published(&channel, capacity) => published?,
+        };
+        let published = published.take_until(token.cancelled_owned());
+        Ok(self.decode_stream::<S>(channel, published))
+    }
+
+    /// Like `subscribe`, keeping only the newest message, see `subscribe_raw_conflated`.
+    pub async fn subscribe_conflated<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> Result<SubscriptionStream<S::Data>> {
+        let channel = subscription.channel_string();
+        let published = self.subscribe_published_conflated(&channel).await?;
+        Ok(self.decode_stream::<S>(channel, published))
+    }
+
+    fn decode_stream<S: Subscription>(
+        &self,
+        channel: String,
+        published: impl Stream<Item = Result<Arc<Published>>> + Send + 'static,
+    ) -> SubscriptionStream<S::Data> {
+        SubscriptionStream::new(published, channel, self.diagnostics.clone())
     }
 }
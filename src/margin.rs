@@ -0,0 +1,356 @@
+//! Typed views of portfolio margin simulations and per-instrument margin attribution,
+//! and alerts when the account's margin runs low.
+//!
+//! The spec types the results of `private/pme/simulate` and `private/simulate_portfolio`
+//! as plain maps; `PmeSimulation` and `PortfolioSimulation` parse the parts used for risk
+//! decisions and keep the rest as JSON.
+
+use crate::{
+    Currency, CurrencyWithAny, DeribitClient, PrivateAccountResponse,
+    PrivateGetAccountSummaryRequest, PrivateGetPositionsRequest, PrivateSimulatePortfolioRequest,
+    Result, UserPortfolioCurrencyChannel, UserPortfolioNotification, money_f64,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+// `private/simulate_portfolio` may be called at most once per second
+const SIMULATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A `private/pme/simulate` result, the Extended Risk Matrix of the portfolio.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct PmeSimulation {
+    /// Margin breakdown per currency, or for the whole portfolio under `cross`.
+    #[serde(default)]
+    pub margins: BTreeMap<String, MarginBreakdown>,
+    /// Scenario P&L per index, e.g. `btc_usd`.
+    #[serde(default)]
+    pub aggregated_risk_vectors: BTreeMap<String, RiskVectors>,
+    #[serde(default, deserialize_with = "numbers")]
+    pub index_price: BTreeMap<String, f64>,
+    /// Fields without a typed counterpart, e.g. the model parameters.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+impl PmeSimulation {
+    pub fn from_response(result: HashMap<String, Value>) -> serde_json::Result<Self> {
+        Self::deserialize(Value::Object(result.into_iter().collect()))
+    }
+}
+
+/// Margin of one currency split into the risk buckets it is made of.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct MarginBreakdown {
+    #[serde(default)]
+    pub initial_margin: f64,
+    #[serde(default)]
+    pub maintenance_margin: f64,
+    /// Every other numeric field, e.g. `risk_matrix_margin` or `delta_margin`: the
+    /// contribution of each bucket.
+    #[serde(flatten, deserialize_with = "numbers")]
+    pub contributions: BTreeMap<String, f64>,
+}
+
+impl MarginBreakdown {
+    /// The bucket contributing the most.
+    pub fn largest_contribution(&self) -> Option<(&str, f64)> {
+        self.contributions
+            .iter()
+            .map(|(bucket, &amount)| (bucket.as_str(), amount))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+/// Portfolio P&L under each price and volatility scenario of the risk matrix.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RiskVectors {
+    #[serde(default)]
+    pub standard: Vec<f64>,
+    /// Scenarios with larger moves, used for the extended risk matrix.
+    #[serde(default)]
+    pub extended: Vec<f64>,
+}
+
+impl RiskVectors {
+    /// Index and P&L of the standard scenario with the largest loss, the one that sets
+    /// the risk matrix margin.
+    pub fn worst_scenario(&self) -> Option<(usize, f64)> {
+        self.standard
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+/// A `private/simulate_portfolio` result.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct PortfolioSimulation {
+    #[serde(default)]
+    pub projected_initial_margin: f64,
+    #[serde(default)]
+    pub projected_maintenance_margin: f64,
+    #[serde(default)]
+    pub projected_delta_total: f64,
+    /// Per index, e.g. `btc_usd`.
+    #[serde(default, deserialize_with = "numbers")]
+    pub delta_total_map: BTreeMap<String, f64>,
+    #[serde(default, deserialize_with = "numbers")]
+    pub options_gamma_map: BTreeMap<String, f64>,
+    #[serde(default, deserialize_with = "numbers")]
+    pub options_vega_map: BTreeMap<String, f64>,
+    #[serde(default, deserialize_with = "numbers")]
+    pub options_theta_map: BTreeMap<String, f64>,
+    /// Fields without a typed counterpart.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+impl PortfolioSimulation {
+    pub fn from_response(result: HashMap<String, Value>) -> serde_json::Result<Self> {
+        Self::deserialize(Value::Object(result.into_iter().collect()))
+    }
+}
+
+// Keeps the numeric values of a map, skipping strings, nulls and nested objects
+fn numbers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, f64>, D::Error> {
+    let values = BTreeMap::<String, Value>::deserialize(deserializer)?;
+    Ok(values
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.as_f64()?)))
+        .collect())
+}
+
+/// Margin a position is responsible for: how much the portfolio's projected margin would
+/// drop without it. Hedges have negative contributions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarginContribution {
+    pub instrument_name: String,
+    pub size: f64,
+    pub initial_margin: f64,
+    pub maintenance_margin: f64,
+}
+
+impl DeribitClient {
+    /// Attributes the projected margin of `currency` to the open positions, largest first,
+    /// by simulating the portfolio without each of them.
+    ///
+    /// Makes one `private/simulate_portfolio` call per position plus one, a second apart
+    /// to respect its rate limit.
+    pub async fn attribute_margin(&self, currency: Currency) -> Result<Vec<MarginContribution>> {
+        let currency_with_any: CurrencyWithAny = currency.clone().try_into()?;
+        let positions = self
+            .call(PrivateGetPositionsRequest {
+                currency: Some(currency_with_any),
+                ..Default::default()
+            })
+            .await?;
+        let simulate = |simulated_positions| PrivateSimulatePortfolioRequest {
+            currency: currency.clone(),
+            add_positions: Some(true),
+            simulated_positions,
+        };
+        let portfolio = PortfolioSimulation::from_response(self.call(simulate(None)).await?)?;
+        let mut contributions = Vec::new();
+        for position in positions.into_iter().filter(|p| p.size != 0.0) {
+            tokio::time::sleep(SIMULATE_INTERVAL).await;
+            // Adding the opposite position closes it
+            let closed = HashMap::from([(position.instrument_name.clone(), -position.size)]);
+            let without = self.call(simulate(Some(closed))).await?;
+            let without = PortfolioSimulation::from_response(without)?;
+            contributions.push(MarginContribution {
+                instrument_name: position.instrument_name,
+                size: position.size,
+                initial_margin: portfolio.projected_initial_margin
+                    - without.projected_initial_margin,
+                maintenance_margin: portfolio.projected_maintenance_margin
+                    - without.projected_maintenance_margin,
+            });
+        }
+        contributions.sort_by(|a, b| b.initial_margin.total_cmp(&a.initial_margin));
+        Ok(contributions)
+    }
+}
+
+/// When `DeribitClient::monitor_margin` alerts. Unset thresholds aren't checked.
+#[derive(Debug, Clone)]
+pub struct MarginThresholds {
+    /// Maintenance margin as a share of the margin balance at which the account is in
+    /// danger; Deribit starts liquidating at 1.
+    pub maintenance_ratio: Option<f64>,
+    /// Available funds below which new orders may be rejected.
+    pub min_available_funds: Option<f64>,
+    /// How often the account summary is polled, in case `user.portfolio` goes quiet.
+    pub poll_interval: Duration,
+}
+
+impl Default for MarginThresholds {
+    fn default() -> Self {
+        Self {
+            maintenance_ratio: Some(0.8),
+            min_available_funds: None,
+            poll_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The margin figures of a currency the thresholds are checked against.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MarginLevel {
+    pub currency: String,
+    pub margin_balance: f64,
+    pub maintenance_margin: f64,
+    pub available_funds: f64,
+}
+
+impl MarginLevel {
+    /// Maintenance margin as a share of the margin balance.
+    pub fn maintenance_ratio(&self) -> f64 {
+        if self.margin_balance > 0.0 {
+            self.maintenance_margin / self.margin_balance
+        } else if self.maintenance_margin > 0.0 {
+            f64::INFINITY
+        } else {
+            0.0
+        }
+    }
+}
+
+impl From<&UserPortfolioNotification> for MarginLevel {
+    fn from(portfolio: &UserPortfolioNotification) -> Self {
+        Self {
+            currency: portfolio.currency.clone(),
+            margin_balance: money_f64(portfolio.margin_balance),
+            maintenance_margin: portfolio.maintenance_margin,
+            available_funds: portfolio.available_funds,
+        }
+    }
+}
+
+impl From<&PrivateAccountResponse> for MarginLevel {
+    fn from(summary: &PrivateAccountResponse) -> Self {
+        Self {
+            currency: summary.currency.clone(),
+            margin_balance: summary.margin_balance.map_or(summary.equity, money_f64),
+            maintenance_margin: summary.maintenance_margin,
+            available_funds: summary.available_funds,
+        }
+    }
+}
+
+/// A threshold of `MarginThresholds` crossed, with the level that crossed it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarginAlert {
+    /// The maintenance ratio reached `maintenance_ratio`.
+    MaintenanceRatioExceeded(MarginLevel),
+    /// The maintenance ratio dropped back below `maintenance_ratio`.
+    MaintenanceRatioRecovered(MarginLevel),
+    /// Available funds fell below `min_available_funds`.
+    AvailableFundsLow(MarginLevel),
+    /// Available funds are back at or above `min_available_funds`.
+    AvailableFundsRecovered(MarginLevel),
+}
+
+// Which thresholds are crossed, to alert only on changes
+#[derive(Debug, Default)]
+struct Crossed {
+    maintenance_ratio: bool,
+    available_funds: bool,
+}
+
+impl Crossed {
+    fn check(&mut self, thresholds: &MarginThresholds, level: MarginLevel) -> Vec<MarginAlert> {
+        let mut alerts = Vec::new();
+        if let Some(threshold) = thresholds.maintenance_ratio {
+            let crossed = level.maintenance_ratio() >= threshold;
+            if crossed != self.maintenance_ratio {
+                self.maintenance_ratio = crossed;
+                alerts.push(if crossed {
+                    MarginAlert::MaintenanceRatioExceeded(level.clone())
+                } else {
+                    MarginAlert::MaintenanceRatioRecovered(level.clone())
+                });
+            }
+        }
+        if let Some(threshold) = thresholds.min_available_funds {
+            let crossed = level.available_funds < threshold;
+            if crossed != self.available_funds {
+                self.available_funds = crossed;
+                alerts.push(if crossed {
+                    MarginAlert::AvailableFundsLow(level)
+                } else {
+                    MarginAlert::AvailableFundsRecovered(level)
+                });
+            }
+        }
+        alerts
+    }
+}
+
+impl DeribitClient {
+    /// Watches the margin of `currency` through `user.portfolio.{currency}` and the
+    /// account summary, polled every `poll_interval` from then on, yielding an alert each time a
+    /// threshold is crossed either way. A level already past a threshold alerts right
+    /// away. Errors of the subscription or the polls are yielded as they come.
+    pub async fn monitor_margin(
+        self: &Arc<Self>,
+        currency: Currency,
+        thresholds: MarginThresholds,
+    ) -> Result<impl Stream<Item = Result<MarginAlert>> + Send + 'static + use<>> {
+        let portfolio = self
+            .subscribe(UserPortfolioCurrencyChannel {
+                currency: currency.clone().try_into()?,
+            })
+            .await?;
+        let summary = PrivateGetAccountSummaryRequest {
+            currency: currency.try_into()?,
+            ..Default::default()
+        };
+        let mut polls = tokio::time::interval_at(
+            tokio::time::Instant::now() + thresholds.poll_interval,
+            thresholds.poll_interval.max(Duration::from_millis(1)),
+        );
+        polls.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let state = (
+            self.clone(),
+            summary,
+            portfolio,
+            polls,
+            thresholds,
+            Crossed::default(),
+            VecDeque::new(),
+        );
+        Ok(futures_util::stream::unfold(
+            state,
+            |(client, summary, mut portfolio, mut polls, thresholds, mut crossed, mut alerts)| async move {
+                loop {
+                    if let Some(alert) = alerts.pop_front() {
+                        let state = (
+                            client, summary, portfolio, polls, thresholds, crossed, alerts,
+                        );
+                        return Some((Ok(alert), state));
+                    }
+                    let level = tokio::select! {
+                        notification = portfolio.next() => notification?.map(|portfolio| MarginLevel::from(&portfolio)),
+                        _ = polls.tick() => client.call(summary.clone()).await.map(|summary| MarginLevel::from(&summary)),
+                    };
+                    match level {
+                        Ok(level) => alerts.extend(crossed.check(&thresholds, level)),
+                        Err(e) => {
+                            let state = (
+                                client, summary, portfolio, polls, thresholds, crossed, alerts,
+                            );
+                            return Some((Err(e), state));
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
@@ -0,0 +1,171 @@
+//! One queryable view of an instrument's market, combined from its ticker, book and
+//! trades channels, see `MarketStateTracker`.
+
+use crate::{
+    BookInstrumentNameGroupDepthChannel, BookInstrumentNameGroupDepthGroup,
+    BookInstrumentNameGroupDepthInterval, BookNotification, DeribitClient, PublicTrade, Result,
+    SubscriptionInterval, TickerInstrumentNameChannel, TickerNotification, Timestamp,
+    TradesInstrumentNameChannel, money_f64,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The latest known state of an instrument.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MarketState {
+    pub instrument_name: String,
+    pub mark_price: Option<f64>,
+    pub index_price: Option<f64>,
+    /// `(price, amount)` of the top of the book.
+    pub best_bid: Option<(f64, f64)>,
+    pub best_ask: Option<(f64, f64)>,
+    pub last_trade: Option<PublicTrade>,
+    /// Perpetuals only.
+    pub current_funding: Option<f64>,
+    pub funding_8h: Option<f64>,
+    pub open_interest: Option<f64>,
+    /// Time of the latest update from any channel.
+    pub timestamp: Option<Timestamp>,
+}
+
+/// The channel whose update changed a `MarketState`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarketChange {
+    Ticker,
+    Book,
+    Trade,
+}
+
+/// A changed `MarketState`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarketUpdate {
+    pub state: MarketState,
+    pub change: MarketChange,
+}
+
+enum Event {
+    Ticker(Box<TickerNotification>),
+    Book(BookNotification),
+    Trades(Vec<PublicTrade>),
+}
+
+impl MarketState {
+    fn apply(&mut self, event: Event) -> MarketChange {
+        match event {
+            Event::Ticker(ticker) => {
+                self.mark_price = Some(money_f64(ticker.mark_price));
+                self.index_price = Some(money_f64(ticker.index_price));
+                self.current_funding = ticker.current_funding;
+                self.funding_8h = ticker.funding_8h;
+                self.open_interest = Some(ticker.open_interest);
+                self.touch(Some(ticker.timestamp));
+                MarketChange::Ticker
+            }
+            Event::Book(book) => {
+                self.best_bid = book.bids.first().copied();
+                self.best_ask = book.asks.first().copied();
+                self.touch(book.timestamp);
+                MarketChange::Book
+            }
+            Event::Trades(trades) => {
+                if let Some(trade) = trades.into_iter().max_by_key(|trade| trade.trade_seq) {
+                    self.touch(Some(trade.timestamp));
+                    self.last_trade = Some(trade);
+                }
+                MarketChange::Trade
+            }
+        }
+    }
+
+    fn touch(&mut self, timestamp: Option<Timestamp>) {
+        self.timestamp = self.timestamp.max(timestamp);
+    }
+}
+
+/// Keeps the `MarketState` of tracked instruments. Clones share the states, so a
+/// tracking task can keep them current while others read them.
+#[derive(Debug, Clone, Default)]
+pub struct MarketStateTracker {
+    states: Arc<Mutex<HashMap<String, MarketState>>>,
+}
+
+impl MarketStateTracker {
+    /// The latest state of `instrument_name`, if tracked.
+    pub fn state(&self, instrument_name: &str) -> Option<MarketState> {
+        self.states.lock().unwrap().get(instrument_name).cloned()
+    }
+
+    /// The latest state of every tracked instrument.
+    pub fn states(&self) -> Vec<MarketState> {
+        self.states.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Subscribes to the ticker, top of the book and trades of each of `instruments` at
+    /// 100ms, keeping their states and yielding each update that changed one. Errors of
+    /// any of the subscriptions, e.g. `Error::SubscriptionLagged`, are yielded as they
+    /// come.
+    pub async fn track(
+        &self,
+        client: &DeribitClient,
+        instruments: &[&str],
+    ) -> Result<impl Stream<Item = Result<MarketUpdate>> + Send + 'static + use<>> {
+        let mut events = Vec::new();
+        for &instrument_name in instruments {
+            let ticker = client
+                .subscribe(TickerInstrumentNameChannel {
+                    instrument_name: instrument_name.to_string(),
+                    interval: SubscriptionInterval::_100ms,
+                })
+                .await?;
+            let book = client
+                .subscribe(BookInstrumentNameGroupDepthChannel {
+                    instrument_name: instrument_name.to_string(),
+                    group: BookInstrumentNameGroupDepthGroup::None,
+                    depth: 1,
+                    interval: BookInstrumentNameGroupDepthInterval::_100ms,
+                })
+                .await?;
+            let trades = client
+                .subscribe(TradesInstrumentNameChannel {
+                    instrument_name: instrument_name.to_string(),
+                    interval: SubscriptionInterval::_100ms,
+                })
+                .await?;
+            let instrument_name = instrument_name.to_string();
+            events.push(
+                futures_util::stream::select(
+                    ticker.map(|ticker| ticker.map(|ticker| Event::Ticker(Box::new(ticker)))),
+                    futures_util::stream::select(
+                        book.map(|book| book.map(Event::Book)),
+                        trades.map(|trades| trades.map(Event::Trades)),
+                    ),
+                )
+                .map(move |event| (instrument_name.clone(), event))
+                .boxed(),
+            );
+        }
+        let states = self.states.clone();
+        Ok(
+            futures_util::stream::select_all(events).filter_map(move |(instrument_name, event)| {
+                let update = event.map(|event| {
+                    let mut states = states.lock().unwrap();
+                    let state =
+                        states
+                            .entry(instrument_name.clone())
+                            .or_insert_with(|| MarketState {
+                                instrument_name,
+                                ..Default::default()
+                            });
+                    let previous = state.clone();
+                    let change = state.apply(event);
+                    (*state != previous).then(|| MarketUpdate {
+                        state: state.clone(),
+                        change,
+                    })
+                });
+                std::future::ready(update.transpose())
+            }),
+        )
+    }
+}
@@ -0,0 +1,222 @@
+//! Mass quoting for option market makers, see `MassQuoter`.
+//!
+//! `private/mass_quote` places or amends one quote per instrument and side of an MMP
+//! group in a single request, and leaves quotes it isn't sent alone. The price or the
+//! amount of a side can be sent on its own to amend just that, and a side sent with an
+//! amount of zero is pulled. It requires cancel-on-disconnect and approval by Deribit.
+
+use crate::{
+    DeribitClient, PrivateCancelQuotesCancelType, PrivateCancelQuotesRequest,
+    PrivateMassQuoteQuotes, PrivateMassQuoteQuotesAsk, PrivateMassQuoteQuotesBid,
+    PrivateMassQuoteRequest, PrivateMassQuoteResponse, QuoteLevel, Result, money,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// The quote wanted on an instrument; a side that is `None` is pulled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstrumentQuote {
+    pub instrument_name: String,
+    /// Groups quotes for `private/cancel_quotes`.
+    pub quote_set_id: String,
+    pub bid: Option<QuoteLevel>,
+    pub ask: Option<QuoteLevel>,
+}
+
+// What one side of a request sets
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct SideChange {
+    price: Option<f64>,
+    amount: Option<f64>,
+}
+
+// The change from `sent` to `wanted`, `None` if there is none
+fn side_change(sent: Option<QuoteLevel>, wanted: Option<QuoteLevel>) -> Option<SideChange> {
+    match (sent, wanted) {
+        (None, None) => None,
+        (Some(_), None) => Some(SideChange {
+            price: None,
+            amount: Some(0.0),
+        }),
+        (None, Some(wanted)) => Some(SideChange {
+            price: Some(wanted.price),
+            amount: Some(wanted.amount),
+        }),
+        (Some(sent), Some(wanted)) => {
+            let change = SideChange {
+                price: (sent.price != wanted.price).then_some(wanted.price),
+                amount: (sent.amount != wanted.amount).then_some(wanted.amount),
+            };
+            (change != SideChange::default()).then_some(change)
+        }
+    }
+}
+
+/// Keeps the quotes of an MMP group in line with the wanted ones, sending only what
+/// changed since the last request. See `MassQuoter::quote`.
+#[derive(Debug)]
+pub struct MassQuoter {
+    client: Arc<DeribitClient>,
+    mmp_group: String,
+    post_only: bool,
+    next_quote_id: u64,
+    // The quotes as last sent, by instrument
+    sent: BTreeMap<String, InstrumentQuote>,
+}
+
+impl MassQuoter {
+    pub fn new(client: Arc<DeribitClient>, mmp_group: impl Into<String>) -> Self {
+        Self {
+            client,
+            mmp_group: mmp_group.into(),
+            post_only: false,
+            next_quote_id: 1,
+            sent: BTreeMap::new(),
+        }
+    }
+
+    /// Sends quotes post-only, repriced rather than taking liquidity.
+    pub fn post_only(mut self) -> Self {
+        self.post_only = true;
+        self
+    }
+
+    /// The request bringing the group from the quotes last sent to `quotes`, which hold
+    /// every quote wanted: sides and instruments missing from them are pulled. `None` if
+    /// nothing changed. Fails with `Error::InvalidMoney` for a price or amount that isn't
+    /// a finite number.
+    pub fn diff(&self, quotes: &[InstrumentQuote]) -> Result<Option<PrivateMassQuoteRequest>> {
+        let wanted: BTreeMap<_, _> = quotes
+            .iter()
+            .map(|quote| (quote.instrument_name.as_str(), quote))
+            .collect();
+        let instruments: BTreeSet<&str> = wanted
+            .keys()
+            .copied()
+            .chain(self.sent.keys().map(String::as_str))
+            .collect();
+        let mut changes = Vec::new();
+        for instrument_name in instruments {
+            let wanted = wanted.get(instrument_name).copied();
+            // A new quote set is sent in full
+            let sent = self.sent.get(instrument_name).filter(|sent| {
+                wanted.is_none_or(|wanted| wanted.quote_set_id == sent.quote_set_id)
+            });
+            let bid = side_change(
+                sent.and_then(|sent| sent.bid),
+                wanted.and_then(|quote| quote.bid),
+            );
+            let ask = side_change(
+                sent.and_then(|sent| sent.ask),
+                wanted.and_then(|quote| quote.ask),
+            );
+            if bid.is_none() && ask.is_none() {
+                continue;
+            }
+            let quote_set_id = wanted
+                .map(|quote| &quote.quote_set_id)
+                .or(sent.map(|sent| &sent.quote_set_id))
+                .cloned()
+                .unwrap_or_default();
+            let post_only = self.post_only.then_some(true);
+            changes.push(PrivateMassQuoteQuotes {
+                instrument_name: instrument_name.to_string(),
+                quote_set_id,
+                bid: bid
+                    .map(|change| -> Result<_> {
+                        Ok(PrivateMassQuoteQuotesBid {
+                            price: change.price.map(money).transpose()?,
+                            amount: change.amount.map(money).transpose()?,
+                            post_only,
+                            ..Default::default()
+                        })
+                    })
+                    .transpose()?,
+                ask: ask
+                    .map(|change| -> Result<_> {
+                        Ok(PrivateMassQuoteQuotesAsk {
+                            price: change.price.map(money).transpose()?,
+                            amount: change.amount.map(money).transpose()?,
+                            post_only,
+                            ..Default::default()
+                        })
+                    })
+                    .transpose()?,
+            });
+        }
+        Ok((!changes.is_empty()).then(|| PrivateMassQuoteRequest {
+            quote_id: self.next_quote_id.to_string(),
+            mmp_group: self.mmp_group.clone(),
+            quotes: changes,
+            ..Default::default()
+        }))
+    }
+
+    /// Sends what changed between the quotes last sent and `quotes`, see `diff`, and
+    /// records them as sent. Returns `None` without a request if nothing changed.
+    ///
+    /// Sides the response reports errors for are sent in full next time. A quote the
+    /// exchange removed on its own, e.g. fully filled, isn't seen here: `invalidate` it to
+    /// have it sent again.
+    pub async fn quote(
+        &mut self,
+        quotes: &[InstrumentQuote],
+    ) -> Result<Option<PrivateMassQuoteResponse>> {
+        let Some(request) = self.diff(quotes)? else {
+            return Ok(None);
+        };
+        self.next_quote_id += 1;
+        let response = self.client.call(request).await?;
+        self.sent = quotes
+            .iter()
+            .map(|quote| (quote.instrument_name.clone(), quote.clone()))
+            .collect();
+        for error in response.errors.iter().flatten() {
+            let Some(sent) = error
+                .instrument_name
+                .as_ref()
+                .and_then(|instrument_name| self.sent.get_mut(instrument_name))
+            else {
+                continue;
+            };
+            match error.side.as_deref() {
+                Some("bid" | "buy") => sent.bid = None,
+                Some("ask" | "sell") => sent.ask = None,
+                _ => {
+                    sent.bid = None;
+                    sent.ask = None;
+                }
+            }
+        }
+        Ok(Some(response))
+    }
+
+    /// Forgets what was sent for `instrument_name`, so its quote is sent in full next
+    /// time.
+    pub fn invalidate(&mut self, instrument_name: &str) {
+        self.sent.remove(instrument_name);
+    }
+
+    /// Cancels every quote set sent with `private/cancel_quotes` and forgets them,
+    /// returning how many quotes were cancelled.
+    pub async fn cancel_all(&mut self) -> Result<usize> {
+        let quote_set_ids: BTreeSet<String> = self
+            .sent
+            .values()
+            .map(|quote| quote.quote_set_id.clone())
+            .collect();
+        let mut cancelled = 0;
+        for set_id in quote_set_ids {
+            cancelled += self
+                .client
+                .call(PrivateCancelQuotesRequest {
+                    cancel_type: PrivateCancelQuotesCancelType::QuoteSetId,
+                    quote_set_id: Some(set_id.clone()),
+                    ..Default::default()
+                })
+                .await? as usize;
+            self.sent.retain(|_, quote| quote.quote_set_id != set_id);
+        }
+        Ok(cancelled)
+    }
+}
@@ -0,0 +1,268 @@
+//! Market maker protection (MMP), see `DeribitClient::set_mmp_limits` and `MmpGuard`.
+//!
+//! Once the trades of orders placed with `mmp` exceed the limits of their index (and
+//! group) within the interval, Deribit cancels those orders and refuses new ones until
+//! the frozen time has passed or, with a frozen time of zero, until `private/reset_mmp`.
+//! `user.mmp_trigger.{index_name}` announces each freeze.
+
+use crate::{
+    DeribitClient, IndexNameDerivative, PrivateGetMmpConfigRequest, PrivateGetMmpStatusRequest,
+    PrivateResetMmpRequest, PrivateSetMmpConfigRequest, Result, Timestamp,
+    UserMmpTriggerIndexNameChannel, UserMmpTriggerNotification,
+};
+use futures_util::{Stream, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Limits of market maker protection, see `DeribitClient::set_mmp_limits`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MmpLimits {
+    /// Period the limits are counted over, in whole seconds.
+    pub interval: Duration,
+    /// How long orders are refused once triggered, in whole seconds. Zero keeps them
+    /// refused until `DeribitClient::reset_mmp`.
+    pub frozen_time: Duration,
+    pub quantity_limit: Option<f64>,
+    pub delta_limit: Option<f64>,
+    pub vega_limit: Option<f64>,
+    pub trade_count_limit: Option<i64>,
+}
+
+/// Whether market maker protection lets an index (and group) trade.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmpState {
+    Active,
+    /// Orders are refused until the timestamp, or until reset if `None`.
+    Frozen {
+        until: Option<Timestamp>,
+    },
+}
+
+impl MmpState {
+    fn frozen_until(frozen_until: Timestamp) -> Self {
+        MmpState::Frozen {
+            until: (frozen_until.0 > 0).then_some(frozen_until),
+        }
+    }
+
+    /// Whether orders are refused at `now`.
+    pub fn is_frozen_at(&self, now: Timestamp) -> bool {
+        match self {
+            MmpState::Active => false,
+            MmpState::Frozen { until } => until.is_none_or(|until| now < until),
+        }
+    }
+}
+
+impl UserMmpTriggerNotification {
+    /// The freeze the trigger started.
+    pub fn state(&self) -> MmpState {
+        MmpState::frozen_until(self.frozen_until)
+    }
+}
+
+impl DeribitClient {
+    /// Sets the market maker protection limits of `index_name`, or of `mmp_group` of it
+    /// for mass quotes.
+    pub async fn set_mmp_limits(
+        &self,
+        index_name: IndexNameDerivative,
+        mmp_group: Option<&str>,
+        limits: &MmpLimits,
+    ) -> Result<()> {
+        self.call(PrivateSetMmpConfigRequest {
+            index_name,
+            interval: limits.interval.as_secs() as i64,
+            frozen_time: limits.frozen_time.as_secs() as i64,
+            mmp_group: mmp_group.map(str::to_string),
+            quantity_limit: limits.quantity_limit,
+            delta_limit: limits.delta_limit,
+            vega_limit: limits.vega_limit,
+            trade_count_limit: limits.trade_count_limit,
+            ..Default::default()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Turns market maker protection off for `index_name` (and `mmp_group`).
+    pub async fn disable_mmp(
+        &self,
+        index_name: IndexNameDerivative,
+        mmp_group: Option<&str>,
+    ) -> Result<()> {
+        self.set_mmp_limits(index_name, mmp_group, &MmpLimits::default())
+            .await
+    }
+
+    /// The market maker protection limits of `index_name` (and `mmp_group`), `None` if
+    /// it is off.
+    pub async fn mmp_limits(
+        &self,
+        index_name: IndexNameDerivative,
+        mmp_group: Option<&str>,
+    ) -> Result<Option<MmpLimits>> {
+        let configs = self
+            .call(PrivateGetMmpConfigRequest {
+                index_name: Some(index_name),
+                mmp_group: mmp_group.map(str::to_string),
+                ..Default::default()
+            })
+            .await?;
+        Ok(configs
+            .into_iter()
+            .find(|config| config.mmp_group.as_deref() == mmp_group && config.interval > 0)
+            .map(|config| MmpLimits {
+                interval: Duration::from_secs(config.interval as u64),
+                frozen_time: Duration::from_secs(config.frozen_time as u64),
+                quantity_limit: config.quantity_limit,
+                delta_limit: config.delta_limit,
+                vega_limit: config.vega_limit,
+                trade_count_limit: config.trade_count_limit,
+            }))
+    }
+
+    /// Whether market maker protection has frozen `index_name` (and `mmp_group`).
+    pub async fn mmp_state(
+        &self,
+        index_name: IndexNameDerivative,
+        mmp_group: Option<&str>,
+    ) -> Result<MmpState> {
+        let statuses = self
+            .call(PrivateGetMmpStatusRequest {
+                index_name: Some(index_name),
+                mmp_group: mmp_group.map(str::to_string),
+                ..Default::default()
+            })
+            .await?;
+        // The group is empty unless frozen by mass quotes
+        Ok(statuses
+            .into_iter()
+            .find(|status| {
+                Some(status.mmp_group.as_str()).filter(|group| !group.is_empty()) == mmp_group
+            })
+            .map_or(MmpState::Active, |status| {
+                MmpState::frozen_until(status.frozen_until)
+            }))
+    }
+
+    /// Lifts a market maker protection freeze of `index_name` (and `mmp_group`).
+    pub async fn reset_mmp(
+        &self,
+        index_name: IndexNameDerivative,
+        mmp_group: Option<&str>,
+    ) -> Result<()> {
+        self.call(PrivateResetMmpRequest {
+            index_name,
+            mmp_group: mmp_group.map(str::to_string),
+            ..Default::default()
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// Whether market maker protection has frozen an index (and group), for a quoting loop
+/// to pause on, e.g. with `Quoter::mmp_guard`. Kept current by `MmpGuard::track`; clones
+/// share the state.
+#[derive(Debug, Clone)]
+pub struct MmpGuard {
+    client: Arc<DeribitClient>,
+    index_name: IndexNameDerivative,
+    mmp_group: Option<String>,
+    state: Arc<watch::Sender<MmpState>>,
+}
+
+impl MmpGuard {
+    pub fn new(
+        client: Arc<DeribitClient>,
+        index_name: IndexNameDerivative,
+        mmp_group: Option<String>,
+    ) -> Self {
+        Self {
+            client,
+            index_name,
+            mmp_group,
+            state: Arc::new(watch::channel(MmpState::Active).0),
+        }
+    }
+
+    /// Fetches the current state and follows `user.mmp_trigger`, yielding the state and
+    /// each freeze of the guarded group. The guard is only updated while the stream is
+    /// polled.
+    pub async fn track(
+        &self,
+    ) -> Result<impl Stream<Item = Result<MmpState>> + Send + 'static + use<>> {
+        let triggers = self
+            .client
+            .subscribe(UserMmpTriggerIndexNameChannel {
+                index_name: self.index_name.clone(),
+            })
+            .await?;
+        let current = self
+            .client
+            .mmp_state(self.index_name.clone(), self.mmp_group.as_deref())
+            .await?;
+        self.state.send_replace(current);
+        let (state, mmp_group) = (self.state.clone(), self.mmp_group.clone());
+        let freezes = triggers.filter_map(move |trigger| {
+            let freeze = match trigger {
+                Ok(trigger) if trigger.mmp_group == mmp_group => {
+                    state.send_replace(trigger.state());
+                    Some(Ok(trigger.state()))
+                }
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            };
+            std::future::ready(freeze)
+        });
+        Ok(futures_util::stream::iter([Ok(current)]).chain(freezes))
+    }
+
+    /// The latest known state.
+    pub fn state(&self) -> MmpState {
+        *self.state.borrow()
+    }
+
+    /// Whether orders are refused right now.
+    pub fn is_paused(&self) -> bool {
+        self.state().is_frozen_at(Timestamp::now())
+    }
+
+    /// Resolves once orders are accepted again: when the freeze ends or is reset.
+    pub async fn resumed(&self) {
+        let mut state = self.state.subscribe();
+        loop {
+            let current = *state.borrow_and_update();
+            let wait = match current {
+                MmpState::Active => return,
+                MmpState::Frozen { until: Some(until) } => {
+                    let remaining = until.0 - Timestamp::now().0;
+                    if remaining <= 0 {
+                        return;
+                    }
+                    Duration::from_millis(remaining as u64)
+                }
+                MmpState::Frozen { until: None } => Duration::MAX,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => return,
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Lifts the freeze with `private/reset_mmp`.
+    pub async fn reset(&self) -> Result<()> {
+        self.client
+            .reset_mmp(self.index_name.clone(), self.mmp_group.as_deref())
+            .await?;
+        self.state.send_replace(MmpState::Active);
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! OHLCV candles built from public trades, see `CandleBuilder` and `candles`.
+//!
+//! Candles are aligned on multiples of the interval since the epoch, like
+//! `MarketClock::bars`, and cover the trades whose timestamps fall in `[start, end)`.
+
+use crate::clock::MarketClock;
+use crate::{PublicTrade, Result, Timestamp, money_f64};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// How long after its end a candle stays open for trades still in flight
+const CLOSE_DELAY_MILLIS: i64 = 1_000;
+
+/// Prices and volume of the trades in `[start, end)`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Candle {
+    pub instrument_name: String,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    /// Sum of trade amounts: USD for inverse futures and perpetuals, the base currency
+    /// otherwise.
+    pub volume: f64,
+    /// Zero for an interval without trades, whose prices are the previous close.
+    pub trade_count: u64,
+}
+
+impl Candle {
+    fn new(trade: &PublicTrade, start: Timestamp, end: Timestamp) -> Self {
+        let price = money_f64(trade.price);
+        Self {
+            instrument_name: trade.instrument_name.clone(),
+            start,
+            end,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 0.0,
+            trade_count: 0,
+        }
+    }
+
+    fn add(&mut self, trade: &PublicTrade) {
+        let price = money_f64(trade.price);
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += money_f64(trade.amount);
+        self.trade_count += 1;
+    }
+
+    // The interval after this one, without trades
+    fn empty_after(&self) -> Self {
+        Self {
+            instrument_name: self.instrument_name.clone(),
+            start: self.end,
+            end: Timestamp(2 * self.end.0 - self.start.0),
+            open: self.close,
+            high: self.close,
+            low: self.close,
+            close: self.close,
+            volume: 0.0,
+            trade_count: 0,
+        }
+    }
+}
+
+/// Builds candles of one instrument from its trades, in order of arrival.
+#[derive(Debug, Clone)]
+pub struct CandleBuilder {
+    interval_millis: i64,
+    fill_empty: bool,
+    current: Option<Candle>,
+    last: Option<Candle>,
+    last_seq: Option<i64>,
+}
+
+impl CandleBuilder {
+    /// Candles of `interval`, e.g. one second or one minute.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval_millis: (interval.as_millis() as i64).max(1),
+            fill_empty: true,
+            current: None,
+            last: None,
+            last_seq: None,
+        }
+    }
+
+    /// Whether intervals without trades yield a candle at the previous close, on by
+    /// default. Off, they are skipped.
+    pub fn fill_empty(mut self, fill_empty: bool) -> Self {
+        self.fill_empty = fill_empty;
+        self
+    }
+
+    /// Adds a trade, returning the candles it closed. Trades at or before the last
+    /// `trade_seq`, e.g. replayed after a reconnect, and trades of closed candles are
+    /// ignored.
+    pub fn push(&mut self, trade: &PublicTrade) -> Vec<Candle> {
+        if self.last_seq.is_some_and(|seq| trade.trade_seq <= seq) {
+            return Vec::new();
+        }
+        let start =
+            Timestamp(trade.timestamp.0.div_euclid(self.interval_millis) * self.interval_millis);
+        let open_from = match (&self.current, &self.last) {
+            (Some(current), _) => Some(current.start),
+            (None, last) => last.as_ref().map(|last| last.end),
+        };
+        if open_from.is_some_and(|open_from| start < open_from) {
+            tracing::debug!(
+                instrument = trade.instrument_name,
+                trade_seq = trade.trade_seq,
+                "trade of a closed candle ignored"
+            );
+            return Vec::new();
+        }
+        self.last_seq = Some(trade.trade_seq);
+        let closed = self.close_before(start);
+        let end = Timestamp(start.0 + self.interval_millis);
+        self.current
+            .get_or_insert_with(|| Candle::new(trade, start, end))
+            .add(trade);
+        closed
+    }
+
+    /// Closes the candles ending at or before `timestamp`, e.g. the current time when no
+    /// trade came to close them. Empty intervals up to it are filled in.
+    pub fn close_before(&mut self, timestamp: Timestamp) -> Vec<Candle> {
+        let mut closed = Vec::new();
+        if let Some(current) = self.current.take_if(|current| current.end <= timestamp) {
+            self.last = Some(current.clone());
+            closed.push(current);
+        }
+        while self.fill_empty && self.current.is_none() {
+            let Some(empty) = self
+                .last
+                .as_ref()
+                .map(Candle::empty_after)
+                .filter(|empty| empty.end <= timestamp)
+            else {
+                break;
+            };
+            self.last = Some(empty.clone());
+            closed.push(empty);
+        }
+        closed
+    }
+
+    /// The candle still taking trades.
+    pub fn current(&self) -> Option<&Candle> {
+        self.current.as_ref()
+    }
+
+    /// Closes the current candle, whether or not its interval has ended.
+    pub fn finish(&mut self) -> Option<Candle> {
+        let current = self.current.take()?;
+        self.last = Some(current.clone());
+        Some(current)
+    }
+
+    // When the next candle to yield ends, if one is expected
+    fn next_end(&self) -> Option<i64> {
+        match (&self.current, &self.last) {
+            (Some(current), _) => Some(current.end.0),
+            (None, Some(last)) if self.fill_empty => Some(last.end.0 + self.interval_millis),
+            _ => None,
+        }
+    }
+}
+
+/// Turns the trades of one instrument, e.g. a `TradesInstrumentNameChannel`
+/// subscription, into candles built by `builder`. Candles close when a later trade
+/// arrives or, in quiet markets, a second after their end by `clock`. The open candle is
+/// yielded when `trades` ends.
+pub fn candles(
+    trades: impl Stream<Item = Result<Vec<PublicTrade>>> + Send + 'static,
+    builder: CandleBuilder,
+    clock: &MarketClock,
+) -> impl Stream<Item = Result<Candle>> + Send + 'static {
+    let clock = clock.clone();
+    futures_util::stream::unfold(
+        (Box::pin(trades), builder, VecDeque::new(), false),
+        move |(mut trades, mut builder, mut closed, mut ended)| {
+            let clock = clock.clone();
+            async move {
+                loop {
+                    if let Some(candle) = closed.pop_front() {
+                        return Some((Ok(candle), (trades, builder, closed, ended)));
+                    }
+                    if ended {
+                        return None;
+                    }
+                    let deadline = builder
+                        .next_end()
+                        .map(|end| Timestamp(end + CLOSE_DELAY_MILLIS));
+                    tokio::select! {
+                        batch = trades.next() => match batch {
+                            Some(Ok(batch)) => {
+                                for trade in &batch {
+                                    closed.extend(builder.push(trade));
+                                }
+                            }
+                            Some(Err(e)) => return Some((Err(e), (trades, builder, closed, ended))),
+                            None => {
+                                closed.extend(builder.finish());
+                                ended = true;
+                            }
+                        },
+                        _ = clock.at(deadline.unwrap_or_default()), if deadline.is_some() => {
+                            let now = clock.clock().now_millis();
+                            closed.extend(builder.close_before(Timestamp(now - CLOSE_DELAY_MILLIS)));
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
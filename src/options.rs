@@ -0,0 +1,410 @@
+//! Options of a currency as a whole, see `VolSurface` and `OptionChain`.
+//!
+//! `VolSurface` keeps the mark implied volatility and greeks of every option from its
+//! ticker, and interpolates the smile of an expiry by strike or delta, and between
+//! expiries in total variance (`iv² × time to expiry`), so a volatility can be read
+//! anywhere on the surface.
+//!
+//! `OptionChain` lays out the calls and puts of one expiry side by side by strike, the
+//! way exchanges display them.
+
+use crate::{
+    Currency, DeribitClient, Expiry, Greeks, Instrument, InstrumentName, InstrumentOptionType,
+    Kind, PublicGetInstrumentsRequest, PublicTickerRequest, Result, SubscriptionInterval,
+    TickerInstrumentNameChannel, TickerNotification, Timestamp, money_f64,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+const YEAR_MILLIS: f64 = 365.0 * 86_400_000.0;
+
+/// An option on the surface.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolPoint {
+    pub instrument_name: String,
+    pub expiry: Timestamp,
+    pub strike: f64,
+    pub option_type: InstrumentOptionType,
+    /// Mark implied volatility, in percent like Deribit quotes it.
+    pub mark_iv: f64,
+    pub mark_price: f64,
+    pub underlying_price: f64,
+    pub greeks: Option<Greeks>,
+    /// Time of the ticker.
+    pub timestamp: Timestamp,
+}
+
+impl VolPoint {
+    /// The point of `instrument` from its ticker, `None` if the ticker has no mark IV.
+    pub fn from_ticker(instrument: &Instrument, ticker: &TickerNotification) -> Option<Self> {
+        Some(Self {
+            instrument_name: instrument.instrument_name.clone(),
+            expiry: instrument.expiration_timestamp,
+            strike: instrument.strike?,
+            option_type: instrument.option_type.clone()?,
+            mark_iv: ticker.mark_iv?,
+            mark_price: money_f64(ticker.mark_price),
+            underlying_price: money_f64(ticker.underlying_price.unwrap_or(ticker.index_price)),
+            greeks: ticker.greeks.clone(),
+            timestamp: ticker.timestamp,
+        })
+    }
+
+    /// Whether the option is out of the money, the side of the smile the surface is
+    /// read from.
+    pub fn is_otm(&self) -> bool {
+        match self.option_type {
+            InstrumentOptionType::Put => self.strike < self.underlying_price,
+            _ => self.strike >= self.underlying_price,
+        }
+    }
+
+    /// Delta of the call of the same strike, from put-call parity for puts.
+    pub fn call_delta(&self) -> Option<f64> {
+        let delta = self.greeks.as_ref()?.delta;
+        Some(match self.option_type {
+            InstrumentOptionType::Put => delta + 1.0,
+            _ => delta,
+        })
+    }
+}
+
+/// Mark volatilities of the options of one currency by instrument. Clones share the
+/// surface, so a tracking task can keep it current while others read it.
+#[derive(Debug, Clone, Default)]
+pub struct VolSurface {
+    points: Arc<Mutex<BTreeMap<String, VolPoint>>>,
+}
+
+impl VolSurface {
+    /// Adds or replaces the point of an option.
+    pub fn insert(&self, point: VolPoint) {
+        self.points
+            .lock()
+            .unwrap()
+            .insert(point.instrument_name.clone(), point);
+    }
+
+    /// Drops the points of options expired by `timestamp`.
+    pub fn remove_expired(&self, timestamp: Timestamp) {
+        self.points
+            .lock()
+            .unwrap()
+            .retain(|_, point| point.expiry > timestamp);
+    }
+
+    pub fn point(&self, instrument_name: &str) -> Option<VolPoint> {
+        self.points.lock().unwrap().get(instrument_name).cloned()
+    }
+
+    /// Expiration timestamps on the surface, earliest first.
+    pub fn expiries(&self) -> Vec<Timestamp> {
+        let mut expiries: Vec<_> = self
+            .points
+            .lock()
+            .unwrap()
+            .values()
+            .map(|point| point.expiry)
+            .collect();
+        expiries.sort_unstable();
+        expiries.dedup();
+        expiries
+    }
+
+    /// Calls and puts expiring at `expiry`, by strike.
+    pub fn smile(&self, expiry: Timestamp) -> Vec<VolPoint> {
+        let mut smile: Vec<_> = self
+            .points
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|point| point.expiry == expiry)
+            .cloned()
+            .collect();
+        smile.sort_by(|a, b| a.strike.total_cmp(&b.strike));
+        smile
+    }
+
+    /// Volatility of `expiry` at `strike`, interpolated linearly between the strikes of
+    /// the out of the money options and flat beyond them.
+    pub fn iv_at_strike(&self, expiry: Timestamp, strike: f64) -> Option<f64> {
+        let smile: Vec<_> = self
+            .smile(expiry)
+            .into_iter()
+            .filter(VolPoint::is_otm)
+            .map(|point| (point.strike, point.mark_iv))
+            .collect();
+        interpolate(&smile, strike)
+    }
+
+    /// Volatility of `expiry` at a call `delta`, e.g. 0.25 for the 25-delta call or 0.75
+    /// for the 25-delta put, interpolated like `iv_at_strike`.
+    pub fn iv_at_delta(&self, expiry: Timestamp, delta: f64) -> Option<f64> {
+        let mut smile: Vec<_> = self
+            .smile(expiry)
+            .into_iter()
+            .filter(VolPoint::is_otm)
+            .filter_map(|point| Some((point.call_delta()?, point.mark_iv)))
+            .collect();
+        smile.sort_by(|a, b| a.0.total_cmp(&b.0));
+        interpolate(&smile, delta)
+    }
+
+    /// Volatility at `strike` for any `expiry`, interpolating the total variance of the
+    /// expiries around it linearly in time, as of `now`. Flat beyond the first and last
+    /// expiry.
+    pub fn iv(&self, expiry: Timestamp, strike: f64, now: Timestamp) -> Option<f64> {
+        let years = |expiry: Timestamp| (expiry.0 - now.0) as f64 / YEAR_MILLIS;
+        let variances: Vec<_> = self
+            .expiries()
+            .into_iter()
+            .filter(|&expiry| expiry > now)
+            .filter_map(|expiry| {
+                let iv = self.iv_at_strike(expiry, strike)?;
+                Some((years(expiry), iv * iv * years(expiry)))
+            })
+            .collect();
+        let (first, last) = (variances.first()?, variances.last()?);
+        let t = years(expiry);
+        if t <= first.0 {
+            return Some((first.1 / first.0).sqrt());
+        }
+        if t >= last.0 {
+            return Some((last.1 / last.0).sqrt());
+        }
+        Some((interpolate(&variances, t)? / t).sqrt())
+    }
+
+    /// Fills the surface from the tickers of the active options of `currency` at 100ms,
+    /// one subscription per option, and yields each point that changed. The surface is
+    /// only updated while the stream is polled; options listed later aren't added.
+    pub async fn track(
+        &self,
+        client: &DeribitClient,
+        currency: Currency,
+    ) -> Result<impl Stream<Item = Result<VolPoint>> + Send + 'static + use<>> {
+        let instruments = client
+            .call(PublicGetInstrumentsRequest {
+                currency: currency.try_into()?,
+                kind: Some(Kind::Option),
+                expired: Some(false),
+            })
+            .await?;
+        let mut tickers = Vec::new();
+        for instrument in &instruments {
+            let ticker = client
+                .subscribe(TickerInstrumentNameChannel {
+                    instrument_name: instrument.instrument_name.clone(),
+                    interval: SubscriptionInterval::_100ms,
+                })
+                .await?;
+            tickers.push(ticker.boxed());
+        }
+        let instruments: HashMap<_, _> = instruments
+            .into_iter()
+            .map(|instrument| (instrument.instrument_name.clone(), instrument))
+            .collect();
+        let surface = self.clone();
+        Ok(
+            futures_util::stream::select_all(tickers).filter_map(move |ticker| {
+                let point = match ticker {
+                    Ok(ticker) => instruments
+                        .get(&ticker.instrument_name)
+                        .and_then(|instrument| VolPoint::from_ticker(instrument, &ticker))
+                        .filter(|point| {
+                            surface.point(&point.instrument_name).as_ref() != Some(point)
+                        })
+                        .map(|point| {
+                            surface.insert(point.clone());
+                            Ok(point)
+                        }),
+                    Err(e) => Some(Err(e)),
+                };
+                std::future::ready(point)
+            }),
+        )
+    }
+}
+
+/// An option of an `OptionChain` and its latest ticker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainOption {
+    pub instrument: Instrument,
+    pub ticker: TickerNotification,
+}
+
+/// The call and put of a strike of an `OptionChain`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainStrike {
+    pub strike: f64,
+    pub call: Option<ChainOption>,
+    pub put: Option<ChainOption>,
+}
+
+/// The options of one currency and expiry by strike, with their tickers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionChain {
+    pub currency: Currency,
+    pub expiry: Expiry,
+    /// Ascending by strike.
+    pub strikes: Vec<ChainStrike>,
+}
+
+impl OptionChain {
+    /// Fetches the active options of `currency` expiring at `expiry` and their tickers.
+    /// The chain is empty if nothing expires then.
+    pub async fn load(client: &DeribitClient, currency: Currency, expiry: Expiry) -> Result<Self> {
+        let instruments = client
+            .call(PublicGetInstrumentsRequest {
+                currency: currency.clone().try_into()?,
+                kind: Some(Kind::Option),
+                expired: Some(false),
+            })
+            .await?;
+        let instruments = instruments.into_iter().filter(|instrument| {
+            instrument
+                .instrument_name
+                .parse::<InstrumentName>()
+                .is_ok_and(|name| name.expiry == Some(expiry))
+        });
+        let options = futures_util::future::try_join_all(instruments.map(|instrument| async {
+            let ticker = client
+                .call(PublicTickerRequest {
+                    instrument_name: instrument.instrument_name.clone(),
+                })
+                .await?;
+            Ok::<_, crate::Error>(ChainOption { instrument, ticker })
+        }))
+        .await?;
+        let mut chain = Self {
+            currency,
+            expiry,
+            strikes: Vec::new(),
+        };
+        for option in options {
+            chain.insert(option);
+        }
+        Ok(chain)
+    }
+
+    fn insert(&mut self, option: ChainOption) {
+        let Some(strike) = option.instrument.strike else {
+            return;
+        };
+        let i = match self
+            .strikes
+            .binary_search_by(|row| row.strike.total_cmp(&strike))
+        {
+            Ok(i) => i,
+            Err(i) => {
+                self.strikes.insert(
+                    i,
+                    ChainStrike {
+                        strike,
+                        ..Default::default()
+                    },
+                );
+                i
+            }
+        };
+        match option.instrument.option_type {
+            Some(InstrumentOptionType::Put) => self.strikes[i].put = Some(option),
+            _ => self.strikes[i].call = Some(option),
+        }
+    }
+
+    pub fn strike(&self, strike: f64) -> Option<&ChainStrike> {
+        self.strikes.iter().find(|row| row.strike == strike)
+    }
+
+    /// The option named `instrument_name`, if in the chain.
+    pub fn option(&self, instrument_name: &str) -> Option<&ChainOption> {
+        self.options()
+            .find(|option| option.instrument.instrument_name == instrument_name)
+    }
+
+    /// Every call and put in the chain.
+    pub fn options(&self) -> impl Iterator<Item = &ChainOption> {
+        self.strikes
+            .iter()
+            .flat_map(|row| row.call.iter().chain(row.put.iter()))
+    }
+
+    /// The strike closest to the underlying price of the options.
+    pub fn atm_strike(&self) -> Option<f64> {
+        let underlying = self
+            .options()
+            .find_map(|option| option.ticker.underlying_price)
+            .map(money_f64)?;
+        self.strikes
+            .iter()
+            .map(|row| row.strike)
+            .min_by(|a, b| (a - underlying).abs().total_cmp(&(b - underlying).abs()))
+    }
+
+    /// Replaces the ticker of its option, returning whether it is in the chain.
+    pub fn apply(&mut self, ticker: TickerNotification) -> bool {
+        let option = self
+            .strikes
+            .iter_mut()
+            .flat_map(|row| row.call.iter_mut().chain(row.put.iter_mut()))
+            .find(|option| option.instrument.instrument_name == ticker.instrument_name);
+        match option {
+            Some(option) => {
+                option.ticker = ticker;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Keeps the chain live from the tickers of its options at 100ms, one subscription
+    /// per option, yielding the chain after each update.
+    pub async fn watch(
+        self,
+        client: &DeribitClient,
+    ) -> Result<impl Stream<Item = Result<OptionChain>> + Send + 'static + use<>> {
+        let mut tickers = Vec::new();
+        for option in self.options() {
+            let ticker = client
+                .subscribe(TickerInstrumentNameChannel {
+                    instrument_name: option.instrument.instrument_name.clone(),
+                    interval: SubscriptionInterval::_100ms,
+                })
+                .await?;
+            tickers.push(ticker.boxed());
+        }
+        let mut chain = self;
+        Ok(
+            futures_util::stream::select_all(tickers).filter_map(move |ticker| {
+                let chain = match ticker {
+                    Ok(ticker) => chain.apply(ticker).then(|| Ok(chain.clone())),
+                    Err(e) => Some(Err(e)),
+                };
+                std::future::ready(chain)
+            }),
+        )
+    }
+}
+
+// Linear interpolation of `(x, y)` points sorted by `x`, flat beyond the ends
+fn interpolate(points: &[(f64, f64)], x: f64) -> Option<f64> {
+    let (first, last) = (points.first()?, points.last()?);
+    if x <= first.0 {
+        return Some(first.1);
+    }
+    if x >= last.0 {
+        return Some(last.1);
+    }
+    points.windows(2).find_map(|pair| {
+        let [(x0, y0), (x1, y1)] = [pair[0], pair[1]];
+        (x0 <= x && x <= x1).then(|| {
+            if x1 == x0 {
+                y0
+            } else {
+                y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+            }
+        })
+    })
+}
@@ -0,0 +1,332 @@
+//! Building orders that pass the exchange's checks before they are sent, see
+//! `OrderBuilder`.
+//!
+//! `private/buy` and `private/sell` take a flat list of optional parameters, several of
+//! which contradict each other, e.g. a post-only market order. The builder only offers
+//! the combinations that make sense for each order type, checks the rest against the
+//! instrument and rounds price and amount to its increments.
+//!
+//! Secondary orders linked with `triggers` or `triggers_oco` are checked the same way
+//! and sent in `otoco_config`; the exchange places them once the primary fills, so they
+//! work while the client is down.
+
+use crate::{
+    Direction, Instrument, Kind, LinkedOrderType, OrderManager, OrderTypeParam, OtocoConfig,
+    PrivateBuyAndSellResponse, PrivateBuyRequest, PrivateSellRequest, Result, TimeInForceParam,
+    Trigger, TriggerFillConditionParam, money,
+};
+
+// Longest label Deribit accepts
+const MAX_LABEL_LEN: usize = 64;
+
+/// Why `OrderBuilder::build` refused an order.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum InvalidOrder {
+    #[error("orders need a label")]
+    MissingLabel,
+    #[error("label is longer than {MAX_LABEL_LEN} characters")]
+    LabelTooLong,
+    #[error("direction must be buy or sell, not {0}")]
+    UnknownDirection(String),
+    #[error("amount {0} is below the minimum trade amount of the instrument")]
+    AmountTooSmall(f64),
+    #[error("amount {0} is not a finite number")]
+    InvalidAmount(f64),
+    #[error("price {0} is not a positive finite number")]
+    InvalidPrice(f64),
+    #[error("post-only orders need a limit price")]
+    PostOnlyWithoutPrice,
+    #[error("post-only orders must be able to rest in the book")]
+    PostOnlyImmediate,
+    #[error("a reduce-only order of {amount} would increase the position of {position}")]
+    ReduceOnlyIncreases { amount: f64, position: f64 },
+    #[error("the take-profit must be above the stop-loss of a long, and below that of a short")]
+    ExitsReversed,
+    #[error("secondary orders can't trigger orders of their own")]
+    NestedLinkedOrders,
+    #[error("spot pairs leave no position for a reduce-only order to reduce")]
+    ReduceOnlySpot,
+}
+
+/// An order checked by `OrderBuilder::build`, ready to be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderRequest {
+    Buy(PrivateBuyRequest),
+    Sell(PrivateSellRequest),
+}
+
+/// A market, limit or stop order, built against the instrument it trades with `build`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBuilder {
+    direction: Direction,
+    r#type: OrderTypeParam,
+    amount: f64,
+    price: Option<f64>,
+    trigger_price: Option<f64>,
+    trigger: Option<Trigger>,
+    label: Option<String>,
+    time_in_force: Option<TimeInForceParam>,
+    post_only: bool,
+    reduce_only: bool,
+    mmp: bool,
+    position: Option<f64>,
+    linked_order_type: Option<LinkedOrderType>,
+    trigger_fill_condition: Option<TriggerFillConditionParam>,
+    secondary: Vec<OrderBuilder>,
+}
+
+impl OrderBuilder {
+    fn new(direction: Direction, r#type: OrderTypeParam, amount: f64) -> Self {
+        Self {
+            direction,
+            r#type,
+            amount,
+            price: None,
+            trigger_price: None,
+            trigger: None,
+            label: None,
+            time_in_force: None,
+            post_only: false,
+            reduce_only: false,
+            mmp: false,
+            position: None,
+            linked_order_type: None,
+            trigger_fill_condition: None,
+            secondary: Vec::new(),
+        }
+    }
+
+    /// Trades `amount` right away at the best prices in the book.
+    pub fn market(direction: Direction, amount: f64) -> Self {
+        Self::new(direction, OrderTypeParam::Market, amount)
+    }
+
+    /// Trades `amount` at `price` or better, resting in the book until filled.
+    pub fn limit(direction: Direction, amount: f64, price: f64) -> Self {
+        Self {
+            price: Some(price),
+            ..Self::new(direction, OrderTypeParam::Limit, amount)
+        }
+    }
+
+    /// A limit order at `price` placed once the `trigger` price reaches
+    /// `trigger_price`.
+    pub fn stop_limit(
+        direction: Direction,
+        amount: f64,
+        price: f64,
+        trigger_price: f64,
+        trigger: Trigger,
+    ) -> Self {
+        Self {
+            price: Some(price),
+            trigger_price: Some(trigger_price),
+            trigger: Some(trigger),
+            ..Self::new(direction, OrderTypeParam::StopLimit, amount)
+        }
+    }
+
+    /// A market order placed once the `trigger` price reaches `trigger_price`.
+    pub fn stop_market(
+        direction: Direction,
+        amount: f64,
+        trigger_price: f64,
+        trigger: Trigger,
+    ) -> Self {
+        Self {
+            trigger_price: Some(trigger_price),
+            trigger: Some(trigger),
+            ..Self::new(direction, OrderTypeParam::StopMarket, amount)
+        }
+    }
+
+    /// Names the order, e.g. for the strategy that placed it. Required.
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn time_in_force(mut self, time_in_force: TimeInForceParam) -> Self {
+        self.time_in_force = Some(time_in_force);
+        self
+    }
+
+    /// Only adds liquidity: the order is repriced rather than taking from the book.
+    pub fn post_only(mut self) -> Self {
+        self.post_only = true;
+        self
+    }
+
+    /// Only reduces the position. With `position`, checked against it.
+    pub fn reduce_only(mut self) -> Self {
+        self.reduce_only = true;
+        self
+    }
+
+    /// Counts the order against market maker protection, see `DeribitClient::set_mmp_limits`.
+    /// Limit orders only.
+    pub fn mmp(mut self) -> Self {
+        self.mmp = true;
+        self
+    }
+
+    /// The current position in the instrument, negative when short, to check a
+    /// reduce-only order against.
+    pub fn position(mut self, size: f64) -> Self {
+        self.position = Some(size);
+        self
+    }
+
+    /// Places `secondary` on the same instrument once this order fills. Can be called
+    /// again to trigger several orders.
+    pub fn triggers(mut self, secondary: OrderBuilder) -> Self {
+        self.linked_order_type = Some(LinkedOrderType::OneTriggersOther);
+        self.secondary.push(secondary);
+        self
+    }
+
+    /// Places `first` and `second` on the same instrument once this order fills, the one
+    /// cancelling the other when it fills, e.g. a take-profit and a stop-loss.
+    pub fn triggers_oco(mut self, first: OrderBuilder, second: OrderBuilder) -> Self {
+        self.linked_order_type = Some(LinkedOrderType::OneTriggersOneCancelsOther);
+        self.secondary = vec![first, second];
+        self
+    }
+
+    /// How much of this order must fill before its secondary orders are placed. The
+    /// exchange's default is `FirstHit`.
+    pub fn trigger_fill_condition(mut self, condition: TriggerFillConditionParam) -> Self {
+        self.trigger_fill_condition = Some(condition);
+        self
+    }
+
+    /// Checks the order and rounds it to the increments of `instrument`: the amount down
+    /// to a multiple of the minimum, the limit price away from the market and the
+    /// trigger price to the nearest tick. Secondary orders are checked alike.
+    pub fn build(
+        &self,
+        instrument: &Instrument,
+    ) -> std::result::Result<OrderRequest, InvalidOrder> {
+        let order = self.checked(instrument)?;
+        let otoco_config = self
+            .secondary
+            .iter()
+            .map(|secondary| {
+                if secondary.secondary.is_empty() {
+                    secondary.checked(instrument)
+                } else {
+                    Err(InvalidOrder::NestedLinkedOrders)
+                }
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let otoco_config = (!otoco_config.is_empty()).then_some(otoco_config);
+        macro_rules! request {
+            ($request:ident) => {
+                $request {
+                    instrument_name: instrument.instrument_name.clone(),
+                    amount: order.amount,
+                    r#type: order.r#type,
+                    label: order.label,
+                    price: order.price,
+                    time_in_force: order.time_in_force,
+                    post_only: order.post_only,
+                    reduce_only: order.reduce_only,
+                    trigger_price: order.trigger_price,
+                    trigger: order.trigger,
+                    mmp: self.mmp.then_some(true),
+                    linked_order_type: self.linked_order_type.clone(),
+                    trigger_fill_condition: self.trigger_fill_condition.clone(),
+                    otoco_config,
+                    ..Default::default()
+                }
+            };
+        }
+        Ok(match order.direction {
+            Direction::Sell => OrderRequest::Sell(request!(PrivateSellRequest)),
+            _ => OrderRequest::Buy(request!(PrivateBuyRequest)),
+        })
+    }
+
+    // The checked and rounded parameters of this order alone
+    fn checked(&self, instrument: &Instrument) -> std::result::Result<OtocoConfig, InvalidOrder> {
+        let label = self.label.clone().ok_or(InvalidOrder::MissingLabel)?;
+        if label.len() > MAX_LABEL_LEN {
+            return Err(InvalidOrder::LabelTooLong);
+        }
+        let selling = match &self.direction {
+            Direction::Buy => false,
+            Direction::Sell => true,
+            Direction::Unknown(direction) => {
+                return Err(InvalidOrder::UnknownDirection(direction.clone()));
+            }
+        };
+        let amount = instrument.round_amount_to_contract(self.amount);
+        if amount <= 0.0 {
+            return Err(InvalidOrder::AmountTooSmall(self.amount));
+        }
+        let price = self
+            .price
+            .map(|price| {
+                if price > 0.0 {
+                    Ok(instrument.round_price_passive(price, &self.direction))
+                } else {
+                    Err(InvalidOrder::InvalidPrice(price))
+                }
+            })
+            .transpose()?;
+        let trigger_price = self
+            .trigger_price
+            .map(|price| {
+                if price > 0.0 {
+                    Ok(instrument.round_price_to_tick(price))
+                } else {
+                    Err(InvalidOrder::InvalidPrice(price))
+                }
+            })
+            .transpose()?;
+        if self.post_only {
+            if price.is_none() {
+                return Err(InvalidOrder::PostOnlyWithoutPrice);
+            }
+            if matches!(
+                self.time_in_force,
+                Some(TimeInForceParam::ImmediateOrCancel | TimeInForceParam::FillOrKill)
+            ) {
+                return Err(InvalidOrder::PostOnlyImmediate);
+            }
+        }
+        if self.reduce_only && instrument.kind == Kind::Spot {
+            return Err(InvalidOrder::ReduceOnlySpot);
+        }
+        if let Some(position) = self.position.filter(|_| self.reduce_only) {
+            let reducible = if selling { position } else { -position };
+            if amount > reducible {
+                return Err(InvalidOrder::ReduceOnlyIncreases { amount, position });
+            }
+        }
+        let price_money = |price: f64| money(price).map_err(|_| InvalidOrder::InvalidPrice(price));
+        Ok(OtocoConfig {
+            amount: Some(money(amount).map_err(|_| InvalidOrder::InvalidAmount(self.amount))?),
+            direction: self.direction.clone(),
+            r#type: Some(self.r#type.clone()),
+            label: Some(label),
+            price: price.map(price_money).transpose()?,
+            time_in_force: self.time_in_force.clone(),
+            post_only: self.post_only.then_some(true),
+            reduce_only: self.reduce_only.then_some(true),
+            trigger_price: trigger_price.map(price_money).transpose()?,
+            trigger: self.trigger.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+impl OrderManager {
+    /// Places an order built with `OrderBuilder`.
+    pub async fn place(&self, order: OrderRequest) -> Result<PrivateBuyAndSellResponse> {
+        match order {
+            OrderRequest::Buy(request) => self.buy(request).await,
+            OrderRequest::Sell(request) => self.sell(request).await,
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Placing orders and following them until they are done, see `OrderManager`.
+//!
+//! The response to `private/buy`, `private/sell`, `private/edit` and `private/cancel`
+//! holds the order as the matching engine left it, and the
+//! `user.orders.{kind}.{currency}.raw` channel sends it again each time it changes. The
+//! two race: a fill can be notified before the response that placed the order arrives,
+//! so the newest state by `last_update_timestamp` wins. The trades of each order come on
+//! `user.trades.{kind}.{currency}.raw`, see `OrderManager::fills`.
+//!
+//! Secondary orders of a linked order (`otoco_config`) are created by the exchange, so
+//! they are only ever notified. They are tracked along with their primary, known by its
+//! `oto_order_ids` or by their `primary_order_id`. So is the order a stop creates when
+//! it triggers, known by its `trigger_order_id`: the stop itself stays `triggered` and
+//! the fill comes on the new order, see `OrderManager::triggered_order`.
+
+use crate::{
+    CurrencyWithAny, DeribitClient, KindWithComboAll, Order, OrderState, OrderStateInUserTrade,
+    PrivateBuyAndSellResponse, PrivateBuyRequest, PrivateCancelRequest, PrivateEditRequest,
+    PrivateEditResponse, PrivateSellRequest, Result, SubscriptionInterval,
+    UserOrdersKindCurrencyRawChannel, UserTrade, UserTradesKindCurrencyChannel, money_f64,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+// Notifications kept for orders whose placing response hasn't arrived yet
+const EARLY_CAPACITY: usize = 256;
+
+impl Order {
+    /// Whether the order is filled, cancelled or rejected, so it won't change again.
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self.order_state,
+            OrderState::Filled | OrderState::Cancelled | OrderState::Rejected
+        )
+    }
+}
+
+/// A trade of an order, yielded by `OrderManager::fills`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    pub trade: UserTrade,
+    /// Amount of the order filled so far, this trade included, out of the trades the
+    /// stream has seen.
+    pub filled_amount: f64,
+    /// Amount-weighted average price of those trades.
+    pub average_price: f64,
+    /// The order as last known, if it was placed through the manager.
+    pub order: Option<Order>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    orders: HashMap<String, Order>,
+    early: VecDeque<Order>,
+    // Amount and amount times price filled so far by order ID
+    filled: HashMap<String, (f64, f64)>,
+}
+
+impl State {
+    // Records `order` if it is newer than the one known, returning whether it was
+    fn update(&mut self, order: Order) -> bool {
+        match self.orders.get(&order.order_id) {
+            Some(known) if known.last_update_timestamp > order.last_update_timestamp => false,
+            Some(known) if *known == order => false,
+            _ => {
+                self.orders.insert(order.order_id.clone(), order);
+                true
+            }
+        }
+    }
+
+    // Whether `order` is a secondary order of a tracked one
+    fn is_secondary(&self, order: &Order) -> bool {
+        order
+            .primary_order_id
+            .as_ref()
+            .is_some_and(|primary| self.orders.contains_key(primary))
+            || self.orders.values().any(|primary| {
+                primary
+                    .oto_order_ids
+                    .as_ref()
+                    .is_some_and(|ids| ids.contains(&order.order_id))
+            })
+    }
+
+    // Whether `order` was created by a tracked stop triggering
+    fn is_triggered(&self, order: &Order) -> bool {
+        order
+            .trigger_order_id
+            .as_ref()
+            .is_some_and(|stop| self.orders.contains_key(stop))
+    }
+
+    fn is_tracked(&self, order: &Order) -> bool {
+        self.orders.contains_key(&order.order_id)
+            || self.is_secondary(order)
+            || self.is_triggered(order)
+    }
+
+    // Records an order placed through the manager, with whatever was notified about it
+    // or its secondary and triggered orders before the response arrived, returning their
+    // latest state
+    fn placed(&mut self, order: Order) -> Vec<Order> {
+        let order_id = order.order_id.clone();
+        let mut changes = Vec::new();
+        if self.update(order) {
+            changes.push(self.orders[&order_id].clone());
+        }
+        let (early, rest) = std::mem::take(&mut self.early)
+            .into_iter()
+            .partition::<Vec<_>, _>(|early| {
+                early.order_id == order_id || self.is_secondary(early) || self.is_triggered(early)
+            });
+        self.early = rest.into();
+        for order in early {
+            let order_id = order.order_id.clone();
+            if self.update(order) {
+                changes.push(self.orders[&order_id].clone());
+            }
+        }
+        changes
+    }
+
+    fn filled(&mut self, trade: UserTrade) -> Fill {
+        let (amount, notional) = self.filled.entry(trade.order_id.clone()).or_default();
+        *amount += money_f64(trade.amount);
+        *notional += money_f64(trade.amount) * money_f64(trade.price);
+        let (filled_amount, average_price) = (*amount, *notional / *amount);
+        // The order won't trade again
+        if trade.state != OrderStateInUserTrade::Open {
+            self.filled.remove(&trade.order_id);
+        }
+        Fill {
+            order: self.orders.get(&trade.order_id).cloned(),
+            trade,
+            filled_amount,
+            average_price,
+        }
+    }
+
+    fn notified(&mut self, order: Order) -> Option<Order> {
+        if !self.is_tracked(&order) {
+            if self.early.len() == EARLY_CAPACITY {
+                self.early.pop_front();
+            }
+            self.early.push_back(order);
+            return None;
+        }
+        let order_id = order.order_id.clone();
+        self.update(order).then(|| self.orders[&order_id].clone())
+    }
+}
+
+/// Places orders and keeps the latest state of each, from the responses and from
+/// `user.orders`. Only orders placed through the manager are tracked. Clones share the
+/// orders, so a tracking task can keep them current while others place orders and read
+/// them.
+#[derive(Debug, Clone)]
+pub struct OrderManager {
+    pub(crate) client: Arc<DeribitClient>,
+    state: Arc<Mutex<State>>,
+    changes: broadcast::Sender<Order>,
+}
+
+impl OrderManager {
+    pub fn new(client: Arc<DeribitClient>) -> Self {
+        Self {
+            client,
+            state: Arc::default(),
+            changes: broadcast::channel(1024).0,
+        }
+    }
+
+    pub(crate) fn placed(&self, order: Order) {
+        for order in self.state.lock().unwrap().placed(order) {
+            let _ = self.changes.send(order);
+        }
+    }
+
+    // Applies a `user.orders` notification, returning the order if a tracked one changed
+    pub(crate) fn notified(&self, order: Order) -> Option<Order> {
+        let change = self.state.lock().unwrap().notified(order);
+        if let Some(order) = &change {
+            let _ = self.changes.send(order.clone());
+        }
+        change
+    }
+
+    pub async fn buy(&self, request: PrivateBuyRequest) -> Result<PrivateBuyAndSellResponse> {
+        let response = self.client.call(request).await?;
+        self.placed(response.order.clone());
+        Ok(response)
+    }
+
+    pub async fn sell(&self, request: PrivateSellRequest) -> Result<PrivateBuyAndSellResponse> {
+        let response = self.client.call(request).await?;
+        self.placed(response.order.clone());
+        Ok(response)
+    }
+
+    pub async fn edit(&self, request: PrivateEditRequest) -> Result<PrivateEditResponse> {
+        let response = self.client.call(request).await?;
+        self.placed(response.order.clone());
+        Ok(response)
+    }
+
+    pub async fn cancel(&self, order_id: &str) -> Result<Order> {
+        let order = self
+            .client
+            .call(PrivateCancelRequest {
+                order_id: order_id.to_string(),
+            })
+            .await?;
+        self.placed(order.clone());
+        Ok(order)
+    }
+
+    /// The latest known state of `order_id`.
+    pub fn order(&self, order_id: &str) -> Option<Order> {
+        self.state.lock().unwrap().orders.get(order_id).cloned()
+    }
+
+    /// The secondary orders `order_id` triggers, as last known, once they are notified.
+    /// They wait, e.g. as `untriggered`, until enough of their primary fills.
+    pub fn secondary_orders(&self, order_id: &str) -> Vec<Order> {
+        let state = self.state.lock().unwrap();
+        let mut ids = state
+            .orders
+            .get(order_id)
+            .and_then(|primary| primary.oto_order_ids.clone())
+            .unwrap_or_default();
+        let mut notified: Vec<_> = state
+            .orders
+            .values()
+            .filter(|order| order.primary_order_id.as_deref() == Some(order_id))
+            .filter(|order| !ids.contains(&order.order_id))
+            .map(|order| order.order_id.clone())
+            .collect();
+        notified.sort();
+        ids.extend(notified);
+        ids.iter()
+            .filter_map(|id| state.orders.get(id).cloned())
+            .collect()
+    }
+
+    /// The order the stop `order_id` created when it triggered, as last known, once it is
+    /// notified. The stop stays `triggered` and the new order is the one that fills.
+    pub fn triggered_order(&self, order_id: &str) -> Option<Order> {
+        self.state
+            .lock()
+            .unwrap()
+            .orders
+            .values()
+            .filter(|order| order.trigger_order_id.as_deref() == Some(order_id))
+            .max_by_key(|order| order.creation_timestamp)
+            .cloned()
+    }
+
+    // The latest state of `order_id`, or once it triggered, of the order it created
+    pub(crate) fn through_trigger(&self, order_id: &str) -> Option<Order> {
+        let order = self.order(order_id)?;
+        if order.order_state != OrderState::Triggered {
+            return Some(order);
+        }
+        self.triggered_order(order_id).or(Some(order))
+    }
+
+    /// Every tracked order, done ones included.
+    pub fn orders(&self) -> Vec<Order> {
+        self.state
+            .lock()
+            .unwrap()
+            .orders
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Tracked orders that may still change, i.e. open or waiting for their trigger.
+    pub fn open_orders(&self) -> Vec<Order> {
+        self.state
+            .lock()
+            .unwrap()
+            .orders
+            .values()
+            .filter(|order| !order.is_done())
+            .cloned()
+            .collect()
+    }
+
+    /// Stops tracking done orders.
+    pub fn remove_done(&self) {
+        self.state
+            .lock()
+            .unwrap()
+            .orders
+            .retain(|_, order| !order.is_done());
+    }
+
+    /// Every change of a tracked order from now on. Changes are dropped if the stream
+    /// falls more than 1024 behind.
+    pub fn changes(&self) -> impl Stream<Item = Order> + Send + 'static + use<> {
+        BroadcastStream::new(self.changes.subscribe()).filter_map(|order| async { order.ok() })
+    }
+
+    /// The state of `order_id` as it changes, starting with the current one if it is
+    /// tracked, and ending once it is done.
+    pub fn order_changes(
+        &self,
+        order_id: &str,
+    ) -> impl Stream<Item = Order> + Send + 'static + use<> {
+        let changes = self.changes();
+        let current = self.order(order_id);
+        let order_id = order_id.to_string();
+        let changes = futures_util::stream::iter(current)
+            .chain(changes.filter(move |order| std::future::ready(order.order_id == order_id)))
+            .boxed();
+        // Ends as soon as the order is done, not with the next change of another order
+        futures_util::stream::unfold((changes, false), |(mut changes, done)| async move {
+            if done {
+                return None;
+            }
+            let order = changes.next().await?;
+            let done = order.is_done();
+            Some((order, (changes, done)))
+        })
+    }
+
+    /// Keeps the tracked orders of `kind` in `currency` current from `user.orders`,
+    /// yielding each change. The orders are only updated from notifications while the
+    /// stream is polled.
+    pub async fn track(
+        &self,
+        kind: KindWithComboAll,
+        currency: CurrencyWithAny,
+    ) -> Result<impl Stream<Item = Result<Order>> + Send + 'static + use<>> {
+        let notifications = self
+            .client
+            .subscribe(UserOrdersKindCurrencyRawChannel { kind, currency })
+            .await?;
+        let manager = self.clone();
+        Ok(notifications.filter_map(move |order| {
+            let change = match order {
+                Ok(order) => manager.notified(order).map(Ok),
+                Err(e) => Some(Err(e)),
+            };
+            std::future::ready(change)
+        }))
+    }
+
+    /// Yields each trade of the account's orders of `kind` in `currency` from
+    /// `user.trades`, with how much of its order is filled so far and at what average
+    /// price. Fills of orders placed elsewhere are yielded too, without the order.
+    pub async fn fills(
+        &self,
+        kind: KindWithComboAll,
+        currency: CurrencyWithAny,
+    ) -> Result<impl Stream<Item = Result<Fill>> + Send + 'static + use<>> {
+        let trades = self
+            .client
+            .subscribe(UserTradesKindCurrencyChannel {
+                kind,
+                currency,
+                interval: SubscriptionInterval::Raw,
+            })
+            .await?;
+        let state = self.state.clone();
+        Ok(trades
+            .map(move |trades| {
+                let fills = match trades {
+                    Ok(trades) => {
+                        let mut state = state.lock().unwrap();
+                        trades
+                            .into_iter()
+                            .map(|trade| Ok(state.filled(trade)))
+                            .collect()
+                    }
+                    Err(e) => vec![Err(e)],
+                };
+                futures_util::stream::iter(fills)
+            })
+            .flatten())
+    }
+}
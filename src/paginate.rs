@@ -0,0 +1,195 @@
+//! Following paginated methods to the end, see `DeribitClient::paginate`.
+//!
+//! Deribit pages results in three ways: by `count` and `offset` (`OffsetPagination`), by a
+//! `continuation` token returned with each page (`ContinuationPagination` and
+//! `private/get_transaction_log`), and, for trades, by sequence number with a
+//! `has_more` flag. `Paginated` tells for a request type how its next page is requested.
+
+use crate::{
+    AccessLog, ApiRequest, ContinuationPagination, Deposit, DeribitClient, OffsetPagination, Order,
+    ParamGroup, PrivateGetAccessLogRequest, PrivateGetDepositsRequest,
+    PrivateGetOrderHistoryByCurrencyRequest, PrivateGetOrderHistoryByInstrumentRequest,
+    PrivateGetSettlementHistoryByCurrencyRequest, PrivateGetSettlementHistoryByInstrumentRequest,
+    PrivateGetTransactionLogRequest, PrivateGetTransfersRequest,
+    PrivateGetTriggerOrderHistoryRequest, PrivateGetUserTradesByInstrumentRequest,
+    PrivateGetWithdrawalsRequest, PublicGetDeliveryPricesRequest,
+    PublicGetDeliveryPricesResponseData, PublicGetLastTradesByInstrumentRequest, PublicTrade,
+    Result, Settlement, Sorting, TransactionLog, TransferItem, TriggerOrderHistoryRecord,
+    UserTrade, Withdrawal,
+};
+use futures_util::Stream;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// A request whose results come in pages.
+pub trait Paginated: ApiRequest + Clone + Send + Sync + 'static {
+    type Item: Send + 'static;
+
+    /// Takes the items out of `page`, the response to `self`, and turns `self` into the
+    /// request for the page after it. Returns `false` as the second value if `page` was
+    /// the last one.
+    fn next_page(&mut self, page: Self::Response) -> (Vec<Self::Item>, bool);
+}
+
+// `count` and `offset`: a page shorter than asked for is the last
+fn next_offset<R: ParamGroup<OffsetPagination>, T>(
+    request: &mut R,
+    items: Vec<T>,
+) -> (Vec<T>, bool) {
+    let pagination = request.group_mut();
+    let more = match pagination.count {
+        Some(count) => items.len() as i64 >= count,
+        None => !items.is_empty(),
+    };
+    pagination.offset = Some(pagination.offset.unwrap_or_default() + items.len() as i64);
+    (items, more)
+}
+
+// A `continuation` token, `none` or missing on the last page
+fn next_continuation<R: ParamGroup<ContinuationPagination>, T>(
+    request: &mut R,
+    items: Vec<T>,
+    continuation: Option<String>,
+) -> (Vec<T>, bool) {
+    let continuation = continuation.filter(|c| !c.is_empty() && c != "none");
+    let more = continuation.is_some() && !items.is_empty();
+    request.group_mut().continuation = continuation;
+    (items, more)
+}
+
+// The sequence number after the last of a page in the direction of `sorting`
+fn next_seq(
+    sorting: &Option<Sorting>,
+    start_seq: &mut Option<i64>,
+    end_seq: &mut Option<i64>,
+    last_seq: Option<i64>,
+) -> bool {
+    let Some(last_seq) = last_seq else {
+        return false;
+    };
+    match sorting {
+        Some(Sorting::Asc) => *start_seq = Some(last_seq + 1),
+        _ => *end_seq = Some(last_seq - 1),
+    }
+    true
+}
+
+macro_rules! offset_paginated {
+    ($($request:ty => $item:ty, |$page:ident| $items:expr;)*) => {
+        $(
+            impl Paginated for $request {
+                type Item = $item;
+
+                fn next_page(&mut self, $page: Self::Response) -> (Vec<$item>, bool) {
+                    next_offset(self, $items)
+                }
+            }
+        )*
+    };
+}
+
+offset_paginated! {
+    PrivateGetOrderHistoryByCurrencyRequest => Order, |page| page;
+    PrivateGetOrderHistoryByInstrumentRequest => Order, |page| page;
+    PrivateGetAccessLogRequest => AccessLog, |page| page;
+    PrivateGetDepositsRequest => Deposit, |page| page.data;
+    PrivateGetWithdrawalsRequest => Withdrawal, |page| page.data;
+    PublicGetDeliveryPricesRequest => PublicGetDeliveryPricesResponseData, |page| page.data;
+    PrivateGetTransfersRequest => TransferItem, |page| page.data;
+}
+
+impl Paginated for PrivateGetSettlementHistoryByCurrencyRequest {
+    type Item = Settlement;
+
+    fn next_page(&mut self, page: Self::Response) -> (Vec<Settlement>, bool) {
+        next_continuation(self, page.settlements, Some(page.continuation))
+    }
+}
+
+impl Paginated for PrivateGetSettlementHistoryByInstrumentRequest {
+    type Item = Settlement;
+
+    fn next_page(&mut self, page: Self::Response) -> (Vec<Settlement>, bool) {
+        next_continuation(self, page.settlements, Some(page.continuation))
+    }
+}
+
+impl Paginated for PrivateGetTriggerOrderHistoryRequest {
+    type Item = TriggerOrderHistoryRecord;
+
+    fn next_page(&mut self, page: Self::Response) -> (Vec<TriggerOrderHistoryRecord>, bool) {
+        next_continuation(self, page.entries.unwrap_or_default(), page.continuation)
+    }
+}
+
+impl Paginated for PrivateGetTransactionLogRequest {
+    type Item = TransactionLog;
+
+    fn next_page(&mut self, page: Self::Response) -> (Vec<TransactionLog>, bool) {
+        // Zero (or null) on the last page
+        let more = page.continuation > 0 && !page.logs.is_empty();
+        self.continuation = more.then_some(page.continuation);
+        (page.logs, more)
+    }
+}
+
+impl Paginated for PublicGetLastTradesByInstrumentRequest {
+    type Item = PublicTrade;
+
+    fn next_page(&mut self, page: Self::Response) -> (Vec<PublicTrade>, bool) {
+        let last_seq = page.trades.last().map(|trade| trade.trade_seq);
+        let more = page.has_more
+            && next_seq(
+                &self.sorting,
+                &mut self.start_seq,
+                &mut self.end_seq,
+                last_seq,
+            );
+        (page.trades, more)
+    }
+}
+
+impl Paginated for PrivateGetUserTradesByInstrumentRequest {
+    type Item = UserTrade;
+
+    fn next_page(&mut self, page: Self::Response) -> (Vec<UserTrade>, bool) {
+        let last_seq = page.trades.last().map(|trade| trade.trade_seq);
+        let more = page.has_more
+            && next_seq(
+                &self.sorting,
+                &mut self.start_seq,
+                &mut self.end_seq,
+                last_seq,
+            );
+        (page.trades, more)
+    }
+}
+
+impl DeribitClient {
+    /// Yields the items of every page of `request`, requesting the next page once the
+    /// items of the previous one are consumed. An error ends the stream.
+    pub fn paginate<R: Paginated>(
+        self: &Arc<Self>,
+        request: R,
+    ) -> impl Stream<Item = Result<R::Item>> + Send + 'static + use<R> {
+        futures_util::stream::unfold(
+            (self.clone(), Some(request), VecDeque::new()),
+            |(client, mut request, mut items)| async move {
+                loop {
+                    if let Some(item) = items.pop_front() {
+                        return Some((Ok(item), (client, request, items)));
+                    }
+                    let mut next = request.take()?;
+                    match client.call(next.clone()).await {
+                        Ok(page) => {
+                            let (page, more) = next.next_page(page);
+                            items.extend(page);
+                            request = more.then_some(next);
+                        }
+                        Err(e) => return Some((Err(e), (client, None, items))),
+                    }
+                }
+            },
+        )
+    }
+}
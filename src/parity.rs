@@ -0,0 +1,34 @@
+//! Differences between the production and testnet API specs, enabled with the `testnet`
+//! feature.
+//!
+//! Request types of methods missing from the other environment say so in their docs and
+//! set `ApiRequest::EXCLUSIVE_TO`. With `parity-warnings` as well, those methods are
+//! listed as build warnings.
+
+/// Methods and enum values present in only one of the specs the client was built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecDiff {
+    pub prod_only_methods: &'static [&'static str],
+    pub testnet_only_methods: &'static [&'static str],
+    /// Values as (enum, value) pairs. Enums generated for only one spec list all their
+    /// values.
+    pub prod_only_enum_values: &'static [(&'static str, &'static str)],
+    pub testnet_only_enum_values: &'static [(&'static str, &'static str)],
+}
+
+/// How the specs differed at build time.
+pub const SPEC_DIFF: SpecDiff = include!(concat!(env!("OUT_DIR"), "/deribit_spec_diff.rs"));
+
+impl SpecDiff {
+    pub fn is_empty(&self) -> bool {
+        self.prod_only_methods.is_empty()
+            && self.testnet_only_methods.is_empty()
+            && self.prod_only_enum_values.is_empty()
+            && self.testnet_only_enum_values.is_empty()
+    }
+
+    /// Whether `method` (e.g. `private/buy`) exists in neither or both environments.
+    pub fn is_environment_generic(&self, method: &str) -> bool {
+        !self.prod_only_methods.contains(&method) && !self.testnet_only_methods.contains(&method)
+    }
+}
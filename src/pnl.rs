@@ -0,0 +1,264 @@
+//! Profit and loss of inverse and linear instruments, see `PnlLedger`.
+//!
+//! Inverse futures, e.g. `BTC-PERPETUAL`, are sized in USD and settle in the base
+//! currency, so their profit is `size * (1 / entry - 1 / exit)` BTC. Linear futures and
+//! all options are sized in the base currency and settle in the currency they're priced
+//! in, so their profit is `size * (exit - entry)`.
+//!
+//! Deribit splits profit and loss into sessions that end at the daily settlement, when
+//! the session's profit is paid into the balance and the settlement price becomes the
+//! entry price for the next session. `user.portfolio` reports the session figures as
+//! `session_upl` and `session_rpl`.
+//!
+//! Everything is computed in `Money`, so with the `decimal` feature profits add up to
+//! the exact amounts Deribit books.
+
+use crate::{Direction, InstrumentName, Money, Position, UserTrade, money};
+use std::iter::Sum;
+use std::ops::Add;
+
+/// How profit and loss of an instrument is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContractType {
+    /// Sized in USD and settled in the base currency.
+    Inverse,
+    /// Sized in the base currency and settled in the currency it's priced in.
+    Linear,
+}
+
+impl ContractType {
+    pub fn of(instrument: &InstrumentName) -> Self {
+        if instrument.kind == crate::Kind::Future && !instrument.is_linear() {
+            Self::Inverse
+        } else {
+            Self::Linear
+        }
+    }
+
+    /// Profit of `size` (negative when short) entered at `entry` and valued at `exit`, in
+    /// the settlement currency.
+    pub fn pnl(self, size: Money, entry: Money, exit: Money) -> Money {
+        let zero = Money::default();
+        match self {
+            Self::Inverse if entry > zero && exit > zero => size * (exit - entry) / (entry * exit),
+            Self::Inverse => zero,
+            Self::Linear => size * (exit - entry),
+        }
+    }
+
+    // Entry price of `size` at `price` added to `added` at `added_price`, on the same side.
+    // `added_price` is positive; a position without a price takes it.
+    fn blend(self, size: Money, price: Money, added: Money, added_price: Money) -> Money {
+        match self {
+            _ if price <= Money::default() => added_price,
+            Self::Inverse => {
+                (size + added) * price * added_price / (size * added_price + added * price)
+            }
+            Self::Linear => (size * price + added * added_price) / (size + added),
+        }
+    }
+}
+
+/// An amount of profit or loss in the base currency and in USD.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pnl {
+    pub base: Money,
+    pub usd: Money,
+}
+
+impl Pnl {
+    /// `amount` in the settlement currency of `instrument`, converted at `index_price`
+    /// (USD per unit of the base currency). Stablecoins count as USD.
+    pub fn new(instrument: &InstrumentName, amount: Money, index_price: Money) -> Self {
+        if instrument.settlement_currency() == instrument.currency {
+            Self {
+                base: amount,
+                usd: amount * index_price,
+            }
+        } else {
+            Self {
+                base: if index_price > Money::default() {
+                    amount / index_price
+                } else {
+                    Money::default()
+                },
+                usd: amount,
+            }
+        }
+    }
+}
+
+impl Add for Pnl {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            base: self.base + other.base,
+            usd: self.usd + other.usd,
+        }
+    }
+}
+
+impl Sum for Pnl {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// Profit and loss of a position as Deribit reports it, e.g. one kept by
+/// `PositionTracker`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PositionPnl {
+    /// Realized this session.
+    pub realized: Pnl,
+    /// Of the open position against its average price.
+    pub unrealized: Pnl,
+    /// Of the open position since the last settlement.
+    pub session_unrealized: Pnl,
+}
+
+impl PositionPnl {
+    /// `None` for positions in instruments that aren't futures, options or spot pairs,
+    /// e.g. combos, and for figures that aren't finite numbers.
+    pub fn of(position: &Position) -> Option<Self> {
+        let instrument = position.instrument_name.parse::<InstrumentName>().ok()?;
+        let pnl = |amount| Pnl::new(&instrument, amount, position.index_price);
+        let unrealized = ContractType::of(&instrument).pnl(
+            money(position.size).ok()?,
+            position.average_price,
+            position.mark_price,
+        );
+        Some(Self {
+            realized: pnl(money(position.realized_profit_loss.unwrap_or_default()).ok()?),
+            unrealized: pnl(unrealized),
+            session_unrealized: pnl(money(position.floating_profit_loss).ok()?),
+        })
+    }
+}
+
+/// Follows the position in one instrument through its trades, accounting profit and
+/// loss since the position was opened and in the current session. Amounts are in the
+/// settlement currency; fees aren't included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PnlLedger {
+    contract: ContractType,
+    size: Money,
+    average_price: Money,
+    // Entry price for the session: the settlement price, blended with trades since
+    session_price: Money,
+    realized: Money,
+    session_realized: Money,
+}
+
+impl PnlLedger {
+    pub fn new(contract: ContractType) -> Self {
+        Self {
+            contract,
+            size: Money::default(),
+            average_price: Money::default(),
+            session_price: Money::default(),
+            realized: Money::default(),
+            session_realized: Money::default(),
+        }
+    }
+
+    /// Starts from an open position, e.g. one from `private/get_positions`, with no
+    /// realized profit yet. `session_price` is the last settlement price if the position
+    /// was opened before it, its average price otherwise.
+    pub fn with_position(
+        contract: ContractType,
+        size: Money,
+        average_price: Money,
+        session_price: Money,
+    ) -> Self {
+        Self {
+            size,
+            average_price,
+            session_price,
+            ..Self::new(contract)
+        }
+    }
+
+    /// Signed size, negative when short.
+    pub fn size(&self) -> Money {
+        self.size
+    }
+
+    pub fn average_price(&self) -> Money {
+        self.average_price
+    }
+
+    /// Profit realized since the ledger started.
+    pub fn realized(&self) -> Money {
+        self.realized
+    }
+
+    /// Profit realized since the last settlement.
+    pub fn session_realized(&self) -> Money {
+        self.session_realized
+    }
+
+    /// Profit of the open position at `mark_price` against its average price.
+    pub fn unrealized(&self, mark_price: Money) -> Money {
+        self.contract.pnl(self.size, self.average_price, mark_price)
+    }
+
+    /// Profit of the open position at `mark_price` since the last settlement.
+    pub fn session_unrealized(&self, mark_price: Money) -> Money {
+        self.contract.pnl(self.size, self.session_price, mark_price)
+    }
+
+    /// Applies a trade of `amount` at `price`, returning the profit it realized.
+    pub fn trade(&mut self, direction: &Direction, amount: Money, price: Money) -> Money {
+        let zero = Money::default();
+        let amount = match direction {
+            Direction::Sell => -amount,
+            _ => amount,
+        };
+        if amount == zero || price <= zero {
+            return zero;
+        }
+        if self.size == zero || (self.size > zero) == (amount > zero) {
+            if self.size == zero {
+                (self.average_price, self.session_price) = (price, price);
+            } else {
+                self.average_price =
+                    self.contract
+                        .blend(self.size, self.average_price, amount, price);
+                self.session_price =
+                    self.contract
+                        .blend(self.size, self.session_price, amount, price);
+            }
+            self.size += amount;
+            return zero;
+        }
+        // Reduces the position, and opens one on the other side with what is left
+        let closed = if self.size > zero {
+            self.size.min(-amount)
+        } else {
+            self.size.max(-amount)
+        };
+        let realized = self.contract.pnl(closed, self.average_price, price);
+        self.realized += realized;
+        self.session_realized += self.contract.pnl(closed, self.session_price, price);
+        self.size -= closed;
+        let left = amount + closed;
+        if left != zero {
+            (self.size, self.average_price, self.session_price) = (left, price, price);
+        } else if self.size == zero {
+            (self.average_price, self.session_price) = (zero, zero);
+        }
+        realized
+    }
+
+    /// Applies one of the account's trades, see `trade`.
+    pub fn apply(&mut self, trade: &UserTrade) -> Money {
+        self.trade(&trade.direction, trade.amount, trade.price)
+    }
+
+    /// Starts a new session at `settlement_price`, as Deribit does daily.
+    pub fn settle(&mut self, settlement_price: Money) {
+        self.session_price = settlement_price;
+        self.session_realized = Money::default();
+    }
+}
@@ -0,0 +1,178 @@
+//! The account's positions kept current from its trades, see `PositionTracker`.
+//!
+//! Positions are fetched with `private/get_positions` and then replaced by those sent on
+//! the `user.changes.{kind}.{currency}.raw` channel, which carries the position of an
+//! instrument after each of its trades and order changes.
+
+use crate::{
+    CurrencyWithAny, DeribitClient, Kind, KindWithComboAll, Money, Position, PositionDirection,
+    PositionPnl, PositionWithElp, PrivateGetPositionsRequest, Result, SubscriptionInterval,
+    UserChange, UserChangesKindCurrencyChannel, UserTrade, money,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A changed position, yielded by `PositionTracker::track`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionUpdate {
+    pub position: Position,
+    /// The trades that changed it, empty if only its orders changed.
+    pub trades: Vec<UserTrade>,
+}
+
+/// Keeps the positions of the account by instrument, including those closed this session
+/// so their realized profit and loss stays available. Clones share the positions, so a
+/// tracking task can keep them current while others read them.
+///
+/// Deribit only sends a position when it changes, so its mark price and floating profit
+/// and loss are as of the latest trade or order change.
+#[derive(Debug, Clone, Default)]
+pub struct PositionTracker {
+    positions: Arc<Mutex<BTreeMap<String, Position>>>,
+}
+
+impl PositionTracker {
+    pub fn position(&self, instrument_name: &str) -> Option<Position> {
+        self.positions.lock().unwrap().get(instrument_name).cloned()
+    }
+
+    /// Every tracked position by instrument name, closed ones included.
+    pub fn positions(&self) -> Vec<Position> {
+        self.positions.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Tracked positions that aren't flat.
+    pub fn open_positions(&self) -> Vec<Position> {
+        self.positions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|position| position.direction != PositionDirection::Zero)
+            .cloned()
+            .collect()
+    }
+
+    /// Average entry price of `instrument_name`, if it has an open position.
+    pub fn average_price(&self, instrument_name: &str) -> Option<Money> {
+        self.position(instrument_name)
+            .filter(|position| position.direction != PositionDirection::Zero)
+            .map(|position| position.average_price)
+    }
+
+    /// Profit and loss realized this session on `instrument_name`, in the settlement
+    /// currency. `None` without a position, or if Deribit sent no finite number.
+    pub fn realized_pnl(&self, instrument_name: &str) -> Option<Money> {
+        let position = self.position(instrument_name)?;
+        money(position.realized_profit_loss.unwrap_or_default()).ok()
+    }
+
+    /// Profit and loss of the open position of `instrument_name` at its mark price, in
+    /// the settlement currency. `None` without a position, or if Deribit sent no finite
+    /// number.
+    pub fn unrealized_pnl(&self, instrument_name: &str) -> Option<Money> {
+        money(self.position(instrument_name)?.floating_profit_loss).ok()
+    }
+
+    /// Profit and loss of the position in `instrument_name`, in the base currency and
+    /// USD.
+    pub fn pnl(&self, instrument_name: &str) -> Option<PositionPnl> {
+        PositionPnl::of(&self.position(instrument_name)?)
+    }
+
+    /// Fetches the positions of `kind` (all kinds if `None`) in `currency` and keeps them
+    /// current from `user.changes`, yielding each position that changed. The positions
+    /// are only updated while the stream is polled.
+    pub async fn track(
+        &self,
+        client: &DeribitClient,
+        kind: Option<Kind>,
+        currency: CurrencyWithAny,
+    ) -> Result<impl Stream<Item = Result<PositionUpdate>> + Send + 'static + use<>> {
+        // Subscribe first, so no change is missed between the fetch and the subscription
+        let changes = client
+            .subscribe(UserChangesKindCurrencyChannel {
+                kind: match kind.clone() {
+                    None => KindWithComboAll::Any,
+                    Some(Kind::Future) => KindWithComboAll::Future,
+                    Some(Kind::Option) => KindWithComboAll::Option,
+                    Some(Kind::Spot) => KindWithComboAll::Spot,
+                    Some(Kind::FutureCombo) => KindWithComboAll::FutureCombo,
+                    Some(Kind::OptionCombo) => KindWithComboAll::OptionCombo,
+                    Some(Kind::Unknown(kind)) => KindWithComboAll::Unknown(kind),
+                },
+                currency: currency.clone(),
+                interval: SubscriptionInterval::Raw,
+            })
+            .await?;
+        let positions = client
+            .call(PrivateGetPositionsRequest {
+                currency: Some(currency),
+                kind,
+                subaccount_id: None,
+            })
+            .await?;
+        self.positions.lock().unwrap().extend(
+            positions
+                .into_iter()
+                .map(|position| (position.instrument_name.clone(), from_snapshot(position))),
+        );
+        let positions = self.positions.clone();
+        Ok(changes
+            .map(move |change| {
+                let updates = match change {
+                    Ok(change) => apply(&positions, change).into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                };
+                futures_util::stream::iter(updates)
+            })
+            .flatten())
+    }
+}
+
+fn apply(positions: &Mutex<BTreeMap<String, Position>>, change: UserChange) -> Vec<PositionUpdate> {
+    let trades = change.trades.unwrap_or_default();
+    let mut positions = positions.lock().unwrap();
+    change
+        .position
+        .into_iter()
+        .flatten()
+        .map(|position| {
+            positions.insert(position.instrument_name.clone(), position.clone());
+            let trades = trades
+                .iter()
+                .filter(|trade| trade.instrument_name == position.instrument_name)
+                .cloned()
+                .collect();
+            PositionUpdate { position, trades }
+        })
+        .collect()
+}
+
+fn from_snapshot(position: PositionWithElp) -> Position {
+    Position {
+        average_price: position.average_price,
+        average_price_usd: position.average_price_usd,
+        delta: position.delta,
+        direction: position.direction,
+        floating_profit_loss: position.floating_profit_loss,
+        floating_profit_loss_usd: position.floating_profit_loss_usd,
+        gamma: position.gamma,
+        index_price: position.index_price,
+        initial_margin: position.initial_margin,
+        instrument_name: position.instrument_name,
+        interest_value: position.interest_value,
+        kind: position.kind,
+        leverage: position.leverage,
+        maintenance_margin: position.maintenance_margin,
+        mark_price: position.mark_price,
+        realized_funding: position.realized_funding,
+        realized_profit_loss: position.realized_profit_loss,
+        settlement_price: position.settlement_price,
+        size: position.size,
+        size_currency: position.size_currency,
+        theta: position.theta,
+        total_profit_loss: position.total_profit_loss,
+        vega: position.vega,
+    }
+}
@@ -0,0 +1,392 @@
+//! Writes subscription notifications to Postgres, enabled with the `postgres` feature.
+//!
+//! Rows are buffered and written with one multi-row `INSERT` per batch, so a busy channel
+//! costs a round trip per batch rather than per message. Each table keeps the full
+//! message as JSONB next to the columns most queries filter on.
+
+use crate::integrity::{Digest, Gap};
+use crate::{BookNotification, Order, PublicTrade, Result, Timestamp};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use sqlx::types::Json;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::collections::HashMap;
+use std::pin::pin;
+use std::time::Duration;
+
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS deribit_trades (
+        trade_id TEXT PRIMARY KEY,
+        instrument_name TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        price NUMERIC NOT NULL,
+        amount NUMERIC NOT NULL,
+        direction TEXT NOT NULL,
+        data JSONB NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS deribit_trades_instrument_timestamp
+        ON deribit_trades (instrument_name, timestamp)",
+    "CREATE TABLE IF NOT EXISTS deribit_orders (
+        order_id TEXT NOT NULL,
+        last_update_timestamp BIGINT NOT NULL,
+        instrument_name TEXT,
+        order_state TEXT NOT NULL,
+        data JSONB NOT NULL,
+        PRIMARY KEY (order_id, last_update_timestamp)
+    )",
+    "CREATE TABLE IF NOT EXISTS deribit_book_snapshots (
+        instrument_name TEXT NOT NULL,
+        change_id BIGINT NOT NULL,
+        timestamp BIGINT,
+        bids JSONB NOT NULL,
+        asks JSONB NOT NULL,
+        PRIMARY KEY (instrument_name, change_id)
+    )",
+    "CREATE TABLE IF NOT EXISTS deribit_digests (
+        channel TEXT NOT NULL,
+        start BIGINT NOT NULL,
+        \"end\" BIGINT NOT NULL,
+        count BIGINT NOT NULL,
+        checksum BIGINT NOT NULL,
+        gaps JSONB NOT NULL,
+        PRIMARY KEY (channel, start)
+    )",
+];
+
+// start, end, count, checksum and gaps of a deribit_digests row
+type DigestRow = (i64, i64, i64, i64, Json<Vec<Gap>>);
+
+/// Writes trades, order updates and book snapshots from subscription streams into the
+/// tables created by `migrate`. Rows already stored (e.g. replayed after a reconnect) are
+/// skipped.
+#[derive(Debug, Clone)]
+pub struct PostgresSink {
+    pool: PgPool,
+    batch_size: usize,
+    flush_interval: Duration,
+    skip_stream_errors: bool,
+}
+
+impl PostgresSink {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1),
+            skip_stream_errors: false,
+        }
+    }
+
+    /// Rows per `INSERT`, 500 by default. Postgres allows at most 65535 bind parameters
+    /// per statement, which caps this at 9000.
+    pub fn batch_size(mut self, rows: usize) -> Self {
+        self.batch_size = rows.clamp(1, 9000);
+        self
+    }
+
+    /// Longest time a row waits in a partial batch, one second by default.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Logs errors yielded by the input streams and goes on writing, rather than
+    /// stopping at the first one, e.g. for `DeribitClient::poll_order_books`, which
+    /// yields a failed fetch and polls again. Database errors still stop a writer.
+    pub fn skip_stream_errors(mut self) -> Self {
+        self.skip_stream_errors = true;
+        self
+    }
+
+    /// Creates the tables and indexes if they don't exist yet.
+    pub async fn migrate(&self) -> Result<()> {
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes trades until `trades` ends, e.g. a `trades.*` subscription. Fails on the
+    /// first stream or database error, after writing what was already received, unless
+    /// stream errors are skipped (`skip_stream_errors`).
+    pub async fn write_trades(
+        &self,
+        trades: impl Stream<Item = Result<Vec<PublicTrade>>>,
+    ) -> Result<()> {
+        self.write(flatten(trades), insert_trades).await
+    }
+
+    /// Writes order updates until `orders` ends, e.g. a `user.orders.*` subscription.
+    pub async fn write_orders(&self, orders: impl Stream<Item = Result<Vec<Order>>>) -> Result<()> {
+        self.write(flatten(orders), insert_orders).await
+    }
+
+    /// Writes the newest book of each instrument in `books` every `every`, skipping
+    /// intervals without an update. A conflated subscription
+    /// (`DeribitClient::subscribe_conflated`) avoids decoding updates that are never
+    /// stored; `DeribitClient::poll_order_books` fetches books at the same pace instead.
+    pub async fn write_book_snapshots(
+        &self,
+        books: impl Stream<Item = Result<BookNotification>>,
+        every: Duration,
+    ) -> Result<()> {
+        let mut books = pin!(books);
+        let mut ticker = tokio::time::interval(every);
+        let mut latest = HashMap::new();
+        let mut batch = Vec::new();
+        let result = loop {
+            tokio::select! {
+                book = books.next() => match book {
+                    Some(Ok(book)) => {
+                        latest.insert(book.instrument_name.clone(), book);
+                    }
+                    Some(Err(e)) if self.skip_stream_errors => {
+                        tracing::warn!(error = %e, "book snapshot skipped");
+                    }
+                    Some(Err(e)) => break Err(e),
+                    None => break Ok(()),
+                },
+                _ = ticker.tick() => {
+                    batch.extend(latest.drain().map(|(_, book)| book));
+                    if batch.len() >= self.batch_size {
+                        self.flush(&mut batch, insert_book_snapshots).await?;
+                    }
+                }
+            }
+        };
+        batch.extend(latest.into_values());
+        self.flush(&mut batch, insert_book_snapshots).await?;
+        result
+    }
+
+    /// Writes recording digests until `digests` ends, e.g. the second stream of
+    /// `integrity::digested`. A period recorded again after a restart keeps its first
+    /// digest, so `integrity::verify` flags it.
+    pub async fn write_digests(&self, digests: impl Stream<Item = Digest>) -> Result<()> {
+        self.write(digests.map(Ok), insert_digests).await
+    }
+
+    /// Digests of `channel` starting in `[start, end)`, oldest first.
+    pub async fn load_digests(
+        &self,
+        channel: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<Digest>> {
+        let rows: Vec<DigestRow> = sqlx::query_as(
+            "SELECT start, \"end\", count, checksum, gaps FROM deribit_digests \
+             WHERE channel = $1 AND start >= $2 AND start < $3 ORDER BY start",
+        )
+        .bind(channel)
+        .bind(start.as_millis())
+        .bind(end.as_millis())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(start, end, count, checksum, gaps)| Digest {
+                channel: channel.to_string(),
+                start: Timestamp(start),
+                end: Timestamp(end),
+                count: count as u64,
+                checksum: checksum as u64,
+                gaps: gaps.0,
+            })
+            .collect())
+    }
+
+    /// Stored trades of `instrument_name` with timestamps in `[start, end)`, e.g. to check
+    /// them with `integrity::verify`.
+    pub async fn load_trades(
+        &self,
+        instrument_name: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<PublicTrade>> {
+        let rows: Vec<(Json<PublicTrade>,)> = sqlx::query_as(
+            "SELECT data FROM deribit_trades \
+             WHERE instrument_name = $1 AND timestamp >= $2 AND timestamp < $3",
+        )
+        .bind(instrument_name)
+        .bind(start.as_millis())
+        .bind(end.as_millis())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|(trade,)| trade.0).collect())
+    }
+
+    async fn write<T>(
+        &self,
+        rows: impl Stream<Item = Result<T>>,
+        insert: fn(&mut QueryBuilder<'_, Postgres>, &[T]),
+    ) -> Result<()> {
+        let mut rows = pin!(rows);
+        let mut ticker = tokio::time::interval(self.flush_interval);
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let result = loop {
+            tokio::select! {
+                row = rows.next() => match row {
+                    Some(Ok(row)) => {
+                        batch.push(row);
+                        if batch.len() >= self.batch_size {
+                            self.flush(&mut batch, insert).await?;
+                        }
+                    }
+                    Some(Err(e)) if self.skip_stream_errors => {
+                        tracing::warn!(error = %e, "row skipped");
+                    }
+                    Some(Err(e)) => break Err(e),
+                    None => break Ok(()),
+                },
+                _ = ticker.tick() => self.flush(&mut batch, insert).await?,
+            }
+        };
+        self.flush(&mut batch, insert).await?;
+        result
+    }
+
+    async fn flush<T>(
+        &self,
+        batch: &mut Vec<T>,
+        insert: fn(&mut QueryBuilder<'_, Postgres>, &[T]),
+    ) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut query = QueryBuilder::new("");
+        insert(&mut query, batch);
+        query.build().execute(&self.pool).await?;
+        batch.clear();
+        Ok(())
+    }
+}
+
+fn insert_trades(query: &mut QueryBuilder<'_, Postgres>, trades: &[PublicTrade]) {
+    query.push(
+        "INSERT INTO deribit_trades \
+         (trade_id, instrument_name, timestamp, price, amount, direction, data) ",
+    );
+    query.push_values(trades, |mut row, trade| {
+        row.push_bind(trade.trade_id.clone())
+            .push_bind(trade.instrument_name.clone())
+            .push_bind(trade.timestamp.as_millis())
+            .push_bind(trade.price)
+            .push_bind(trade.amount)
+            .push_bind(enum_str(&trade.direction))
+            .push_bind(Json(trade.clone()));
+    });
+    query.push(" ON CONFLICT DO NOTHING");
+}
+
+fn insert_orders(query: &mut QueryBuilder<'_, Postgres>, orders: &[Order]) {
+    query.push(
+        "INSERT INTO deribit_orders \
+         (order_id, last_update_timestamp, instrument_name, order_state, data) ",
+    );
+    query.push_values(orders, |mut row, order| {
+        row.push_bind(order.order_id.clone())
+            .push_bind(order.last_update_timestamp.as_millis())
+            .push_bind(order.instrument_name.clone())
+            .push_bind(enum_str(&order.order_state))
+            .push_bind(Json(order.clone()));
+    });
+    query.push(" ON CONFLICT DO NOTHING");
+}
+
+fn insert_book_snapshots(query: &mut QueryBuilder<'_, Postgres>, books: &[BookNotification]) {
+    query.push(
+        "INSERT INTO deribit_book_snapshots \
+         (instrument_name, change_id, timestamp, bids, asks) ",
+    );
+    query.push_values(books, |mut row, book| {
+        row.push_bind(book.instrument_name.clone())
+            .push_bind(book.change_id)
+            .push_bind(book.timestamp.map(Timestamp::as_millis))
+            .push_bind(Json(book.bids.clone()))
+            .push_bind(Json(book.asks.clone()));
+    });
+    query.push(" ON CONFLICT DO NOTHING");
+}
+
+// Checksums are stored bit for bit, as Postgres has no unsigned integers
+fn insert_digests(query: &mut QueryBuilder<'_, Postgres>, digests: &[Digest]) {
+    query.push(
+        "INSERT INTO deribit_digests \
+         (channel, start, \"end\", count, checksum, gaps) ",
+    );
+    query.push_values(digests, |mut row, digest| {
+        row.push_bind(digest.channel.clone())
+            .push_bind(digest.start.as_millis())
+            .push_bind(digest.end.as_millis())
+            .push_bind(digest.count as i64)
+            .push_bind(digest.checksum as i64)
+            .push_bind(Json(digest.gaps.clone()));
+    });
+    query.push(" ON CONFLICT DO NOTHING");
+}
+
+// Wire name of a generated enum value
+fn enum_str<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+// Flattens batches of rows, keeping errors in place
+fn flatten<T>(batches: impl Stream<Item = Result<Vec<T>>>) -> impl Stream<Item = Result<T>> {
+    batches.flat_map(|batch| {
+        let rows = match batch {
+            Ok(rows) => rows.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        };
+        futures_util::stream::iter(rows)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql<T>(insert: fn(&mut QueryBuilder<'_, Postgres>, &[T]), rows: &[T]) -> String {
+        let mut query = QueryBuilder::new("");
+        insert(&mut query, rows);
+        query.sql().to_string()
+    }
+
+    #[test]
+    fn inserts_bind_every_column_of_each_row() {
+        let trades = [PublicTrade::default(), PublicTrade::default()];
+        assert_eq!(
+            sql(insert_trades, &trades),
+            "INSERT INTO deribit_trades \
+             (trade_id, instrument_name, timestamp, price, amount, direction, data) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7), ($8, $9, $10, $11, $12, $13, $14) \
+             ON CONFLICT DO NOTHING"
+        );
+        assert_eq!(
+            sql(insert_orders, &[Order::default()]),
+            "INSERT INTO deribit_orders \
+             (order_id, last_update_timestamp, instrument_name, order_state, data) \
+             VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING"
+        );
+        assert_eq!(
+            sql(insert_book_snapshots, &[BookNotification::default()]),
+            "INSERT INTO deribit_book_snapshots \
+             (instrument_name, change_id, timestamp, bids, asks) \
+             VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING"
+        );
+        assert_eq!(
+            sql(insert_digests, &[Digest::default()]),
+            "INSERT INTO deribit_digests \
+             (channel, start, \"end\", count, checksum, gaps) \
+             VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING"
+        );
+    }
+
+    #[test]
+    fn enum_values_are_stored_by_wire_name() {
+        assert_eq!(enum_str(&crate::Direction::Sell), "sell");
+        assert_eq!(enum_str(&"not an enum".len()), "");
+    }
+}
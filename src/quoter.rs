@@ -0,0 +1,277 @@
+//! Two-sided quoting on an instrument, see `Quoter`.
+//!
+//! The quoter keeps at most one post-only limit order per side and brings it in line with
+//! the latest desired quote: placing it, editing its price and amount in place, or
+//! cancelling it. Desired quotes arriving faster than `Quoter::min_interval` are
+//! conflated, so a burst of market data becomes a single round of requests.
+//!
+//! Once the connection is gone the client can't cancel anything, so `Quoter::run` first
+//! enables `private/enable_cancel_on_disconnect` for the connection and the exchange
+//! pulls the quotes if it drops. Both orders carry the quoter's label, so
+//! `CancelFilter::label` clears them after a crash.
+//!
+//! With `Quoter::mmp_guard`, the orders count against market maker protection and the
+//! quoter pauses while it has frozen the index, then places fresh orders once it lifts.
+
+use crate::{
+    CodScopeParam, Direction, Instrument, MmpGuard, Order, OrderBuilder, OrderManager,
+    OrderRequest, PrivateEditRequest, PrivateEnableCancelOnDisconnectRequest, Result, money_f64,
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// One side of a quote: a price and the amount offered at it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuoteLevel {
+    pub price: f64,
+    pub amount: f64,
+}
+
+/// The orders a `Quoter` should have working; a side that is `None` is pulled.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quote {
+    pub bid: Option<QuoteLevel>,
+    pub ask: Option<QuoteLevel>,
+}
+
+/// A request made by a `Quoter`, yielded by `Quoter::run`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuoteEvent {
+    Placed(Order),
+    Edited(Order),
+    Cancelled(Order),
+}
+
+/// Maintains a bid and an ask on an instrument, see `Quoter::run`.
+#[derive(Debug, Clone)]
+pub struct Quoter {
+    manager: OrderManager,
+    instrument: Instrument,
+    label: String,
+    min_interval: Duration,
+    guard: Option<MmpGuard>,
+}
+
+impl Quoter {
+    /// Quotes `instrument` through `manager` with orders labelled `label`. Keep
+    /// `OrderManager::track` running so fills are seen: a side that fills is placed
+    /// again right away, without waiting for the next quote.
+    pub fn new(manager: OrderManager, instrument: Instrument, label: impl Into<String>) -> Self {
+        Self {
+            manager,
+            instrument,
+            label: label.into(),
+            min_interval: Duration::from_millis(100),
+            guard: None,
+        }
+    }
+
+    /// Shortest time between two rounds of requests, 100ms by default. Each round makes
+    /// up to one request per side.
+    pub fn min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Places the orders with `mmp` and pauses quoting while `guard` reports a freeze.
+    /// Keep `MmpGuard::track` running so freezes are seen.
+    pub fn mmp_guard(mut self, guard: MmpGuard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Quotes the latest of `quotes` until it ends, then cancels both orders, yielding
+    /// each request made. Ends without cancelling if the connection drops first, leaving
+    /// the exchange to cancel them: nothing is placed until cancel on disconnect is
+    /// enabled for the connection, and if that fails the error is yielded and the stream
+    /// ends.
+    ///
+    /// Ending `quotes` is how quoting is shut down, e.g. with `take_until` on a shutdown
+    /// signal, polling the stream to its end so the cancels are sent. Dropping the stream
+    /// instead leaves both orders working.
+    ///
+    /// Prices are rounded away from the market and amounts down to the instrument's
+    /// increments, and an order is only edited when either changes. A failed request is
+    /// yielded and retried in the next round.
+    pub fn run<S>(self, quotes: S) -> impl Stream<Item = Result<QuoteEvent>> + Send + 'static
+    where
+        S: Stream<Item = Quote> + Send + 'static,
+    {
+        let changes = self.manager.changes().boxed();
+        let quoting = Quoting {
+            quoter: self,
+            orders: [None, None],
+            desired: Quote::default(),
+            dirty: false,
+            next_round: Instant::now(),
+            events: VecDeque::new(),
+            guarded: false,
+            finished: false,
+        };
+        futures_util::stream::unfold(
+            (quoting, quotes.boxed(), changes),
+            |(mut quoting, mut quotes, mut changes)| async move {
+                loop {
+                    if let Some(event) = quoting.events.pop_front() {
+                        return Some((event, (quoting, quotes, changes)));
+                    }
+                    if quoting.finished {
+                        return None;
+                    }
+                    let client = quoting.quoter.manager.client.clone();
+                    if !quoting.guarded {
+                        let request = PrivateEnableCancelOnDisconnectRequest {
+                            scope: Some(CodScopeParam::Connection),
+                        };
+                        if let Err(e) = client.call(request).await {
+                            quoting.finished = true;
+                            return Some((Err(e), (quoting, quotes, changes)));
+                        }
+                        quoting.guarded = true;
+                    }
+                    tokio::select! {
+                        biased;
+                        reason = client.disconnected() => {
+                            tracing::warn!(?reason, label = quoting.quoter.label, "quoting stopped by disconnect");
+                            quoting.finished = true;
+                        }
+                        quote = quotes.next() => match quote {
+                            Some(quote) => {
+                                quoting.desired = quote;
+                                quoting.dirty = true;
+                            }
+                            None => {
+                                quoting.desired = Quote::default();
+                                quoting.round().await;
+                                quoting.finished = true;
+                            }
+                        },
+                        Some(order) = changes.next() => {
+                            // A side filled or pulled by the exchange is placed again
+                            if order.is_done() && quoting.is_working(&order.order_id) {
+                                quoting.dirty = true;
+                            }
+                        }
+                        _ = tokio::time::sleep_until(quoting.next_round), if quoting.dirty => {
+                            quoting.round().await;
+                        }
+                    }
+                }
+            },
+        )
+    }
+}
+
+struct Quoting {
+    quoter: Quoter,
+    // The bid and ask orders
+    orders: [Option<Order>; 2],
+    desired: Quote,
+    // Whether the orders may differ from `desired`
+    dirty: bool,
+    next_round: Instant,
+    events: VecDeque<Result<QuoteEvent>>,
+    // Whether cancel on disconnect is enabled for the connection
+    guarded: bool,
+    finished: bool,
+}
+
+impl Quoting {
+    fn is_working(&self, order_id: &str) -> bool {
+        self.orders
+            .iter()
+            .flatten()
+            .any(|order| order.order_id == order_id)
+    }
+
+    async fn round(&mut self) {
+        self.dirty = false;
+        self.next_round = Instant::now() + self.quoter.min_interval;
+        if self.quoter.guard.as_ref().is_some_and(MmpGuard::is_paused) {
+            // The exchange cancelled the orders when it froze the index; checked again
+            // next round
+            self.orders = [None, None];
+            self.dirty = true;
+            return;
+        }
+        let sides = [
+            (Direction::Buy, self.desired.bid),
+            (Direction::Sell, self.desired.ask),
+        ];
+        for (side, (direction, level)) in sides.into_iter().enumerate() {
+            match self.side(side, direction, level).await {
+                Ok(Some(event)) => self.events.push_back(Ok(event)),
+                Ok(None) => {}
+                Err(e) => {
+                    self.dirty = true;
+                    self.events.push_back(Err(e));
+                }
+            }
+        }
+    }
+
+    // Brings the order of one side in line with `level`
+    async fn side(
+        &mut self,
+        side: usize,
+        direction: Direction,
+        level: Option<QuoteLevel>,
+    ) -> Result<Option<QuoteEvent>> {
+        let manager = &self.quoter.manager;
+        let instrument = &self.quoter.instrument;
+        let working = self.orders[side]
+            .as_ref()
+            .map(|order| {
+                manager
+                    .order(&order.order_id)
+                    .unwrap_or_else(|| order.clone())
+            })
+            .filter(|order| !order.is_done());
+        // Checked and rounded like any new order, also when editing
+        let request = level
+            .map(|level| {
+                let order = OrderBuilder::limit(direction, level.amount, level.price)
+                    .label(&self.quoter.label)
+                    .post_only();
+                match &self.quoter.guard {
+                    Some(_) => order.mmp(),
+                    None => order,
+                }
+                .build(instrument)
+            })
+            .transpose()?;
+        let event = match (request, working) {
+            (None, None) => None,
+            (None, Some(order)) => Some(QuoteEvent::Cancelled(
+                manager.cancel(&order.order_id).await?,
+            )),
+            (Some(request), None) => Some(QuoteEvent::Placed(manager.place(request).await?.order)),
+            (Some(request), Some(order)) => {
+                let (price, amount) = match &request {
+                    OrderRequest::Buy(request) => (request.price, request.amount),
+                    OrderRequest::Sell(request) => (request.price, request.amount),
+                };
+                if order.price.as_f64() == price.map(money_f64) && order.amount == amount {
+                    return Ok(None);
+                }
+                let edited = manager
+                    .edit(PrivateEditRequest {
+                        order_id: order.order_id.clone(),
+                        amount,
+                        price,
+                        post_only: Some(true),
+                        ..Default::default()
+                    })
+                    .await?;
+                Some(QuoteEvent::Edited(edited.order))
+            }
+        };
+        self.orders[side] = match &event {
+            Some(QuoteEvent::Placed(order) | QuoteEvent::Edited(order)) => Some(order.clone()),
+            _ => None,
+        };
+        Ok(event)
+    }
+}
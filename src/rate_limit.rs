@@ -0,0 +1,73 @@
+//! Client-side request credits, see `DeribitClientBuilder::rate_limit`.
+
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Credit bucket mirroring Deribit's rate limits: every request costs `cost` credits,
+/// at most `max_credits` are available at once and `refill_per_second` are restored each
+/// second. The default matches the exchange's default for non-matching-engine requests,
+/// 20 requests per second with bursts of 100.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RateLimit {
+    pub max_credits: u64,
+    pub refill_per_second: u64,
+    pub cost: u64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            max_credits: 50_000,
+            refill_per_second: 10_000,
+            cost: 500,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CreditBucket {
+    limit: RateLimit,
+    credits: f64,
+    refilled_at: Instant,
+}
+
+impl CreditBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            credits: limit.max_credits as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    pub(crate) fn remaining(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.credits as u64
+    }
+
+    /// Spends the credits of a request, or returns how long to wait until there are
+    /// enough.
+    pub(crate) fn take(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        let cost = self.limit.cost as f64;
+        if self.credits >= cost {
+            self.credits -= cost;
+            return None;
+        }
+        let missing = cost - self.credits;
+        Some(Duration::from_secs_f64(
+            missing / self.limit.refill_per_second.max(1) as f64,
+        ))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.credits = (self.credits + elapsed.as_secs_f64() * self.limit.refill_per_second as f64)
+            .min(self.limit.max_credits as f64);
+        self.refilled_at = now;
+    }
+}
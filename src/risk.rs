@@ -0,0 +1,232 @@
+//! Client-side order and position limits, see `DeribitClientBuilder::risk_limits`.
+//!
+//! The API doesn't expose per-instrument open order or position limits, so these are
+//! configured; what the exchange does expose (minimum trade amounts, open orders and
+//! positions) is fetched with `DeribitClient::sync_risk_limits`.
+
+use crate::{Instrument, Order, PositionWithElp, money_f64};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Limits for one instrument. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InstrumentLimits {
+    /// Open orders allowed at a time.
+    pub max_open_orders: Option<usize>,
+    /// Absolute position size allowed, in the unit of the order `amount`.
+    pub max_position: Option<f64>,
+}
+
+/// Limits checked before `private/buy` and `private/sell` are sent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RiskLimits {
+    /// Limits of instruments without an entry in `per_instrument`.
+    pub default: InstrumentLimits,
+    pub per_instrument: HashMap<String, InstrumentLimits>,
+}
+
+/// Why a submission was rejected locally, with the usage at that time.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum LimitExceeded {
+    #[error("{instrument} has {open} open orders, the limit is {max}")]
+    OpenOrders {
+        instrument: String,
+        open: usize,
+        max: usize,
+    },
+    #[error("{instrument} position of {position} changed by {amount} would exceed {max}")]
+    Position {
+        instrument: String,
+        position: f64,
+        amount: f64,
+        max: f64,
+    },
+    #[error("{instrument} amount {amount} is below the minimum trade amount of {min}")]
+    MinTradeAmount {
+        instrument: String,
+        amount: f64,
+        min: f64,
+    },
+}
+
+/// What an instrument can still take before hitting its limits. `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Headroom {
+    pub open_orders: Option<usize>,
+    /// Amount that can still be bought.
+    pub buy: Option<f64>,
+    /// Amount that can still be sold.
+    pub sell: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Usage {
+    open_orders: usize,
+    // Signed, negative for short positions
+    position: f64,
+    min_trade_amount: Option<f64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RiskGuard {
+    limits: RiskLimits,
+    usage: HashMap<String, Usage>,
+}
+
+impl RiskGuard {
+    pub(crate) fn new(limits: RiskLimits) -> Self {
+        Self {
+            limits,
+            usage: HashMap::new(),
+        }
+    }
+
+    fn limits(&self, instrument: &str) -> &InstrumentLimits {
+        self.limits
+            .per_instrument
+            .get(instrument)
+            .unwrap_or(&self.limits.default)
+    }
+
+    // Replaces the usage of `instruments` with the exchange's view
+    pub(crate) fn sync(
+        &mut self,
+        instruments: &[Instrument],
+        positions: &[PositionWithElp],
+        open_orders: &[Order],
+    ) {
+        for instrument in instruments {
+            self.usage.insert(
+                instrument.instrument_name.clone(),
+                Usage {
+                    min_trade_amount: Some(money_f64(instrument.min_trade_amount)),
+                    ..Default::default()
+                },
+            );
+        }
+        for position in positions {
+            self.usage
+                .entry(position.instrument_name.clone())
+                .or_default()
+                .position = position.size;
+        }
+        for order in open_orders {
+            if let Some(instrument) = &order.instrument_name {
+                self.usage
+                    .entry(instrument.clone())
+                    .or_default()
+                    .open_orders += 1;
+            }
+        }
+    }
+
+    pub(crate) fn headroom(&self, instrument: &str) -> Headroom {
+        let limits = self.limits(instrument);
+        let usage = self.usage.get(instrument).cloned().unwrap_or_default();
+        Headroom {
+            open_orders: limits
+                .max_open_orders
+                .map(|max| max.saturating_sub(usage.open_orders)),
+            buy: limits
+                .max_position
+                .map(|max| (max - usage.position).max(0.0)),
+            sell: limits
+                .max_position
+                .map(|max| (max + usage.position).max(0.0)),
+        }
+    }
+
+    pub(crate) fn check(&self, method: &str, params: &Value) -> Result<(), LimitExceeded> {
+        let sign = match method {
+            "private/buy" => 1.0,
+            "private/sell" => -1.0,
+            _ => return Ok(()),
+        };
+        let Some(instrument) = params["instrument_name"].as_str() else {
+            return Ok(());
+        };
+        let limits = self.limits(instrument);
+        let usage = self.usage.get(instrument).cloned().unwrap_or_default();
+
+        if let Some(max) = limits.max_open_orders
+            && usage.open_orders >= max
+        {
+            return Err(LimitExceeded::OpenOrders {
+                instrument: instrument.to_string(),
+                open: usage.open_orders,
+                max,
+            });
+        }
+        let Some(amount) = params["amount"].as_f64() else {
+            return Ok(());
+        };
+        if let Some(min) = usage.min_trade_amount
+            && amount < min
+        {
+            return Err(LimitExceeded::MinTradeAmount {
+                instrument: instrument.to_string(),
+                amount,
+                min,
+            });
+        }
+        // Orders reducing the position are always allowed
+        let projected = usage.position + sign * amount;
+        if let Some(max) = limits.max_position
+            && projected.abs() > max
+            && projected.abs() > usage.position.abs()
+        {
+            return Err(LimitExceeded::Position {
+                instrument: instrument.to_string(),
+                position: usage.position,
+                amount: sign * amount,
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    // Updates usage from a successful response. Fills of resting orders and cancellations
+    // other than `cancel`, `cancel_all` and `cancel_all_by_instrument` are only picked up
+    // by the next sync.
+    pub(crate) fn record(&mut self, method: &str, instrument: Option<&str>, response: &Value) {
+        match method {
+            "private/buy" | "private/sell" => {
+                let order = &response["order"];
+                let Some(instrument) = order["instrument_name"].as_str() else {
+                    return;
+                };
+                let usage = self.usage.entry(instrument.to_string()).or_default();
+                let filled = order["filled_amount"].as_f64().unwrap_or_default();
+                usage.position += if method == "private/buy" {
+                    filled
+                } else {
+                    -filled
+                };
+                if matches!(order["order_state"].as_str(), Some("open" | "untriggered")) {
+                    usage.open_orders += 1;
+                }
+            }
+            "private/cancel" => {
+                if let Some(usage) = response["instrument_name"]
+                    .as_str()
+                    .and_then(|instrument| self.usage.get_mut(instrument))
+                {
+                    usage.open_orders = usage.open_orders.saturating_sub(1);
+                }
+            }
+            "private/cancel_all" => {
+                self.usage
+                    .values_mut()
+                    .for_each(|usage| usage.open_orders = 0);
+            }
+            "private/cancel_all_by_instrument" => {
+                if let Some(usage) =
+                    instrument.and_then(|instrument| self.usage.get_mut(instrument))
+                {
+                    usage.open_orders = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+}
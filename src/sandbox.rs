@@ -0,0 +1,125 @@
+//! Restricted views of a client for code that shouldn't reach every method, see
+//! `DeribitClient::sandboxed`.
+
+use crate::{ApiRequest, DeribitClient, Error, Result, Subscription, SubscriptionStream};
+use futures_util::Stream;
+use serde_json::Value;
+use serde_json::value::RawValue;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+/// Methods a `SandboxedClient` may call. Patterns are method names where `*` matches any
+/// run of characters, e.g. `public/*` or `private/cancel*`. A method is permitted when it
+/// matches an allowed pattern (any method if none were given) and no denied one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MethodPolicy {
+    allowed: Option<Vec<String>>,
+    denied: Vec<String>,
+}
+
+impl MethodPolicy {
+    /// Permits only methods matching `patterns`.
+    pub fn allow<I, S>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            allowed: Some(patterns.into_iter().map(Into::into).collect()),
+            denied: Vec::new(),
+        }
+    }
+
+    /// Rejects methods matching `patterns`, even if allowed.
+    pub fn deny<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.denied.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn permits(&self, method: &str) -> bool {
+        let allowed = self.allowed.as_ref().is_none_or(|allowed| {
+            allowed
+                .iter()
+                .any(|pattern| crate::glob_matches(pattern, method))
+        });
+        allowed
+            && !self
+                .denied
+                .iter()
+                .any(|pattern| crate::glob_matches(pattern, method))
+    }
+
+    fn check(&self, method: &str) -> Result<()> {
+        if self.permits(method) {
+            Ok(())
+        } else {
+            Err(Error::MethodNotAllowed(method.to_string()))
+        }
+    }
+}
+
+/// A client sharing the connection and credentials of another, that fails calls to
+/// methods outside its `MethodPolicy` locally with `Error::MethodNotAllowed`. Hand it to
+/// third-party or experimental strategy code to limit what it can do.
+#[derive(Debug, Clone)]
+pub struct SandboxedClient {
+    client: Arc<DeribitClient>,
+    policy: MethodPolicy,
+}
+
+impl DeribitClient {
+    /// Wraps the client in a `SandboxedClient` restricted to `policy`. The client itself
+    /// stays unrestricted.
+    pub fn sandboxed(self: &Arc<Self>, policy: MethodPolicy) -> SandboxedClient {
+        SandboxedClient {
+            client: self.clone(),
+            policy,
+        }
+    }
+}
+
+impl SandboxedClient {
+    pub fn policy(&self) -> &MethodPolicy {
+        &self.policy
+    }
+
+    pub async fn call<T: ApiRequest>(&self, req: T) -> Result<T::Response> {
+        self.policy.check(req.method_name())?;
+        self.client.call(req).await
+    }
+
+    pub async fn call_raw(&self, method: &str, params: Value) -> Result<Value> {
+        self.policy.check(method)?;
+        self.client.call_raw(method, params).await
+    }
+
+    /// Subscribes if the policy permits the subscribe method the client uses,
+    /// `private/subscribe` once authenticated and `public/subscribe` before.
+    pub async fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> Result<SubscriptionStream<S::Data>> {
+        self.check_subscribe()?;
+        self.client.subscribe(subscription).await
+    }
+
+    pub async fn subscribe_raw(
+        &self,
+        channel: &str,
+    ) -> Result<impl Stream<Item = Result<Arc<RawValue>>> + Send + 'static + use<>> {
+        self.check_subscribe()?;
+        self.client.subscribe_raw(channel).await
+    }
+
+    fn check_subscribe(&self) -> Result<()> {
+        if self.client.authenticated.load(Ordering::Acquire) {
+            self.policy.check("private/subscribe")
+        } else {
+            self.policy.check("public/subscribe")
+        }
+    }
+}
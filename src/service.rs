@@ -0,0 +1,79 @@
+//! Calls as a `tower::Service`, see `DeribitService`.
+//!
+//! Standard tower middleware, e.g. timeouts, retries, rate limits and load shedding, can
+//! then be layered around Deribit calls like around any other service. Each request type
+//! is a request of the service, answered with its response type.
+
+use crate::{ApiRequest, DeribitClient, Error, SLOT_HELD};
+use futures_util::future::BoxFuture;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_util::sync::PollSemaphore;
+use tower_service::Service;
+
+/// A `tower::Service` making each `ApiRequest` with `DeribitClient::call`. Clones share
+/// the client.
+///
+/// With `DeribitClientBuilder::max_in_flight_requests`, the service is only ready once a
+/// call may be sent, so middleware like load shedding sees the limit.
+#[derive(Debug)]
+pub struct DeribitService {
+    client: Arc<DeribitClient>,
+    slots: Option<PollSemaphore>,
+    // Taken by `poll_ready` for the next call
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Clone for DeribitService {
+    // A clone is not ready until polled itself
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            slots: self.slots.clone(),
+            slot: None,
+        }
+    }
+}
+
+impl DeribitClient {
+    /// Wraps the client in a `DeribitService`, for tower middleware.
+    pub fn service(self: &Arc<Self>) -> DeribitService {
+        DeribitService {
+            client: self.clone(),
+            slots: self.in_flight_slots.clone().map(PollSemaphore::new),
+            slot: None,
+        }
+    }
+}
+
+impl DeribitService {
+    pub fn client(&self) -> &Arc<DeribitClient> {
+        &self.client
+    }
+}
+
+impl<R: ApiRequest + Send + 'static> Service<R> for DeribitService {
+    type Response = R::Response;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<R::Response, Error>>;
+
+    // Ready once an in-flight slot is free, always without a limit
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let (Some(slots), None) = (&mut self.slots, &self.slot) {
+            self.slot = ready!(slots.poll_acquire(cx));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let client = self.client.clone();
+        // Held until the response arrives or the call is dropped
+        let slot = self.slot.take();
+        Box::pin(SLOT_HELD.scope(slot.is_some(), async move {
+            let result = client.call(request).await;
+            drop(slot);
+            result
+        }))
+    }
+}
@@ -0,0 +1,238 @@
+//! Spawning authenticated connections from an existing session, see
+//! `DeribitClient::fork_session`.
+//!
+//! The refresh token from the last `public/auth` is kept, so further connections can be
+//! authenticated without the client secret: `public/exchange_token` trades it for tokens
+//! of another subject, e.g. a subaccount, and `public/fork_token` for tokens of a new
+//! named session of the same one. Each new connection then authenticates with
+//! `public/auth` and a `refresh_token` grant.
+//!
+//! Named sessions can also share this client's connection, see
+//! `DeribitClient::open_session`: private requests carry the `access_token` of the
+//! session they are made for, so each session keeps its own orders, cancel-on-disconnect
+//! and subscriptions apart from the connection's.
+
+use crate::{
+    ApiRequest, DeribitClient, Error, PublicAuthGrantType, PublicAuthRequest,
+    PublicExchangeTokenRequest, PublicForkTokenRequest, PublicTokenResponse, Result, Subscription,
+    SubscriptionStream,
+};
+
+// Kept out of `Debug` output, as it authenticates without the secret
+#[derive(Clone)]
+pub(crate) struct RefreshToken(pub(crate) String);
+
+impl std::fmt::Debug for RefreshToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RefreshToken(..)")
+    }
+}
+
+// The access token of a named session sharing the connection, kept out of `Debug`
+// output
+#[derive(Clone)]
+pub(crate) struct NamedSession {
+    access_token: String,
+}
+
+impl std::fmt::Debug for NamedSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NamedSession(..)")
+    }
+}
+
+impl DeribitClient {
+    /// A new connection authenticated as `subject_id`, e.g. a subaccount, from this
+    /// client's session, with `public/exchange_token`. Fails with
+    /// `Error::NotAuthenticated` before a successful `public/auth`.
+    ///
+    /// The new client connects with the builder settings of this one, except for its
+    /// credentials, and is shut down along with this one's cancellation token.
+    pub async fn fork_session(&self, subject_id: i64) -> Result<DeribitClient> {
+        let refresh_token = self.refresh_token()?;
+        let client = self.connect_like().await?;
+        let tokens = client
+            .call(PublicExchangeTokenRequest {
+                refresh_token,
+                subject_id,
+                scope: None,
+            })
+            .await?;
+        client.authenticate_with(tokens).await?;
+        Ok(client)
+    }
+
+    /// A new connection authenticated as the same subject in a new session named
+    /// `session_name`, with `public/fork_token`. This client's token must have a
+    /// `session:` scope. Otherwise like `fork_session`.
+    pub async fn fork_named_session(&self, session_name: &str) -> Result<DeribitClient> {
+        let refresh_token = self.refresh_token()?;
+        let client = self.connect_like().await?;
+        let tokens = client
+            .call(PublicForkTokenRequest {
+                refresh_token,
+                session_name: session_name.to_string(),
+            })
+            .await?;
+        client.authenticate_with(tokens).await?;
+        Ok(client)
+    }
+
+    fn refresh_token(&self) -> Result<String> {
+        self.session
+            .lock()
+            .unwrap()
+            .refresh_token
+            .as_ref()
+            .map(|token| token.0.clone())
+            .ok_or(Error::NotAuthenticated)
+    }
+
+    // Not logged in: the caller authenticates it with the forked tokens
+    async fn connect_like(&self) -> Result<DeribitClient> {
+        let mut builder = self
+            .builder
+            .clone()
+            .cancellation_token(self.cancellation.child_token());
+        builder.credentials = None;
+        builder.connect().await
+    }
+
+    async fn authenticate_with(&self, tokens: PublicTokenResponse) -> Result<()> {
+        self.call(PublicAuthRequest {
+            grant_type: PublicAuthGrantType::RefreshToken,
+            refresh_token: tokens.refresh_token,
+            ..Default::default()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Opens a session named `session_name` on this connection with `public/fork_token`,
+    /// or renews the tokens of one already open. This client's token must have a
+    /// `session:` scope. Fails with `Error::NotAuthenticated` before a successful
+    /// `public/auth`.
+    pub async fn open_session(&self, session_name: &str) -> Result<Session<'_>> {
+        let tokens = self
+            .call(PublicForkTokenRequest {
+                refresh_token: self.refresh_token()?,
+                session_name: session_name.to_string(),
+            })
+            .await?;
+        self.session.lock().unwrap().named_sessions.insert(
+            session_name.to_string(),
+            NamedSession {
+                access_token: tokens.access_token,
+            },
+        );
+        Ok(Session {
+            client: self,
+            name: session_name.to_string(),
+        })
+    }
+
+    /// The session named `session_name`, if opened with `open_session`.
+    pub fn session(&self, session_name: &str) -> Option<Session<'_>> {
+        self.session
+            .lock()
+            .unwrap()
+            .named_sessions
+            .contains_key(session_name)
+            .then(|| Session {
+                client: self,
+                name: session_name.to_string(),
+            })
+    }
+
+    /// The names of the sessions opened with `open_session`.
+    pub fn session_names(&self) -> Vec<String> {
+        self.session
+            .lock()
+            .unwrap()
+            .named_sessions
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// The named session `channel` was subscribed for, `None` if it was subscribed for
+    /// the connection itself or not at all.
+    pub fn subscription_session(&self, channel: &str) -> Option<String> {
+        self.session
+            .lock()
+            .unwrap()
+            .session_subscriptions
+            .get(channel)
+            .cloned()
+    }
+}
+
+/// A named session sharing a `DeribitClient`'s connection, see
+/// `DeribitClient::open_session`.
+#[derive(Debug, Clone)]
+pub struct Session<'a> {
+    client: &'a DeribitClient,
+    name: String,
+}
+
+impl Session<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Makes a call for this session, with its access token.
+    pub async fn call<T: ApiRequest>(&self, request: T) -> Result<T::Response> {
+        self.client.call_as(&self.access_token()?, request).await
+    }
+
+    /// Subscribes for this session, like `DeribitClient::subscribe`. Notifications only
+    /// name their channel, so subscribe to each private channel for one session only:
+    /// the streams of a channel receive the notifications of every session subscribed.
+    pub async fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> Result<SubscriptionStream<S::Data>> {
+        let channel = subscription.channel_string();
+        let access_token = self.access_token()?;
+        let published = self
+            .client
+            .subscribe_published_as(
+                &channel,
+                self.client.subscription_capacity(&channel),
+                Some(&access_token),
+            )
+            .await?;
+        self.client
+            .session
+            .lock()
+            .unwrap()
+            .session_subscriptions
+            .entry(channel.clone())
+            .or_insert_with(|| self.name.clone());
+        Ok(self.client.decode_stream::<S>(channel, published))
+    }
+
+    /// The channels subscribed for this session.
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.client
+            .session
+            .lock()
+            .unwrap()
+            .session_subscriptions
+            .iter()
+            .filter(|(_, name)| **name == self.name)
+            .map(|(channel, _)| channel.clone())
+            .collect()
+    }
+
+    fn access_token(&self) -> Result<String> {
+        self.client
+            .session
+            .lock()
+            .unwrap()
+            .named_sessions
+            .get(&self.name)
+            .map(|session| session.access_token.clone())
+            .ok_or(Error::NotAuthenticated)
+    }
+}
@@ -0,0 +1,178 @@
+//! Helpers for settlement, delivery and bankruptcy history.
+//!
+//! `private/get_settlement_history_by_*` and `public/get_delivery_prices` return their
+//! events newest first, a page at a time; `DeribitClient::settlement_history`,
+//! `DeribitClient::instrument_settlement_history` and `DeribitClient::delivery_prices`
+//! collect those of a time range.
+
+use crate::{
+    ContinuationPagination, Currency, DeribitClient, Expiry, IndexName, OffsetPagination, Position,
+    PrivateGetSettlementHistoryByCurrencyRequest, PrivateGetSettlementHistoryByInstrumentRequest,
+    PublicGetDeliveryPricesRequest, PublicGetDeliveryPricesResponseData, Result, Settlement,
+    SettlementType, Timestamp,
+};
+use futures_util::{Stream, StreamExt};
+use std::sync::Arc;
+
+// Events requested per page
+const PAGE_SIZE: i64 = 1000;
+
+impl Settlement {
+    /// Bankruptcy events are account-wide: they carry the socialized loss fields
+    /// instead of an instrument.
+    pub fn is_bankruptcy(&self) -> bool {
+        self.r#type == SettlementType::Bankruptcy
+    }
+}
+
+/// Settlement history matched against positions, see `link_settlements`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettlementLinks<'a> {
+    /// Each position with the settlement and delivery events of its instrument.
+    pub by_position: Vec<(&'a Position, Vec<&'a Settlement>)>,
+    /// Settlement and delivery events of instruments without a matching position.
+    pub unmatched: Vec<&'a Settlement>,
+    /// Bankruptcy events, which affect the whole currency rather than one position.
+    pub bankruptcies: Vec<&'a Settlement>,
+}
+
+/// Links settlements (e.g. from `private/get_settlement_history_by_currency`) to the
+/// positions (e.g. from `private/get_positions`) they affected.
+pub fn link_settlements<'a>(
+    positions: &'a [Position],
+    settlements: &'a [Settlement],
+) -> SettlementLinks<'a> {
+    let mut links = SettlementLinks {
+        by_position: positions.iter().map(|p| (p, Vec::new())).collect(),
+        ..Default::default()
+    };
+    for settlement in settlements {
+        if settlement.is_bankruptcy() {
+            links.bankruptcies.push(settlement);
+        } else if let Some((_, linked)) = links
+            .by_position
+            .iter_mut()
+            .find(|(position, _)| position.instrument_name == settlement.instrument_name)
+        {
+            linked.push(settlement);
+        } else {
+            links.unmatched.push(settlement);
+        }
+    }
+    links
+}
+
+impl PublicGetDeliveryPricesResponseData {
+    /// The day of the delivery, from `date` (`YYYY-MM-DD`).
+    pub fn expiry(&self) -> Option<Expiry> {
+        let mut parts = self.date.splitn(3, '-');
+        let expiry = Expiry {
+            year: parts.next()?.parse().ok()?,
+            month: parts.next()?.parse().ok()?,
+            day: parts.next()?.parse().ok()?,
+        };
+        ((1..=12).contains(&expiry.month) && (1..=31).contains(&expiry.day)).then_some(expiry)
+    }
+}
+
+// Collects the items of `items`, newest first, from `start_timestamp` to
+// `end_timestamp`, oldest first; items without a timestamp are left out
+async fn collect_range<T>(
+    items: impl Stream<Item = Result<T>>,
+    timestamp: impl Fn(&T) -> Option<Timestamp>,
+    start_timestamp: Timestamp,
+    end_timestamp: Timestamp,
+) -> Result<Vec<T>> {
+    let mut items = std::pin::pin!(items);
+    let mut collected = Vec::new();
+    while let Some(item) = items.next().await {
+        let item = item?;
+        match timestamp(&item) {
+            Some(t) if t < start_timestamp => break,
+            Some(t) if t <= end_timestamp => collected.push(item),
+            _ => {}
+        }
+    }
+    collected.reverse();
+    Ok(collected)
+}
+
+impl DeribitClient {
+    /// Settlement, delivery and bankruptcy events of `currency` between
+    /// `start_timestamp` and `end_timestamp`, oldest first; only those of `type` if given.
+    pub async fn settlement_history(
+        self: &Arc<Self>,
+        currency: Currency,
+        r#type: Option<SettlementType>,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+    ) -> Result<Vec<Settlement>> {
+        let settlements = self.paginate(PrivateGetSettlementHistoryByCurrencyRequest {
+            currency,
+            r#type,
+            pagination: ContinuationPagination {
+                count: Some(PAGE_SIZE),
+                continuation: None,
+            },
+            search_start_timestamp: Some(end_timestamp),
+        });
+        collect_range(
+            settlements,
+            |settlement| Some(settlement.timestamp),
+            start_timestamp,
+            end_timestamp,
+        )
+        .await
+    }
+
+    /// Settlement and delivery events of `instrument_name` between `start_timestamp` and
+    /// `end_timestamp`, oldest first; only those of `type` if given.
+    pub async fn instrument_settlement_history(
+        self: &Arc<Self>,
+        instrument_name: &str,
+        r#type: Option<SettlementType>,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+    ) -> Result<Vec<Settlement>> {
+        let settlements = self.paginate(PrivateGetSettlementHistoryByInstrumentRequest {
+            instrument_name: instrument_name.to_string(),
+            r#type,
+            pagination: ContinuationPagination {
+                count: Some(PAGE_SIZE),
+                continuation: None,
+            },
+            search_start_timestamp: Some(end_timestamp),
+        });
+        collect_range(
+            settlements,
+            |settlement| Some(settlement.timestamp),
+            start_timestamp,
+            end_timestamp,
+        )
+        .await
+    }
+
+    /// Delivery prices of `index_name` delivered between `start_timestamp` and
+    /// `end_timestamp`, at 08:00 UTC of their `date`, oldest first.
+    pub async fn delivery_prices(
+        self: &Arc<Self>,
+        index_name: IndexName,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+    ) -> Result<Vec<PublicGetDeliveryPricesResponseData>> {
+        let prices = self.paginate(PublicGetDeliveryPricesRequest {
+            index_name,
+            pagination: OffsetPagination {
+                count: Some(PAGE_SIZE),
+                offset: None,
+            },
+        });
+        collect_range(
+            prices,
+            |price| price.expiry().map(|expiry| expiry.timestamp()),
+            start_timestamp,
+            end_timestamp,
+        )
+        .await
+    }
+}
@@ -0,0 +1,78 @@
+//! Spot pairs, see `InstrumentCache::spot_pairs`, `OrderBuilder::spot_limit_for` and
+//! `DeribitClient::spot_balances`.
+//!
+//! Spot pairs such as `BTC_USDC` trade one currency for another with no position left
+//! behind: amounts are in the base currency, BTC here, not in contracts or USD as on
+//! futures, and there is nothing for a reduce-only order to reduce. Each trade moves the
+//! balances of both currencies instead, which `private/get_account_summaries` reports.
+
+use crate::{
+    DeribitClient, Direction, Instrument, InstrumentCache, InstrumentFilter, Kind, OrderBuilder,
+    PrivateGetAccountSummariesRequest, Result, money_f64,
+};
+use std::collections::BTreeMap;
+
+impl InstrumentCache {
+    /// The cached spot pairs, by name.
+    pub fn spot_pairs(&self) -> Vec<Instrument> {
+        self.filter(&InstrumentFilter {
+            kind: Some(Kind::Spot),
+            ..Default::default()
+        })
+    }
+
+    /// The spot pair trading `base` for `quote`, e.g. `BTC` for `USDC`.
+    pub fn spot_pair(&self, base: &str, quote: &str) -> Option<Instrument> {
+        self.get(&format!("{base}_{quote}"))
+            .filter(|instrument| instrument.kind == Kind::Spot)
+    }
+}
+
+impl OrderBuilder {
+    /// A limit order on a spot pair worth `value` in the quote currency at `price`, e.g.
+    /// 1000 USDC of BTC. The amount is in the base currency, and `build` rounds it down
+    /// to the pair's minimum, so the order never costs more than `value`.
+    pub fn spot_limit_for(direction: Direction, value: f64, price: f64) -> Self {
+        Self::limit(direction, value / price, price)
+    }
+}
+
+/// The balance of a currency, as spot trades move it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpotBalance {
+    pub balance: f64,
+    /// Free to trade with, after margin and reserves.
+    pub available: f64,
+    /// Held for open spot orders.
+    pub reserved: f64,
+}
+
+impl DeribitClient {
+    /// The balances of every currency held, of a subaccount if given, from
+    /// `private/get_account_summaries`, by currency.
+    pub async fn spot_balances(
+        &self,
+        subaccount_id: Option<i64>,
+    ) -> Result<BTreeMap<String, SpotBalance>> {
+        let account = self
+            .call(PrivateGetAccountSummariesRequest {
+                subaccount_id,
+                extended: None,
+            })
+            .await?;
+        Ok(account
+            .summaries
+            .into_iter()
+            .flatten()
+            .filter(|summary| money_f64(summary.balance) != 0.0)
+            .map(|summary| {
+                let balance = SpotBalance {
+                    balance: money_f64(summary.balance),
+                    available: summary.available_funds,
+                    reserved: summary.spot_reserve.unwrap_or_default(),
+                };
+                (summary.currency, balance)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,218 @@
+//! Typed subscription streams and adapters for market data, see `SubscriptionStream`.
+
+use crate::{Diagnostic, Error, Published, Result, Utf8Bytes, diagnostics};
+use futures_util::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{Interval, MissedTickBehavior};
+
+type RawStream = Pin<Box<dyn Stream<Item = Result<Arc<Published>>> + Send>>;
+
+/// Stream of typed messages returned by `DeribitClient::subscribe`.
+///
+/// Messages arrive as raw JSON and are decoded as they are polled, so adapters like
+/// `latest` and `sample` that drop messages never parse the dropped ones. A message is
+/// parsed once however many typed streams of the channel receive it.
+pub struct SubscriptionStream<T> {
+    raw: RawStream,
+    decoder: Decoder,
+    _data: PhantomData<fn() -> T>,
+}
+
+impl<T> std::fmt::Debug for SubscriptionStream<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubscriptionStream")
+            .field("channel", &self.decoder.channel)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Decoder {
+    channel: String,
+    diagnostics: broadcast::Sender<Diagnostic>,
+}
+
+impl Decoder {
+    // Decodes each message once for all typed subscribers of its channel, cloning the
+    // cached value for the others
+    fn decode<T: DeserializeOwned + Clone + Send + Sync + 'static>(
+        &self,
+        msg: Result<Arc<Published>>,
+    ) -> Result<T> {
+        let msg = msg?;
+        if let Some(decoded) = msg.decoded.get().and_then(|decoded| decoded.downcast_ref()) {
+            return Ok(T::clone(decoded));
+        }
+        let decoded: T = diagnostics::decode_raw(&msg.data, &self.channel, &self.diagnostics)
+            .map_err(Error::JsonError)?;
+        if msg.receivers > 1 {
+            let _ = msg.decoded.set(Box::new(decoded.clone()));
+        }
+        Ok(decoded)
+    }
+}
+
+impl<T: DeserializeOwned + Clone + Send + Sync + 'static> SubscriptionStream<T> {
+    pub(crate) fn new(
+        raw: impl Stream<Item = Result<Arc<Published>>> + Send + 'static,
+        channel: String,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) -> Self {
+        Self {
+            raw: Box::pin(raw),
+            decoder: Decoder {
+                channel,
+                diagnostics,
+            },
+            _data: PhantomData,
+        }
+    }
+
+    /// A stream of `messages`, the `data` of notifications on `channel`, e.g. for a mock
+    /// `DeribitApi` to return from `subscribe`.
+    pub fn from_json(
+        channel: impl Into<String>,
+        messages: impl Stream<Item = Result<Value>> + Send + 'static,
+    ) -> Self {
+        let published = messages.map(|message| {
+            let data = serde_json::value::to_raw_value(&message?)?;
+            Ok(Arc::new(Published {
+                frame: Utf8Bytes::default(),
+                data: Arc::from(data),
+                receivers: 1,
+                decoded: OnceLock::new(),
+            }))
+        });
+        Self::new(published, channel.into(), broadcast::channel(1).0)
+    }
+
+    /// Yields only the newest message available at each poll, skipping (and not decoding)
+    /// older ones. `Error::SubscriptionLagged` is swallowed, since skipping is the point.
+    pub fn latest(mut self) -> impl Stream<Item = Result<T>> + Send + 'static {
+        let mut ended = false;
+        futures_util::stream::poll_fn(move |cx| {
+            let mut newest = None;
+            while !ended {
+                match self.raw.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(Err(Error::SubscriptionLagged(_)))) => {}
+                    Poll::Ready(Some(msg)) => newest = Some(msg),
+                    Poll::Ready(None) => ended = true,
+                    Poll::Pending => break,
+                }
+            }
+            match newest {
+                Some(msg) => Poll::Ready(Some(self.decoder.decode(msg))),
+                None if ended => Poll::Ready(None),
+                None => Poll::Pending,
+            }
+        })
+    }
+
+    /// Yields the newest message of every `period` in which one arrived, skipping (and not
+    /// decoding) the others. `Error::SubscriptionLagged` is swallowed.
+    pub fn sample(mut self, period: Duration) -> impl Stream<Item = Result<T>> + Send + 'static {
+        let mut ticker = ticker(period);
+        let mut newest = None;
+        let mut ended = false;
+        futures_util::stream::poll_fn(move |cx| {
+            while !ended {
+                match self.raw.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(Err(Error::SubscriptionLagged(_)))) => {}
+                    Poll::Ready(Some(msg)) => newest = Some(msg),
+                    Poll::Ready(None) => ended = true,
+                    Poll::Pending => break,
+                }
+            }
+            if ended {
+                return Poll::Ready(newest.take().map(|msg| self.decoder.decode(msg)));
+            }
+            while ticker.poll_tick(cx).is_ready() {
+                if let Some(msg) = newest.take() {
+                    return Poll::Ready(Some(self.decoder.decode(msg)));
+                }
+            }
+            Poll::Pending
+        })
+    }
+
+    /// Collects the messages of consecutive `period`-long windows, yielding each non-empty
+    /// window when it closes.
+    pub fn window(self, period: Duration) -> impl Stream<Item = Result<Vec<T>>> + Send + 'static {
+        let mut ticker = ticker(period);
+        self.collect(move |cx, _| ticker.poll_tick(cx).is_ready(), false)
+    }
+
+    /// Groups up to `size` messages that are ready at once, without waiting for more.
+    pub fn batch(self, size: usize) -> impl Stream<Item = Result<Vec<T>>> + Send + 'static {
+        let size = size.max(1);
+        self.collect(move |_, collected| collected >= size, true)
+    }
+
+    // Buffers decoded messages until `full` says so, the raw stream ends, or (with
+    // `flush_when_idle`) nothing more is ready. Errors are yielded after the messages
+    // received before them.
+    fn collect(
+        mut self,
+        mut full: impl FnMut(&mut Context<'_>, usize) -> bool + Send + 'static,
+        flush_when_idle: bool,
+    ) -> impl Stream<Item = Result<Vec<T>>> + Send + 'static {
+        let mut buffer = Vec::new();
+        let mut error = None;
+        let mut ended = false;
+        futures_util::stream::poll_fn(move |cx| {
+            if let Some(e) = error.take() {
+                return Poll::Ready(Some(Err(e)));
+            }
+            loop {
+                while !ended && !full(cx, buffer.len()) {
+                    match self.raw.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(msg)) => match self.decoder.decode(msg) {
+                            Ok(msg) => buffer.push(msg),
+                            Err(e) if buffer.is_empty() => return Poll::Ready(Some(Err(e))),
+                            Err(e) => {
+                                error = Some(e);
+                                break;
+                            }
+                        },
+                        Poll::Ready(None) => ended = true,
+                        Poll::Pending if flush_when_idle && !buffer.is_empty() => break,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                if !buffer.is_empty() {
+                    return Poll::Ready(Some(Ok(std::mem::take(&mut buffer))));
+                }
+                if ended {
+                    return Poll::Ready(None);
+                }
+                // An empty window closed, start collecting the next one
+            }
+        })
+    }
+}
+
+impl<T: DeserializeOwned + Clone + Send + Sync + 'static> Stream for SubscriptionStream<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        this.raw
+            .as_mut()
+            .poll_next(cx)
+            .map(|msg| msg.map(|msg| this.decoder.decode(msg)))
+    }
+}
+
+// Ticks every `period`, starting one period from now
+fn ticker(period: Duration) -> Interval {
+    let period = period.max(Duration::from_millis(1));
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
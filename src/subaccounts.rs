@@ -0,0 +1,167 @@
+//! Managing subaccounts and moving funds between them, see `Subaccounts`.
+//!
+//! Only the main account can create subaccounts, name them and fund them. A subaccount is
+//! created with a generated name, renamed with `private/change_subaccount_name`, and
+//! identified by its id everywhere else. Funds move with
+//! `private/submit_transfer_to_subaccount` from the main account, or with
+//! `private/submit_transfer_between_subaccounts` from any other, see
+//! `SubaccountTransfer`. Transfers between subaccounts need no address book entry.
+
+use crate::{
+    Currency, CurrencyPortfolio, DeribitClient, Money, PrivateChangeSubaccountNameRequest,
+    PrivateCreateSubaccountRequest, PrivateCreateSubaccountResponse, PrivateGetSubaccountsRequest,
+    PrivateGetSubaccountsResponse, PrivateGetSubaccountsResponseType,
+    PrivateSubmitTransferBetweenSubaccountsRequest, PrivateSubmitTransferToSubaccountRequest,
+    Result, TransferItem,
+};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A transfer of funds to a subaccount, from the main account unless `from` says
+/// otherwise, see `Subaccounts::transfer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubaccountTransfer {
+    currency: Currency,
+    amount: Money,
+    source: Option<i64>,
+    destination: i64,
+}
+
+impl SubaccountTransfer {
+    /// Moves `amount` of `currency` to the subaccount `destination`.
+    pub fn new(currency: Currency, amount: Money, destination: i64) -> Self {
+        Self {
+            currency,
+            amount,
+            source: None,
+            destination,
+        }
+    }
+
+    /// Takes the funds from the subaccount `source` instead of the main account.
+    pub fn from(mut self, source: i64) -> Self {
+        self.source = Some(source);
+        self
+    }
+}
+
+/// The balances of a subaccount, see `Subaccounts::balances`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubaccountBalances {
+    pub id: i64,
+    pub username: String,
+    /// By currency, e.g. `BTC`.
+    pub balances: BTreeMap<String, CurrencyPortfolio>,
+}
+
+/// The subaccounts of the main account the client is authenticated as.
+#[derive(Debug, Clone)]
+pub struct Subaccounts {
+    client: Arc<DeribitClient>,
+}
+
+impl Subaccounts {
+    pub fn new(client: Arc<DeribitClient>) -> Self {
+        Self { client }
+    }
+
+    /// The subaccounts, without the main account `private/get_subaccounts` lists too.
+    pub async fn list(&self) -> Result<Vec<PrivateGetSubaccountsResponse>> {
+        self.subaccounts(false).await
+    }
+
+    /// The subaccount named `username`, if any.
+    pub async fn find(&self, username: &str) -> Result<Option<PrivateGetSubaccountsResponse>> {
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .find(|account| account.username == username))
+    }
+
+    /// Creates a subaccount, renamed to `name` if given.
+    pub async fn create(&self, name: Option<&str>) -> Result<PrivateCreateSubaccountResponse> {
+        let mut account = self.client.call(PrivateCreateSubaccountRequest {}).await?;
+        if let Some(name) = name {
+            self.rename(account.id, name).await?;
+            account.username = name.to_string();
+        }
+        Ok(account)
+    }
+
+    /// Renames the subaccount `id`; the name must be unique across Deribit.
+    pub async fn rename(&self, id: i64, name: &str) -> Result<()> {
+        self.client
+            .call(PrivateChangeSubaccountNameRequest {
+                sid: id,
+                name: name.to_string(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Submits `transfer`, which completes right away between accounts of the same
+    /// owner.
+    pub async fn transfer(&self, transfer: &SubaccountTransfer) -> Result<TransferItem> {
+        match transfer.source {
+            None => {
+                self.client
+                    .call(PrivateSubmitTransferToSubaccountRequest {
+                        currency: transfer.currency.clone(),
+                        amount: transfer.amount,
+                        destination: transfer.destination,
+                    })
+                    .await
+            }
+            Some(source) => {
+                self.client
+                    .call(PrivateSubmitTransferBetweenSubaccountsRequest {
+                        currency: transfer.currency.clone(),
+                        amount: transfer.amount,
+                        destination: transfer.destination,
+                        source: Some(source),
+                    })
+                    .await
+            }
+        }
+    }
+
+    /// The BTC and ETH balances of each subaccount, in one request. Other currencies
+    /// come with `DeribitClient::spot_balances` for one subaccount at a time.
+    pub async fn balances(&self) -> Result<Vec<SubaccountBalances>> {
+        Ok(self
+            .subaccounts(true)
+            .await?
+            .into_iter()
+            .map(|account| SubaccountBalances {
+                id: account.id,
+                username: account.username,
+                balances: account
+                    .portfolio
+                    .map(|portfolio| {
+                        BTreeMap::from([
+                            ("BTC".to_string(), portfolio.btc),
+                            ("ETH".to_string(), portfolio.eth),
+                        ])
+                    })
+                    .unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn subaccounts(
+        &self,
+        with_portfolio: bool,
+    ) -> Result<Vec<PrivateGetSubaccountsResponse>> {
+        let accounts = self
+            .client
+            .call(PrivateGetSubaccountsRequest {
+                with_portfolio: Some(with_portfolio),
+            })
+            .await?;
+        Ok(accounts
+            .into_iter()
+            .filter(|account| account.r#type == PrivateGetSubaccountsResponseType::Subaccount)
+            .collect())
+    }
+}
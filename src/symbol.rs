@@ -0,0 +1,239 @@
+//! Parsing and formatting instrument names, see `InstrumentName`.
+//!
+//! Deribit names instruments `{currency}[_{quote}]` followed by `-PERPETUAL` for
+//! perpetuals, `-{expiry}` for futures and `-{expiry}-{strike}-{C|P}` for options, e.g.
+//! `BTC-PERPETUAL`, `ETH-27DEC24`, `BTC-28MAR25-60000-C` or `XRP_USDC-28MAR25-0d625-P`,
+//! where `d` stands in for the decimal point. Spot pairs are just `{currency}_{quote}`.
+
+use crate::{InstrumentOptionType, Kind, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+// Instruments expire at 08:00 UTC
+const EXPIRY_HOUR_MILLIS: i64 = 8 * 3_600_000;
+
+/// A name that isn't a spot pair, future, perpetual or option, e.g. a combo.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid instrument name: {0}")]
+pub struct ParseInstrumentNameError(pub String);
+
+/// The expiry date in an instrument name, e.g. `28MAR25`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Expiry {
+    pub year: i32,
+    /// 1 to 12.
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Expiry {
+    /// When instruments of this expiry expire, 08:00 UTC of the day.
+    pub fn timestamp(&self) -> Timestamp {
+        Timestamp(
+            days_from_civil(self.year, self.month, self.day) * 86_400_000 + EXPIRY_HOUR_MILLIS,
+        )
+    }
+}
+
+impl FromStr for Expiry {
+    type Err = ParseInstrumentNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseInstrumentNameError(s.to_string());
+        let month_at = s
+            .find(|c: char| c.is_ascii_alphabetic())
+            .ok_or_else(invalid)?;
+        let (day, rest) = s.split_at(month_at);
+        let (month, year) = rest.split_at_checked(3).ok_or_else(invalid)?;
+        let month = MONTHS
+            .iter()
+            .position(|m| *m == month)
+            .ok_or_else(invalid)? as u8
+            + 1;
+        let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if !(1..=2).contains(&day.len()) || year.len() != 2 || !digits(day) || !digits(year) {
+            return Err(invalid());
+        }
+        let day: u8 = day.parse().map_err(|_| invalid())?;
+        let year = 2000 + year.parse::<i32>().map_err(|_| invalid())?;
+        if day == 0 || day > days_in_month(year, month) {
+            return Err(invalid());
+        }
+        Ok(Self { year, month, day })
+    }
+}
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let month = MONTHS[usize::from(self.month - 1)];
+        write!(f, "{}{}{:02}", self.day, month, self.year % 100)
+    }
+}
+
+/// An instrument name taken apart. `Display` puts it back together, and it serializes as
+/// the name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct InstrumentName {
+    /// `Future` for perpetuals too.
+    pub kind: Kind,
+    /// Base currency, e.g. `BTC`.
+    pub currency: String,
+    /// Quote currency of spot pairs and linear instruments, e.g. `USDC` in
+    /// `BTC_USDC-PERPETUAL`; `None` for inverse instruments.
+    pub quote: Option<String>,
+    /// `None` for perpetuals and spot pairs.
+    pub expiry: Option<Expiry>,
+    pub strike: Option<f64>,
+    pub option_type: Option<InstrumentOptionType>,
+}
+
+impl InstrumentName {
+    pub fn is_option(&self) -> bool {
+        self.kind == Kind::Option
+    }
+
+    pub fn is_future(&self) -> bool {
+        self.kind == Kind::Future && self.expiry.is_some()
+    }
+
+    pub fn is_perpetual(&self) -> bool {
+        self.kind == Kind::Future && self.expiry.is_none()
+    }
+
+    pub fn is_spot(&self) -> bool {
+        self.kind == Kind::Spot
+    }
+
+    /// Whether profits settle in the quote currency, as for `BTC_USDC-PERPETUAL`, rather
+    /// than in the base currency like inverse instruments.
+    pub fn is_linear(&self) -> bool {
+        self.quote.is_some() && !self.is_spot()
+    }
+
+    /// The currency profits settle in.
+    pub fn settlement_currency(&self) -> &str {
+        self.quote.as_deref().unwrap_or(&self.currency)
+    }
+
+    /// When the instrument expires, `None` if it doesn't.
+    pub fn expiry_timestamp(&self) -> Option<Timestamp> {
+        self.expiry.as_ref().map(Expiry::timestamp)
+    }
+}
+
+impl FromStr for InstrumentName {
+    type Err = ParseInstrumentNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseInstrumentNameError(s.to_string());
+        let parts: Vec<&str> = s.split('-').collect();
+        let (currency, quote) = match parts[0].split_once('_') {
+            Some((currency, quote)) => (currency, Some(quote)),
+            None => (parts[0], None),
+        };
+        if !is_currency(currency) || quote.is_some_and(|quote| !is_currency(quote)) {
+            return Err(invalid());
+        }
+        let expiry = |part: &str| part.parse::<Expiry>().map_err(|_| invalid());
+        let (kind, expiry, strike, option_type) = match parts[1..] {
+            [] if quote.is_some() => (Kind::Spot, None, None, None),
+            ["PERPETUAL"] => (Kind::Future, None, None, None),
+            [date] => (Kind::Future, Some(expiry(date)?), None, None),
+            [date, strike, option_type] => {
+                let strike = Some(strike)
+                    .filter(|strike| strike.chars().all(|c| c.is_ascii_digit() || c == 'd'))
+                    .and_then(|strike| strike.replace('d', ".").parse::<f64>().ok())
+                    .filter(|strike| *strike > 0.0)
+                    .ok_or_else(invalid)?;
+                let option_type = match option_type {
+                    "C" => InstrumentOptionType::Call,
+                    "P" => InstrumentOptionType::Put,
+                    _ => return Err(invalid()),
+                };
+                (
+                    Kind::Option,
+                    Some(expiry(date)?),
+                    Some(strike),
+                    Some(option_type),
+                )
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            kind,
+            currency: currency.to_string(),
+            quote: quote.map(str::to_string),
+            expiry,
+            strike,
+            option_type,
+        })
+    }
+}
+
+impl fmt::Display for InstrumentName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.currency)?;
+        if let Some(quote) = &self.quote {
+            write!(f, "_{quote}")?;
+        }
+        match &self.expiry {
+            Some(expiry) => write!(f, "-{expiry}")?,
+            None if self.kind == Kind::Future => f.write_str("-PERPETUAL")?,
+            None => {}
+        }
+        if let Some(strike) = self.strike {
+            write!(f, "-{}", strike.to_string().replace('.', "d"))?;
+        }
+        match &self.option_type {
+            Some(InstrumentOptionType::Call) => f.write_str("-C"),
+            Some(InstrumentOptionType::Put) => f.write_str("-P"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<String> for InstrumentName {
+    type Error = ParseInstrumentNameError;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+impl From<InstrumentName> for String {
+    fn from(name: InstrumentName) -> Self {
+        name.to_string()
+    }
+}
+
+fn is_currency(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+fn days_in_month(year: i32, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since the Unix epoch of a proleptic Gregorian date
+fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let (month, day) = (i64::from(month), i64::from(day));
+    let year = i64::from(year) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
@@ -0,0 +1,114 @@
+//! TLS settings for `DeribitClientBuilder::tls_connector`.
+//!
+//! The backend is chosen with the `native-tls` (default) or `rustls` cargo feature. Without
+//! a custom connector the backend's default trust roots are used (the system store for
+//! `native-tls`, the bundled webpki roots for `rustls`).
+
+pub use tokio_tungstenite::Connector;
+
+#[cfg(feature = "native-tls")]
+pub use native_tls;
+#[cfg(feature = "rustls")]
+pub use rustls;
+
+#[cfg(feature = "rustls")]
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+#[cfg(feature = "rustls")]
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+#[cfg(feature = "rustls")]
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+#[cfg(feature = "rustls")]
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+
+#[cfg(feature = "rustls")]
+fn rustls_config_builder() -> rustls::ConfigBuilder<rustls::ClientConfig, rustls::WantsVerifier> {
+    rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+}
+
+/// Trusts only the certificates in `roots` instead of the bundled webpki roots.
+#[cfg(feature = "rustls")]
+pub fn rustls_with_roots(roots: rustls::RootCertStore) -> Connector {
+    let config = rustls_config_builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Connector::Rustls(Arc::new(config))
+}
+
+/// Accepts only a server presenting exactly `certificate` as its end-entity certificate.
+#[cfg(feature = "rustls")]
+pub fn rustls_pinned(certificate: CertificateDer<'static>) -> Connector {
+    let verifier = PinnedCertificate {
+        certificate,
+        provider: rustls::crypto::ring::default_provider(),
+    };
+    let config = rustls_config_builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Connector::Rustls(Arc::new(config))
+}
+
+#[cfg(feature = "rustls")]
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    provider: CryptoProvider,
+}
+
+#[cfg(feature = "rustls")]
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,78 @@
+//! Backfilling the public trades of an instrument over a time range, see
+//! `DeribitClient::trade_history`.
+//!
+//! `public/get_last_trades_by_instrument_and_time` returns at most `count` (up to 1000)
+//! trades per call with `has_more` set when the range holds more. Trades sharing a
+//! millisecond can straddle two pages, so each page after the first starts at the
+//! timestamp of the last trade seen and repeats are dropped by `trade_seq`.
+
+use crate::{
+    DeribitClient, PublicGetLastTradesByInstrumentAndTimeRequest, PublicTrade, Result, Sorting,
+    TimeRange, Timestamp,
+};
+use futures_util::Stream;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+// Largest page `public/get_last_trades_by_instrument_and_time` returns
+const MAX_PAGE_SIZE: i64 = 1000;
+
+impl DeribitClient {
+    /// Yields the public trades of `instrument_name` between `start_timestamp` and
+    /// `end_timestamp`, oldest first and each once, requesting the next page once
+    /// the trades of the previous one are consumed. An error ends the stream.
+    pub fn trade_history(
+        self: &Arc<Self>,
+        instrument_name: &str,
+        start_timestamp: Timestamp,
+        end_timestamp: Timestamp,
+    ) -> impl Stream<Item = Result<PublicTrade>> + Send + 'static + use<> {
+        let request = PublicGetLastTradesByInstrumentAndTimeRequest {
+            instrument_name: instrument_name.to_string(),
+            time_range: TimeRange {
+                start_timestamp,
+                end_timestamp,
+            },
+            count: Some(MAX_PAGE_SIZE),
+            sorting: Some(Sorting::Asc),
+        };
+        futures_util::stream::unfold(
+            (self.clone(), Some(request), None, VecDeque::new()),
+            |(client, mut request, mut last_seq, mut trades)| async move {
+                loop {
+                    if let Some(trade) = trades.pop_front() {
+                        return Some((Ok(trade), (client, request, last_seq, trades)));
+                    }
+                    let mut next = request.take()?;
+                    let page = match client.call(next.clone()).await {
+                        Ok(page) => page,
+                        Err(e) => return Some((Err(e), (client, None, last_seq, trades))),
+                    };
+                    let start = next.time_range.start_timestamp.as_millis();
+                    let last_timestamp =
+                        page.trades.last().map(|trade| trade.timestamp.as_millis());
+                    for trade in page.trades {
+                        if last_seq.is_none_or(|seq| trade.trade_seq > seq) {
+                            last_seq = Some(trade.trade_seq);
+                            trades.push_back(trade);
+                        }
+                    }
+                    request = match last_timestamp {
+                        Some(timestamp) if page.has_more => {
+                            // A full page within one millisecond would be fetched forever
+                            next.time_range.start_timestamp = if timestamp > start {
+                                timestamp
+                            } else {
+                                tracing::warn!(timestamp, "skipping trades of a full millisecond");
+                                timestamp + 1
+                            }
+                            .into();
+                            Some(next)
+                        }
+                        _ => None,
+                    };
+                }
+            },
+        )
+    }
+}
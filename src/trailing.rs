@@ -0,0 +1,226 @@
+//! Trailing stops kept by the client, see `OrderManager::trailing_stop`.
+//!
+//! The stop is an ordinary reduce-only stop-market order in the book, so the exchange
+//! triggers it even while the client is down. The client only moves its trigger price
+//! with `private/edit` as the followed price makes new highs (for a long) or lows (for a
+//! short), so it stops trailing, but keeps protecting, when the client stops.
+
+use crate::{
+    Direction, Instrument, Order, OrderBuilder, OrderManager, OrderState, PrivateEditRequest,
+    Result, SubscriptionInterval, TickerInstrumentNameChannel, TickerNotification, Trigger,
+    UserOrdersInstrumentNameRawChannel, money, money_f64,
+};
+use futures_util::{Stream, StreamExt};
+
+/// How far a trailing stop stays from the best price.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailDistance {
+    /// A fixed distance in price.
+    Price(f64),
+    /// A fraction of the best price, e.g. 0.02 for 2%.
+    Ratio(f64),
+}
+
+/// A stop that follows the price, see `OrderManager::trailing_stop`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrailingStop {
+    /// Labels the stop order.
+    pub label: String,
+    /// The side of the position protected: a long (`Buy`) by a sell stop below the
+    /// price, a short by a buy stop above it.
+    pub position: Direction,
+    pub amount: f64,
+    pub distance: TrailDistance,
+    /// The price followed, and the one the stop triggers on.
+    pub trigger: Trigger,
+    /// Smallest move of the stop worth an edit, to keep within rate limits.
+    pub min_step: f64,
+}
+
+impl TrailingStop {
+    fn is_long(&self) -> bool {
+        self.position != Direction::Sell
+    }
+
+    /// The trigger price of the stop for the best price seen so far.
+    pub fn stop_price(&self, best_price: f64) -> f64 {
+        let distance = match self.distance {
+            TrailDistance::Price(distance) => distance,
+            TrailDistance::Ratio(ratio) => best_price * ratio,
+        };
+        if self.is_long() {
+            best_price - distance
+        } else {
+            best_price + distance
+        }
+    }
+
+    fn price(&self, ticker: &TickerNotification) -> f64 {
+        match self.trigger {
+            Trigger::IndexPrice => money_f64(ticker.index_price),
+            Trigger::LastPrice => money_f64(ticker.last_price),
+            _ => money_f64(ticker.mark_price),
+        }
+    }
+}
+
+/// A change of a trailing stop, yielded by `OrderManager::trailing_stop`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrailingStopEvent {
+    /// The stop order was placed at the first price.
+    Placed(Order),
+    /// The stop was moved after the price.
+    Moved(Order),
+    /// The stop order is done: triggered and filled, or cancelled. A triggered stop
+    /// fills through the order it created, which is the one given. Ends the stream.
+    Done(Order),
+}
+
+// Moved once per input, not worth boxing the order for
+#[allow(clippy::large_enum_variant)]
+enum Input {
+    Price(Result<f64>),
+    Order(Result<Order>),
+}
+
+struct Trail {
+    manager: OrderManager,
+    instrument: Instrument,
+    stop: TrailingStop,
+    best_price: Option<f64>,
+    order: Option<Order>,
+    done: bool,
+}
+
+impl Trail {
+    async fn follow(&mut self, price: f64) -> Result<Option<TrailingStopEvent>> {
+        let best_price = match self.best_price {
+            Some(best) if self.stop.is_long() => best.max(price),
+            Some(best) => best.min(price),
+            None => price,
+        };
+        self.best_price = Some(best_price);
+        let stop_price = self
+            .instrument
+            .round_price_to_tick(self.stop.stop_price(best_price));
+        if self
+            .order
+            .as_ref()
+            .is_some_and(|order| order.order_state == OrderState::Triggered)
+        {
+            return Ok(None);
+        }
+        let Some(order) = &self.order else {
+            let exit = if self.stop.is_long() {
+                Direction::Sell
+            } else {
+                Direction::Buy
+            };
+            let request = OrderBuilder::stop_market(
+                exit,
+                self.stop.amount,
+                stop_price,
+                self.stop.trigger.clone(),
+            )
+            .label(&self.stop.label)
+            .reduce_only()
+            .build(&self.instrument)?;
+            let order = self.manager.place(request).await?.order;
+            self.order = Some(order.clone());
+            return Ok(Some(TrailingStopEvent::Placed(order)));
+        };
+        let current = order.trigger_price.map_or(stop_price, money_f64);
+        let gain = if self.stop.is_long() {
+            stop_price - current
+        } else {
+            current - stop_price
+        };
+        if gain <= 0.0 || gain < self.stop.min_step {
+            return Ok(None);
+        }
+        let order = self
+            .manager
+            .edit(PrivateEditRequest {
+                order_id: order.order_id.clone(),
+                amount: order.amount,
+                trigger_price: Some(money(stop_price)?),
+                ..Default::default()
+            })
+            .await?
+            .order;
+        self.order = Some(order.clone());
+        Ok(Some(TrailingStopEvent::Moved(order)))
+    }
+
+    fn notified(&mut self, order: Order) -> Option<TrailingStopEvent> {
+        let order = self.manager.notified(order)?;
+        let ours = self.order.as_ref()?;
+        if order.order_id == ours.order_id {
+            self.order = Some(order.clone());
+        } else if order.trigger_order_id.as_ref() != Some(&ours.order_id) {
+            return None;
+        }
+        // Once triggered, the stop stays `triggered` and the order it created fills
+        self.done = order.is_done();
+        self.done.then_some(TrailingStopEvent::Done(order))
+    }
+}
+
+impl OrderManager {
+    /// Protects a position in `instrument` with a stop that trails the price `stop`
+    /// follows. The stop order is placed at the first ticker and moved each time the
+    /// stop would improve by at least `min_step`; it never moves back.
+    ///
+    /// Once the stop triggers it is no longer moved, and the stream ends when the order
+    /// it created is done. The stop only trails while the stream is polled. An error
+    /// placing or editing the order is yielded, and retried at the next price.
+    pub async fn trailing_stop(
+        &self,
+        instrument: &Instrument,
+        stop: TrailingStop,
+    ) -> Result<impl Stream<Item = Result<TrailingStopEvent>> + Send + 'static + use<>> {
+        let orders = self
+            .client
+            .subscribe(UserOrdersInstrumentNameRawChannel {
+                instrument_name: instrument.instrument_name.clone(),
+            })
+            .await?;
+        let tickers = self
+            .client
+            .subscribe(TickerInstrumentNameChannel {
+                instrument_name: instrument.instrument_name.clone(),
+                interval: SubscriptionInterval::_100ms,
+            })
+            .await?;
+        let followed = stop.clone();
+        let prices =
+            tickers.map(move |ticker| Input::Price(ticker.map(|ticker| followed.price(&ticker))));
+        let inputs = futures_util::stream::select(prices, orders.map(Input::Order));
+        let trail = Trail {
+            manager: self.clone(),
+            instrument: instrument.clone(),
+            stop,
+            best_price: None,
+            order: None,
+            done: false,
+        };
+        Ok(futures_util::stream::unfold(
+            (trail, inputs.boxed()),
+            |(mut trail, mut inputs)| async move {
+                loop {
+                    if trail.done {
+                        return None;
+                    }
+                    let event = match inputs.next().await? {
+                        Input::Price(Ok(price)) => trail.follow(price).await.transpose(),
+                        Input::Order(Ok(order)) => trail.notified(order).map(Ok),
+                        Input::Price(Err(e)) | Input::Order(Err(e)) => Some(Err(e)),
+                    };
+                    if let Some(event) = event {
+                        return Some((event, (trail, inputs)));
+                    }
+                }
+            },
+        ))
+    }
+}
@@ -0,0 +1,64 @@
+//! The complete transaction log of an account over a time range, see
+//! `DeribitClient::transaction_log`.
+//!
+//! `private/get_transaction_log` returns the entries between `start_timestamp` and
+//! `end_timestamp` newest first, at most `count` (up to 250) per call, with a numeric
+//! `continuation` to pass back for the next page; it is zero on the last one.
+
+use crate::{DeribitClient, PrivateGetTransactionLogRequest, Result, TransactionLog};
+use futures_util::{Stream, StreamExt};
+use std::sync::Arc;
+
+// Largest page `private/get_transaction_log` returns
+const MAX_PAGE_SIZE: i64 = 250;
+
+/// The category of a transaction log entry, from its `type`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TransactionType {
+    Trade,
+    Deposit,
+    Withdrawal,
+    Settlement,
+    Delivery,
+    Transfer,
+    Swap,
+    Correction,
+    /// A type Deribit added since, as reported.
+    Other(String),
+}
+
+impl TransactionLog {
+    pub fn kind(&self) -> TransactionType {
+        match self.r#type.as_str() {
+            "trade" => TransactionType::Trade,
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "settlement" => TransactionType::Settlement,
+            "delivery" => TransactionType::Delivery,
+            "transfer" => TransactionType::Transfer,
+            "swap" => TransactionType::Swap,
+            "correction" => TransactionType::Correction,
+            other => TransactionType::Other(other.to_string()),
+        }
+    }
+}
+
+impl DeribitClient {
+    /// Yields every entry of the transaction log `request` selects, newest first,
+    /// following `continuation` from page to page. Entries outside
+    /// `request.time_range` are left out, and pages are as large as Deribit allows
+    /// unless `count` is set. An error ends the stream.
+    pub fn transaction_log(
+        self: &Arc<Self>,
+        mut request: PrivateGetTransactionLogRequest,
+    ) -> impl Stream<Item = Result<TransactionLog>> + Send + 'static + use<> {
+        request.count.get_or_insert(MAX_PAGE_SIZE);
+        let range = request.time_range.start_timestamp..=request.time_range.end_timestamp;
+        self.paginate(request).filter(move |entry| {
+            let keep = entry
+                .as_ref()
+                .map_or(true, |entry| range.contains(&entry.timestamp));
+            std::future::ready(keep)
+        })
+    }
+}
@@ -0,0 +1,71 @@
+use deribit_api::*;
+use futures_util::StreamExt;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+// Answers calls from canned results by method, recording the methods called
+#[derive(Default)]
+struct MockApi {
+    results: HashMap<&'static str, Value>,
+    calls: Mutex<Vec<String>>,
+}
+
+impl DeribitApi for MockApi {
+    fn call<T: ApiRequest + Send>(
+        &self,
+        request: T,
+    ) -> impl Future<Output = Result<T::Response>> + Send {
+        let method = request.method_name();
+        self.calls.lock().unwrap().push(method.to_string());
+        let result = self.results.get(method).cloned().unwrap_or_default();
+        async move { Ok(serde_json::from_value(result)?) }
+    }
+
+    fn subscribe<S: Subscription + Send + 'static>(
+        &self,
+        subscription: S,
+    ) -> impl Future<Output = Result<SubscriptionStream<S::Data>>> + Send {
+        let ticks = [
+            Ok(json!({ "last_price": 100.0 })),
+            Ok(json!({ "last_price": 101.5 })),
+        ];
+        let stream = SubscriptionStream::from_json(
+            subscription.channel_string(),
+            futures_util::stream::iter(ticks),
+        );
+        async move { Ok(stream) }
+    }
+}
+
+// Strategy code only knows the trait
+async fn last_price(api: &impl DeribitApi, instrument_name: &str) -> Result<Option<Money>> {
+    let mut ticker = api
+        .subscribe(TickerInstrumentNameChannel {
+            instrument_name: instrument_name.to_string(),
+            interval: SubscriptionInterval::_100ms,
+        })
+        .await?;
+    let mut last = None;
+    while let Some(tick) = ticker.next().await {
+        last = Some(tick?.last_price);
+    }
+    Ok(last)
+}
+
+#[tokio::test]
+async fn strategies_run_against_a_mock() {
+    let api = MockApi {
+        results: HashMap::from([("public/get_time", json!(1_700_000_000_000_i64))]),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        last_price(&api, "BTC-PERPETUAL").await.unwrap(),
+        Some("101.5".parse().unwrap())
+    );
+    // The endpoint methods work on any implementation
+    assert_eq!(api.get_time().await.unwrap(), Timestamp(1_700_000_000_000));
+    assert_eq!(*api.calls.lock().unwrap(), ["public/get_time"]);
+}
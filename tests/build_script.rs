@@ -0,0 +1,5 @@
+// The build script generating the client, included to test its pure parts on small
+// fixture specs instead of the downloaded ones
+#[allow(dead_code)]
+#[path = "../build.rs"]
+mod build;
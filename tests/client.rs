@@ -0,0 +1,4218 @@
+use deribit_api::integrity::{Gap, GapDetected, GapEvent};
+use deribit_api::*;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+// Starts a WebSocket server on a random local port that answers every request with
+// the messages returned by `handle`, and returns its URL. Notifications are sent after
+// a short pause so the client has registered the subscription by then.
+async fn mock_server<F>(handle: F) -> String
+where
+    F: Fn(&Value) -> Vec<Value> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws.next().await {
+            let request: Value = serde_json::from_str(&text).unwrap();
+            let mut paused = false;
+            for message in handle(&request) {
+                if message.get("method").is_some() && !paused {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    paused = true;
+                }
+                ws.send(Message::Text(message.to_string().into()))
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+    format!("ws://{addr}")
+}
+
+// Like `mock_server`, for clients that connect more than once: accepts any number of
+// connections, answering requests on each with `handle`.
+async fn reconnectable_server<F>(handle: F) -> String
+where
+    F: Fn(&Value) -> Vec<Value> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = std::sync::Arc::new(handle);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(text))) = ws.next().await {
+                    let request: Value = serde_json::from_str(&text).unwrap();
+                    for message in handle(&request) {
+                        ws.send(Message::Text(message.to_string().into()))
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    format!("ws://{addr}")
+}
+
+fn response(request: &Value, result: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": request["id"],
+        "result": result,
+        "testnet": false,
+        "usIn": 1_000,
+        "usOut": 1_250,
+        "usDiff": 250,
+    })
+}
+
+fn notification(channel: &str, data: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "subscription",
+        "params": { "channel": channel, "data": data },
+    })
+}
+
+// Confirms subscriptions and then publishes `count` numbered messages on each channel.
+fn subscribe_and_publish(request: &Value, count: u64) -> Vec<Value> {
+    let channels = request["params"]["channels"].clone();
+    let mut messages = vec![response(request, channels.clone())];
+    for i in 0..count {
+        for channel in channels.as_array().unwrap() {
+            messages.push(notification(channel.as_str().unwrap(), json!(i)));
+        }
+    }
+    messages
+}
+
+#[tokio::test]
+async fn custom_env_connects_to_given_url() {
+    let url = mock_server(|request| {
+        assert_eq!(request["method"], "public/get_time");
+        vec![response(request, json!(1_755_765_833_825i64))]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let (time, meta) = client
+        .call_with_meta(PublicGetTimeRequest {})
+        .await
+        .unwrap();
+    assert_eq!(time, Timestamp(1_755_765_833_825));
+    assert_eq!(
+        meta,
+        ResponseMeta {
+            testnet: false,
+            us_in: 1_000,
+            us_out: 1_250,
+            us_diff: 250,
+        }
+    );
+}
+
+#[tokio::test]
+async fn gateway_is_verified_against_its_environment_on_connect() {
+    let handle = |request: &Value| {
+        assert_eq!(request["method"], "public/test");
+        vec![response(request, json!({ "version": "1.2.26" }))]
+    };
+
+    let env = Env::Gateway {
+        url: mock_server(handle).await,
+        testnet: false,
+    };
+    DeribitClient::connect(env).await.unwrap();
+
+    let env = Env::Gateway {
+        url: mock_server(handle).await,
+        testnet: true,
+    };
+    let error = DeribitClient::connect(env).await.unwrap_err();
+    assert!(matches!(
+        error,
+        Error::EnvironmentMismatch {
+            expected_testnet: true
+        }
+    ));
+}
+
+#[tokio::test]
+async fn info_reports_limits_and_reconnects() {
+    let url =
+        reconnectable_server(|request| vec![response(request, json!(1_755_765_833_825i64))]).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .rate_limit(RateLimit {
+            max_credits: 1_000,
+            refill_per_second: 1_000,
+            cost: 500,
+        })
+        .retry_rate_limited(2)
+        .circuit_breaker(CircuitBreakerConfig::default())
+        .slow_call_threshold(std::time::Duration::from_millis(50))
+        .max_in_flight_requests(std::num::NonZeroUsize::new(4).unwrap())
+        .connect()
+        .await
+        .unwrap();
+
+    let info = client.info();
+    assert_eq!(info.remaining_credits, Some(1_000));
+    assert_eq!(info.rate_limit.unwrap().cost, 500);
+    assert_eq!(info.rate_limit_retries, 2);
+    assert_eq!(info.rate_limited_for, None);
+    assert_eq!(info.circuit_state, Some(CircuitState::Closed));
+    assert_eq!(
+        info.slow_call_threshold,
+        Some(std::time::Duration::from_millis(50))
+    );
+    assert_eq!(info.max_in_flight_requests, Some(4));
+    assert_eq!(info.reconnects, 0);
+
+    // The third call waits for the bucket to refill
+    let started = std::time::Instant::now();
+    for _ in 0..3 {
+        client.call(PublicGetTimeRequest {}).await.unwrap();
+    }
+    assert!(started.elapsed() >= std::time::Duration::from_millis(400));
+    assert!(client.info().remaining_credits.unwrap() < 500);
+
+    let client = client.reconnect().await.unwrap();
+    let client = client.reconnect().await.unwrap();
+    let info = client.info();
+    assert_eq!(info.reconnects, 2);
+    // Builder settings carry over
+    assert_eq!(info.remaining_credits, Some(1_000));
+    assert_eq!(info.rate_limit_retries, 2);
+    assert_eq!(info.circuit_state, Some(CircuitState::Closed));
+    assert_eq!(
+        info.slow_call_threshold,
+        Some(std::time::Duration::from_millis(50))
+    );
+    assert_eq!(info.max_in_flight_requests, Some(4));
+}
+
+#[tokio::test]
+async fn undecodable_response_fails_its_call() {
+    let url = mock_server(|request| {
+        vec![json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "testnet": false,
+            "usIn": 1_000,
+            "usOut": 1_250,
+            "usDiff": 250,
+        })]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let result = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        client.call(PublicGetTimeRequest {}),
+    )
+    .await
+    .expect("the call is answered");
+    assert!(matches!(result, Err(Error::JsonError(_))));
+    assert_eq!(client.info().pending_requests, 0);
+}
+
+#[tokio::test]
+async fn decode_workers_preserve_per_channel_order() {
+    let url = mock_server(|request| subscribe_and_publish(request, 50)).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .decode_workers(4)
+        .connect()
+        .await
+        .unwrap();
+
+    for channel in ["trades.BTC-PERPETUAL.raw", "trades.ETH-PERPETUAL.raw"] {
+        let stream = client.subscribe_raw(channel).await.unwrap();
+        let received = stream
+            .take(50)
+            .map(|msg| msg.unwrap().get().parse::<u64>().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received, (0..50).collect::<Vec<_>>());
+    }
+
+    let info = client.info();
+    assert!(!info.authenticated);
+    assert_eq!(info.decode_workers, 4);
+    assert_eq!(
+        info.subscriptions,
+        vec!["trades.BTC-PERPETUAL.raw", "trades.ETH-PERPETUAL.raw"]
+    );
+}
+
+#[tokio::test]
+async fn raw_bytes_subscription_yields_frames_unchanged() {
+    for workers in [0, 2] {
+        let url = mock_server(|request| subscribe_and_publish(request, 3)).await;
+        let client = DeribitClient::builder(Env::Custom(url))
+            .decode_workers(workers)
+            .connect()
+            .await
+            .unwrap();
+
+        let frames = client
+            .subscribe_raw_bytes("trades.BTC-PERPETUAL.raw")
+            .await
+            .unwrap();
+        let received = frames
+            .take(3)
+            .map(|frame| frame.unwrap().to_string())
+            .collect::<Vec<_>>()
+            .await;
+        let expected = (0..3)
+            .map(|i| notification("trades.BTC-PERPETUAL.raw", json!(i)).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
+}
+
+#[tokio::test]
+#[allow(clippy::result_large_err)]
+async fn handshake_sends_custom_headers() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut headers = None;
+        let _ws = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response| {
+            headers = Some(request.headers().clone());
+            Ok::<Response, _>(response)
+        })
+        .await
+        .unwrap();
+        headers.unwrap()
+    });
+
+    let _client = DeribitClient::builder(Env::Custom(url))
+        .user_agent("my-bot/1.0")
+        .header("X-Request-Source", "tests")
+        .connect()
+        .await
+        .unwrap();
+
+    let headers = server.await.unwrap();
+    assert_eq!(headers["user-agent"], "my-bot/1.0");
+    assert_eq!(headers["x-request-source"], "tests");
+}
+
+#[tokio::test]
+async fn builder_credentials_authenticate_on_connect() {
+    let url = mock_server(|request| {
+        assert_eq!(request["method"], "public/auth");
+        assert_eq!(request["params"]["grant_type"], "client_credentials");
+        assert_eq!(request["params"]["client_id"], "id");
+        assert_eq!(request["params"]["client_secret"], "secret");
+        vec![response(
+            request,
+            json!({ "access_token": "a", "refresh_token": "r", "expires_in": 900, "scope": "trade:read" }),
+        )]
+    })
+    .await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .credentials(Credentials::new("id", "secret"))
+        .connect()
+        .await
+        .unwrap();
+    assert!(client.info().authenticated);
+}
+
+// Answers auth with a token, get_time with a timestamp and everything else with "ok", recording the methods called.
+fn recording_server(
+    methods: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+) -> impl Fn(&Value) -> Vec<Value> + Send + 'static {
+    move |request| {
+        let method = request["method"].as_str().unwrap();
+        methods.lock().unwrap().push(method.to_string());
+        let result = match method {
+            "public/auth" => {
+                json!({ "access_token": "a", "refresh_token": "r", "expires_in": 900, "scope": "trade:read_write" })
+            }
+            "public/get_time" => json!(1_755_765_833_825i64),
+            _ => json!("ok"),
+        };
+        vec![response(request, result)]
+    }
+}
+
+#[tokio::test]
+async fn safety_sets_heartbeat_and_cancel_on_disconnect() {
+    let methods = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let url = mock_server(recording_server(methods.clone())).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .credentials(Credentials::new("id", "secret"))
+        .safety(SafetyConfig {
+            heartbeat_interval: Some(30),
+            cancel_on_disconnect: true,
+            inactivity_timeout: None,
+        })
+        .connect()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        *methods.lock().unwrap(),
+        vec![
+            "public/set_heartbeat",
+            "public/auth",
+            "private/enable_cancel_on_disconnect"
+        ]
+    );
+    let info = client.info();
+    assert_eq!(info.heartbeat_interval, Some(30));
+    assert!(info.cancel_on_disconnect);
+}
+
+#[tokio::test]
+async fn inactivity_watchdog_cancels_all_orders_once_idle() {
+    let methods = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let url = mock_server(recording_server(methods.clone())).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .credentials(Credentials::new("id", "secret"))
+        .safety(SafetyConfig {
+            inactivity_timeout: Some(std::time::Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .connect()
+        .await
+        .unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+    // Fires once per idle period, not on every check
+    assert_eq!(
+        *methods.lock().unwrap(),
+        vec!["public/auth", "private/cancel_all"]
+    );
+
+    client.call(PublicGetTimeRequest {}).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+    assert_eq!(
+        methods
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|method| *method == "private/cancel_all")
+            .count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn inactivity_watchdog_waits_for_authentication() {
+    let methods = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let url = mock_server(recording_server(methods.clone())).await;
+
+    let _client = DeribitClient::builder(Env::Custom(url))
+        .safety(SafetyConfig {
+            inactivity_timeout: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        })
+        .connect()
+        .await
+        .unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(methods.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn calls_outside_the_granted_scope_are_refused() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/auth" => vec![response(
+            request,
+            json!({ "access_token": "a", "refresh_token": "r", "expires_in": 900, "scope": "account:read trade:read_write wallet:none" }),
+        )],
+        "private/get_positions" => vec![response(request, json!([]))],
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let buy = PrivateBuyRequest::default();
+    assert_eq!(
+        buy.required_scope().unwrap().to_string(),
+        "trade:read_write"
+    );
+    assert_eq!(PublicGetTimeRequest {}.required_scope(), None);
+    let reset = PrivateResetMmpRequest::default().required_scope().unwrap();
+    assert_eq!(
+        reset.to_string(),
+        "trade:read_write or block_rfq:read_write"
+    );
+    assert!(reset.granted_by("block_rfq:read_write"));
+    assert!(!reset.granted_by("trade:read block_rfq:read"));
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .credentials(Credentials::new("id", "secret"))
+        .connect()
+        .await
+        .unwrap();
+    client
+        .call(PrivateGetPositionsRequest::default())
+        .await
+        .unwrap();
+    let withdrawal = PrivateGetWithdrawalsRequest {
+        currency: Currency::Btc,
+        ..Default::default()
+    };
+    match client.call(withdrawal).await {
+        Err(Error::InsufficientScope { method, required }) => {
+            assert_eq!(method, "private/get_withdrawals");
+            assert_eq!(required.areas, ["wallet"]);
+        }
+        other => panic!("expected InsufficientScope, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn endpoint_methods_call_without_request_structs() {
+    let url = mock_server(|request| {
+        assert_eq!(request["method"], "public/get_order_book");
+        assert_eq!(
+            request["params"],
+            json!({ "instrument_name": "BTC-PERPETUAL", "depth": 5 })
+        );
+        vec![response(
+            request,
+            json!({ "instrument_name": "BTC-PERPETUAL", "bids": [[100.0, 10.0]], "asks": [] }),
+        )]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let book = client
+        .get_order_book("BTC-PERPETUAL", Some(5))
+        .await
+        .unwrap();
+    assert_eq!(book.instrument_name, "BTC-PERPETUAL");
+}
+
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn service_calls_through_tower_middleware() {
+    use tower::ServiceExt;
+
+    let url = mock_server(|request| {
+        assert_eq!(request["method"], "public/get_time");
+        vec![response(request, json!(1_700_000_000_000_i64))]
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let service = tower::timeout::Timeout::new(client.service(), std::time::Duration::from_secs(5));
+    let time = service.oneshot(PublicGetTimeRequest {}).await.unwrap();
+    assert_eq!(time, Timestamp(1_700_000_000_000));
+}
+
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn service_is_not_ready_while_in_flight_calls_are_at_the_limit() {
+    use tower::ServiceExt;
+
+    let calls = std::sync::atomic::AtomicUsize::new(0);
+    let url = mock_server(move |request| {
+        // The first call is never answered, so it keeps its slot
+        match calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+            0 => vec![],
+            _ => vec![response(request, json!(1_700_000_000_000_i64))],
+        }
+    })
+    .await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .max_in_flight_requests(std::num::NonZeroUsize::new(1).unwrap())
+        .connect()
+        .await
+        .unwrap();
+    let client = std::sync::Arc::new(client);
+    let stuck = tokio::spawn(client.service().oneshot(PublicGetTimeRequest {}));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let shed = tower::load_shed::LoadShed::new(client.service());
+    let error = shed.oneshot(PublicGetTimeRequest {}).await.unwrap_err();
+    assert!(error.is::<tower::load_shed::error::Overloaded>());
+    stuck.abort();
+    let _ = stuck.await;
+    let time = client.service().oneshot(PublicGetTimeRequest {}).await;
+    assert_eq!(time.unwrap(), Timestamp(1_700_000_000_000));
+}
+
+#[tokio::test]
+async fn builder_signs_auth_without_sending_the_secret() {
+    use hmac::{Hmac, Mac};
+
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        assert_eq!(params["grant_type"], "client_signature");
+        assert_eq!(params["client_id"], "id");
+        assert_eq!(params["client_secret"], "");
+        let payload = format!(
+            "{}\n{}\n{}",
+            params["timestamp"],
+            params["nonce"].as_str().unwrap(),
+            params["data"].as_str().unwrap()
+        );
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(payload.as_bytes());
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        assert_eq!(params["signature"], expected);
+        vec![response(
+            request,
+            json!({ "access_token": "a", "refresh_token": "r", "expires_in": 900, "scope": "trade:read" }),
+        )]
+    })
+    .await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .credentials(Credentials::new("id", "secret"))
+        .sign_auth()
+        .connect()
+        .await
+        .unwrap();
+    assert!(client.info().authenticated);
+}
+
+#[tokio::test]
+async fn sessions_are_forked_onto_new_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let connections = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection = accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(text))) = ws.next().await {
+                    let request: Value = serde_json::from_str(&text).unwrap();
+                    let params = &request["params"];
+                    let tokens = |refresh_token: &str| {
+                        json!({
+                            "access_token": "access",
+                            "refresh_token": refresh_token,
+                            "expires_in": 900,
+                            "scope": "trade:read_write",
+                            "token_type": "bearer",
+                        })
+                    };
+                    let result = match (connection, request["method"].as_str().unwrap()) {
+                        (0, "public/auth") => {
+                            assert_eq!(params["grant_type"], "client_credentials");
+                            tokens("main-refresh")
+                        }
+                        (1, "public/exchange_token") => {
+                            assert_eq!(params["refresh_token"], "main-refresh");
+                            assert_eq!(params["subject_id"], 11);
+                            tokens("sub-refresh")
+                        }
+                        (1, "public/auth") => {
+                            assert_eq!(params["grant_type"], "refresh_token");
+                            assert_eq!(params["refresh_token"], "sub-refresh");
+                            tokens("sub-refresh-2")
+                        }
+                        (connection, method) => panic!("unexpected {method} on {connection}"),
+                    };
+                    let message = response(&request, result).to_string();
+                    ws.send(Message::Text(message.into())).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .slow_call_threshold(std::time::Duration::from_millis(50))
+        .connect()
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.fork_session(11).await,
+        Err(Error::NotAuthenticated)
+    ));
+    client
+        .call(PublicAuthRequest {
+            grant_type: PublicAuthGrantType::ClientCredentials,
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(!format!("{client:?}").contains("main-refresh"));
+
+    let subaccount = client.fork_session(11).await.unwrap();
+    assert!(subaccount.info().authenticated);
+    assert_eq!(
+        subaccount.info().slow_call_threshold,
+        Some(std::time::Duration::from_millis(50))
+    );
+    assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 2);
+    client.cancellation_token().cancel();
+    subaccount.disconnected().await;
+}
+
+#[tokio::test]
+async fn named_sessions_share_the_connection() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        let tokens = |access_token: &str| {
+            json!({
+                "access_token": access_token,
+                "refresh_token": format!("{access_token}-refresh"),
+                "expires_in": 900,
+                "scope": "session:bot trade:read_write",
+                "token_type": "bearer",
+            })
+        };
+        match request["method"].as_str().unwrap() {
+            "public/auth" => vec![response(request, tokens("main"))],
+            "public/fork_token" => {
+                assert_eq!(params["refresh_token"], "main-refresh");
+                assert_eq!(params["session_name"], "hedger");
+                vec![response(request, tokens("hedger"))]
+            }
+            "private/get_positions" => {
+                assert_eq!(params["access_token"], "hedger");
+                assert_eq!(params["currency"], "BTC");
+                vec![response(request, json!([]))]
+            }
+            "private/subscribe" => {
+                assert_eq!(params["access_token"], "hedger");
+                vec![response(request, params["channels"].clone())]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    client
+        .call(PublicAuthRequest {
+            grant_type: PublicAuthGrantType::ClientCredentials,
+            scope: Some("session:bot".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(client.session("hedger").is_none());
+    let hedger = client.open_session("hedger").await.unwrap();
+    let positions = hedger
+        .call(PrivateGetPositionsRequest {
+            currency: Some(CurrencyWithAny::Btc),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(positions.is_empty());
+
+    let portfolio = UserPortfolioCurrencyChannel {
+        currency: CurrencyWithAny::Btc,
+    };
+    let channel = portfolio.channel_string();
+    let _portfolio = hedger.subscribe(portfolio).await.unwrap();
+    assert_eq!(client.session_names(), ["hedger"]);
+    assert_eq!(
+        client.subscription_session(&channel).as_deref(),
+        Some("hedger")
+    );
+    assert_eq!(client.session("hedger").unwrap().subscriptions(), [channel]);
+}
+
+#[tokio::test]
+async fn calls_can_be_made_with_another_access_token() {
+    let url = mock_server(|request| {
+        assert_eq!(request["method"], "private/get_open_orders");
+        assert_eq!(request["params"], json!({ "access_token": "other" }));
+        vec![response(request, json!([]))]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let orders = client
+        .call_as("other", PrivateGetOpenOrdersRequest::default())
+        .await
+        .unwrap();
+    assert!(orders.is_empty());
+}
+
+#[tokio::test]
+async fn subscription_capacity_is_configurable_per_channel() {
+    let url = mock_server(|request| subscribe_and_publish(request, 300)).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .subscription_capacity("book.*.raw", 500)
+        .connect()
+        .await
+        .unwrap();
+
+    let book = client
+        .subscribe_raw("book.BTC-PERPETUAL.raw")
+        .await
+        .unwrap();
+    let trades = client
+        .subscribe_raw("trades.BTC-PERPETUAL.raw")
+        .await
+        .unwrap();
+    let ticker = client
+        .subscribe_raw_with_capacity("ticker.BTC-PERPETUAL.raw", 300)
+        .await
+        .unwrap();
+    // Let everything be published before reading
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let received = book
+        .take(300)
+        .map(|msg| msg.unwrap().get().parse::<u64>().unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(received, (0..300).collect::<Vec<_>>());
+    let received = ticker
+        .take(300)
+        .map(|msg| msg.unwrap().get().parse::<u64>().unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(received, (0..300).collect::<Vec<_>>());
+    let mut trades = Box::pin(trades);
+    assert!(matches!(
+        trades.next().await,
+        Some(Err(Error::SubscriptionLagged(_)))
+    ));
+
+    let stats = client.subscription_stats();
+    let channels = stats.iter().map(|s| s.channel.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        channels,
+        [
+            "book.BTC-PERPETUAL.raw",
+            "ticker.BTC-PERPETUAL.raw",
+            "trades.BTC-PERPETUAL.raw"
+        ]
+    );
+    assert!(
+        stats
+            .iter()
+            .all(|s| s.messages == 300 && s.last_message.is_some())
+    );
+    // The book and ticker streams were dropped after reading
+    assert_eq!(stats[0].subscribers, 0);
+    assert_eq!((stats[0].lag_events, stats[0].lagged_messages), (0, 0));
+    assert_eq!(stats[2].subscribers, 1);
+    assert_eq!(stats[2].lag_events, 1);
+    assert!(stats[2].lagged_messages > 0);
+}
+
+#[tokio::test]
+async fn conflated_subscription_yields_latest_message() {
+    let url = mock_server(|request| subscribe_and_publish(request, 300)).await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let mut ticker = Box::pin(
+        client
+            .subscribe_raw_conflated("ticker.BTC-PERPETUAL.raw")
+            .await
+            .unwrap(),
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    assert_eq!(ticker.next().await.unwrap().unwrap().get(), "299");
+    let next = tokio::time::timeout(std::time::Duration::from_millis(50), ticker.next()).await;
+    assert!(next.is_err(), "no messages after the latest");
+}
+
+#[tokio::test]
+async fn clock_sync_estimates_server_offset() {
+    // A server clock running an hour ahead
+    let url = mock_server(|request| {
+        let server_now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64
+            + 3_600_000_000;
+        let mut reply = response(request, json!(server_now / 1000));
+        reply["usIn"] = json!(server_now);
+        reply["usOut"] = json!(server_now);
+        vec![reply]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let clock = ClockSync::default();
+    let round_trip = clock.sync(&client, 3).await.unwrap();
+
+    let error = (clock.offset_micros() - 3_600_000_000).abs();
+    assert!(
+        error <= round_trip.as_micros() as i64 + 1_000,
+        "off by {error}µs"
+    );
+}
+
+// Channel publishing plain numbers, as `subscribe_and_publish` does
+#[derive(Clone)]
+struct Numbers(&'static str);
+
+impl Subscription for Numbers {
+    type Data = u64;
+    fn channel_string(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+#[tokio::test]
+async fn subscription_stream_adapters() {
+    let url = mock_server(|request| subscribe_and_publish(request, 300)).await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let latest = client
+        .subscribe(Numbers("ticker.BTC-PERPETUAL.raw"))
+        .await
+        .unwrap();
+    let sample = client
+        .subscribe(Numbers("ticker.ETH-PERPETUAL.raw"))
+        .await
+        .unwrap();
+    let batch = client
+        .subscribe_with_capacity(Numbers("trades.BTC-PERPETUAL.raw"), 1000)
+        .await
+        .unwrap();
+    let window = client
+        .subscribe_with_capacity(Numbers("trades.ETH-PERPETUAL.raw"), 1000)
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    // Tokio's cooperative budget may interrupt skipping ahead, but only a few times
+    for skipping in [
+        Box::pin(latest.latest()) as std::pin::Pin<Box<dyn futures_util::Stream<Item = _>>>,
+        Box::pin(sample.sample(std::time::Duration::from_millis(20))),
+    ] {
+        let received = skipping
+            .map(|msg| msg.unwrap())
+            .take_while(|&n| std::future::ready(n != 299))
+            .collect::<Vec<_>>()
+            .await;
+        assert!(received.len() < 5, "received {received:?}");
+    }
+    let mut batch = Box::pin(batch.batch(100));
+    assert_eq!(
+        batch.next().await.unwrap().unwrap(),
+        (0..100).collect::<Vec<_>>()
+    );
+    let mut window = Box::pin(window.window(std::time::Duration::from_millis(20)));
+    assert_eq!(
+        window.next().await.unwrap().unwrap(),
+        (0..300).collect::<Vec<_>>()
+    );
+}
+
+// Number of `Counted` values deserialized so far
+static DECODED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct Counted(u64);
+
+impl<'de> serde::Deserialize<'de> for Counted {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        DECODED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        u64::deserialize(deserializer).map(Counted)
+    }
+}
+
+struct CountedNumbers(&'static str);
+
+impl Subscription for CountedNumbers {
+    type Data = Counted;
+    fn channel_string(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+#[tokio::test]
+async fn adaptive_subscription_steps_interval_down_under_lag() {
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    let url = mock_server(move |request| {
+        let method = request["method"].as_str().unwrap();
+        let channel = request["params"]["channels"][0].as_str().unwrap();
+        seen.lock().unwrap().push(format!("{method} {channel}"));
+        match method {
+            "public/subscribe" => subscribe_and_publish(request, 10),
+            _ => vec![response(request, request["params"]["channels"].clone())],
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(
+        DeribitClient::builder(Env::Custom(url))
+            .subscription_capacity("*", 1)
+            .connect()
+            .await
+            .unwrap(),
+    );
+    let config = AdaptiveIntervalConfig {
+        max_lag_events: 1,
+        recover_after: std::time::Duration::ZERO,
+        ..Default::default()
+    };
+    let ticker = TickerInstrumentNameChannel {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        interval: SubscriptionInterval::Raw,
+    };
+    let mut events = Box::pin(client.subscribe_adaptive(ticker, config).await.unwrap());
+    // Let the buffer overflow before reading
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let AdaptiveEvent::IntervalChanged { channel, interval } =
+        events.next().await.unwrap().unwrap()
+    else {
+        panic!("expected the interval to step down");
+    };
+    assert_eq!(channel, "ticker.BTC-PERPETUAL.100ms");
+    assert_eq!(interval, SubscriptionInterval::_100ms);
+    assert_eq!(client.info().subscriptions, ["ticker.BTC-PERPETUAL.100ms"]);
+
+    // No lag since, so it steps right back up
+    let AdaptiveEvent::IntervalChanged { channel, .. } = events.next().await.unwrap().unwrap()
+    else {
+        panic!("expected the interval to step up");
+    };
+    assert_eq!(channel, "ticker.BTC-PERPETUAL.raw");
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [
+            "public/subscribe ticker.BTC-PERPETUAL.raw",
+            "public/subscribe ticker.BTC-PERPETUAL.100ms",
+            "public/unsubscribe ticker.BTC-PERPETUAL.raw",
+            "public/subscribe ticker.BTC-PERPETUAL.raw",
+            "public/unsubscribe ticker.BTC-PERPETUAL.100ms",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn typed_subscribers_share_decoded_messages() {
+    let url = mock_server(|request| subscribe_and_publish(request, 50)).await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let first = client
+        .subscribe(CountedNumbers("ticker.BTC-PERPETUAL.raw"))
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // Subscribing again publishes another 50 messages, received by both streams
+    let second = client
+        .subscribe(CountedNumbers("ticker.BTC-PERPETUAL.raw"))
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let expected = (0..50).map(Counted).collect::<Vec<_>>();
+    let received = first
+        .take(100)
+        .map(|msg| msg.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(received, [expected.clone(), expected.clone()].concat());
+    let received = second
+        .take(50)
+        .map(|msg| msg.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(received, expected);
+    assert_eq!(DECODED.load(std::sync::atomic::Ordering::Relaxed), 100);
+}
+
+#[tokio::test]
+async fn checkpointed_user_trades_backfill_and_resume() {
+    let trade = |id: &str, timestamp: i64| json!({ "trade_id": id, "timestamp": timestamp });
+    let url = mock_server(move |request| match request["method"].as_str().unwrap() {
+        "private/get_user_trades_by_currency_and_time" => {
+            assert_eq!(request["params"]["start_timestamp"], 2000);
+            let trades = json!([trade("t1", 2000), trade("t2", 2000), trade("t3", 3000)]);
+            vec![response(
+                request,
+                json!({ "trades": trades, "has_more": false }),
+            )]
+        }
+        _ => vec![
+            response(request, request["params"]["channels"].clone()),
+            // Overlaps with the backfill
+            notification(
+                "user.trades.future.BTC.raw",
+                json!([trade("t3", 3000), trade("t4", 4000)]),
+            ),
+        ],
+    })
+    .await;
+    let dir = std::env::temp_dir().join(format!("deribit-checkpoints-{}", std::process::id()));
+    let store = FileCheckpointStore::new(&dir);
+    let checkpoint = Checkpoint {
+        timestamp: Timestamp(2000),
+        ids: vec!["t1".to_string()],
+    };
+    store
+        .save("user.trades.future.BTC.raw", &checkpoint)
+        .unwrap();
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let trades = client
+        .user_trades_checkpointed(Currency::Btc, KindWithComboAll::Future, store.clone())
+        .await
+        .unwrap();
+    let mut trades = Box::pin(trades);
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        received.push(trades.next().await.unwrap().unwrap().trade_id);
+    }
+    assert_eq!(received, ["t2", "t3", "t4"]);
+    // Asking for the next trade marks the last one as processed
+    let next = tokio::time::timeout(std::time::Duration::from_millis(50), trades.next()).await;
+    assert!(next.is_err());
+    let checkpoint = store.load("user.trades.future.BTC.raw").unwrap().unwrap();
+    assert_eq!(checkpoint.timestamp, Timestamp(4000));
+    assert_eq!(checkpoint.ids, ["t4"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn sandboxed_client_rejects_methods_outside_policy() {
+    let url = mock_server(|request| vec![response(request, json!(1))]).await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let sandbox = client
+        .sandboxed(MethodPolicy::allow(["public/*", "private/cancel*"]).deny(["public/subscribe"]));
+
+    assert_eq!(
+        sandbox
+            .call_raw("public/get_time", json!({}))
+            .await
+            .unwrap(),
+        json!(1)
+    );
+    assert!(sandbox.policy().permits("private/cancel_all"));
+    assert!(matches!(
+        sandbox.call_raw("private/buy", json!({})).await,
+        Err(Error::MethodNotAllowed(method)) if method == "private/buy"
+    ));
+    assert!(matches!(
+        sandbox.subscribe_raw("trades.BTC-PERPETUAL.raw").await,
+        Err(Error::MethodNotAllowed(_))
+    ));
+    // The client itself is unrestricted
+    assert_eq!(
+        client.call_raw("private/buy", json!({})).await.unwrap(),
+        json!(1)
+    );
+}
+
+#[tokio::test]
+async fn index_tracker_flags_constituent_outages() {
+    let url = mock_server(|request| {
+        let channel = "deribit_price_ranking.btc_usd";
+        assert_eq!(request["params"]["channels"], json!([channel]));
+        let ranking = |exchange: &str, enabled: bool, weight: f64| {
+            json!({ "identifier": exchange, "enabled": enabled, "weight": weight, "price": 60000.0 })
+        };
+        vec![
+            response(request, json!([channel])),
+            notification(
+                channel,
+                json!([ranking("bitstamp", true, 50.0), ranking("coinbase", true, 50.0)]),
+            ),
+            notification(
+                channel,
+                json!([ranking("bitstamp", false, 0.0), ranking("coinbase", true, 100.0)]),
+            ),
+            notification(
+                channel,
+                json!([ranking("bitstamp", true, 40.0), ranking("coinbase", true, 60.0)]),
+            ),
+        ]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let tracker = IndexTracker::default();
+    let mut updates = Box::pin(tracker.track(&client, IndexName::BtcUsd).await.unwrap());
+
+    let first = updates.next().await.unwrap().unwrap();
+    assert_eq!(first.composition.contributing().count(), 2);
+    assert!(first.outages.is_empty() && first.restored.is_empty());
+
+    let second = updates.next().await.unwrap().unwrap();
+    assert_eq!(second.outages, ["bitstamp"]);
+    assert!(second.restored.is_empty());
+
+    let third = updates.next().await.unwrap().unwrap();
+    assert!(third.outages.is_empty());
+    assert_eq!(third.restored, ["bitstamp"]);
+    let cached = tracker.composition(&IndexName::BtcUsd).unwrap();
+    assert_eq!(cached.get("coinbase").unwrap().weight, 60.0);
+}
+
+#[tokio::test]
+async fn local_order_book_applies_changes_and_refetches_on_gaps() {
+    let url = mock_server(|request| {
+        let channel = "book.BTC-PERPETUAL.raw";
+        let change = |prev: i64, id: i64, bids: Value, asks: Value| {
+            json!({
+                "type": "change", "instrument_name": "BTC-PERPETUAL", "timestamp": id,
+                "prev_change_id": prev, "change_id": id, "bids": bids, "asks": asks,
+            })
+        };
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![
+                response(request, json!([channel])),
+                notification(
+                    channel,
+                    json!({
+                        "type": "snapshot", "instrument_name": "BTC-PERPETUAL", "timestamp": 10,
+                        "change_id": 10,
+                        "bids": [["new", 100.0, 1.0], ["new", 99.0, 2.0]],
+                        "asks": [["new", 101.0, 1.0], ["new", 102.0, 3.0]],
+                    }),
+                ),
+                notification(
+                    channel,
+                    change(10, 11, json!([["change", 100.0, 5.0]]), json!([])),
+                ),
+                // Changes 12 and 13 are lost
+                notification(
+                    channel,
+                    change(13, 14, json!([]), json!([["delete", 101.0, 0.0]])),
+                ),
+                notification(
+                    channel,
+                    change(14, 15, json!([]), json!([["delete", 101.5, 0.0]])),
+                ),
+            ],
+            "public/get_order_book" => {
+                assert_eq!(request["params"]["instrument_name"], "BTC-PERPETUAL");
+                vec![response(
+                    request,
+                    json!({
+                        "instrument_name": "BTC-PERPETUAL", "timestamp": 14, "change_id": 14,
+                        "bids": [[100.0, 3.0], [99.0, 2.0]],
+                        "asks": [[101.5, 2.0], [102.0, 3.0]],
+                    }),
+                )]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let mut books = Box::pin(
+        client
+            .local_order_book("BTC-PERPETUAL", SubscriptionInterval::Raw)
+            .await
+            .unwrap(),
+    );
+
+    let book = books.next().await.unwrap().unwrap();
+    assert_eq!(book.change_id, 10);
+    assert_eq!(book.best_bid(), Some((100.0, 1.0)));
+    assert_eq!(book.best_ask(), Some((101.0, 1.0)));
+    assert_eq!(book.spread(), Some(1.0));
+
+    let book = books.next().await.unwrap().unwrap();
+    assert_eq!(book.change_id, 11);
+    assert_eq!(book.best_bid(), Some((100.0, 5.0)));
+    assert_eq!(book.bid_amount_above(99.0), 7.0);
+    assert_eq!(book.ask_amount_below(101.5), 1.0);
+
+    // The gap is filled by fetching the book, which already contains change 14
+    let book = books.next().await.unwrap().unwrap();
+    assert_eq!(book.change_id, 14);
+    assert_eq!(book.timestamp, Some(Timestamp(14)));
+    assert_eq!(book.best_bid(), Some((100.0, 3.0)));
+    assert_eq!(book.best_ask(), Some((101.5, 2.0)));
+
+    let book = books.next().await.unwrap().unwrap();
+    assert_eq!(book.change_id, 15);
+    let snapshot = book.snapshot(Some(1));
+    assert_eq!(snapshot.timestamp, Some(Timestamp(15)));
+    assert_eq!(snapshot.bids, [(100.0, 3.0)]);
+    assert_eq!(snapshot.asks, [(102.0, 3.0)]);
+    assert_eq!(book.asks().count(), 1);
+}
+
+#[tokio::test]
+async fn order_books_are_polled_in_turn() {
+    let polls = std::sync::atomic::AtomicI64::new(0);
+    let url = mock_server(move |request| {
+        assert_eq!(request["method"], "public/get_order_book");
+        assert_eq!(request["params"]["depth"], 5);
+        let change_id = polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let instrument_name = request["params"]["instrument_name"].clone();
+        if instrument_name == "ETH-PERPETUAL" && change_id == 1 {
+            let mut reply = response(request, Value::Null);
+            reply.as_object_mut().unwrap().remove("result");
+            reply["error"] = json!({ "code": 10028, "message": "too_many_requests" });
+            return vec![reply];
+        }
+        vec![response(
+            request,
+            json!({
+                "instrument_name": instrument_name,
+                "change_id": change_id,
+                "timestamp": 1_000 + change_id,
+                "bids": [[100.0, 1.0]],
+                "asks": [[101.0, 2.0]],
+                "state": "open",
+            }),
+        )]
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let started = std::time::Instant::now();
+    let books: Vec<_> = client
+        .poll_order_books(
+            &["BTC-PERPETUAL", "ETH-PERPETUAL"],
+            Some(5),
+            std::time::Duration::from_millis(100),
+        )
+        .take(4)
+        .collect()
+        .await;
+    assert!(
+        started.elapsed() >= std::time::Duration::from_millis(150),
+        "requests are spread over the interval"
+    );
+    assert_eq!(books[0].as_ref().unwrap().instrument_name, "BTC-PERPETUAL");
+    assert!(books[1].is_err(), "failed polls are yielded");
+    let book = books[2].as_ref().unwrap();
+    assert_eq!(book.instrument_name, "BTC-PERPETUAL");
+    assert_eq!(book.change_id, 2);
+    assert_eq!(book.bids, [(100.0, 1.0)]);
+    assert_eq!(books[3].as_ref().unwrap().instrument_name, "ETH-PERPETUAL");
+}
+
+#[tokio::test]
+async fn book_deltas_are_shared_between_subscribers() {
+    let subscribes = std::sync::atomic::AtomicUsize::new(0);
+    let url = mock_server(move |request| {
+        let channel = "book.BTC-PERPETUAL.100ms";
+        let mut messages = vec![response(request, json!([channel]))];
+        // Publish once both subscribers are in
+        if subscribes.fetch_add(1, std::sync::atomic::Ordering::Relaxed) == 1 {
+            messages.push(notification(
+                channel,
+                json!({
+                    "type": "change", "instrument_name": "BTC-PERPETUAL", "timestamp": 5,
+                    "prev_change_id": 10, "change_id": 11,
+                    "bids": [["new", 100.0, 1.0], ["delete", 99.0, 0.0]],
+                    "asks": [["change", 101.0, 3.0]],
+                }),
+            ));
+        }
+        messages
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let channel = BookDeltaChannel {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        interval: SubscriptionInterval::_100ms,
+    };
+    let mut first = client.subscribe(channel.clone()).await.unwrap();
+    let mut second = client.subscribe(channel).await.unwrap();
+    let first = first.next().await.unwrap().unwrap();
+    let second = second.next().await.unwrap().unwrap();
+
+    assert_eq!(
+        first.bids().collect::<Vec<_>>(),
+        [(100.0, 1.0), (99.0, 0.0)]
+    );
+    assert_eq!(first.asks().collect::<Vec<_>>(), [(101.0, 3.0)]);
+    assert_eq!((first.change_id, first.prev_change_id), (11, Some(10)));
+    assert!(!first.snapshot);
+    // Decoded once, the levels are the same memory for both
+    assert_eq!(first.bid_prices().as_ptr(), second.bid_prices().as_ptr());
+
+    let mut book = LocalOrderBook::default();
+    book.change_id = 10;
+    assert_eq!(book.apply_delta(&first), Ok(true));
+    assert_eq!(book.best_bid(), Some((100.0, 1.0)));
+}
+
+#[tokio::test]
+async fn gaps_in_book_changes_are_detected() {
+    // Change 12 and 13 are lost
+    let publish = |request: &Value| {
+        let channel = "book.BTC-PERPETUAL.raw";
+        let change = |prev: Option<i64>, id: i64| {
+            json!({
+                "type": if prev.is_some() { "change" } else { "snapshot" },
+                "instrument_name": "BTC-PERPETUAL", "timestamp": id,
+                "prev_change_id": prev, "change_id": id, "bids": [], "asks": [],
+            })
+        };
+        let mut messages = vec![response(request, json!([channel]))];
+        for (prev, id) in [(None, 10), (Some(10), 11), (Some(13), 14), (Some(14), 15)] {
+            messages.push(notification(channel, change(prev, id)));
+        }
+        messages
+    };
+    let channel = BookInstrumentNameChannel {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        interval: SubscriptionInterval::Raw,
+    };
+    let change_ids = |events: Vec<GapEvent<BookNotificationRaw>>| {
+        events
+            .into_iter()
+            .map(|event| match event {
+                GapEvent::Message(book) => Ok(book.change_id),
+                GapEvent::GapDetected(gap) => Err(gap),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let url = mock_server(publish).await;
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let events = client
+        .subscribe(channel.clone())
+        .await
+        .unwrap()
+        .detect_gaps();
+    let events = events.take(5).map(|event| event.unwrap()).collect().await;
+    assert_eq!(
+        change_ids(events),
+        [
+            Ok(10),
+            Ok(11),
+            Err(GapDetected::Sequence(Gap {
+                key: "BTC-PERPETUAL".to_string(),
+                after: 11,
+                next: 14
+            })),
+            Ok(14),
+            Ok(15),
+        ]
+    );
+
+    // Lag loses messages of any key, so sequences start over after it
+    let url = mock_server(publish).await;
+    let client = DeribitClient::builder(Env::Custom(url))
+        .subscription_capacity("*", 2)
+        .connect()
+        .await
+        .unwrap();
+    let events = client.subscribe(channel).await.unwrap().detect_gaps();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let events = events.take(3).map(|event| event.unwrap()).collect().await;
+    assert_eq!(
+        change_ids(events),
+        [Err(GapDetected::Lagged { skipped: 2 }), Ok(14), Ok(15)]
+    );
+}
+
+#[tokio::test]
+async fn market_state_combines_ticker_book_and_trades() {
+    let url = mock_server(|request| {
+        let channel = request["params"]["channels"][0].as_str().unwrap();
+        let data = match channel.split('.').next().unwrap() {
+            "ticker" => json!({
+                "instrument_name": "BTC-PERPETUAL", "timestamp": 1_000,
+                "mark_price": 60_010.0, "index_price": 60_000.0, "open_interest": 5e8,
+                "current_funding": 0.0001, "funding_8h": 0.0002,
+            }),
+            "book" => json!({
+                "instrument_name": "BTC-PERPETUAL", "timestamp": 1_100, "change_id": 7,
+                "bids": [[60_005.0, 1_000.0]], "asks": [[60_006.0, 2_000.0]],
+            }),
+            _ => json!([
+                { "instrument_name": "BTC-PERPETUAL", "timestamp": 1_200, "trade_seq": 1, "price": 60_005.0 },
+                { "instrument_name": "BTC-PERPETUAL", "timestamp": 1_200, "trade_seq": 2, "price": 60_006.0 },
+            ]),
+        };
+        vec![response(request, json!([channel])), notification(channel, data)]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let tracker = MarketStateTracker::default();
+    let updates = tracker.track(&client, &["BTC-PERPETUAL"]).await.unwrap();
+    let mut changes = updates
+        .take(3)
+        .map(|update| update.unwrap().change)
+        .collect::<Vec<_>>()
+        .await;
+    changes.sort_by_key(|change| *change as u8);
+    assert_eq!(
+        changes,
+        [
+            MarketChange::Ticker,
+            MarketChange::Book,
+            MarketChange::Trade
+        ]
+    );
+
+    let state = tracker.state("BTC-PERPETUAL").unwrap();
+    assert_eq!(state.mark_price, Some(60_010.0));
+    assert_eq!(state.index_price, Some(60_000.0));
+    assert_eq!(state.best_bid, Some((60_005.0, 1_000.0)));
+    assert_eq!(state.best_ask, Some((60_006.0, 2_000.0)));
+    assert_eq!(state.last_trade.unwrap().trade_seq, 2);
+    assert_eq!(state.funding_8h, Some(0.0002));
+    assert_eq!(state.open_interest, Some(5e8));
+    assert_eq!(state.timestamp, Some(Timestamp(1_200)));
+}
+
+#[tokio::test]
+async fn instrument_cache_follows_listings() {
+    let url = mock_server(|request| {
+        let instrument = |name: &str, kind: &str, expiration: i64| {
+            json!({
+                "instrument_name": name, "kind": kind, "base_currency": "BTC",
+                "expiration_timestamp": expiration, "tick_size": 0.0005, "contract_size": 1.0,
+                "tick_size_steps": [{ "above_price": 0.005, "tick_size": 0.001 }],
+            })
+        };
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => {
+                let channel = "instrument.state.any.any";
+                let state = |name: &str, state: &str| {
+                    json!({ "instrument_name": name, "state": state, "timestamp": 1 })
+                };
+                vec![
+                    response(request, json!([channel])),
+                    notification(channel, state("BTC-27JUN25", "created")),
+                    notification(channel, state("BTC-28MAR25-80000-C", "closed")),
+                ]
+            }
+            "public/get_instruments" => vec![response(
+                request,
+                json!([
+                    instrument("BTC-PERPETUAL", "future", 32_503_708_800_000),
+                    instrument("BTC-28MAR25-80000-C", "option", 1_743_148_800_000),
+                ]),
+            )],
+            "public/get_instrument" => vec![response(
+                request,
+                instrument("BTC-27JUN25", "future", 1_751_011_200_000),
+            )],
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let cache = InstrumentCache::default();
+    let mut events = Box::pin(
+        cache
+            .track(&client, std::time::Duration::from_secs(3600))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(
+        events.next().await.unwrap().unwrap(),
+        InstrumentEvent::Refreshed { count: 2 }
+    );
+    assert_eq!(cache.tick_size("BTC-28MAR25-80000-C", 0.001), Some(0.0005));
+    assert_eq!(cache.tick_size("BTC-28MAR25-80000-C", 0.01), Some(0.001));
+    assert_eq!(cache.contract_size("BTC-PERPETUAL"), Some(1.0));
+
+    let InstrumentEvent::Listed(listed) = events.next().await.unwrap().unwrap() else {
+        panic!("expected a listing");
+    };
+    assert_eq!(listed.instrument_name, "BTC-27JUN25");
+    assert_eq!(
+        events.next().await.unwrap().unwrap(),
+        InstrumentEvent::Delisted {
+            instrument_name: "BTC-28MAR25-80000-C".to_string(),
+            state: StateNotificationState::Closed,
+        }
+    );
+
+    let futures = cache.filter(&InstrumentFilter {
+        kind: Some(Kind::Future),
+        currency: Some("BTC".to_string()),
+        expires_before: Some(Timestamp(2_000_000_000_000)),
+        ..Default::default()
+    });
+    assert_eq!(futures.len(), 1);
+    assert_eq!(futures[0].instrument_name, "BTC-27JUN25");
+    assert_eq!(cache.len(), 2);
+}
+
+#[tokio::test]
+async fn spot_pairs_are_listed_and_balances_aggregated() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/get_instruments" => vec![response(
+            request,
+            json!([
+                { "instrument_name": "BTC-PERPETUAL", "kind": "future", "tick_size": 0.5 },
+                { "instrument_name": "BTC_USDC", "kind": "spot", "tick_size": 1.0, "min_trade_amount": 0.0001 },
+                { "instrument_name": "ETH_USDC", "kind": "spot", "tick_size": 0.05, "min_trade_amount": 0.001 },
+            ]),
+        )],
+        "private/get_account_summaries" => {
+            assert_eq!(request["params"]["subaccount_id"], 7);
+            vec![response(
+                request,
+                json!({ "summaries": [
+                    { "currency": "BTC", "balance": 0.5, "available_funds": 0.4, "spot_reserve": 0.1 },
+                    { "currency": "ETH", "balance": 0.0 },
+                    { "currency": "USDC", "balance": 1000.0, "available_funds": 1000.0 },
+                ] }),
+            )]
+        }
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let cache = InstrumentCache::default();
+    cache.refresh(&client).await.unwrap();
+    let pairs: Vec<_> = cache
+        .spot_pairs()
+        .into_iter()
+        .map(|pair| pair.instrument_name)
+        .collect();
+    assert_eq!(pairs, ["BTC_USDC", "ETH_USDC"]);
+    let pair = cache.spot_pair("BTC", "USDC").unwrap();
+    assert!(cache.spot_pair("BTC", "PERPETUAL").is_none());
+
+    let OrderRequest::Buy(request) = OrderBuilder::spot_limit_for(Direction::Buy, 1000.0, 60_000.0)
+        .label("spot")
+        .build(&pair)
+        .unwrap()
+    else {
+        panic!("expected a buy");
+    };
+    assert_eq!(
+        request.amount,
+        "0.0166".parse().ok(),
+        "BTC worth at most 1000 USDC"
+    );
+
+    let balances = client.spot_balances(Some(7)).await.unwrap();
+    assert_eq!(balances.len(), 2, "empty balances are left out");
+    assert_eq!(
+        balances["BTC"],
+        SpotBalance {
+            balance: 0.5,
+            available: 0.4,
+            reserved: 0.1,
+        }
+    );
+    assert_eq!(balances["USDC"].available, 1000.0);
+}
+
+#[tokio::test]
+async fn subaccounts_are_created_funded_and_summarised() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "private/create_subaccount" => vec![response(
+                request,
+                json!({ "id": 11, "username": "user_1", "type": "subaccount" }),
+            )],
+            "private/change_subaccount_name" => {
+                assert_eq!(params, &json!({ "sid": 11, "name": "hedging" }));
+                vec![response(request, json!("ok"))]
+            }
+            "private/submit_transfer_to_subaccount" => {
+                assert_eq!(
+                    params,
+                    &json!({ "currency": "BTC", "amount": 0.5, "destination": 11 })
+                );
+                vec![response(
+                    request,
+                    json!({ "id": 1, "amount": 0.5, "state": "confirmed" }),
+                )]
+            }
+            "private/submit_transfer_between_subaccounts" => {
+                assert_eq!(params["source"], 11);
+                assert_eq!(params["destination"], 12);
+                vec![response(
+                    request,
+                    json!({ "id": 2, "amount": 0.1, "state": "confirmed" }),
+                )]
+            }
+            "private/get_subaccounts" => {
+                assert_eq!(params["with_portfolio"], true);
+                vec![response(
+                    request,
+                    json!([
+                        { "id": 10, "username": "main", "type": "main" },
+                        {
+                            "id": 11,
+                            "username": "hedging",
+                            "type": "subaccount",
+                            "portfolio": {
+                                "btc": { "currency": "btc", "balance": 0.4, "equity": 0.4 },
+                                "eth": { "currency": "eth", "balance": 0.0 },
+                            },
+                        },
+                    ]),
+                )]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let subaccounts = Subaccounts::new(client);
+    let account = subaccounts.create(Some("hedging")).await.unwrap();
+    assert_eq!((account.id, account.username.as_str()), (11, "hedging"));
+    subaccounts
+        .transfer(&SubaccountTransfer::new(
+            Currency::Btc,
+            "0.5".parse().unwrap(),
+            11,
+        ))
+        .await
+        .unwrap();
+    let moved = subaccounts
+        .transfer(&SubaccountTransfer::new(Currency::Btc, "0.1".parse().unwrap(), 12).from(11))
+        .await
+        .unwrap();
+    assert_eq!(moved.id, 2);
+
+    let balances = subaccounts.balances().await.unwrap();
+    assert_eq!(balances.len(), 1, "the main account is left out");
+    assert_eq!(balances[0].username, "hedging");
+    assert_eq!(
+        balances[0].balances["BTC"].balance,
+        "0.4".parse::<Money>().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn positions_are_seeded_and_follow_user_changes() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/subscribe" => {
+            let channel = "user.changes.future.BTC.raw";
+            vec![
+                response(request, json!([channel])),
+                notification(
+                    channel,
+                    json!({
+                        "instrument_name": "BTC-PERPETUAL",
+                        "trades": [{ "instrument_name": "BTC-PERPETUAL", "trade_seq": 9, "amount": 100.0 }],
+                        "position": [{
+                            "instrument_name": "BTC-PERPETUAL", "direction": "zero", "size": 0.0,
+                            "average_price": 0.0, "realized_profit_loss": 0.0025,
+                        }],
+                    }),
+                ),
+            ]
+        }
+        "private/get_positions" => {
+            assert_eq!(request["params"], json!({ "currency": "BTC", "kind": "future" }));
+            vec![response(
+                request,
+                json!([
+                    {
+                        "instrument_name": "BTC-PERPETUAL", "direction": "buy", "size": 100.0,
+                        "average_price": 60_000.0, "floating_profit_loss": 0.001,
+                    },
+                    {
+                        "instrument_name": "BTC-27JUN25", "direction": "sell", "size": -50.0,
+                        "average_price": 62_000.0, "floating_profit_loss": -0.0005,
+                    },
+                ]),
+            )]
+        }
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let tracker = PositionTracker::default();
+    let mut updates = Box::pin(
+        tracker
+            .track(&client, Some(Kind::Future), CurrencyWithAny::Btc)
+            .await
+            .unwrap(),
+    );
+    assert_eq!(tracker.positions().len(), 2);
+    assert_eq!(tracker.average_price("BTC-PERPETUAL"), "60000".parse().ok());
+    assert_eq!(
+        tracker.unrealized_pnl("BTC-27JUN25"),
+        "-0.0005".parse().ok()
+    );
+
+    let update = updates.next().await.unwrap().unwrap();
+    assert_eq!(update.position.instrument_name, "BTC-PERPETUAL");
+    assert_eq!(update.trades.len(), 1);
+    assert_eq!(update.trades[0].trade_seq, 9);
+    assert_eq!(tracker.average_price("BTC-PERPETUAL"), None, "closed");
+    assert_eq!(tracker.realized_pnl("BTC-PERPETUAL"), "0.0025".parse().ok());
+    let open = tracker.open_positions();
+    assert_eq!(open.len(), 1);
+    assert_eq!(open[0].instrument_name, "BTC-27JUN25");
+}
+
+#[tokio::test]
+async fn order_manager_follows_placed_orders_to_the_end() {
+    let order = |order_id: &str,
+                 order_state: &str,
+                 filled_amount: f64,
+                 last_update_timestamp: i64| {
+        json!({
+            "order_id": order_id, "order_state": order_state, "instrument_name": "BTC-PERPETUAL",
+            "amount": 100.0, "filled_amount": filled_amount, "last_update_timestamp": last_update_timestamp,
+        })
+    };
+    let url = mock_server(move |request| match request["method"].as_str().unwrap() {
+        "public/subscribe" => {
+            let channel = "user.orders.future.BTC.raw";
+            vec![
+                response(request, json!([channel])),
+                // Neither placed through the manager, nor yet known to it
+                notification(channel, order("other", "open", 0.0, 1)),
+                notification(channel, order("o1", "open", 40.0, 2)),
+                notification(channel, order("o1", "filled", 100.0, 3)),
+            ]
+        }
+        "private/buy" => vec![response(
+            request,
+            json!({ "order": order("o1", "open", 0.0, 1), "trades": [] }),
+        )],
+        "private/sell" => vec![response(
+            request,
+            json!({ "order": order("o2", "open", 0.0, 4), "trades": [] }),
+        )],
+        "private/cancel" => {
+            assert_eq!(request["params"]["order_id"], "o2");
+            vec![response(request, order("o2", "cancelled", 0.0, 5))]
+        }
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let mut notified = Box::pin(
+        manager
+            .track(KindWithComboAll::Future, CurrencyWithAny::Btc)
+            .await
+            .unwrap(),
+    );
+    let placed = manager
+        .buy(PrivateBuyRequest {
+            instrument_name: "BTC-PERPETUAL".to_string(),
+            amount: "100".parse().ok(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(placed.order.order_id, "o1");
+    let o1 = manager.order_changes("o1");
+
+    let filled = notified.next().await.unwrap().unwrap();
+    assert_eq!(filled.filled_amount, "40".parse().ok());
+    let filled = notified.next().await.unwrap().unwrap();
+    assert_eq!(filled.order_state, OrderState::Filled);
+    let states = o1.map(|order| order.order_state).collect::<Vec<_>>().await;
+    assert_eq!(
+        states,
+        [OrderState::Open, OrderState::Open, OrderState::Filled]
+    );
+    assert!(manager.order("other").is_none());
+
+    manager
+        .sell(PrivateSellRequest {
+            instrument_name: "BTC-PERPETUAL".to_string(),
+            amount: "100".parse().ok(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(manager.open_orders().len(), 1);
+    manager.cancel("o2").await.unwrap();
+    assert!(manager.open_orders().is_empty());
+    assert_eq!(manager.orders().len(), 2);
+    manager.remove_done();
+    assert!(manager.orders().is_empty());
+}
+
+#[tokio::test]
+async fn fills_carry_the_progress_of_their_order() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/subscribe" => {
+            let channel = "user.trades.any.BTC.raw";
+            let trade = |order_id: &str, amount: f64, price: f64, state: &str| {
+                json!({
+                    "order_id": order_id, "label": "entry", "amount": amount, "price": price,
+                    "state": state, "instrument_name": "BTC-PERPETUAL",
+                })
+            };
+            vec![
+                response(request, json!([channel])),
+                notification(channel, json!([trade("o1", 30.0, 60_000.0, "open"), trade("o1", 10.0, 60_400.0, "open")])),
+                notification(channel, json!([trade("elsewhere", 5.0, 59_000.0, "filled")])),
+                notification(channel, json!([trade("o1", 60.0, 60_000.0, "filled")])),
+            ]
+        }
+        "private/buy" => vec![response(
+            request,
+            json!({ "order": { "order_id": "o1", "label": "entry", "amount": 100.0 }, "trades": [] }),
+        )],
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let fills = manager
+        .fills(KindWithComboAll::Any, CurrencyWithAny::Btc)
+        .await
+        .unwrap();
+    manager
+        .buy(PrivateBuyRequest {
+            instrument_name: "BTC-PERPETUAL".to_string(),
+            amount: "100".parse().ok(),
+            label: Some("entry".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let fills: Vec<Fill> = fills.take(4).map(Result::unwrap).collect().await;
+    let progress: Vec<_> = fills
+        .iter()
+        .map(|fill| {
+            (
+                fill.trade.order_id.as_str(),
+                fill.filled_amount,
+                fill.average_price,
+            )
+        })
+        .collect();
+    assert_eq!(
+        progress,
+        [
+            ("o1", 30.0, 60_000.0),
+            ("o1", 40.0, 60_100.0),
+            ("elsewhere", 5.0, 59_000.0),
+            ("o1", 100.0, 60_040.0),
+        ]
+    );
+    assert_eq!(fills[0].order.as_ref().unwrap().label, "entry");
+    assert!(fills[2].order.is_none());
+}
+
+fn bracket_order(
+    order_id: &str,
+    label: &str,
+    order_state: &str,
+    filled_amount: f64,
+    ts: i64,
+) -> Value {
+    json!({
+        "order_id": order_id, "label": label, "order_state": order_state, "instrument_name": "BTC-PERPETUAL",
+        "amount": 100.0, "filled_amount": filled_amount, "last_update_timestamp": ts,
+    })
+}
+
+fn bracket_setup() -> (Instrument, Bracket) {
+    let instrument = serde_json::from_value(json!({
+        "instrument_name": "BTC-PERPETUAL", "tick_size": 0.5, "min_trade_amount": 10.0,
+        "contract_size": 10.0, "settlement_currency": "BTC",
+    }))
+    .unwrap();
+    let bracket = Bracket {
+        label: "b1".to_string(),
+        direction: Direction::Buy,
+        amount: 100.0,
+        entry_price: Some(60_000.0),
+        take_profit: 62_000.0,
+        stop_loss: 59_000.0,
+        trigger: Trigger::MarkPrice,
+    };
+    (instrument, bracket)
+}
+
+#[tokio::test]
+async fn orders_are_cancelled_by_filter_or_listed_in_a_dry_run() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "private/get_open_orders_by_currency" => {
+                assert_eq!(params["currency"], "BTC");
+                assert_eq!(params["kind"], "future");
+                assert_eq!(params["type"], "stop_all");
+                vec![response(
+                    request,
+                    json!([{ "order_id": "a" }, { "order_id": "b" }]),
+                )]
+            }
+            "private/cancel_all_by_kind_or_type" => {
+                assert_eq!(params["currency"], "any");
+                assert_eq!(params["type"], "stop");
+                vec![response(request, json!(3))]
+            }
+            "private/cancel_by_label" => {
+                assert_eq!(params["label"], "grid");
+                vec![response(request, json!(1))]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let summary = client
+        .cancel_orders(
+            &CancelFilter::currency(Currency::Btc)
+                .kind(Kind::Future)
+                .order_type(SimpleOrderType::Stop)
+                .dry_run(),
+        )
+        .await
+        .unwrap();
+    assert!(summary.dry_run);
+    assert_eq!(summary.count, 2);
+    assert_eq!(summary.orders[1].order_id, "b");
+
+    let summary = client
+        .cancel_orders(&CancelFilter::all().order_type(SimpleOrderType::Stop))
+        .await
+        .unwrap();
+    assert_eq!((summary.dry_run, summary.count), (false, 3));
+    assert!(summary.orders.is_empty());
+    let summary = client
+        .cancel_orders(&CancelFilter::label("grid", None))
+        .await
+        .unwrap();
+    assert_eq!(summary.count, 1);
+    // Refused rather than widened to every order under the label or in the instrument
+    for filter in [
+        CancelFilter::label("grid", None).order_type(SimpleOrderType::Stop),
+        CancelFilter::instrument("BTC-PERPETUAL").kind(Kind::Future),
+    ] {
+        let result = client.cancel_orders(&filter).await;
+        assert!(matches!(result, Err(Error::UnsupportedCancelFilter(_))));
+    }
+}
+
+#[tokio::test]
+async fn secondary_orders_are_tracked_with_their_primary() {
+    let channel = "user.orders.any.any.raw";
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![
+                response(request, json!([channel])),
+                // Notified before the response placing its primary
+                notification(
+                    channel,
+                    json!({ "order_id": "s1", "order_state": "untriggered", "primary_order_id": "p1", "last_update_timestamp": 1 }),
+                ),
+            ],
+            "private/buy" => {
+                assert_eq!(params["linked_order_type"], "one_triggers_one_cancels_other");
+                assert_eq!(params["otoco_config"].as_array().unwrap().len(), 2);
+                vec![
+                    response(
+                        request,
+                        json!({ "order": { "order_id": "p1", "order_state": "open", "oto_order_ids": ["s1", "s2"], "last_update_timestamp": 1 } }),
+                    ),
+                    notification(
+                        channel,
+                        json!({ "order_id": "s2", "order_state": "untriggered", "last_update_timestamp": 2 }),
+                    ),
+                    notification(
+                        channel,
+                        json!({ "order_id": "p1", "order_state": "filled", "oto_order_ids": ["s1", "s2"], "last_update_timestamp": 3 }),
+                    ),
+                    notification(
+                        channel,
+                        json!({ "order_id": "s1", "order_state": "open", "primary_order_id": "p1", "last_update_timestamp": 4 }),
+                    ),
+                ]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let changes = manager.changes();
+    let notified = manager
+        .track(KindWithComboAll::Any, CurrencyWithAny::Any)
+        .await
+        .unwrap();
+    tokio::spawn(notified.for_each(|_| async {}));
+    let (instrument, _) = bracket_setup();
+    let exit = |label: &str| OrderBuilder::limit(Direction::Sell, 100.0, 62_000.0).label(label);
+    let order = OrderBuilder::limit(Direction::Buy, 100.0, 60_000.0)
+        .label("entry")
+        .triggers_oco(exit("tp"), exit("tp2"))
+        .build(&instrument)
+        .unwrap();
+    manager.place(order).await.unwrap();
+    let changes: Vec<_> = changes.take(5).collect().await;
+    assert_eq!(changes.last().unwrap().order_id, "s1");
+
+    let secondary = manager.secondary_orders("p1");
+    let states: Vec<_> = secondary
+        .iter()
+        .map(|order| (order.order_id.as_str(), order.order_state.clone()))
+        .collect();
+    assert_eq!(
+        states,
+        [("s1", OrderState::Open), ("s2", OrderState::Untriggered)]
+    );
+}
+
+#[tokio::test]
+async fn quoter_places_edits_and_cancels_on_shutdown() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        let order = |order_id: &str, order_state: &str, price: f64| {
+            json!({
+                "order_id": order_id, "order_state": order_state, "price": price,
+                "amount": 100.0, "label": "mm",
+            })
+        };
+        match request["method"].as_str().unwrap() {
+            "private/enable_cancel_on_disconnect" => {
+                assert_eq!(params["scope"], "connection");
+                vec![response(request, json!("ok"))]
+            }
+            "private/buy" => {
+                assert_eq!(params["post_only"], true);
+                assert_eq!(params["price"], 59_000.0, "rounded away from the market");
+                vec![response(
+                    request,
+                    json!({ "order": order("b", "open", 59_000.0) }),
+                )]
+            }
+            "private/sell" => {
+                assert_eq!(params["price"], 61_000.0);
+                vec![response(
+                    request,
+                    json!({ "order": order("a", "open", 61_000.0) }),
+                )]
+            }
+            "private/edit" => {
+                assert_eq!(params["order_id"], "b");
+                assert_eq!(params["price"], 59_100.0);
+                vec![response(
+                    request,
+                    json!({ "order": order("b", "open", 59_100.0) }),
+                )]
+            }
+            "private/cancel" => {
+                let order_id = params["order_id"].as_str().unwrap();
+                vec![response(request, order(order_id, "cancelled", 0.0))]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let (instrument, _) = bracket_setup();
+    let quoter = Quoter::new(OrderManager::new(client), instrument, "mm")
+        .min_interval(std::time::Duration::from_millis(10));
+    let (quotes, desired) = tokio::sync::mpsc::channel(8);
+    let mut events = Box::pin(quoter.run(tokio_stream::wrappers::ReceiverStream::new(desired)));
+    let level = |price| {
+        Some(QuoteLevel {
+            price,
+            amount: 100.0,
+        })
+    };
+    let mut next = async || events.next().await.unwrap().unwrap();
+
+    quotes
+        .send(Quote {
+            bid: level(59_000.2),
+            ask: level(61_000.0),
+        })
+        .await
+        .unwrap();
+    assert!(matches!(next().await, QuoteEvent::Placed(order) if order.order_id == "b"));
+    assert!(matches!(next().await, QuoteEvent::Placed(order) if order.order_id == "a"));
+    // Only the bid moved
+    quotes
+        .send(Quote {
+            bid: level(59_100.0),
+            ask: level(61_000.0),
+        })
+        .await
+        .unwrap();
+    assert!(matches!(next().await, QuoteEvent::Edited(order) if order.order_id == "b"));
+    drop(quotes);
+    assert!(matches!(next().await, QuoteEvent::Cancelled(order) if order.order_id == "b"));
+    assert!(matches!(next().await, QuoteEvent::Cancelled(order) if order.order_id == "a"));
+    assert!(events.next().await.is_none());
+}
+
+#[tokio::test]
+async fn quoter_places_a_filled_side_again_without_a_new_quote() {
+    let bids = std::sync::atomic::AtomicUsize::new(0);
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        let order = |order_id: &str, order_state: &str, ts: i64| {
+            json!({
+                "order_id": order_id, "order_state": order_state, "price": 59_000.0,
+                "amount": 100.0, "label": "mm", "last_update_timestamp": ts,
+            })
+        };
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![response(request, params["channels"].clone())],
+            "private/enable_cancel_on_disconnect" => vec![response(request, json!("ok"))],
+            "private/buy" => {
+                let order_id = match bids.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 => "b1",
+                    _ => "b2",
+                };
+                vec![response(
+                    request,
+                    json!({ "order": order(order_id, "open", 1) }),
+                )]
+            }
+            "private/sell" => {
+                let channel = "user.orders.future.BTC.raw";
+                vec![
+                    response(request, json!({ "order": order("a", "open", 1) })),
+                    notification(channel, order("b1", "filled", 2)),
+                ]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let tracking = manager
+        .track(KindWithComboAll::Future, CurrencyWithAny::Btc)
+        .await
+        .unwrap();
+    tokio::spawn(tracking.for_each(|_| async {}));
+    let (instrument, _) = bracket_setup();
+    let quoter =
+        Quoter::new(manager, instrument, "mm").min_interval(std::time::Duration::from_millis(10));
+    let level = Some(QuoteLevel {
+        price: 59_000.0,
+        amount: 100.0,
+    });
+    // A single quote, never updated
+    let quotes = futures_util::stream::iter([Quote {
+        bid: level,
+        ask: Some(QuoteLevel {
+            price: 61_000.0,
+            amount: 100.0,
+        }),
+    }])
+    .chain(futures_util::stream::pending());
+    let events: Vec<_> = quoter
+        .run(quotes)
+        .take(3)
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert!(matches!(&events[2], QuoteEvent::Placed(order) if order.order_id == "b2"));
+}
+
+#[tokio::test]
+async fn quoter_places_nothing_without_cancel_on_disconnect() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "private/enable_cancel_on_disconnect" => {
+            let mut reply = response(request, Value::Null);
+            reply.as_object_mut().unwrap().remove("result");
+            reply["error"] = json!({ "code": 13009, "message": "unauthorized" });
+            vec![reply]
+        }
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let (instrument, _) = bracket_setup();
+    let quoter = Quoter::new(OrderManager::new(client), instrument, "mm");
+    let quotes = futures_util::stream::iter([Quote {
+        bid: Some(QuoteLevel {
+            price: 59_000.0,
+            amount: 100.0,
+        }),
+        ask: None,
+    }])
+    .chain(futures_util::stream::pending());
+    let events: Vec<_> = quoter.run(quotes).collect().await;
+    assert!(matches!(&events[..], [Err(Error::RpcError(_))]));
+}
+
+#[tokio::test]
+async fn mmp_guard_pauses_on_a_trigger_until_reset() {
+    let channel = "user.mmp_trigger.btc_usd";
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "private/set_mmp_config" => {
+                assert_eq!(params["interval"], 1);
+                assert_eq!(params["frozen_time"], 0);
+                assert_eq!(params["delta_limit"], 5.0);
+                vec![response(request, json!([]))]
+            }
+            "public/subscribe" => vec![
+                response(request, json!([channel])),
+                notification(
+                    channel,
+                    json!({ "index_name": "btc_usd", "frozen_until": 0 }),
+                ),
+            ],
+            "private/get_mmp_status" => vec![response(request, json!([]))],
+            "private/reset_mmp" => {
+                assert_eq!(params["index_name"], "btc_usd");
+                vec![response(request, json!("ok"))]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let limits = MmpLimits {
+        interval: std::time::Duration::from_secs(1),
+        delta_limit: Some(5.0),
+        ..Default::default()
+    };
+    client
+        .set_mmp_limits(IndexNameDerivative::BtcUsd, None, &limits)
+        .await
+        .unwrap();
+    let guard = MmpGuard::new(client, IndexNameDerivative::BtcUsd, None);
+    let mut states = Box::pin(guard.track().await.unwrap());
+    assert_eq!(states.next().await.unwrap().unwrap(), MmpState::Active);
+    assert!(!guard.is_paused());
+    assert_eq!(
+        states.next().await.unwrap().unwrap(),
+        MmpState::Frozen { until: None },
+        "frozen until reset"
+    );
+    assert!(guard.is_paused());
+    guard.reset().await.unwrap();
+    assert!(!guard.is_paused());
+    guard.resumed().await;
+}
+
+#[tokio::test]
+async fn mass_quotes_send_only_what_changed() {
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "private/mass_quote" => {
+                seen.lock().unwrap().push(params.clone());
+                let errors = if params["quote_id"] == "2" {
+                    json!([{ "instrument_name": "BTC-1", "side": "ask", "message": "post_only_reject" }])
+                } else {
+                    json!([])
+                };
+                vec![response(request, json!({ "errors": errors }))]
+            }
+            "private/cancel_quotes" => {
+                assert_eq!(params["cancel_type"], "quote_set_id");
+                assert_eq!(params["quote_set_id"], "s1");
+                vec![response(request, json!(2))]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let mut quoter = MassQuoter::new(client, "mm").post_only();
+    let level = |price, amount| Some(QuoteLevel { price, amount });
+    let quote = |instrument_name: &str, bid, ask| InstrumentQuote {
+        instrument_name: instrument_name.to_string(),
+        quote_set_id: "s1".to_string(),
+        bid,
+        ask,
+    };
+    let full = [
+        quote("BTC-1", level(0.05, 1.5), level(0.06, 1.5)),
+        quote("BTC-2", level(0.10, 1.5), None),
+    ];
+    quoter.quote(&full).await.unwrap().unwrap();
+    assert!(
+        quoter.quote(&full).await.unwrap().is_none(),
+        "nothing changed"
+    );
+    // The bid price of BTC-1 moves and BTC-2 is dropped
+    quoter
+        .quote(&[quote("BTC-1", level(0.055, 1.5), level(0.06, 1.5))])
+        .await
+        .unwrap()
+        .unwrap();
+    // The ask the last request was refused is sent again in full
+    quoter
+        .quote(&[quote("BTC-1", level(0.055, 1.5), level(0.06, 1.5))])
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(quoter.cancel_all().await.unwrap(), 2);
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0]["mmp_group"], "mm");
+    assert_eq!(requests[0]["quotes"].as_array().unwrap().len(), 2);
+    assert_eq!(requests[0]["quotes"][0]["bid"]["post_only"], true);
+    // Zero is written `0` rather than `0.0` under the decimal feature
+    let mut pulled = requests[1]["quotes"].clone();
+    assert_eq!(pulled[1]["bid"]["amount"].take(), 0.0);
+    assert_eq!(
+        pulled,
+        json!([
+            { "instrument_name": "BTC-1", "quote_set_id": "s1", "bid": { "price": 0.055, "post_only": true } },
+            { "instrument_name": "BTC-2", "quote_set_id": "s1", "bid": { "amount": null, "post_only": true } },
+        ])
+    );
+    assert_eq!(
+        requests[2]["quotes"],
+        json!([{ "instrument_name": "BTC-1", "quote_set_id": "s1", "ask": { "price": 0.06, "amount": 1.5, "post_only": true } }])
+    );
+}
+
+#[tokio::test]
+async fn block_trades_are_signed_and_executed_and_rfqs_accepted_at_the_best_quote() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "private/verify_block_trade" => {
+                assert_eq!(params["role"], "maker");
+                vec![response(request, json!({ "signature": "1590485535899.1Mn52L_Q" }))]
+            }
+            "private/execute_block_trade" => {
+                assert_eq!(params["role"], "taker");
+                assert_eq!(params["counterparty_signature"], "1590485535899.1Mn52L_Q");
+                assert_eq!(params["trades"][0]["direction"], "buy");
+                vec![response(
+                    request,
+                    json!({ "id": "BLOCK-1", "timestamp": params["timestamp"], "trades": [] }),
+                )]
+            }
+            "private/accept_block_rfq" => {
+                assert_eq!(params["block_rfq_id"], 7);
+                assert_eq!(params["price"], 0.049);
+                assert_eq!(params["direction"], "buy");
+                assert_eq!(params["time_in_force"], "fill_or_kill");
+                assert_eq!(
+                    params["legs"],
+                    json!([{ "instrument_name": "BTC-27JUN25-70000-C", "direction": "buy", "ratio": 1 }])
+                );
+                vec![response(request, json!({ "block_trades": [] }))]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let terms = BlockTradeTerms::new().trade(
+        "BTC-PERPETUAL",
+        Direction::Buy,
+        "60000".parse().unwrap(),
+        "200000".parse().unwrap(),
+    );
+    assert_ne!(terms.nonce, BlockTradeTerms::new().nonce);
+    let signed = client.sign_block_trade(&terms, Role::Maker).await.unwrap();
+    // The counterparty gets the signed terms, e.g. as JSON
+    let signed: SignedBlockTrade =
+        serde_json::from_value(serde_json::to_value(&signed).unwrap()).unwrap();
+    let trade = client.execute_block_trade(&signed).await.unwrap();
+    assert_eq!(trade.id, "BLOCK-1");
+
+    let rfq: BlockRfq = serde_json::from_value(json!({
+        "block_rfq_id": 7,
+        "amount": 10.0,
+        "legs": [{ "instrument_name": "BTC-27JUN25-70000-C", "direction": "buy", "ratio": 1 }],
+        "asks": [{ "price": 0.051, "amount": 10.0 }, { "price": 0.049, "amount": 10.0 }],
+        "bids": [{ "price": 0.045, "amount": 10.0 }],
+    }))
+    .unwrap();
+    let best = rfq.best_price(&Direction::Buy).unwrap();
+    assert_eq!(best, "0.049".parse::<Money>().unwrap());
+    assert_eq!(rfq.best_price(&Direction::Sell), "0.045".parse().ok());
+    client
+        .accept_block_rfq(&rfq, Direction::Buy, best, "10".parse().unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn bracket_places_exits_after_the_entry_and_cancels_the_sibling() {
+    let channel = "user.orders.BTC-PERPETUAL.raw";
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![
+                response(request, json!([channel])),
+                notification(channel, bracket_order("e1", "b1", "filled", 100.0, 2)),
+            ],
+            "private/buy" => {
+                assert_eq!(params["label"], "b1");
+                vec![response(request, json!({ "order": bracket_order("e1", "b1", "open", 0.0, 1) }))]
+            }
+            "private/sell" => {
+                assert_eq!(params["reduce_only"], true);
+                assert_eq!(params["amount"], 100.0);
+                match params["label"].as_str().unwrap() {
+                    "b1-tp" => {
+                        assert_eq!(params["price"], 62_000.0);
+                        vec![response(request, json!({ "order": bracket_order("tp", "b1-tp", "open", 0.0, 3) }))]
+                    }
+                    "b1-sl" => {
+                        assert_eq!(params["trigger_price"], 59_000.0);
+                        vec![
+                            response(request, json!({ "order": bracket_order("sl", "b1-sl", "untriggered", 0.0, 3) })),
+                            notification(channel, bracket_order("tp", "b1-tp", "filled", 100.0, 4)),
+                        ]
+                    }
+                    label => panic!("unexpected label {label}"),
+                }
+            }
+            "private/cancel" => {
+                assert_eq!(params["order_id"], "sl");
+                vec![response(request, bracket_order("sl", "b1-sl", "cancelled", 0.0, 5))]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let (instrument, bracket) = bracket_setup();
+    let events: Vec<_> = manager
+        .bracket(&instrument, bracket)
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert_eq!(events.len(), 3);
+    assert!(matches!(&events[0], BracketEvent::EntryPlaced(entry) if entry.order_id == "e1"));
+    let BracketEvent::ExitsPlaced {
+        entry,
+        take_profit,
+        stop_loss,
+    } = &events[1]
+    else {
+        panic!("expected the exits, got {:?}", events[1]);
+    };
+    assert_eq!(entry.order_state, OrderState::Filled);
+    assert_eq!(
+        (take_profit.order_id.as_str(), stop_loss.order_id.as_str()),
+        ("tp", "sl")
+    );
+    let BracketEvent::Closed { exit, sibling } = &events[2] else {
+        panic!("expected the bracket to close, got {:?}", events[2]);
+    };
+    assert_eq!(exit.order_id, "tp");
+    assert_eq!(sibling.as_ref().unwrap().order_state, OrderState::Cancelled);
+}
+
+#[tokio::test]
+async fn bracket_replaces_a_cancelled_exit_and_closes_through_the_triggered_stop() {
+    let channel = "user.orders.BTC-PERPETUAL.raw";
+    let take_profits = std::sync::atomic::AtomicUsize::new(0);
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![
+                response(request, json!([channel])),
+                notification(channel, bracket_order("e1", "b1", "filled", 100.0, 2)),
+            ],
+            "private/buy" => {
+                vec![response(
+                    request,
+                    json!({ "order": bracket_order("e1", "b1", "open", 0.0, 1) }),
+                )]
+            }
+            "private/sell" => match params["label"].as_str().unwrap() {
+                "b1-tp" if take_profits.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 => {
+                    vec![response(
+                        request,
+                        json!({ "order": bracket_order("tp", "b1-tp", "open", 0.0, 3) }),
+                    )]
+                }
+                "b1-tp" => {
+                    let mut market = bracket_order("sl-market", "b1-sl", "filled", 100.0, 6);
+                    market["trigger_order_id"] = json!("sl");
+                    vec![
+                        response(
+                            request,
+                            json!({ "order": bracket_order("tp2", "b1-tp", "open", 0.0, 5) }),
+                        ),
+                        notification(channel, bracket_order("sl", "b1-sl", "triggered", 0.0, 6)),
+                        notification(channel, market),
+                    ]
+                }
+                "b1-sl" => vec![
+                    response(
+                        request,
+                        json!({ "order": bracket_order("sl", "b1-sl", "untriggered", 0.0, 3) }),
+                    ),
+                    // Cancelled by hand, which leaves the stop-loss working
+                    notification(channel, bracket_order("tp", "b1-tp", "cancelled", 0.0, 4)),
+                ],
+                label => panic!("unexpected label {label}"),
+            },
+            "private/cancel" => {
+                assert_eq!(params["order_id"], "tp2");
+                vec![response(
+                    request,
+                    bracket_order("tp2", "b1-tp", "cancelled", 0.0, 7),
+                )]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let (instrument, bracket) = bracket_setup();
+    let events: Vec<_> = manager
+        .bracket(&instrument, bracket)
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert_eq!(events.len(), 4, "{events:?}");
+    let BracketEvent::ExitReplaced { ended, replacement } = &events[2] else {
+        panic!(
+            "expected the take-profit to be replaced, got {:?}",
+            events[2]
+        );
+    };
+    assert_eq!(
+        (ended.order_id.as_str(), replacement.order_id.as_str()),
+        ("tp", "tp2")
+    );
+    let BracketEvent::Closed { exit, sibling } = &events[3] else {
+        panic!("expected the bracket to close, got {:?}", events[3]);
+    };
+    assert_eq!(exit.order_id, "sl-market");
+    assert_eq!(exit.order_state, OrderState::Filled);
+    assert_eq!(sibling.as_ref().unwrap().order_id, "tp2");
+    assert_eq!(manager.triggered_order("sl").unwrap().order_id, "sl-market");
+}
+
+#[tokio::test]
+async fn bracket_waits_for_an_order_update_after_a_failed_replacement() {
+    let channel = "user.orders.BTC-PERPETUAL.raw";
+    let take_profits = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let placed = take_profits.clone();
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![
+                response(request, json!([channel])),
+                notification(channel, bracket_order("e1", "b1", "filled", 100.0, 2)),
+            ],
+            "private/buy" => {
+                vec![response(
+                    request,
+                    json!({ "order": bracket_order("e1", "b1", "open", 0.0, 1) }),
+                )]
+            }
+            "private/sell" => match params["label"].as_str().unwrap() {
+                "b1-tp" if placed.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 => {
+                    vec![response(
+                        request,
+                        json!({ "order": bracket_order("tp", "b1-tp", "open", 0.0, 3) }),
+                    )]
+                }
+                "b1-tp" => {
+                    let mut reply = response(request, Value::Null);
+                    reply.as_object_mut().unwrap().remove("result");
+                    reply["error"] = json!({ "code": 11044, "message": "not_open_order" });
+                    vec![reply]
+                }
+                "b1-sl" => vec![
+                    response(
+                        request,
+                        json!({ "order": bracket_order("sl", "b1-sl", "untriggered", 0.0, 3) }),
+                    ),
+                    notification(channel, bracket_order("tp", "b1-tp", "cancelled", 0.0, 4)),
+                ],
+                label => panic!("unexpected label {label}"),
+            },
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let (instrument, bracket) = bracket_setup();
+    let mut events = Box::pin(manager.bracket(&instrument, bracket).await.unwrap());
+    assert!(matches!(
+        events.next().await,
+        Some(Ok(BracketEvent::EntryPlaced(_)))
+    ));
+    assert!(matches!(
+        events.next().await,
+        Some(Ok(BracketEvent::ExitsPlaced { .. }))
+    ));
+    assert!(matches!(events.next().await, Some(Err(Error::RpcError(_)))));
+    let idle = tokio::time::timeout(std::time::Duration::from_millis(200), events.next()).await;
+    assert!(idle.is_err(), "retried without an order update");
+    assert_eq!(take_profits.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn bracket_abandons_an_exit_with_too_little_left() {
+    let channel = "user.orders.BTC-PERPETUAL.raw";
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![
+                response(request, json!([channel])),
+                notification(channel, bracket_order("e1", "b1", "filled", 100.0, 2)),
+            ],
+            "private/buy" => {
+                vec![response(
+                    request,
+                    json!({ "order": bracket_order("e1", "b1", "open", 0.0, 1) }),
+                )]
+            }
+            "private/sell" => match params["label"].as_str().unwrap() {
+                "b1-tp" => {
+                    vec![response(
+                        request,
+                        json!({ "order": bracket_order("tp", "b1-tp", "open", 0.0, 3) }),
+                    )]
+                }
+                // The take-profit fills all but 5, below the minimum of 10, and is cancelled
+                "b1-sl" => vec![
+                    response(
+                        request,
+                        json!({ "order": bracket_order("sl", "b1-sl", "untriggered", 0.0, 3) }),
+                    ),
+                    notification(channel, bracket_order("tp", "b1-tp", "cancelled", 95.0, 4)),
+                ],
+                label => panic!("unexpected label {label}"),
+            },
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let (instrument, bracket) = bracket_setup();
+    let events: Vec<_> = manager
+        .bracket(&instrument, bracket)
+        .await
+        .unwrap()
+        .take(3)
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert!(matches!(&events[2], BracketEvent::ExitAbandoned(order) if order.order_id == "tp"));
+    assert_eq!(
+        manager.order("sl").unwrap().order_state,
+        OrderState::Untriggered
+    );
+}
+
+#[tokio::test]
+async fn bracket_is_resumed_from_the_labels_of_its_orders() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" => vec![response(request, params["channels"].clone())],
+            "private/get_order_state_by_label" => {
+                assert_eq!(params["currency"], "BTC");
+                let orders = match params["label"].as_str().unwrap() {
+                    "b1" => json!([bracket_order("e1", "b1", "filled", 50.0, 2)]),
+                    // Placed before the restart, unlike the stop-loss
+                    "b1-tp" => json!([bracket_order("tp", "b1-tp", "open", 0.0, 3)]),
+                    _ => json!([]),
+                };
+                vec![response(request, orders)]
+            }
+            "private/sell" => {
+                assert_eq!(params["label"], "b1-sl");
+                assert_eq!(params["amount"], 50.0, "sized to the entry's fill");
+                vec![response(
+                    request,
+                    json!({ "order": bracket_order("sl", "b1-sl", "untriggered", 0.0, 4) }),
+                )]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let (instrument, bracket) = bracket_setup();
+    let mut events = Box::pin(manager.resume_bracket(&instrument, bracket).await.unwrap());
+    let event = events.next().await.unwrap().unwrap();
+    assert!(
+        matches!(event, BracketEvent::ExitsPlaced { stop_loss, .. } if stop_loss.order_id == "sl")
+    );
+    assert_eq!(manager.open_orders().len(), 2);
+}
+
+#[tokio::test]
+async fn trailing_stop_follows_new_highs_only() {
+    let orders = "user.orders.BTC-PERPETUAL.raw";
+    let tickers = "ticker.BTC-PERPETUAL.100ms";
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        let stop = |order_state: &str, trigger_price: f64, ts: i64| {
+            json!({
+                "order_id": "s1", "label": "trail", "order_state": order_state, "amount": 100.0,
+                "instrument_name": "BTC-PERPETUAL", "trigger_price": trigger_price,
+                "last_update_timestamp": ts,
+            })
+        };
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" if params["channels"][0] == tickers => {
+                let mut replies = vec![response(request, json!([tickers]))];
+                for mark_price in [60_000.0, 60_300.0, 60_350.0, 59_000.0] {
+                    replies.push(notification(tickers, json!({ "mark_price": mark_price })));
+                }
+                replies
+            }
+            "public/subscribe" => vec![response(request, json!([orders]))],
+            "private/sell" => {
+                assert_eq!(params["reduce_only"], true);
+                assert_eq!(params["trigger_price"], 59_200.0);
+                vec![response(
+                    request,
+                    json!({ "order": stop("untriggered", 59_200.0, 1) }),
+                )]
+            }
+            "private/edit" => {
+                // The move to 60 350 is below the minimum step
+                assert_eq!(params["trigger_price"], 59_500.0);
+                vec![
+                    response(
+                        request,
+                        json!({ "order": stop("untriggered", 59_500.0, 2) }),
+                    ),
+                    notification(orders, stop("filled", 59_500.0, 3)),
+                ]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let (instrument, _) = bracket_setup();
+    let stop = TrailingStop {
+        label: "trail".to_string(),
+        position: Direction::Buy,
+        amount: 100.0,
+        distance: TrailDistance::Price(800.0),
+        trigger: Trigger::MarkPrice,
+        min_step: 100.0,
+    };
+    let events: Vec<_> = manager
+        .trailing_stop(&instrument, stop)
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert_eq!(events.len(), 3, "{events:?}");
+    assert!(
+        matches!(&events[0], TrailingStopEvent::Placed(order) if order.trigger_price == "59200".parse().ok())
+    );
+    assert!(
+        matches!(&events[1], TrailingStopEvent::Moved(order) if order.trigger_price == "59500".parse().ok())
+    );
+    assert!(
+        matches!(&events[2], TrailingStopEvent::Done(order) if order.order_state == OrderState::Filled)
+    );
+}
+
+#[tokio::test]
+async fn trailing_stop_ends_when_the_triggered_order_fills() {
+    let orders = "user.orders.BTC-PERPETUAL.raw";
+    let tickers = "ticker.BTC-PERPETUAL.100ms";
+    let url = mock_server(move |request| {
+        let params = &request["params"];
+        let order = |order_id: &str, order_state: &str, ts: i64| {
+            json!({
+                "order_id": order_id, "label": "trail", "order_state": order_state, "amount": 100.0,
+                "instrument_name": "BTC-PERPETUAL", "last_update_timestamp": ts,
+            })
+        };
+        match request["method"].as_str().unwrap() {
+            "public/subscribe" if params["channels"][0] == tickers => vec![
+                response(request, json!([tickers])),
+                notification(tickers, json!({ "mark_price": 60_000.0 })),
+            ],
+            "public/subscribe" => vec![response(request, json!([orders]))],
+            "private/sell" => {
+                let mut market = order("m1", "filled", 3);
+                market["trigger_order_id"] = json!("s1");
+                vec![
+                    response(request, json!({ "order": order("s1", "untriggered", 1) })),
+                    notification(orders, order("s1", "triggered", 2)),
+                    notification(orders, market),
+                ]
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let manager = OrderManager::new(client);
+    let (instrument, _) = bracket_setup();
+    let stop = TrailingStop {
+        label: "trail".to_string(),
+        position: Direction::Buy,
+        amount: 100.0,
+        distance: TrailDistance::Price(800.0),
+        trigger: Trigger::MarkPrice,
+        min_step: 100.0,
+    };
+    let events: Vec<_> = manager
+        .trailing_stop(&instrument, stop)
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert_eq!(events.len(), 2, "{events:?}");
+    assert!(
+        matches!(&events[1], TrailingStopEvent::Done(order) if order.order_id == "m1" && order.order_state == OrderState::Filled)
+    );
+}
+
+#[tokio::test]
+async fn funding_is_followed_and_accrued_over_a_range() {
+    const HOUR: i64 = 3_600_000;
+    const DAY: i64 = 24 * HOUR;
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/subscribe" => {
+            let channel = "ticker.BTC-PERPETUAL.100ms";
+            let ticker = |timestamp: i64, current_funding: f64| {
+                json!({
+                    "instrument_name": "BTC-PERPETUAL", "timestamp": timestamp,
+                    "index_price": 50_000.0, "current_funding": current_funding,
+                    "funding_8h": 0.0003,
+                })
+            };
+            vec![
+                response(request, json!([channel])),
+                notification(channel, ticker(1, 0.0001)),
+                notification(channel, ticker(2, 0.0001)),
+                notification(channel, ticker(3, 0.0002)),
+            ]
+        }
+        "public/get_funding_rate_history" => {
+            let record = |timestamp: i64, interest_1h: f64| {
+                json!({ "timestamp": timestamp, "interest_1h": interest_1h, "index_price": 50_000.0 })
+            };
+            let records = match request["params"]["start_timestamp"].as_i64().unwrap() {
+                0 => {
+                    assert_eq!(request["params"]["end_timestamp"], 30 * DAY);
+                    json!([record(HOUR, 0.0001), record(2 * HOUR, 0.0002), record(30 * DAY, -0.0001)])
+                }
+                start => {
+                    assert_eq!(start, 30 * DAY);
+                    json!([record(30 * DAY, -0.0001), record(45 * DAY + HOUR, 0.01)])
+                }
+            };
+            vec![response(request, records)]
+        }
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let updates = client
+        .subscribe_funding("BTC-PERPETUAL")
+        .await
+        .unwrap()
+        .take(2)
+        .map(|update| update.unwrap().timestamp)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        updates,
+        [Timestamp(1), Timestamp(3)],
+        "unchanged rates are skipped"
+    );
+
+    let history = client
+        .funding_rate_history("BTC-PERPETUAL", Timestamp(0), Timestamp(45 * DAY))
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 4, "the chunk boundary is not repeated");
+    // Long 10000 USD, 0.2 BTC at the index, paying 0.0002 in total
+    let accrued = client
+        .accrued_funding(
+            "BTC-PERPETUAL",
+            "10000".parse().unwrap(),
+            Timestamp(0),
+            Timestamp(45 * DAY),
+        )
+        .await
+        .unwrap();
+    let expected: Money = "-0.00004".parse().unwrap();
+    let tolerance: Money = "0.000000000001".parse().unwrap();
+    assert!((accrued - expected).abs() < tolerance, "{accrued}");
+}
+
+#[tokio::test]
+async fn dvol_store_backfills_pages_and_follows_the_index() {
+    const MINUTE: i64 = 60_000;
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/subscribe" => {
+            let channel = "deribit_volatility_index.btc_usd";
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64;
+            let value = |timestamp: i64, volatility: f64| {
+                json!({ "index_name": "btc_usd", "timestamp": timestamp, "volatility": volatility })
+            };
+            vec![
+                response(request, json!([channel])),
+                notification(channel, value(now, 52.0)),
+                notification(channel, value(now + 1, 53.5)),
+            ]
+        }
+        "public/get_volatility_index_data" => {
+            assert_eq!(request["params"]["resolution"], "60");
+            let start = request["params"]["start_timestamp"].as_i64().unwrap();
+            let end = request["params"]["end_timestamp"].as_i64().unwrap();
+            let row = |minute: i64| json!([start + minute * MINUTE, 50.0, 51.0, 49.0, 50.5]);
+            let page = if end == start + MINUTE {
+                json!({ "data": [row(0), row(1)], "continuation": null })
+            } else {
+                json!({ "data": [row(1), row(2)], "continuation": start + MINUTE })
+            };
+            vec![response(request, page)]
+        }
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    assert!(DvolStore::new(VixResolution::Unknown("2h".to_string()), 3).is_none());
+    let store = DvolStore::new(VixResolution::_60, 3).unwrap();
+    let mut candles = Box::pin(store.track(&client, Currency::Btc).await.unwrap());
+    let history = store.candles();
+    assert_eq!(history.len(), 3);
+    assert!(
+        history
+            .windows(2)
+            .all(|w| w[1].timestamp.0 - w[0].timestamp.0 == MINUTE)
+    );
+
+    let opened = candles.next().await.unwrap().unwrap();
+    assert_eq!((opened.open, opened.close), (52.0, 52.0));
+    let updated = candles.next().await.unwrap().unwrap();
+    assert_eq!(updated.timestamp, opened.timestamp);
+    assert_eq!((updated.high, updated.close), (53.5, 53.5));
+    assert_eq!(store.len(), 3, "the oldest candle was dropped");
+    assert_eq!(store.latest(), Some(updated));
+    assert_eq!(store.candles()[0].timestamp, history[1].timestamp);
+}
+
+#[tokio::test]
+async fn vol_surface_follows_option_tickers() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/get_instruments" => {
+            assert_eq!(request["params"]["kind"], "option");
+            let option = |name: &str, strike: f64, option_type: &str| {
+                json!({
+                    "instrument_name": name, "kind": "option", "strike": strike,
+                    "option_type": option_type, "expiration_timestamp": 1_743_148_800_000i64,
+                })
+            };
+            vec![response(
+                request,
+                json!([
+                    option("BTC-28MAR25-50000-P", 50_000.0, "put"),
+                    option("BTC-28MAR25-70000-C", 70_000.0, "call"),
+                ]),
+            )]
+        }
+        "public/subscribe" => {
+            let channel = request["params"]["channels"][0].as_str().unwrap();
+            let name = channel.split('.').nth(1).unwrap();
+            let iv = if name.ends_with('P') { 70.0 } else { 60.0 };
+            let ticker = json!({
+                "instrument_name": name, "timestamp": 1, "mark_iv": iv,
+                "mark_price": 0.01, "underlying_price": 60_000.0,
+                "greeks": { "delta": if name.ends_with('P') { -0.2 } else { 0.25 } },
+            });
+            vec![
+                response(request, json!([channel])),
+                notification(channel, ticker.clone()),
+                notification(channel, ticker),
+            ]
+        }
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let surface = options::VolSurface::default();
+    let mut points = Box::pin(surface.track(&client, Currency::Btc).await.unwrap());
+    points.next().await.unwrap().unwrap();
+    points.next().await.unwrap().unwrap();
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(200), points.next())
+            .await
+            .is_err(),
+        "unchanged tickers are skipped"
+    );
+    let expiry = Timestamp(1_743_148_800_000);
+    assert_eq!(surface.expiries(), [expiry]);
+    assert_eq!(surface.iv_at_strike(expiry, 60_000.0), Some(65.0));
+    assert_eq!(
+        surface.point("BTC-28MAR25-50000-P").unwrap().call_delta(),
+        Some(0.8)
+    );
+}
+
+#[tokio::test]
+async fn option_chain_is_loaded_by_strike_and_watched() {
+    let url = mock_server(|request| {
+        let ticker = |name: &str, mark_price: f64| {
+            json!({ "instrument_name": name, "mark_price": mark_price, "underlying_price": 61_000.0 })
+        };
+        match request["method"].as_str().unwrap() {
+            "public/get_instruments" => {
+                let option = |name: &str| {
+                    let parts: Vec<&str> = name.split('-').collect();
+                    json!({
+                        "instrument_name": name, "kind": "option",
+                        "strike": parts[2].parse::<f64>().unwrap(),
+                        "option_type": if parts[3] == "C" { "call" } else { "put" },
+                    })
+                };
+                vec![response(
+                    request,
+                    json!([
+                        option("BTC-28MAR25-60000-C"),
+                        option("BTC-28MAR25-60000-P"),
+                        option("BTC-28MAR25-55000-P"),
+                        option("BTC-27JUN25-60000-C"),
+                    ]),
+                )]
+            }
+            "public/ticker" => {
+                let name = request["params"]["instrument_name"].as_str().unwrap();
+                vec![response(request, ticker(name, 0.05))]
+            }
+            "public/subscribe" => {
+                let channel = request["params"]["channels"][0].as_str().unwrap();
+                let name = channel.split('.').nth(1).unwrap();
+                let mut messages = vec![response(request, json!([channel]))];
+                if name == "BTC-28MAR25-60000-P" {
+                    messages.push(notification(channel, ticker(name, 0.04)));
+                }
+                messages
+            }
+            method => panic!("unexpected {method}"),
+        }
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let expiry: Expiry = "28MAR25".parse().unwrap();
+    let chain = options::OptionChain::load(&client, Currency::Btc, expiry)
+        .await
+        .unwrap();
+    let strikes: Vec<_> = chain.strikes.iter().map(|row| row.strike).collect();
+    assert_eq!(strikes, [55_000.0, 60_000.0]);
+    let atm = chain.strike(60_000.0).unwrap();
+    assert_eq!(
+        atm.call.as_ref().unwrap().ticker.mark_price,
+        "0.05".parse::<Money>().unwrap()
+    );
+    assert!(chain.strikes[0].call.is_none());
+    assert_eq!(chain.atm_strike(), Some(60_000.0));
+
+    let mut updates = Box::pin(chain.watch(&client).await.unwrap());
+    let chain = updates.next().await.unwrap().unwrap();
+    let put = chain.option("BTC-28MAR25-60000-P").unwrap();
+    assert_eq!(put.ticker.mark_price, "0.04".parse::<Money>().unwrap());
+}
+
+#[tokio::test]
+async fn paginated_requests_are_followed_to_the_last_page() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        let page = match request["method"].as_str().unwrap() {
+            "private/get_settlement_history_by_currency" => {
+                let settlement = |timestamp: i64| json!({ "timestamp": timestamp, "type": "settlement" });
+                match params["continuation"].as_str() {
+                    None => json!({ "settlements": [settlement(3), settlement(2)], "continuation": "c1" }),
+                    Some("c1") => json!({ "settlements": [settlement(1)], "continuation": "none" }),
+                    Some(c) => panic!("unexpected continuation {c}"),
+                }
+            }
+            "public/get_last_trades_by_instrument" => {
+                assert_eq!(params["sorting"], "asc");
+                let trade = |trade_seq: i64| json!({ "trade_seq": trade_seq });
+                match params["start_seq"].as_i64() {
+                    None => json!({ "trades": [trade(1), trade(2)], "has_more": true }),
+                    Some(3) => json!({ "trades": [trade(3)], "has_more": false }),
+                    Some(seq) => panic!("unexpected start_seq {seq}"),
+                }
+            }
+            method => panic!("unexpected {method}"),
+        };
+        vec![response(request, page)]
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let settlements = client
+        .paginate(PrivateGetSettlementHistoryByCurrencyRequest {
+            currency: Currency::Btc,
+            ..Default::default()
+        })
+        .map(|settlement| settlement.unwrap().timestamp.as_millis())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(settlements, [3, 2, 1]);
+
+    let trades = client
+        .paginate(PublicGetLastTradesByInstrumentRequest {
+            instrument_name: "BTC-PERPETUAL".to_string(),
+            count: Some(2),
+            sorting: Some(Sorting::Asc),
+            ..Default::default()
+        })
+        .map(|trade| trade.unwrap().trade_seq)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(trades, [1, 2, 3]);
+}
+
+#[tokio::test]
+async fn transaction_log_follows_continuation_within_the_time_range() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        assert_eq!(request["method"], "private/get_transaction_log");
+        assert_eq!(params["count"], 250);
+        let entry = |id: i64, timestamp: i64, r#type: &str| {
+            json!({ "id": id, "timestamp": timestamp, "type": r#type })
+        };
+        let page = match params["continuation"].as_i64() {
+            None => json!({
+                "logs": [entry(4, 4_500, "trade"), entry(3, 3_000, "deposit")],
+                "continuation": 3,
+            }),
+            Some(3) => json!({
+                "logs": [entry(2, 2_000, "options_settlement_summary"), entry(1, 500, "trade")],
+                "continuation": 0,
+            }),
+            Some(c) => panic!("unexpected continuation {c}"),
+        };
+        vec![response(request, page)]
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let logs = client
+        .transaction_log(PrivateGetTransactionLogRequest {
+            currency: WalletCurrency::Btc,
+            time_range: TimeRange {
+                start_timestamp: 1_000.into(),
+                end_timestamp: 4_000.into(),
+            },
+            ..Default::default()
+        })
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.id, entry.kind())
+        })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        logs,
+        [
+            (3, TransactionType::Deposit),
+            (
+                2,
+                TransactionType::Other("options_settlement_summary".to_string())
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn trade_history_is_backfilled_in_order_without_repeats() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        assert_eq!(request["method"], "public/get_last_trades_by_instrument_and_time");
+        assert_eq!(params["sorting"], "asc");
+        assert_eq!(params["end_timestamp"], 5_000);
+        let trade = |trade_seq: i64, timestamp: i64| {
+            json!({ "trade_seq": trade_seq, "timestamp": timestamp })
+        };
+        let page = match params["start_timestamp"].as_i64().unwrap() {
+            1_000 => json!({ "trades": [trade(1, 1_000), trade(2, 2_000), trade(3, 3_000)], "has_more": true }),
+            // The trades of the last millisecond again
+            3_000 => json!({ "trades": [trade(3, 3_000), trade(4, 3_000), trade(5, 4_000)], "has_more": false }),
+            start => panic!("unexpected start_timestamp {start}"),
+        };
+        vec![response(request, page)]
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let trades = client
+        .trade_history("BTC-PERPETUAL", Timestamp(1_000), Timestamp(5_000))
+        .map(|trade| trade.unwrap().trade_seq)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(trades, [1, 2, 3, 4, 5]);
+}
+
+#[tokio::test]
+async fn settlement_and_delivery_history_is_collected_within_the_range() {
+    let url = mock_server(|request| {
+        let params = &request["params"];
+        let page = match request["method"].as_str().unwrap() {
+            "private/get_settlement_history_by_instrument" => {
+                assert_eq!(params["search_start_timestamp"], 4_000);
+                let settlement = |timestamp: i64| json!({ "timestamp": timestamp, "type": "delivery" });
+                match params["continuation"].as_str() {
+                    None => json!({ "settlements": [settlement(4_000), settlement(3_000)], "continuation": "c1" }),
+                    Some("c1") => json!({ "settlements": [settlement(2_000), settlement(1_000)], "continuation": "c2" }),
+                    Some(c) => panic!("the range ends before continuation {c}"),
+                }
+            }
+            "public/get_delivery_prices" => {
+                let price = |date: &str, delivery_price: f64| json!({ "date": date, "delivery_price": delivery_price });
+                json!({
+                    "data": [price("2024-03-04", 4.0), price("2024-03-03", 3.0), price("2024-03-02", 2.0), price("2024-03-01", 1.0)],
+                    "records_total": 4,
+                })
+            }
+            method => panic!("unexpected {method}"),
+        };
+        vec![response(request, page)]
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let settlements = client
+        .instrument_settlement_history("BTC-29MAR24", None, Timestamp(2_500), Timestamp(4_000))
+        .await
+        .unwrap();
+    let timestamps: Vec<_> = settlements
+        .iter()
+        .map(|s| s.timestamp.as_millis())
+        .collect();
+    assert_eq!(timestamps, [3_000, 4_000]);
+
+    // Delivered at 08:00 UTC
+    let march_2 = Expiry {
+        year: 2024,
+        month: 3,
+        day: 2,
+    }
+    .timestamp();
+    let prices = client
+        .delivery_prices(
+            IndexName::BtcUsd,
+            march_2,
+            Timestamp(march_2.0 + 86_400_000),
+        )
+        .await
+        .unwrap();
+    let prices: Vec<_> = prices.iter().map(|p| p.delivery_price).collect();
+    assert_eq!(prices, [Money::from(2), Money::from(3)]);
+}
+
+#[tokio::test]
+async fn margin_is_attributed_by_simulating_without_each_position() {
+    let url = mock_server(|request| {
+        let result = match request["method"].as_str().unwrap() {
+            "private/get_positions" => json!([
+                { "instrument_name": "BTC-PERPETUAL", "size": 100.0 },
+                { "instrument_name": "BTC-27DEC24", "size": 0.0 },
+            ]),
+            _ => match request["params"]["simulated_positions"]["BTC-PERPETUAL"].as_f64() {
+                Some(size) => {
+                    assert_eq!(size, -100.0);
+                    json!({ "projected_initial_margin": 0.25, "projected_maintenance_margin": 0.125 })
+                }
+                None => json!({ "projected_initial_margin": 0.75, "projected_maintenance_margin": 0.5 }),
+            },
+        };
+        vec![response(request, result)]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let contributions = client.attribute_margin(Currency::Btc).await.unwrap();
+    assert_eq!(contributions.len(), 1, "flat positions are skipped");
+    assert_eq!(contributions[0].instrument_name, "BTC-PERPETUAL");
+    assert_eq!(contributions[0].initial_margin, 0.5);
+    assert_eq!(contributions[0].maintenance_margin, 0.375);
+}
+
+#[tokio::test]
+async fn margin_alerts_fire_when_thresholds_are_crossed() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/subscribe" => {
+            let channel = "user.portfolio.BTC";
+            vec![
+                response(request, json!([channel])),
+                notification(
+                    channel,
+                    json!({
+                        "currency": "BTC", "margin_balance": 1.0,
+                        "maintenance_margin": 0.9, "available_funds": 0.05,
+                    }),
+                ),
+            ]
+        }
+        "private/get_account_summary" => vec![response(
+            request,
+            json!({
+                "currency": "BTC", "margin_balance": 1.0,
+                "maintenance_margin": 0.3, "available_funds": 0.6,
+            }),
+        )],
+        method => panic!("unexpected {method}"),
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let thresholds = margin::MarginThresholds {
+        maintenance_ratio: Some(0.8),
+        min_available_funds: Some(0.1),
+        poll_interval: std::time::Duration::from_millis(300),
+    };
+    let alerts = client
+        .monitor_margin(Currency::Btc, thresholds)
+        .await
+        .unwrap()
+        .take(4)
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    let [
+        margin::MarginAlert::MaintenanceRatioExceeded(breached),
+        margin::MarginAlert::AvailableFundsLow(_),
+        margin::MarginAlert::MaintenanceRatioRecovered(recovered),
+        margin::MarginAlert::AvailableFundsRecovered(_),
+    ] = &alerts[..]
+    else {
+        panic!("unexpected alerts {alerts:?}");
+    };
+    assert_eq!(breached.maintenance_ratio(), 0.9);
+    assert_eq!(recovered.available_funds, 0.6);
+}
+
+#[tokio::test]
+async fn address_verification_is_awaited_through_status_changes() {
+    let polls = std::sync::atomic::AtomicUsize::new(0);
+    let url = mock_server(move |request| {
+        let result = match request["method"].as_str().unwrap() {
+            "private/get_address_book" => {
+                let status = match polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 => return vec![response(request, json!([]))],
+                    1..=3 => "waiting",
+                    4 => "confirmed",
+                    _ => "ready",
+                };
+                json!([{ "address": "bc1q", "currency": "BTC", "status": status }])
+            }
+            "private/add_to_address_book" => {
+                assert_eq!(request["params"]["address"], "bc1q");
+                json!({ "address": "bc1q", "currency": "BTC", "status": "waiting" })
+            }
+            method => panic!("unexpected {method}"),
+        };
+        vec![response(request, result)]
+    })
+    .await;
+
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+    let added = client
+        .add_address(PrivateAddToAddressBookRequest {
+            currency: WalletCurrency::Btc,
+            r#type: AddressBookType::Withdrawal,
+            address: "bc1q".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(added.is_pending());
+
+    let changes = client.address_status_changes(
+        WalletCurrency::Btc,
+        AddressBookType::Withdrawal,
+        "bc1q",
+        std::time::Duration::from_millis(1),
+    );
+    let statuses: Vec<_> = changes
+        .map(|item| item.unwrap().unwrap().status.unwrap())
+        .collect()
+        .await;
+    assert_eq!(
+        statuses,
+        [Status::Waiting, Status::Confirmed, Status::Ready],
+        "repeated statuses are skipped and the stream ends once ready"
+    );
+
+    let verification = client
+        .wait_for_address(
+            WalletCurrency::Btc,
+            AddressBookType::Withdrawal,
+            "bc1q",
+            std::time::Duration::from_millis(1),
+            std::time::Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(verification, AddressVerification::Ready(item) if item.is_ready()));
+}
+
+#[tokio::test]
+async fn failover_without_endpoints_fails() {
+    let client =
+        FailoverClient::connect(vec![], FailoverConfig::default(), DeribitClient::connect).await;
+    assert!(matches!(client, Err(Error::NoEndpoints)));
+}
+
+#[tokio::test]
+async fn failover_moves_subscriptions_off_a_degraded_endpoint() {
+    // Stops answering `public/test`, and publishes nothing
+    let degraded = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/test" => vec![],
+        _ => subscribe_and_publish(request, 0),
+    })
+    .await;
+    let healthy = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/test" => vec![response(request, json!({ "version": "1.2.26" }))],
+        _ => subscribe_and_publish(request, 1),
+    })
+    .await;
+
+    let config = FailoverConfig {
+        probe_interval: std::time::Duration::from_millis(10),
+        max_round_trip: std::time::Duration::from_millis(50),
+        degraded_probes: 2,
+    };
+    // Nothing listens on port 1
+    let closed = "ws://127.0.0.1:1".to_string();
+    let endpoints = vec![
+        Env::Custom(degraded.clone()),
+        Env::Custom(closed.clone()),
+        Env::Custom(healthy.clone()),
+    ];
+    let connects = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let connected = connects.clone();
+    let count = move || connected.load(std::sync::atomic::Ordering::SeqCst);
+    let client = FailoverClient::connect(endpoints, config, move |env| {
+        connects.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        DeribitClient::connect(env)
+    })
+    .await
+    .unwrap();
+    assert_eq!(client.endpoint().url(), degraded);
+    let mut numbers = Box::pin(client.subscribe(Numbers("trades.BTC-PERPETUAL.raw")));
+    let quiet = tokio::time::timeout(std::time::Duration::from_millis(50), numbers.next()).await;
+    assert!(quiet.is_err(), "subscribed on the degraded endpoint");
+
+    // The standby is connected on the probe schedule, before the degradation shows
+    let mut events = Box::pin(client.monitor());
+    assert!(
+        matches!(events.next().await.unwrap(), FailoverEvent::Unavailable { url, .. } if url == closed)
+    );
+    assert_eq!(count(), 2);
+    assert!(
+        matches!(events.next().await.unwrap(), FailoverEvent::StandbyReady { url, .. } if url == healthy)
+    );
+    assert_eq!(count(), 3);
+    assert_eq!(
+        events.next().await.unwrap(),
+        FailoverEvent::Degraded {
+            url: degraded.clone()
+        }
+    );
+    assert!(
+        matches!(events.next().await.unwrap(), FailoverEvent::Migrated { from, to, .. } if from == degraded && to == healthy)
+    );
+    assert_eq!(count(), 3, "failing over connects nothing");
+    assert_eq!(client.endpoint().url(), healthy);
+
+    let number = tokio::time::timeout(std::time::Duration::from_secs(1), numbers.next()).await;
+    assert_eq!(
+        number.unwrap().unwrap().unwrap(),
+        0,
+        "resubscribed on the new endpoint"
+    );
+}
+
+// Collects formatted trace output
+#[derive(Clone, Default)]
+struct TraceBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn calls_and_subscriptions_are_traced() {
+    let buffer = TraceBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/get_time" => vec![json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": { "code": 10028, "message": "too_many_requests" },
+            "testnet": false,
+            "usIn": 1_000,
+            "usOut": 1_250,
+            "usDiff": 250,
+        })],
+        _ => subscribe_and_publish(request, 0),
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    assert!(client.call(PublicGetTimeRequest {}).await.is_err());
+    let _stream = client
+        .subscribe_raw("ticker.BTC-PERPETUAL.raw")
+        .await
+        .unwrap();
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let logged = |span: &str, message: &str| {
+        output
+            .lines()
+            .any(|line| line.contains(span) && line.contains(message))
+    };
+    assert!(
+        logged("call{method=\"public/get_time\" id=", "call failed"),
+        "{output}"
+    );
+    assert!(
+        logged(
+            "subscribe{channel=\"ticker.BTC-PERPETUAL.raw\"}",
+            "subscribed"
+        ),
+        "{output}"
+    );
+}
+
+#[tokio::test]
+async fn raw_message_tap_sees_frames_both_ways() {
+    let url = mock_server(|request| vec![response(request, json!(1_755_765_833_825i64))]).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .tap_raw_messages()
+        .connect()
+        .await
+        .unwrap();
+    let mut frames = Box::pin(client.raw_messages());
+    client.call(PublicGetTimeRequest {}).await.unwrap();
+
+    let sent = frames.next().await.unwrap();
+    assert_eq!(sent.direction, FrameDirection::Outbound);
+    let sent: Value = serde_json::from_str(&sent.payload).unwrap();
+    assert_eq!(sent["method"], "public/get_time");
+    let received = frames.next().await.unwrap();
+    assert_eq!(received.direction, FrameDirection::Inbound);
+    let received: Value = serde_json::from_str(&received.payload).unwrap();
+    assert_eq!(received["id"], sent["id"]);
+
+    let untapped = DeribitClient::connect(Env::Custom(
+        mock_server(|request| vec![response(request, json!(0))]).await,
+    ))
+    .await
+    .unwrap();
+    assert!(Box::pin(untapped.raw_messages()).next().await.is_none());
+}
+
+#[tokio::test]
+async fn health_reports_live_connection() {
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/test" => vec![response(request, json!({ "version": "1.2.26" }))],
+        _ => subscribe_and_publish(request, 1),
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let report = client.health(std::time::Duration::from_secs(5)).await;
+    assert!(report.is_live() && report.is_ready());
+    assert_eq!(report.token_valid, None);
+    assert_eq!(report.subscriptions_flowing, None);
+
+    let mut stream = client
+        .subscribe_raw("ticker.BTC-PERPETUAL.raw")
+        .await
+        .unwrap();
+    stream.next().await.unwrap().unwrap();
+    let report = client.health(std::time::Duration::from_secs(5)).await;
+    assert_eq!(report.subscriptions_flowing, Some(true));
+    assert!(report.is_ready());
+}
+
+#[tokio::test]
+async fn connects_from_local_address_with_ip_preference() {
+    let url = mock_server(|request| vec![response(request, json!(1))]).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .ip_preference(IpPreference::Ipv4Only)
+        .local_address("127.0.0.1".parse().unwrap())
+        .tcp_nodelay(false)
+        .connect_timeout(std::time::Duration::from_secs(5))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(
+        client.call(PublicGetTimeRequest {}).await.unwrap(),
+        Timestamp(1)
+    );
+}
+
+#[tokio::test]
+async fn connect_times_out_when_handshake_stalls() {
+    // Accepts the TCP connection but never answers the WebSocket handshake
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let result = DeribitClient::builder(Env::Custom(url))
+        .connect_timeout(std::time::Duration::from_millis(100))
+        .connect()
+        .await;
+    assert!(matches!(result, Err(Error::ConnectTimeout)));
+}
+
+#[tokio::test]
+async fn control_and_binary_frames_are_handled() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+        // The pong and the client's first request may arrive in either order
+        ws.send(Message::Ping("ping".into())).await.unwrap();
+        let (mut ponged, mut request) = (false, None);
+        while !ponged || request.is_none() {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Pong(payload) => ponged = payload == "ping".as_bytes(),
+                Message::Text(text) => request = serde_json::from_str::<Value>(&text).ok(),
+                other => panic!("unexpected frame {other:?}"),
+            }
+        }
+
+        // Answer the request with a binary frame, then close
+        let request = request.unwrap();
+        let reply = response(&request, json!(42)).to_string();
+        ws.send(Message::Binary(reply.into_bytes().into()))
+            .await
+            .unwrap();
+        ws.close(Some(tokio_tungstenite::tungstenite::protocol::CloseFrame {
+            code: 1001.into(),
+            reason: "maintenance".into(),
+        }))
+        .await
+        .unwrap();
+    });
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    assert_eq!(client.disconnect_reason(), None);
+    assert_eq!(
+        client.call(PublicGetTimeRequest {}).await.unwrap(),
+        Timestamp(42)
+    );
+    assert_eq!(
+        client.disconnected().await,
+        Disconnect::Closed {
+            code: Some(1001),
+            reason: "maintenance".to_string(),
+        }
+    );
+    server.await.unwrap();
+    assert!(client.call(PublicGetTimeRequest {}).await.is_err());
+}
+
+#[tokio::test]
+async fn malformed_messages_are_ignored() {
+    let url = mock_server(|request| {
+        vec![
+            json!("not a json-rpc message"),
+            json!({ "jsonrpc": "2.0", "id": "x", "result": 1 }),
+            response(request, json!(7)),
+        ]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    assert_eq!(
+        client.call(PublicGetTimeRequest {}).await.unwrap(),
+        Timestamp(7)
+    );
+    assert_eq!(client.disconnect_reason(), None);
+}
+
+#[tokio::test]
+async fn unknown_enum_values_are_reported() {
+    let url = mock_server(|request| {
+        vec![response(
+            request,
+            json!([
+                { "instrument_name": "BTC-PERPETUAL", "kind": "future" },
+                { "instrument_name": "BTC-SPREAD", "kind": "spread" },
+            ]),
+        )]
+    })
+    .await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    let mut diagnostics = Box::pin(client.diagnostics());
+    let instruments = client
+        .call(PublicGetInstrumentsRequest::default())
+        .await
+        .unwrap();
+    assert_eq!(instruments[0].kind, Kind::Future);
+    assert_eq!(instruments[1].kind, Kind::Unknown("spread".to_string()));
+    assert_eq!(
+        diagnostics.next().await.unwrap(),
+        Diagnostic::UnknownEnumValue {
+            channel: "public/get_instruments".to_string(),
+            field: "[1].kind".to_string(),
+            enum_name: "Kind",
+            value: "spread".to_string(),
+        }
+    );
+}
+
+#[tokio::test]
+async fn changed_result_shapes_are_tolerated_where_configured() {
+    let url = mock_server(|request| vec![response(request, json!({ "cancelled": 3 }))]).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .tolerate_result_shapes("private/cancel_all")
+        .connect()
+        .await
+        .unwrap();
+    let mut diagnostics = Box::pin(client.diagnostics());
+    let cancelled = client
+        .call(PrivateCancelAllRequest::default())
+        .await
+        .unwrap();
+    assert_eq!(cancelled, 3.0);
+    assert_eq!(
+        diagnostics.next().await.unwrap(),
+        Diagnostic::ResultShapeChanged {
+            method: "private/cancel_all".to_string(),
+            decoded_as: "field `cancelled` of an object".to_string(),
+        }
+    );
+
+    // Other methods still fail on the changed shape
+    let result = client
+        .call(PrivateCancelAllByCurrencyRequest {
+            currency_kind: CurrencyKind {
+                currency: Currency::Btc,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+    assert!(matches!(result, Err(Error::JsonError(_))));
+}
+
+#[tokio::test]
+async fn slow_calls_are_reported_with_server_time() {
+    let url = mock_server(|request| vec![response(request, json!(1_755_765_833_825i64))]).await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .slow_call_threshold(std::time::Duration::ZERO)
+        .connect()
+        .await
+        .unwrap();
+    let mut diagnostics = Box::pin(client.diagnostics());
+    client.call(PublicGetTimeRequest {}).await.unwrap();
+
+    let Diagnostic::SlowCall {
+        method,
+        total,
+        server,
+        network,
+    } = diagnostics.next().await.unwrap()
+    else {
+        panic!("expected a slow call");
+    };
+    assert_eq!(method, "public/get_time");
+    assert_eq!(server, std::time::Duration::from_micros(250));
+    assert_eq!(network, total.saturating_sub(server));
+}
+
+#[tokio::test]
+async fn driver_runs_connection_and_reports_end() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let Message::Text(text) = ws.next().await.unwrap().unwrap() else {
+            panic!("expected a request");
+        };
+        let request: Value = serde_json::from_str(&text).unwrap();
+        ws.send(Message::Text(
+            response(&request, json!(3)).to_string().into(),
+        ))
+        .await
+        .unwrap();
+        ws.close(None).await.unwrap();
+    });
+
+    let (client, driver) = DeribitClient::builder(Env::Custom(url))
+        .connect_with_driver()
+        .await
+        .unwrap();
+    let driver = tokio::spawn(driver);
+    assert_eq!(
+        client.call(PublicGetTimeRequest {}).await.unwrap(),
+        Timestamp(3)
+    );
+    assert_eq!(
+        driver.await.unwrap(),
+        Disconnect::Closed {
+            code: None,
+            reason: String::new(),
+        }
+    );
+}
+
+#[tokio::test]
+async fn dropped_calls_are_pruned() {
+    // Never answers, so every call is abandoned by its timeout
+    let url = mock_server(|_| Vec::new()).await;
+
+    let client = DeribitClient::connect(Env::Custom(url)).await.unwrap();
+    for _ in 0..500 {
+        let call = client.call(PublicGetTimeRequest {});
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(1), call).await;
+    }
+    assert!(client.info().pending_requests < 200);
+}
+
+#[tokio::test]
+async fn circuit_breaker_opens_on_rejections_and_recovers() {
+    let buys = std::sync::atomic::AtomicUsize::new(0);
+    let url = mock_server(move |request| {
+        let rejected = request["method"] == "private/buy"
+            && buys.fetch_add(1, std::sync::atomic::Ordering::Relaxed) < 2;
+        if rejected {
+            let mut reply = response(request, Value::Null);
+            reply.as_object_mut().unwrap().remove("result");
+            reply["error"] = json!({ "code": 10009, "message": "not_enough_funds" });
+            vec![reply]
+        } else {
+            vec![response(request, json!({ "trades": [] }))]
+        }
+    })
+    .await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .circuit_breaker(CircuitBreakerConfig {
+            min_rejections: 2,
+            cool_down: std::time::Duration::from_millis(100),
+            ..Default::default()
+        })
+        .connect()
+        .await
+        .unwrap();
+    let buy = || {
+        client.call_raw(
+            "private/buy",
+            json!({ "instrument_name": "BTC-PERPETUAL", "amount": 10 }),
+        )
+    };
+
+    assert!(matches!(buy().await, Err(Error::RpcError(_))));
+    assert!(matches!(buy().await, Err(Error::RpcError(_))));
+    assert_eq!(client.circuit_state(), Some(CircuitState::Open));
+    assert!(matches!(buy().await, Err(Error::CircuitOpen { .. })));
+    // Cancellations are not blocked
+    client
+        .call_raw("private/cancel_all", json!({}))
+        .await
+        .unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_millis(120)).await;
+    assert_eq!(client.circuit_state(), Some(CircuitState::HalfOpen));
+    buy().await.unwrap();
+    assert_eq!(client.circuit_state(), Some(CircuitState::Closed));
+}
+
+#[tokio::test]
+async fn risk_limits_reject_orders_before_sending() {
+    let url = mock_server(|request| {
+        let result = match request["method"].as_str().unwrap() {
+            "public/get_instruments" => json!([
+                { "instrument_name": "BTC-PERPETUAL", "min_trade_amount": 10.0 },
+            ]),
+            "private/get_positions" => {
+                json!([{ "instrument_name": "BTC-PERPETUAL", "size": 80.0 }])
+            }
+            "private/get_open_orders_by_currency" => json!([]),
+            "private/buy" => json!({
+                "order": {
+                    "instrument_name": "BTC-PERPETUAL",
+                    "order_state": "open",
+                    "filled_amount": 0.0,
+                },
+                "trades": [],
+            }),
+            method => panic!("unexpected {method}"),
+        };
+        vec![response(request, result)]
+    })
+    .await;
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .risk_limits(RiskLimits {
+            default: InstrumentLimits {
+                max_open_orders: Some(1),
+                max_position: Some(100.0),
+            },
+            ..Default::default()
+        })
+        .connect()
+        .await
+        .unwrap();
+    client.sync_risk_limits(Currency::Btc).await.unwrap();
+    let order = |method: &'static str, amount: f64| {
+        client.call_raw(
+            method,
+            json!({ "instrument_name": "BTC-PERPETUAL", "amount": amount }),
+        )
+    };
+
+    assert!(matches!(
+        order("private/buy", 5.0).await,
+        Err(Error::RiskLimit(LimitExceeded::MinTradeAmount { .. }))
+    ));
+    assert!(matches!(
+        order("private/buy", 30.0).await,
+        Err(Error::RiskLimit(LimitExceeded::Position { .. }))
+    ));
+    assert_eq!(
+        client.risk_headroom("BTC-PERPETUAL"),
+        Some(Headroom {
+            open_orders: Some(1),
+            buy: Some(20.0),
+            sell: Some(180.0),
+        })
+    );
+    order("private/buy", 20.0).await.unwrap();
+    assert!(matches!(
+        order("private/sell", 10.0).await,
+        Err(Error::RiskLimit(LimitExceeded::OpenOrders {
+            open: 1,
+            max: 1,
+            ..
+        }))
+    ));
+}
+
+#[tokio::test]
+async fn in_flight_requests_are_limited() {
+    // Answers requests in batches, 100ms after the last request of each batch, and
+    // records the largest batch
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let largest = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let largest_clone = largest.clone();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut batch = Vec::new();
+        loop {
+            let flush = tokio::time::sleep(std::time::Duration::from_millis(100));
+            tokio::select! {
+                message = ws.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        batch.push(serde_json::from_str::<Value>(&text).unwrap());
+                    }
+                    _ => break,
+                },
+                _ = flush, if !batch.is_empty() => {
+                    largest_clone.fetch_max(batch.len(), std::sync::atomic::Ordering::Relaxed);
+                    for request in batch.drain(..) {
+                        let reply = response(&request, json!(1)).to_string();
+                        ws.send(Message::Text(reply.into())).await.unwrap();
+                    }
+                }
+            }
+        }
+    });
+
+    let client = DeribitClient::builder(Env::Custom(url))
+        .max_in_flight_requests(std::num::NonZeroUsize::new(2).unwrap())
+        .connect()
+        .await
+        .unwrap();
+    let calls = (0..6).map(|_| client.call(PublicGetTimeRequest {}));
+    for result in futures_util::future::join_all(calls).await {
+        assert_eq!(result.unwrap(), Timestamp(1));
+    }
+    assert_eq!(client.info().max_in_flight_requests, Some(2));
+    assert_eq!(largest.load(std::sync::atomic::Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn cancellation_tokens_stop_calls_subscriptions_and_the_client() {
+    // `public/get_time` is never answered, so only cancellation ends it
+    let url = mock_server(|request| match request["method"].as_str().unwrap() {
+        "public/subscribe" => subscribe_and_publish(request, 1),
+        "public/get_time" => vec![],
+        _ => vec![response(request, json!("ok"))],
+    })
+    .await;
+    let shutdown = CancellationToken::new();
+    let client = DeribitClient::builder(Env::Custom(url))
+        .cancellation_token(shutdown.clone())
+        .connect()
+        .await
+        .unwrap();
+
+    let call = CancellationToken::new();
+    let cancel = call.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        cancel.cancel();
+    });
+    let result = client
+        .call_with_cancellation(PublicGetTimeRequest {}, &call)
+        .await;
+    assert!(matches!(result, Err(Error::Cancelled)));
+
+    let subscription = CancellationToken::new();
+    let mut cancelled = client
+        .subscribe_with_cancellation(Numbers("ticker.BTC-PERPETUAL.raw"), subscription.clone())
+        .await
+        .unwrap();
+    let mut live = client
+        .subscribe(Numbers("ticker.ETH-PERPETUAL.raw"))
+        .await
+        .unwrap();
+    assert_eq!(cancelled.next().await.unwrap().unwrap(), 0);
+    subscription.cancel();
+    assert!(cancelled.next().await.is_none());
+    assert_eq!(live.next().await.unwrap().unwrap(), 0);
+
+    let pending = client.call(PublicGetTimeRequest {});
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        shutdown.cancel();
+    });
+    assert!(matches!(pending.await, Err(Error::Cancelled)));
+    assert_eq!(client.disconnected().await, Disconnect::Cancelled);
+    assert!(live.next().await.is_none());
+    assert!(client.cancellation_token().is_cancelled());
+}
+
+#[tokio::test]
+async fn rate_limited_calls_wait_as_told_and_retry() {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = calls.clone();
+    // Every other call is rate limited
+    let handle = move |request: &Value| {
+        if counted
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            .is_multiple_of(2)
+        {
+            let mut reply = response(request, Value::Null);
+            reply.as_object_mut().unwrap().remove("result");
+            reply["error"] = json!({
+                "code": 10028,
+                "message": "too_many_requests",
+                "data": { "reason": "credits exhausted", "wait": 200 }
+            });
+            vec![reply]
+        } else {
+            vec![response(request, json!(1_755_765_833_825i64))]
+        }
+    };
+
+    let client = DeribitClient::connect(Env::Custom(mock_server(handle.clone()).await))
+        .await
+        .unwrap();
+    let error = client.call(PublicGetTimeRequest {}).await.unwrap_err();
+    assert_eq!(
+        error.retry_after(),
+        Some(std::time::Duration::from_millis(200))
+    );
+
+    calls.store(0, std::sync::atomic::Ordering::Relaxed);
+    let client = DeribitClient::builder(Env::Custom(mock_server(handle).await))
+        .retry_rate_limited(1)
+        .connect()
+        .await
+        .unwrap();
+    let started = std::time::Instant::now();
+    assert_eq!(
+        client.call(PublicGetTimeRequest {}).await.unwrap(),
+        Timestamp(1_755_765_833_825)
+    );
+    assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 2);
+}
@@ -0,0 +1,32 @@
+use deribit_api::*;
+use futures_util::StreamExt;
+
+#[tokio::test]
+async fn bars_tick_on_period_boundaries() {
+    let clock = MarketClock::default();
+    let ticks = clock
+        .bars(std::time::Duration::from_millis(50))
+        .take(3)
+        .collect::<Vec<_>>()
+        .await;
+
+    let ticks: Vec<_> = ticks.into_iter().map(Timestamp::as_millis).collect();
+    assert!(ticks.iter().all(|tick| tick % 50 == 0));
+    assert_eq!(ticks[1] - ticks[0], 50);
+    assert_eq!(ticks[2] - ticks[1], 50);
+    assert!(clock.clock().now_millis() >= ticks[2]);
+}
+
+#[tokio::test]
+async fn expiries_skip_past_timestamps_and_fire_in_order() {
+    let clock = MarketClock::default();
+    let now = clock.clock().now_millis();
+    let at = |offset: i64| Timestamp(now + offset);
+    let fired = clock
+        .expiries(&[at(60), at(-1_000), at(20), at(60)])
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(fired, vec![at(20), at(60)]);
+    assert!(clock.clock().now_millis() >= now + 60);
+}
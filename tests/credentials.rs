@@ -0,0 +1,60 @@
+use deribit_api::*;
+
+#[test]
+fn missing_environment_variables_are_reported() {
+    let provider = EnvCredentials::vars("DERIBIT_TEST_UNSET_ID", "DERIBIT_TEST_UNSET_SECRET");
+    let Err(Error::Credentials(reason)) = provider.credentials() else {
+        panic!("expected missing credentials");
+    };
+    assert!(reason.starts_with("DERIBIT_TEST_UNSET_ID"), "{reason}");
+}
+
+#[test]
+fn secrets_stay_out_of_debug_output() {
+    let credentials = Credentials::new("id", "very-secret");
+    assert!(!format!("{credentials:?}").contains("very-secret"));
+    assert!(
+        !format!(
+            "{:?}",
+            DeribitClient::builder(Env::Production).credentials(credentials)
+        )
+        .contains("very-secret")
+    );
+}
+
+#[cfg(feature = "credentials-file")]
+#[test]
+fn credentials_are_read_from_a_file_or_its_profiles() {
+    let path =
+        std::env::temp_dir().join(format!("deribit-credentials-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+client_id = "main-id"
+client_secret = "main-secret"
+
+[testnet]
+client_id = "test-id"
+client_secret = "test-secret"
+"#,
+    )
+    .unwrap();
+    let main = FileCredentials::new(&path).credentials().unwrap();
+    assert_eq!(main, Credentials::new("main-id", "main-secret"));
+    let testnet = FileCredentials::new(&path)
+        .profile("testnet")
+        .credentials()
+        .unwrap();
+    assert_eq!(testnet.client_id, "test-id");
+    assert!(matches!(
+        FileCredentials::new(&path).profile("staging").credentials(),
+        Err(Error::Credentials(_))
+    ));
+    std::fs::remove_file(&path).unwrap();
+    match FileCredentials::new(&path).credentials() {
+        Err(Error::Credentials(message)) => {
+            assert!(message.starts_with(&path.display().to_string()))
+        }
+        other => panic!("expected a credentials error, got {other:?}"),
+    }
+}
@@ -0,0 +1,43 @@
+#![cfg(feature = "decimal")]
+
+use deribit_api::*;
+use serde_json::json;
+
+#[test]
+fn monetary_fields_decode_exactly() {
+    type Resp = <PublicGetIndexPriceRequest as ApiRequest>::Response;
+    let raw = json!({ "estimated_delivery_price": 0.1, "index_price": 64_123.45 });
+    let resp: Resp = serde_json::from_value(raw).unwrap();
+    assert_eq!(
+        resp.estimated_delivery_price,
+        "0.1".parse::<Money>().unwrap()
+    );
+    assert_eq!(resp.index_price, "64123.45".parse::<Money>().unwrap());
+}
+
+#[test]
+fn digits_beyond_f64_are_kept() {
+    type Resp = <PublicGetIndexPriceRequest as ApiRequest>::Response;
+    // 17 significant digits, one more than an f64 holds
+    let text =
+        r#"{ "estimated_delivery_price": 1234567.8901234567, "index_price": 0.10000000000000001 }"#;
+    let resp: Resp = serde_json::from_str(text).unwrap();
+    assert_eq!(
+        resp.estimated_delivery_price.to_string(),
+        "1234567.8901234567"
+    );
+    assert_eq!(resp.index_price.to_string(), "0.10000000000000001");
+}
+
+#[test]
+fn monetary_fields_encode_as_numbers() {
+    let request = PrivateBuyRequest {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        amount: Some("10".parse().unwrap()),
+        price: Some("64123.50000000000001".parse().unwrap()),
+        ..Default::default()
+    };
+    let text = serde_json::to_string(&request).unwrap();
+    assert!(text.contains(r#""amount":10,"#), "{text}");
+    assert!(text.contains(r#""price":64123.50000000000001"#), "{text}");
+}
@@ -0,0 +1,34 @@
+#![cfg(feature = "fuzz")]
+
+use deribit_api::fuzz::arbitrary::{Arbitrary, Unstructured};
+use deribit_api::fuzz::{arbitrary_message, decode_message};
+use deribit_api::{Order, PrivateBuyRequest};
+
+#[test]
+fn decode_message_accepts_any_input() {
+    for text in [
+        "",
+        "{",
+        "null",
+        r#"{"jsonrpc":"2.0","method":"subscription","params":{"channel":"x","data":1}}"#,
+        r#"{"jsonrpc":"2.0","method":"subscription","params":{"channel":"x"}}"#,
+        r#"{"jsonrpc":"2.0","method":"heartbeat","params":{"type":"test_request"}}"#,
+    ] {
+        decode_message(text, 0);
+        decode_message(text, 4);
+    }
+}
+
+#[test]
+fn arbitrary_messages_and_models() {
+    let data = (0..4096u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect::<Vec<_>>();
+    let mut u = Unstructured::new(&data);
+    let message = arbitrary_message(&mut u).unwrap();
+    serde_json::from_str::<serde_json::Value>(&message).unwrap();
+    decode_message(&message, 4);
+
+    PrivateBuyRequest::arbitrary(&mut u).unwrap();
+    Order::arbitrary(&mut u).unwrap();
+}
@@ -0,0 +1,48 @@
+use deribit_api::*;
+use serde_json::json;
+
+#[test]
+fn prices_and_amounts_are_rounded_to_what_the_exchange_accepts() {
+    let option: Instrument = serde_json::from_value(json!({
+        "instrument_name": "BTC-28MAR25-80000-C",
+        "tick_size": 0.0001,
+        "tick_size_steps": [{ "above_price": 0.005, "tick_size": 0.0005 }],
+        "min_trade_amount": 0.1,
+        "contract_size": 1.0,
+    }))
+    .unwrap();
+    assert_eq!(option.round_price_to_tick(0.00123), 0.0012);
+    assert_eq!(
+        option.round_price_to_tick(0.0123),
+        0.0125,
+        "coarser tick above 0.005"
+    );
+    assert_eq!(option.round_price_passive(0.0123, &Direction::Buy), 0.012);
+    assert_eq!(option.round_price_passive(0.0123, &Direction::Sell), 0.0125);
+    assert_eq!(
+        option.round_price_passive(0.0015, &Direction::Sell),
+        0.0015,
+        "prices on a tick stay put"
+    );
+    assert!(option.is_valid_price(0.0125));
+    assert!(!option.is_valid_price(0.0123));
+    assert!(!option.is_valid_price(0.0));
+
+    assert_eq!(option.round_amount_to_contract(0.37), 0.3);
+    assert_eq!(option.round_amount_to_contract(0.3), 0.3);
+    assert_eq!(option.round_amount_to_contract(0.05), 0.0);
+    assert!(option.is_valid_amount(0.7));
+    assert!(!option.is_valid_amount(0.75));
+    assert!(!option.is_valid_amount(0.0));
+
+    let perpetual: Instrument = serde_json::from_value(json!({
+        "instrument_name": "BTC-PERPETUAL",
+        "tick_size": 0.5,
+        "min_trade_amount": 10.0,
+        "contract_size": 10.0,
+    }))
+    .unwrap();
+    assert_eq!(perpetual.round_price_to_tick(60_000.3), 60_000.5);
+    assert_eq!(perpetual.round_amount_to_contract(1_234.0), 1_230.0);
+    assert!(perpetual.is_valid_amount(1_230.0));
+}
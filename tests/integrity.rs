@@ -0,0 +1,132 @@
+use deribit_api::integrity::{Digest, Digester, Discrepancy, Gap, digested, verify};
+use deribit_api::{Money, PublicTrade, Timestamp};
+use futures_util::StreamExt;
+use std::time::Duration;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+// Two instruments trading every 10 seconds over three minutes
+fn trades() -> Vec<PublicTrade> {
+    (0..18)
+        .flat_map(|i| {
+            ["BTC-PERPETUAL", "ETH-PERPETUAL"].map(|instrument| PublicTrade {
+                instrument_name: instrument.to_string(),
+                trade_id: format!("{instrument}-{i}"),
+                trade_seq: 100 + i,
+                timestamp: (i * 10_000).into(),
+                price: Money::from(50_000 + i as i32),
+                amount: Money::from(10),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn digests(trades: &[PublicTrade]) -> Vec<Digest> {
+    let mut digester = Digester::new("trades.any.raw", MINUTE);
+    let mut digests = Vec::new();
+    for trade in trades {
+        digests.extend(digester.push(trade));
+    }
+    digests.extend(digester.finish());
+    digests
+}
+
+#[test]
+fn digests_close_a_period_after_their_end() {
+    let mut digester = Digester::new("trades.any.raw", MINUTE);
+    let trades = trades();
+    // Trades of the second minute keep the first one open for late trades
+    for trade in trades
+        .iter()
+        .take_while(|t| t.timestamp < Timestamp(120_000))
+    {
+        assert!(digester.push(trade).is_empty());
+    }
+    let closed = digester.push(&trades[24]);
+    assert_eq!(closed.len(), 1);
+    assert_eq!(
+        (closed[0].start, closed[0].end, closed[0].count),
+        (Timestamp(0), Timestamp(60_000), 12)
+    );
+    assert!(closed[0].gaps.is_empty());
+}
+
+#[test]
+fn clean_recording_verifies_in_any_order() {
+    let trades = trades();
+    let digests = digests(&trades);
+    assert_eq!(digests.len(), 3);
+
+    // Stored out of order, with a replayed trade the store kept once
+    let mut recorded = trades.clone();
+    recorded.reverse();
+    recorded.push(trades[3].clone());
+    assert!(verify(recorded, &digests, MINUTE).is_empty());
+}
+
+#[test]
+fn missing_and_altered_trades_are_found() {
+    let trades = trades();
+    let digests = digests(&trades);
+
+    let mut recorded = trades.clone();
+    recorded.retain(|t| t.trade_id != "BTC-PERPETUAL-8");
+    recorded
+        .iter_mut()
+        .find(|t| t.trade_id == "ETH-PERPETUAL-14")
+        .unwrap()
+        .price = Money::from(1);
+
+    assert_eq!(
+        verify(recorded, &digests, MINUTE),
+        vec![
+            Discrepancy::CountMismatch {
+                start: Timestamp(60_000),
+                expected: 12,
+                found: 11,
+            },
+            Discrepancy::Gap(Gap {
+                key: "BTC-PERPETUAL".to_string(),
+                after: 107,
+                next: 109,
+            }),
+            Discrepancy::ChecksumMismatch {
+                start: Timestamp(120_000)
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn gaps_seen_live_are_not_reported_again() {
+    // The stream never delivered trade 3 of ETH-PERPETUAL
+    let mut received = trades();
+    received.retain(|t| t.trade_id != "ETH-PERPETUAL-3");
+    let batches = received.chunks(5).map(<[_]>::to_vec).map(Ok);
+
+    let (passed, digests) = digested(
+        futures_util::stream::iter(batches.collect::<Vec<_>>()),
+        "trades.any.raw",
+        MINUTE,
+    );
+    let passed = passed.collect::<Vec<_>>().await;
+    let digests = digests.collect::<Vec<_>>().await;
+
+    assert_eq!(
+        passed
+            .iter()
+            .map(|b| b.as_ref().unwrap().len())
+            .sum::<usize>(),
+        35
+    );
+    assert_eq!(
+        digests[0].gaps,
+        vec![Gap {
+            key: "ETH-PERPETUAL".to_string(),
+            after: 102,
+            next: 104,
+        }]
+    );
+    assert!(verify(received, &digests, MINUTE).is_empty());
+}
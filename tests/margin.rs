@@ -0,0 +1,66 @@
+use deribit_api::margin::{PmeSimulation, PortfolioSimulation};
+use deribit_api::*;
+use serde_json::json;
+
+#[test]
+fn pme_simulation_breaks_down_margin_by_bucket() {
+    type Resp = <PrivatePmeSimulateRequest as ApiRequest>::Response;
+    let raw = json!({
+        "margins": {
+            "btc": {
+                "currency": "btc",
+                "initial_margin": 0.42,
+                "maintenance_margin": 0.31,
+                "risk_matrix_margin": 0.25,
+                "delta_margin": 0.04,
+                "roll_shock_margin": 0.02,
+                "correlation_contingency": 0.0
+            }
+        },
+        "aggregated_risk_vectors": {
+            "btc_usd": {
+                "standard": [0.1, -0.25, 0.05],
+                "extended": [-0.4, 0.3]
+            }
+        },
+        "index_price": { "btc_usd": 60000.0, "eth_usd": 3000.0 },
+        "model_params": { "general": { "mm_factor": 0.75 } }
+    });
+    let resp: Resp = serde_json::from_value(raw).unwrap();
+    let simulation = PmeSimulation::from_response(resp).expect("simulation should be typed");
+
+    let btc = &simulation.margins["btc"];
+    assert_eq!((btc.initial_margin, btc.maintenance_margin), (0.42, 0.31));
+    assert_eq!(btc.contributions.len(), 4, "currency is not a contribution");
+    assert_eq!(
+        btc.largest_contribution(),
+        Some(("risk_matrix_margin", 0.25))
+    );
+    assert_eq!(
+        simulation.aggregated_risk_vectors["btc_usd"].worst_scenario(),
+        Some((1, -0.25))
+    );
+    assert_eq!(simulation.index_price["eth_usd"], 3000.0);
+    assert!(simulation.other.contains_key("model_params"));
+}
+
+#[test]
+fn portfolio_simulation_types_per_index_maps() {
+    type Resp = <PrivateSimulatePortfolioRequest as ApiRequest>::Response;
+    let raw = json!({
+        "projected_initial_margin": 0.5,
+        "projected_maintenance_margin": 0.3,
+        "projected_delta_total": -1.2,
+        "delta_total_map": { "btc_usd": -1.2 },
+        "options_gamma_map": { "btc_usd": 0.001, "eth_usd": null },
+        "margin_model": "cross_pm"
+    });
+    let resp: Resp = serde_json::from_value(raw).unwrap();
+    let simulation = PortfolioSimulation::from_response(resp).expect("simulation should be typed");
+
+    assert_eq!(simulation.projected_initial_margin, 0.5);
+    assert_eq!(simulation.delta_total_map["btc_usd"], -1.2);
+    assert_eq!(simulation.options_gamma_map.len(), 1, "nulls are skipped");
+    assert!(simulation.options_vega_map.is_empty());
+    assert_eq!(simulation.other["margin_model"], "cross_pm");
+}
@@ -0,0 +1,94 @@
+use deribit_api::clock::MarketClock;
+use deribit_api::ohlc::candles;
+use deribit_api::{Candle, CandleBuilder, PublicTrade, Timestamp};
+use futures_util::StreamExt;
+use std::time::Duration;
+
+const SECOND: Duration = Duration::from_secs(1);
+
+fn trade(trade_seq: i64, timestamp: i64, price: f64, amount: f64) -> PublicTrade {
+    PublicTrade {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        trade_seq,
+        timestamp: timestamp.into(),
+        price: price.to_string().parse().unwrap(),
+        amount: amount.to_string().parse().unwrap(),
+        ..Default::default()
+    }
+}
+
+fn candle(start: i64, [open, high, low, close]: [f64; 4], volume: f64, trade_count: u64) -> Candle {
+    Candle {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        start: Timestamp(start),
+        end: Timestamp(start + 1_000),
+        open,
+        high,
+        low,
+        close,
+        volume,
+        trade_count,
+    }
+}
+
+#[test]
+fn trades_build_candles_and_fill_empty_intervals() {
+    let mut builder = CandleBuilder::new(SECOND);
+    assert!(builder.push(&trade(1, 1_100, 100.0, 10.0)).is_empty());
+    assert!(builder.push(&trade(2, 1_500, 103.0, 20.0)).is_empty());
+    assert!(builder.push(&trade(3, 1_900, 99.0, 5.0)).is_empty());
+    // Replayed and late trades don't count
+    assert!(builder.push(&trade(3, 1_900, 99.0, 5.0)).is_empty());
+    assert!(builder.push(&trade(4, 900, 50.0, 5.0)).is_empty());
+    assert_eq!(builder.current().unwrap().trade_count, 3);
+
+    // The next trade comes two intervals later
+    let closed = builder.push(&trade(5, 3_200, 101.0, 1.0));
+    assert_eq!(
+        closed,
+        [
+            candle(1_000, [100.0, 103.0, 99.0, 99.0], 35.0, 3),
+            candle(2_000, [99.0; 4], 0.0, 0),
+        ]
+    );
+
+    assert_eq!(
+        builder.close_before(Timestamp(5_000)),
+        [
+            candle(3_000, [101.0; 4], 1.0, 1),
+            candle(4_000, [101.0; 4], 0.0, 0),
+        ]
+    );
+    assert!(builder.finish().is_none());
+
+    let mut builder = CandleBuilder::new(SECOND).fill_empty(false);
+    builder.push(&trade(1, 1_100, 100.0, 10.0));
+    assert_eq!(builder.push(&trade(2, 3_200, 101.0, 1.0)).len(), 1);
+    assert_eq!(builder.close_before(Timestamp(10_000)).len(), 1);
+}
+
+#[tokio::test]
+async fn candle_stream_closes_quiet_intervals_on_time() {
+    let clock = MarketClock::default();
+    let now = clock.clock().now_millis();
+    let start = now - now % 100;
+    let trades = futures_util::stream::iter([Ok(vec![trade(1, start, 100.0, 1.0)])])
+        .chain(futures_util::stream::pending());
+    let builder = CandleBuilder::new(Duration::from_millis(100));
+    let mut bars = Box::pin(candles(trades, builder, &clock));
+
+    // No later trade arrives, so the clock closes the candle and the empty one after it
+    let first = tokio::time::timeout(2 * SECOND, bars.next()).await.unwrap();
+    assert_eq!(first.unwrap().unwrap().trade_count, 1);
+    let second = bars.next().await.unwrap().unwrap();
+    assert_eq!(
+        (second.start, second.trade_count),
+        (Timestamp(start + 100), 0)
+    );
+
+    // Candles still open when the trades end are yielded too
+    let trades = futures_util::stream::iter([Ok(vec![trade(1, start, 100.0, 1.0)])]);
+    let builder = CandleBuilder::new(SECOND);
+    let all = candles(trades, builder, &clock).collect::<Vec<_>>().await;
+    assert_eq!(all.len(), 1);
+}
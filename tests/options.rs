@@ -0,0 +1,67 @@
+use deribit_api::options::{VolPoint, VolSurface};
+use deribit_api::{Greeks, InstrumentOptionType, Timestamp};
+
+const YEAR: i64 = 365 * 86_400_000;
+
+fn point(
+    expiry: i64,
+    strike: f64,
+    option_type: InstrumentOptionType,
+    iv: f64,
+    delta: f64,
+) -> VolPoint {
+    VolPoint {
+        instrument_name: format!("BTC-{expiry}-{strike}-{option_type:?}"),
+        expiry: Timestamp(expiry),
+        strike,
+        option_type,
+        mark_iv: iv,
+        mark_price: 0.0,
+        underlying_price: 60_000.0,
+        greeks: Some(Greeks {
+            delta,
+            ..Default::default()
+        }),
+        timestamp: Timestamp(0),
+    }
+}
+
+#[test]
+fn surface_interpolates_by_strike_delta_and_expiry() {
+    use InstrumentOptionType::{Call, Put};
+    let surface = VolSurface::default();
+    let near = YEAR / 4;
+    surface.insert(point(near, 50_000.0, Put, 70.0, -0.2));
+    surface.insert(point(near, 60_000.0, Put, 61.0, -0.5));
+    surface.insert(point(near, 60_000.0, Call, 60.0, 0.5));
+    surface.insert(point(near, 70_000.0, Call, 65.0, 0.25));
+    surface.insert(point(YEAR, 60_000.0, Call, 50.0, 0.5));
+
+    assert_eq!(surface.expiries(), [Timestamp(near), Timestamp(YEAR)]);
+    let near = Timestamp(near);
+    assert_eq!(surface.smile(near).len(), 4);
+    // Read from the out of the money side
+    assert_eq!(surface.iv_at_strike(near, 55_000.0), Some(65.0));
+    assert_eq!(surface.iv_at_strike(near, 65_000.0), Some(62.5));
+    assert_eq!(surface.iv_at_strike(near, 40_000.0), Some(70.0));
+    // 0.8 call delta is the 20-delta put
+    assert_eq!(surface.iv_at_delta(near, 0.375), Some(62.5));
+    assert_eq!(surface.iv_at_delta(near, 0.65), Some(65.0));
+
+    // Halfway between the expiries in time: (60² × 0.25 + 50² × 1) / 2 / 0.625 = 52.15²
+    let iv = surface
+        .iv(Timestamp(YEAR * 5 / 8), 60_000.0, Timestamp(0))
+        .unwrap();
+    assert!((iv - 2_720f64.sqrt()).abs() < 1e-9, "{iv}");
+    assert_eq!(
+        surface.iv(Timestamp(YEAR / 8), 60_000.0, Timestamp(0)),
+        Some(60.0)
+    );
+    assert_eq!(
+        surface.iv(Timestamp(2 * YEAR), 60_000.0, Timestamp(0)),
+        Some(50.0)
+    );
+
+    surface.remove_expired(near);
+    assert_eq!(surface.expiries(), [Timestamp(YEAR)]);
+}
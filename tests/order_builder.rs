@@ -0,0 +1,182 @@
+use deribit_api::*;
+use serde_json::json;
+
+fn perpetual() -> Instrument {
+    serde_json::from_value(json!({
+        "instrument_name": "BTC-PERPETUAL",
+        "tick_size": 0.5,
+        "min_trade_amount": 10.0,
+        "contract_size": 10.0,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn orders_are_rounded_to_the_instrument() {
+    let order = OrderBuilder::limit(Direction::Sell, 125.0, 60_000.2)
+        .label("exit")
+        .post_only()
+        .build(&perpetual())
+        .unwrap();
+    let OrderRequest::Sell(request) = order else {
+        panic!("expected a sell, got {order:?}");
+    };
+    assert_eq!(request.instrument_name, "BTC-PERPETUAL");
+    assert_eq!(request.amount, "120".parse().ok());
+    assert_eq!(
+        request.price,
+        "60000.5".parse().ok(),
+        "rounded away from the market"
+    );
+    assert_eq!(request.r#type, Some(OrderTypeParam::Limit));
+    assert_eq!(request.post_only, Some(true));
+    assert_eq!(request.reduce_only, None);
+
+    let order = OrderBuilder::stop_market(Direction::Buy, 10.0, 61_000.3, Trigger::MarkPrice)
+        .label("stop")
+        .build(&perpetual())
+        .unwrap();
+    let OrderRequest::Buy(request) = order else {
+        panic!("expected a buy, got {order:?}");
+    };
+    assert_eq!(request.trigger_price, "61000.5".parse().ok());
+    assert_eq!(request.trigger, Some(Trigger::MarkPrice));
+    assert_eq!(request.price, None);
+}
+
+#[test]
+fn contradictory_orders_are_refused() {
+    let instrument = perpetual();
+    let build = |order: OrderBuilder| order.build(&instrument).unwrap_err();
+    assert_eq!(
+        build(OrderBuilder::market(Direction::Buy, 10.0)),
+        InvalidOrder::MissingLabel
+    );
+    assert_eq!(
+        build(
+            OrderBuilder::market(Direction::Buy, 10.0)
+                .label("a")
+                .post_only()
+        ),
+        InvalidOrder::PostOnlyWithoutPrice
+    );
+    assert_eq!(
+        build(
+            OrderBuilder::limit(Direction::Buy, 10.0, 60_000.0)
+                .label("a")
+                .post_only()
+                .time_in_force(TimeInForceParam::ImmediateOrCancel)
+        ),
+        InvalidOrder::PostOnlyImmediate
+    );
+    assert_eq!(
+        build(OrderBuilder::market(Direction::Buy, 5.0).label("a")),
+        InvalidOrder::AmountTooSmall(5.0)
+    );
+    assert_eq!(
+        build(OrderBuilder::limit(Direction::Buy, 10.0, -1.0).label("a")),
+        InvalidOrder::InvalidPrice(-1.0)
+    );
+    // Buying reduces a short, selling a long
+    assert_eq!(
+        build(
+            OrderBuilder::market(Direction::Buy, 30.0)
+                .label("a")
+                .reduce_only()
+                .position(-20.0)
+        ),
+        InvalidOrder::ReduceOnlyIncreases {
+            amount: 30.0,
+            position: -20.0
+        }
+    );
+    assert!(
+        OrderBuilder::market(Direction::Sell, 30.0)
+            .label("a")
+            .reduce_only()
+            .position(50.0)
+            .build(&instrument)
+            .is_ok()
+    );
+}
+
+#[test]
+fn secondary_orders_are_checked_and_linked() {
+    let take_profit = OrderBuilder::limit(Direction::Sell, 100.0, 62_000.2)
+        .label("tp")
+        .reduce_only();
+    let stop_loss = OrderBuilder::stop_market(Direction::Sell, 100.0, 59_000.0, Trigger::MarkPrice)
+        .label("sl")
+        .reduce_only();
+    let order = OrderBuilder::limit(Direction::Buy, 100.0, 60_000.0)
+        .label("entry")
+        .triggers_oco(take_profit.clone(), stop_loss)
+        .trigger_fill_condition(TriggerFillConditionParam::CompleteFill)
+        .build(&perpetual())
+        .unwrap();
+    let OrderRequest::Buy(request) = order else {
+        panic!("expected a buy, got {order:?}");
+    };
+    assert_eq!(
+        request.linked_order_type,
+        Some(LinkedOrderType::OneTriggersOneCancelsOther)
+    );
+    assert_eq!(
+        request.trigger_fill_condition,
+        Some(TriggerFillConditionParam::CompleteFill)
+    );
+    let secondary = request.otoco_config.unwrap();
+    assert_eq!(secondary.len(), 2);
+    assert_eq!(secondary[0].direction, Direction::Sell);
+    assert_eq!(
+        secondary[0].price,
+        "62000.5".parse().ok(),
+        "rounded away from the market"
+    );
+    assert_eq!(secondary[1].r#type, Some(OrderTypeParam::StopMarket));
+    assert_eq!(secondary[1].label.as_deref(), Some("sl"));
+
+    let nested = OrderBuilder::market(Direction::Buy, 10.0)
+        .label("entry")
+        .triggers(take_profit.triggers(OrderBuilder::market(Direction::Buy, 10.0).label("re")));
+    assert_eq!(
+        nested.build(&perpetual()).unwrap_err(),
+        InvalidOrder::NestedLinkedOrders
+    );
+    assert_eq!(
+        OrderBuilder::market(Direction::Buy, 10.0)
+            .label("entry")
+            .triggers(OrderBuilder::market(Direction::Sell, 10.0))
+            .build(&perpetual())
+            .unwrap_err(),
+        InvalidOrder::MissingLabel
+    );
+}
+
+#[test]
+fn spot_orders_are_sized_in_the_base_currency() {
+    let pair: Instrument = serde_json::from_value(json!({
+        "instrument_name": "ETH_USDC",
+        "kind": "spot",
+        "tick_size": 0.05,
+        "min_trade_amount": 0.001,
+    }))
+    .unwrap();
+    let order = OrderBuilder::spot_limit_for(Direction::Sell, 500.0, 3_000.02)
+        .label("spot")
+        .build(&pair)
+        .unwrap();
+    let OrderRequest::Sell(request) = order else {
+        panic!("expected a sell, got {order:?}");
+    };
+    assert_eq!(request.amount, "0.166".parse().ok());
+    assert_eq!(request.price, "3000.05".parse().ok());
+    assert_eq!(
+        OrderBuilder::market(Direction::Sell, 0.1)
+            .label("spot")
+            .reduce_only()
+            .build(&pair)
+            .unwrap_err(),
+        InvalidOrder::ReduceOnlySpot
+    );
+}
@@ -19,3 +19,80 @@ fn public_auth_grant_type_serializes() {
     let val = serde_json::to_value(PublicAuthGrantType::ClientCredentials).unwrap();
     assert_eq!(val, Value::String("client_credentials".into()));
 }
+
+#[test]
+fn unknown_enum_values_decode_into_the_catch_all() {
+    let kind: Kind = serde_json::from_value(Value::String("spread".into())).unwrap();
+    assert_eq!(kind, Kind::Unknown("spread".to_string()));
+    assert_eq!(
+        serde_json::to_value(&kind).unwrap(),
+        Value::String("spread".into())
+    );
+
+    let known: Kind = serde_json::from_value(Value::String("future".into())).unwrap();
+    assert_eq!(known, Kind::Future);
+
+    let trade: PublicTrade = serde_json::from_value(serde_json::json!({
+        "trade_id": "1",
+        "instrument_name": "BTC-PERPETUAL",
+        "direction": "cross",
+    }))
+    .unwrap();
+    assert_eq!(trade.direction, Direction::Unknown("cross".to_string()));
+}
+
+#[test]
+fn enums_display_and_parse_their_wire_values() {
+    assert_eq!(SubscriptionInterval::_100ms.to_string(), "100ms");
+    assert_eq!("100ms".parse(), Ok(SubscriptionInterval::_100ms));
+    assert_eq!(
+        "spread".parse::<Kind>(),
+        Err(ParseEnumError {
+            value: "spread".to_string(),
+            target: "Kind",
+        })
+    );
+    assert_eq!(Kind::Unknown("spread".to_string()).to_string(), "spread");
+
+    let kinds = std::collections::HashSet::from([Kind::Future, Kind::Option, Kind::Future]);
+    assert_eq!(kinds.len(), 2);
+}
+
+#[test]
+fn currency_enums_convert_through_currency() {
+    assert_eq!(Currency::from(WalletCurrency::Sol), Currency::Sol);
+    assert_eq!(
+        Currency::from(CurrencyPortfolioCurrency::Btc),
+        Currency::Btc
+    );
+    assert_eq!(
+        Currency::from(WalletCurrency::Unknown("DOGE".to_string())),
+        Currency::Doge
+    );
+    assert_eq!(Currency::try_from(CurrencyWithAny::Eth), Ok(Currency::Eth));
+    assert_eq!(
+        Currency::try_from(CurrencyWithAny::Any),
+        Err(UnsupportedCurrency {
+            value: "any".to_string(),
+            target: "Currency",
+        })
+    );
+
+    assert_eq!(
+        CurrencyWithAprCoinType::try_from(Currency::from(WalletCurrency::Usde)),
+        Ok(CurrencyWithAprCoinType::Usde)
+    );
+    let error = CurrencyWithAny::try_from(Currency::Sol).unwrap_err();
+    assert_eq!(error.to_string(), "SOL is not a CurrencyWithAny");
+
+    // Currencies missing from the spec take the case of the enum's values
+    let pepe = || Currency::Unknown("PEPE".to_string());
+    assert_eq!(
+        CurrencyPortfolioCurrency::try_from(pepe()),
+        Ok(CurrencyPortfolioCurrency::Unknown("pepe".to_string()))
+    );
+    assert_eq!(
+        CurrencyWithAny::try_from(pepe()),
+        Ok(CurrencyWithAny::Unknown("PEPE".to_string()))
+    );
+}
@@ -0,0 +1,121 @@
+use deribit_api::*;
+use serde_json::json;
+
+fn contract(instrument_name: &str) -> ContractType {
+    ContractType::of(&instrument_name.parse().unwrap())
+}
+
+fn money(value: &str) -> Money {
+    value.parse().unwrap()
+}
+
+fn assert_close(actual: Money, expected: f64) {
+    let actual: f64 = actual.to_string().parse().unwrap();
+    assert!((actual - expected).abs() < 1e-8, "{actual} != {expected}");
+}
+
+// A session of BTC futures and options trades, checked against the session figures
+// `user.portfolio` reported for it
+#[test]
+fn session_pnl_matches_the_portfolio() {
+    let portfolio: UserPortfolioNotification = serde_json::from_value(json!({
+        "currency": "BTC",
+        "futures_session_upl": 0.00309283,
+        "futures_session_rpl": 0.00267936,
+        "options_session_upl": 0.003,
+        "options_session_rpl": 0.004,
+        "session_upl": 0.00609283,
+        "session_rpl": 0.00667936,
+    }))
+    .unwrap();
+
+    // Opened at 58000 in an earlier session that settled at 60000
+    let mut perpetual = PnlLedger::with_position(
+        contract("BTC-PERPETUAL"),
+        money("10000"),
+        money("58000"),
+        money("60000"),
+    );
+    perpetual.trade(&Direction::Buy, money("5000"), money("61000"));
+    perpetual.trade(&Direction::Sell, money("6000"), money("62000"));
+    let mut future = PnlLedger::new(contract("BTC-27JUN25"));
+    future.trade(&Direction::Sell, money("2000"), money("63000"));
+    let mut option = PnlLedger::new(contract("BTC-28MAR25-60000-C"));
+    option.trade(&Direction::Buy, money("1"), money("0.05"));
+    option.trade(&Direction::Sell, money("0.4"), money("0.06"));
+
+    let futures_upl =
+        perpetual.session_unrealized(money("61500")) + future.session_unrealized(money("62500"));
+    let futures_rpl = perpetual.session_realized() + future.session_realized();
+    assert_close(futures_upl, portfolio.futures_session_upl);
+    assert_close(futures_rpl, portfolio.futures_session_rpl);
+    assert_close(
+        option.session_unrealized(money("0.055")),
+        portfolio.options_session_upl,
+    );
+    assert_close(option.session_realized(), portfolio.options_session_rpl);
+    assert_close(
+        futures_upl + option.session_unrealized(money("0.055")),
+        portfolio.session_upl,
+    );
+    assert_close(
+        futures_rpl + option.session_realized(),
+        portfolio.session_rpl,
+    );
+
+    // Against the entry price rather than the settlement price
+    assert_eq!(perpetual.size(), money("9000"));
+    assert!(perpetual.realized() > perpetual.session_realized());
+    perpetual.settle(money("61000"));
+    assert_eq!(perpetual.session_realized(), money("0"));
+    assert_close(perpetual.session_unrealized(money("61000")), 0.0);
+}
+
+#[test]
+fn linear_pnl_is_in_the_quote_currency() {
+    let name: InstrumentName = "BTC_USDC-PERPETUAL".parse().unwrap();
+    let mut ledger = PnlLedger::new(ContractType::of(&name));
+    assert_eq!(ContractType::of(&name), ContractType::Linear);
+    ledger.trade(&Direction::Buy, money("0.5"), money("60000"));
+    assert_eq!(
+        ledger.trade(&Direction::Sell, money("0.2"), money("61000")),
+        money("200")
+    );
+    // Flips short with what is left
+    ledger.trade(&Direction::Sell, money("0.5"), money("62000"));
+    assert_eq!(ledger.realized(), money("800"));
+    assert_eq!(ledger.size(), money("-0.2"));
+    assert_eq!(ledger.average_price(), money("62000"));
+    assert_eq!(ledger.unrealized(money("61000")), money("200"));
+
+    let pnl = Pnl::new(&name, money("800"), money("64000"));
+    assert_eq!(
+        pnl,
+        Pnl {
+            base: money("0.0125"),
+            usd: money("800")
+        }
+    );
+    let inverse = Pnl::new(
+        &"BTC-PERPETUAL".parse().unwrap(),
+        money("0.0125"),
+        money("64000"),
+    );
+    assert_eq!(inverse.usd, money("800"));
+    assert_eq!([pnl, inverse].into_iter().sum::<Pnl>().base, money("0.025"));
+}
+
+#[test]
+fn position_pnl_converts_deribit_figures() {
+    let position: Position = serde_json::from_value(json!({
+        "instrument_name": "BTC-PERPETUAL", "direction": "sell", "size": -12_000.0,
+        "average_price": 60_000.0, "mark_price": 50_000.0, "index_price": 50_000.0,
+        "floating_profit_loss": 0.01, "realized_profit_loss": 0.002,
+    }))
+    .unwrap();
+    let pnl = PositionPnl::of(&position).unwrap();
+    assert_close(pnl.unrealized.base, 0.04);
+    assert_close(pnl.unrealized.usd, 2_000.0);
+    assert_eq!(pnl.session_unrealized.usd, money("500"));
+    assert_eq!(pnl.realized.usd, money("100"));
+}
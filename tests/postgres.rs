@@ -0,0 +1,122 @@
+#![cfg(feature = "postgres")]
+
+//! Run with `DATABASE_URL=postgres://... cargo test --features postgres -- --ignored`;
+//! each test gets a fresh database.
+
+use deribit_api::postgres::PostgresSink;
+use deribit_api::*;
+use futures_util::{SinkExt, StreamExt, stream};
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+fn trade(id: &str, timestamp: i64) -> PublicTrade {
+    PublicTrade {
+        trade_id: id.to_string(),
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        timestamp: Timestamp(timestamp),
+        ..Default::default()
+    }
+}
+
+async fn count(pool: &PgPool) -> i64 {
+    sqlx::query_scalar("SELECT count(*) FROM deribit_trades")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(migrations = false)]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn trades_are_written_in_batches_and_flushed(pool: PgPool) {
+    let sink = PostgresSink::new(pool.clone())
+        .batch_size(2)
+        .flush_interval(Duration::from_millis(100));
+    sink.migrate().await.unwrap();
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let trades = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|t| (t, rx)) });
+    let writer = tokio::spawn({
+        let sink = sink.clone();
+        async move { sink.write_trades(trades).await }
+    });
+
+    // A partial batch is written once the flush interval passes
+    tx.send(Ok(vec![trade("1", 1_000)])).unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(count(&pool).await, 1);
+
+    // A replayed trade is skipped
+    tx.send(Ok(vec![
+        trade("1", 1_000),
+        trade("2", 2_000),
+        trade("3", 3_000),
+    ]))
+    .unwrap();
+    drop(tx);
+    writer.await.unwrap().unwrap();
+    assert_eq!(count(&pool).await, 3);
+
+    let loaded = sink
+        .load_trades("BTC-PERPETUAL", Timestamp(1_500), Timestamp(3_000))
+        .await
+        .unwrap();
+    assert_eq!(loaded, vec![trade("2", 2_000)]);
+}
+
+// Answers `public/get_order_book` with a book per call, failing the first one
+async fn polled_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut change_id = 0;
+        while let Some(Ok(Message::Text(text))) = ws.next().await {
+            let request: Value = serde_json::from_str(&text).unwrap();
+            change_id += 1;
+            let mut reply = json!({
+                "jsonrpc": "2.0", "id": request["id"], "testnet": false,
+                "usIn": 1_000, "usOut": 1_250, "usDiff": 250,
+            });
+            if change_id == 1 {
+                reply["error"] = json!({ "code": 10028, "message": "too_many_requests" });
+            } else {
+                reply["result"] = json!({
+                    "instrument_name": request["params"]["instrument_name"],
+                    "change_id": change_id, "timestamp": change_id,
+                    "bids": [[100.0, 1.0]], "asks": [[101.0, 2.0]], "state": "open",
+                });
+            }
+            ws.send(Message::Text(reply.to_string().into()))
+                .await
+                .unwrap();
+        }
+    });
+    format!("ws://{addr}")
+}
+
+#[sqlx::test(migrations = false)]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn polled_books_are_written_past_a_failed_poll(pool: PgPool) {
+    let sink = PostgresSink::new(pool.clone()).skip_stream_errors();
+    sink.migrate().await.unwrap();
+    let url = polled_server().await;
+    let client = std::sync::Arc::new(DeribitClient::connect(Env::Custom(url)).await.unwrap());
+
+    let books = client
+        .poll_order_books(&["BTC-PERPETUAL"], Some(1), Duration::from_millis(20))
+        .take(3);
+    sink.write_book_snapshots(books, Duration::from_millis(10))
+        .await
+        .unwrap();
+    let stored: Vec<i64> =
+        sqlx::query_scalar("SELECT change_id FROM deribit_book_snapshots ORDER BY change_id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(stored.last(), Some(&3), "written after the failed poll");
+}
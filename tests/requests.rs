@@ -51,3 +51,88 @@ fn public_auth_request_serialization_skips_nones() {
     assert!(params.get("nonce").is_none());
     assert!(params.get("state").is_none());
 }
+
+#[test]
+fn raw_params_match_value_params() {
+    let req = PrivateBuyRequest {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        amount: "10".parse().ok(),
+        price: "65000.5".parse().ok(),
+        label: Some("hedge".to_string()),
+        ..Default::default()
+    };
+    let raw = req.to_raw_params();
+    assert_eq!(
+        serde_json::from_str::<Value>(raw.get()).unwrap(),
+        req.to_params()
+    );
+    assert_eq!(PublicGetTimeRequest {}.to_raw_params().get(), "{}");
+}
+
+// Works with any request paginated by offset
+fn next_page<R: ApiRequest + ParamGroup<OffsetPagination>>(request: &mut R) {
+    let pagination = request.group_mut();
+    let count = pagination.count.unwrap_or(20);
+    pagination.offset = Some(pagination.offset.unwrap_or_default() + count);
+}
+
+#[test]
+fn shared_parameter_groups_are_flattened() {
+    let mut deposits = PrivateGetDepositsRequest {
+        currency: Currency::Btc,
+        pagination: OffsetPagination {
+            count: Some(50),
+            offset: None,
+        },
+    };
+    let mut orders = PrivateGetOrderHistoryByCurrencyRequest {
+        currency_kind: CurrencyKind {
+            currency: Currency::Eth,
+            kind: Some(KindWithComboAll::Future),
+        },
+        ..Default::default()
+    };
+    next_page(&mut deposits);
+    next_page(&mut orders);
+    assert_eq!(
+        deposits.to_params(),
+        json!({ "currency": "BTC", "count": 50, "offset": 50 })
+    );
+    assert_eq!(
+        orders.to_params(),
+        json!({ "currency": "ETH", "kind": "future", "offset": 20 })
+    );
+    assert_eq!(
+        serde_json::to_string(&orders).unwrap(),
+        orders.to_raw_params().get()
+    );
+
+    let parsed: PublicGetTradingviewChartDataRequest = serde_json::from_value(json!({
+        "instrument_name": "BTC-PERPETUAL",
+        "start_timestamp": 1_000,
+        "end_timestamp": 2_000,
+        "resolution": "60",
+    }))
+    .unwrap();
+    assert_eq!(
+        parsed.group(),
+        &TimeRange {
+            start_timestamp: 1_000.into(),
+            end_timestamp: 2_000.into()
+        }
+    );
+}
+
+#[test]
+fn builders_set_required_params_and_optional_ones() {
+    let req = PublicGetOrderBookRequest::new("BTC-PERPETUAL").depth(5);
+    assert_eq!(
+        req.to_params(),
+        json!({ "instrument_name": "BTC-PERPETUAL", "depth": 5 })
+    );
+
+    // Parameters of a flattened group are set through the request too
+    let req = PrivateGetWithdrawalsRequest::new(Currency::Btc).count(10);
+    assert_eq!(req.pagination, OffsetPagination::new().count(10));
+    assert_eq!(req.to_params(), json!({ "currency": "BTC", "count": 10 }));
+}
@@ -6,7 +6,7 @@ fn public_get_time_response_deserializes_from_number() {
     type Resp = <PublicGetTimeRequest as ApiRequest>::Response;
     let raw = json!(1_755_765_833_825i64);
     let resp: Resp = serde_json::from_value(raw).expect("response type should accept a number");
-    assert_eq!(resp, 1_755_765_833_825i64);
+    assert_eq!(resp, Timestamp(1_755_765_833_825));
 }
 
 #[test]
@@ -44,8 +44,8 @@ fn public_get_currencies_response_deserializes() {
             CurrencyWithApr {
                 currency: "ETH".to_string(),
                 apr: Some(0.0),
-                min_withdrawal_fee: Some(0.001),
-                withdrawal_fee: 0.001,
+                min_withdrawal_fee: "0.001".parse().ok(),
+                withdrawal_fee: "0.001".parse().unwrap(),
                 fee_precision: Some(4),
                 coin_type: CurrencyWithAprCoinType::Eth,
                 withdrawal_priorities: Some(vec![]),
@@ -56,8 +56,8 @@ fn public_get_currencies_response_deserializes() {
             CurrencyWithApr {
                 currency: "BTC".to_string(),
                 apr: Some(0.0),
-                min_withdrawal_fee: Some(0.00001),
-                withdrawal_fee: 0.00001,
+                min_withdrawal_fee: "0.00001".parse().ok(),
+                withdrawal_fee: "0.00001".parse().unwrap(),
                 fee_precision: Some(5),
                 coin_type: CurrencyWithAprCoinType::Btc,
                 withdrawal_priorities: Some(vec![]),
@@ -68,3 +68,70 @@ fn public_get_currencies_response_deserializes() {
         ]
     );
 }
+
+#[test]
+fn rpc_error_details_parse_common_shapes() {
+    let error: RpcError = serde_json::from_value(json!({
+        "code": -32602,
+        "message": "Invalid params",
+        "data": { "reason": "must be one of: limit, market", "param": "type", "value": "lmt" }
+    }))
+    .unwrap();
+    let details = error.details().unwrap();
+    assert_eq!(details.param.as_deref(), Some("type"));
+    assert_eq!(
+        details.reason.as_deref(),
+        Some("must be one of: limit, market")
+    );
+    assert_eq!(details.other["value"], "lmt");
+    assert_eq!(
+        error.to_string(),
+        "RPC Error -32602: Invalid params (type: must be one of: limit, market)"
+    );
+
+    let error: RpcError = serde_json::from_value(json!({
+        "code": 10009,
+        "message": "not_enough_funds",
+        "data": "insufficient margin"
+    }))
+    .unwrap();
+    assert_eq!(
+        error.details().unwrap().reason.as_deref(),
+        Some("insufficient margin")
+    );
+
+    let error: RpcError =
+        serde_json::from_value(json!({ "code": 10028, "message": "too_many_requests" })).unwrap();
+    assert_eq!(error.details(), None);
+    assert_eq!(error.to_string(), "RPC Error 10028: too_many_requests");
+}
+
+#[test]
+fn timestamps_decode_from_milliseconds() {
+    type Resp = <PublicGetTimeRequest as ApiRequest>::Response;
+    let resp: Resp = serde_json::from_value(json!(1_700_000_000_123i64)).unwrap();
+    assert_eq!(resp.as_millis(), 1_700_000_000_123);
+    assert_eq!(
+        serde_json::to_value(resp).unwrap(),
+        json!(1_700_000_000_123i64)
+    );
+
+    let time = std::time::SystemTime::from(resp);
+    assert_eq!(
+        time.duration_since(std::time::UNIX_EPOCH).unwrap(),
+        std::time::Duration::from_millis(1_700_000_000_123)
+    );
+    assert_eq!(Timestamp::from(time), resp);
+    assert_eq!(
+        Timestamp::from(std::time::UNIX_EPOCH - std::time::Duration::from_millis(5)),
+        Timestamp(-5)
+    );
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn timestamps_convert_to_chrono() {
+    let time = chrono::DateTime::<chrono::Utc>::from(Timestamp(1_700_000_000_123));
+    assert_eq!(time.to_rfc3339(), "2023-11-14T22:13:20.123+00:00");
+    assert_eq!(Timestamp::from(time), Timestamp(1_700_000_000_123));
+}
@@ -0,0 +1,54 @@
+use deribit_api::settlements::link_settlements;
+use deribit_api::*;
+use serde_json::json;
+
+#[test]
+fn settlements_link_to_positions() {
+    type Resp = <PrivateGetSettlementHistoryByCurrencyRequest as ApiRequest>::Response;
+    let raw = json!({
+        "settlements": [
+            {
+                "type": "settlement",
+                "timestamp": 1550475692526i64,
+                "session_profit_loss": 0.038358299,
+                "profit_loss": -0.001783937,
+                "position": -66,
+                "mark_price": 121.67,
+                "instrument_name": "ETH-22FEB19",
+                "index_price": 119.8,
+                "funding": 0
+            },
+            {
+                "type": "bankruptcy",
+                "timestamp": 1550475692526i64,
+                "session_profit_loss": 0.001160788,
+                "session_bankruptcy": 0.001160788,
+                "socialized": -0.001160788,
+                "session_tax": -0.001160788,
+                "session_tax_rate": 0.000103333,
+                "funded": 0
+            }
+        ],
+        "continuation": "xY7T6cusbMBNpH9SNmKb94jXSBxUPojJEdCPL4YociHBUgAhWQvEP"
+    });
+    let resp: Resp = serde_json::from_value(raw).expect("settlements should be typed");
+
+    let positions = vec![
+        Position {
+            instrument_name: "ETH-22FEB19".to_string(),
+            ..Default::default()
+        },
+        Position {
+            instrument_name: "ETH-PERPETUAL".to_string(),
+            ..Default::default()
+        },
+    ];
+    let links = link_settlements(&positions, &resp.settlements);
+
+    assert_eq!(links.by_position[0].1, vec![&resp.settlements[0]]);
+    assert!(links.by_position[1].1.is_empty());
+    assert!(links.unmatched.is_empty());
+    assert_eq!(links.bankruptcies.len(), 1);
+    assert!(links.bankruptcies[0].is_bankruptcy());
+    assert_eq!(links.bankruptcies[0].socialized, Some(-0.001160788));
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "simd-json")]
+
+//! Runs response and notification fixtures through the client, which parses them with
+//! simd-json under this feature, and compares the result with serde_json's.
+
+use deribit_api::*;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+fn currencies() -> Value {
+    json!([{
+        "currency": "BTC",
+        "apr": 0,
+        "min_withdrawal_fee": 0.00001,
+        "withdrawal_fee": 0.00001,
+        "fee_precision": 5,
+        "coin_type": "BTC",
+        "withdrawal_priorities": [{ "name": "high", "value": 1.5 }],
+        "min_confirmations": 1,
+        "currency_long": "Bitcoin \u{20bf}",
+        "in_cross_collateral_pool": true
+    }])
+}
+
+fn trades() -> Value {
+    json!([{
+        "trade_seq": 30289432,
+        "trade_id": "48079254",
+        "timestamp": 1_590_484_156_350i64,
+        "tick_direction": 0,
+        "price": 8950.5,
+        "mark_price": 8948.9,
+        "instrument_name": "BTC-PERPETUAL",
+        "index_price": 8955.88,
+        "direction": "sell",
+        "amount": 10
+    }])
+}
+
+// Answers `public/get_currencies` and subscriptions, then publishes `trades()` on every
+// subscribed channel, with a frame formatted unlike serde_json's output
+async fn server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws.next().await {
+            let request: Value = serde_json::from_str(&text).unwrap();
+            let result = match request["method"].as_str().unwrap() {
+                "public/get_currencies" => currencies(),
+                _ => request["params"]["channels"].clone(),
+            };
+            let reply = json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": result,
+                "testnet": false,
+                "usIn": 1_000,
+                "usOut": 1_250,
+                "usDiff": 250,
+            });
+            ws.send(Message::Text(reply.to_string().into()))
+                .await
+                .unwrap();
+            if request["method"] == "public/subscribe" {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                for channel in result.as_array().unwrap() {
+                    let frame = format!(
+                        "{{ \"jsonrpc\": \"2.0\", \"method\": \"subscription\",\n  \
+                         \"params\": {{ \"channel\": {channel}, \"data\": {:#} }} }}",
+                        trades()
+                    );
+                    ws.send(Message::Text(frame.into())).await.unwrap();
+                }
+            }
+        }
+    });
+    format!("ws://{addr}")
+}
+
+#[tokio::test]
+async fn fixtures_decode_as_with_serde_json() {
+    let client = DeribitClient::connect(Env::Custom(server().await))
+        .await
+        .unwrap();
+
+    let currencies_resp = client.call(PublicGetCurrenciesRequest {}).await.unwrap();
+    assert_eq!(
+        currencies_resp,
+        serde_json::from_value::<Vec<CurrencyWithApr>>(currencies()).unwrap()
+    );
+
+    let channel = TradesInstrumentNameChannel {
+        instrument_name: "BTC-PERPETUAL".to_string(),
+        interval: SubscriptionInterval::Raw,
+    };
+    let mut typed = client.subscribe(channel).await.unwrap();
+    let mut raw = client
+        .subscribe_raw("trades.ETH-PERPETUAL.raw")
+        .await
+        .unwrap();
+    assert_eq!(
+        typed.next().await.unwrap().unwrap(),
+        serde_json::from_value::<Vec<PublicTrade>>(trades()).unwrap()
+    );
+    let data = raw.next().await.unwrap().unwrap();
+    assert_eq!(serde_json::from_str::<Value>(data.get()).unwrap(), trades());
+}
@@ -0,0 +1,72 @@
+use deribit_api::*;
+
+#[test]
+fn instrument_names_round_trip() {
+    let option: InstrumentName = "BTC-28MAR25-60000-C".parse().unwrap();
+    assert!(option.is_option());
+    assert_eq!(option.currency, "BTC");
+    assert_eq!(option.settlement_currency(), "BTC");
+    assert_eq!(
+        option.expiry,
+        Some(Expiry {
+            year: 2025,
+            month: 3,
+            day: 28
+        })
+    );
+    assert_eq!(option.strike, Some(60000.0));
+    assert_eq!(option.option_type, Some(InstrumentOptionType::Call));
+    // 2025-03-28T08:00:00Z
+    assert_eq!(
+        option.expiry_timestamp(),
+        Some(Timestamp(1_743_148_800_000))
+    );
+
+    let linear: InstrumentName = "XRP_USDC-7MAR25-0d625-P".parse().unwrap();
+    assert!(linear.is_linear());
+    assert_eq!(linear.settlement_currency(), "USDC");
+    assert_eq!(linear.strike, Some(0.625));
+    assert_eq!(linear.option_type, Some(InstrumentOptionType::Put));
+
+    let perpetual: InstrumentName = "ETH-PERPETUAL".parse().unwrap();
+    assert!(perpetual.is_perpetual() && !perpetual.is_future());
+    assert_eq!(perpetual.expiry_timestamp(), None);
+
+    let spot: InstrumentName = "BTC_USDC".parse().unwrap();
+    assert!(spot.is_spot() && !spot.is_linear());
+
+    for name in [
+        "BTC-28MAR25-60000-C",
+        "XRP_USDC-7MAR25-0d625-P",
+        "ETH-PERPETUAL",
+        "BTC_USDC-PERPETUAL",
+        "ETH-27DEC24",
+        "BTC_USDC",
+    ] {
+        let parsed: InstrumentName = name.parse().unwrap();
+        assert_eq!(parsed.to_string(), name);
+        let json = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(json, name);
+        assert_eq!(
+            serde_json::from_value::<InstrumentName>(json).unwrap(),
+            parsed
+        );
+    }
+}
+
+#[test]
+fn invalid_instrument_names_are_rejected() {
+    for name in [
+        "",
+        "BTC",
+        "btc-PERPETUAL",
+        "BTC-30FEB25",
+        "BTC-28MAR25-60000",
+        "BTC-28MAR25-60000-X",
+        "BTC-28MAR25-0-C",
+        "BTC-FS-27DEC24_PERP",
+    ] {
+        let error = name.parse::<InstrumentName>().unwrap_err();
+        assert_eq!(error, ParseInstrumentNameError(name.to_string()));
+    }
+}